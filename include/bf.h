@@ -0,0 +1,98 @@
+/*
+ * The C API of the Brainfuck interpreter, implemented in src/ffi.rs. Link
+ * with the shared library built by `cargo build --release --lib`
+ * (target/release/libbf.so, libbf.dylib or bf.dll).
+ */
+#ifndef BF_H
+#define BF_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* What bf_step returns. */
+#define BF_RUNNING 0
+#define BF_INPUT 1
+#define BF_FINISHED 2
+#define BF_OUT_OF_FUEL 3
+#define BF_ERROR (-1)
+
+/* A program running on its own tape, with buffered input and output. */
+typedef struct BfSession BfSession;
+
+/*
+ * Creates a session running `program`, a NUL-terminated string, on a tape
+ * of 30,000 8-bit cells. A program that doesn't compile still gets a
+ * session, whose bf_step fails with the reason.
+ */
+BfSession *bf_new(const char *program);
+
+/*
+ * Runs up to `steps` instructions. Returns BF_RUNNING if they all ran,
+ * BF_INPUT if a `,` is waiting for bf_set_input or bf_close_input,
+ * BF_OUT_OF_FUEL if the fuel given with bf_set_fuel ran out, BF_FINISHED at
+ * the end of the program and BF_ERROR if it failed.
+ */
+int bf_step(BfSession *bf, uint32_t steps);
+
+/*
+ * Meters the program: every instruction burns fuel, one unit for most, more
+ * for I/O, and bf_step returns BF_OUT_OF_FUEL before one there isn't enough
+ * left for. The same program and input always run out at the same point.
+ */
+void bf_set_fuel(BfSession *bf, uint64_t fuel);
+
+/* Adds fuel, for bf_step to go on after BF_OUT_OF_FUEL. */
+void bf_add_fuel(BfSession *bf, uint64_t fuel);
+
+/* The fuel left, or UINT64_MAX if the program isn't metered. */
+uint64_t bf_fuel(const BfSession *bf);
+
+/*
+ * The instructions executed since the program was loaded. They're those of
+ * the optimized program, so the count for a program only changes when the
+ * optimizer does, which makes it something tests can assert on.
+ */
+uint64_t bf_steps(const BfSession *bf);
+
+/* Adds `len` bytes from `input` for `,` to read. */
+void bf_set_input(BfSession *bf, const uint8_t *input, size_t len);
+
+/*
+ * Makes `,` see the end of input once what was given to bf_set_input is
+ * read; by default that stops the program.
+ */
+void bf_close_input(BfSession *bf);
+
+/*
+ * Copies up to `len` bytes of the output not yet taken into `buf`, oldest
+ * first, and returns how many it copied. Call it until it returns less than
+ * `len` to take all of it.
+ */
+size_t bf_get_output(BfSession *bf, uint8_t *buf, size_t len);
+
+/*
+ * Why the session failed, as a string that lives as long as the session, or
+ * NULL if it hasn't.
+ */
+const char *bf_error(const BfSession *bf);
+
+/* Frees a session. NULL is ignored. */
+void bf_free(BfSession *bf);
+
+/*
+ * Writes a random program of `size` commands with balanced brackets to
+ * `buf`, which must have room for `size + 1` bytes, followed by a NUL, for
+ * fuzzing. The same `seed` gives the same program, and `loops`, from 0 to 1,
+ * is the chance of each command being a bracket.
+ */
+void bf_gen_random(char *buf, size_t size, uint64_t seed, double loops);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,153 @@
+//! `bf bench`: runs a program several times in one or more interpreter
+//! configurations and reports how long the runs took.
+
+use crate::bf_interpreter::BfInterpreter;
+use crate::cli::{BenchConfig, BenchOptions, Input};
+use crate::{bytecode, fuse};
+use std::fmt::Write;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+/// Wall times of the runs of one configuration.
+#[derive(Debug, PartialEq)]
+struct Summary {
+    min: Duration,
+    mean: Duration,
+    stddev: Duration,
+}
+
+impl Summary {
+    fn new(times: &[Duration]) -> Self {
+        let secs: Vec<f64> = times.iter().map(Duration::as_secs_f64).collect();
+        let mean = secs.iter().sum::<f64>() / secs.len() as f64;
+        let variance = secs.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / secs.len() as f64;
+        Summary {
+            min: times.iter().copied().min().unwrap_or_default(),
+            mean: Duration::from_secs_f64(mean),
+            stddev: Duration::from_secs_f64(variance.sqrt()),
+        }
+    }
+}
+
+pub fn run(options: BenchOptions) -> Result<(), String> {
+    let content = crate::runner::read_file(&options.program)?;
+    let (ops, _) = crate::runner::load_ops(&content, false)?;
+    if bytecode::is_bytecode(&content) && options.configs.iter().any(|c| c.unsafe_fast) {
+        return Err("--unsafe-fast only works with source, not bytecode".to_owned());
+    }
+    let input = match options.input {
+        Input::Stdin => crate::runner::read_file("-")?,
+        Input::File(path) => crate::runner::read_file(&path)?,
+        Input::String(input) => input.into_bytes(),
+    };
+
+    let mut rows = vec![];
+    for config in &options.configs {
+        let program = if config.fuse {
+            fuse::fuse(&ops, None).ops
+        } else {
+            ops.clone()
+        };
+        let mut times = vec![];
+        let mut steps = 0;
+        for _ in 0..options.iterations {
+            let mut interpreter = BfInterpreter::builder()
+                .config(options.config.clone())
+                .dispatch(config.dispatch)
+                .unsafe_fast(config.unsafe_fast)
+                .build_ops(program.clone())?;
+            let start = Instant::now();
+            crate::runner::run_interpreter(
+                &mut interpreter,
+                &mut Cursor::new(&input),
+                &mut std::io::sink(),
+                None,
+                None,
+                None,
+                false,
+            )
+            .map_err(|e| format!("{}: {}", config.name, e))?;
+            times.push(start.elapsed());
+            steps = interpreter.stats().steps;
+        }
+        rows.push((config, Summary::new(&times), steps));
+    }
+    print!("{}", report(&rows));
+    Ok(())
+}
+
+/// A table with a row for each configuration.
+fn report(rows: &[(&BenchConfig, Summary, u64)]) -> String {
+    let width = rows
+        .iter()
+        .map(|(config, _, _)| config.name.len())
+        .chain(["config".len()])
+        .max()
+        .unwrap();
+    let mut out = format!(
+        "{:<width$}  {:>10}  {:>10}  {:>10}  {:>14}\n",
+        "config",
+        "min",
+        "mean",
+        "stddev",
+        "instructions/s",
+        width = width
+    );
+    for (config, summary, steps) in rows {
+        let rate = *steps as f64 / summary.mean.as_secs_f64();
+        writeln!(
+            out,
+            "{:<width$}  {:>10}  {:>10}  {:>10}  {:>14}",
+            config.name,
+            time(summary.min),
+            time(summary.mean),
+            time(summary.stddev),
+            if rate.is_finite() {
+                format!("{:.0}", rate)
+            } else {
+                "-".to_owned()
+            },
+            width = width
+        )
+        .unwrap();
+    }
+    out
+}
+
+fn time(duration: Duration) -> String {
+    format!("{:.2?}", duration)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::bf_interpreter::Dispatch;
+
+    #[test]
+    fn summarize() {
+        let ms = Duration::from_millis;
+        let summary = Summary::new(&[ms(30), ms(10), ms(20)]);
+        assert_eq!(summary.min, ms(10));
+        assert_eq!(summary.mean.as_micros(), 20_000);
+        // The population standard deviation: sqrt(200/3) ms.
+        assert_eq!(summary.stddev.as_micros(), 8164);
+    }
+
+    #[test]
+    fn report_table() {
+        let config = BenchConfig {
+            name: "-O0 --dispatch threaded".to_owned(),
+            fuse: false,
+            dispatch: Dispatch::Threaded,
+            unsafe_fast: false,
+        };
+        let summary = Summary::new(&[Duration::from_millis(500)]);
+        assert_eq!(
+            report(&[(&config, summary, 1000)]),
+            "config                          min        mean      stddev  instructions/s
+-O0 --dispatch threaded    500.00ms    500.00ms      0.00ns            2000
+"
+        );
+    }
+}
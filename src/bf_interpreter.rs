@@ -1,174 +1,1036 @@
+use crate::bounds;
+use crate::config::{Config, Eof, TapeMode};
+use crate::ir::{self, Op};
+use crate::parser::Extended;
+use crate::plugin::{InterpreterState, Registry};
+use crate::tape::Tape;
 use std::collections::HashMap;
+use std::fmt::Write;
+use std::ops::Range;
+use std::str::FromStr;
 
-#[derive(Debug)]
-pub(crate) struct BfInterpreter {
+/// Cells shown on either side of the data pointer by `debug_dump`.
+const DEBUG_CONTEXT: usize = 4;
+
+/// Nested pbrain calls allowed, so runaway recursion fails instead of using
+/// up all memory.
+const MAX_CALL_DEPTH: usize = 1 << 20;
+
+#[derive(Clone, Debug)]
+pub struct BfInterpreter {
     pc: usize,
     data_ptr: usize,
-    program: Box<[Token]>,
-    cells: Vec<u8>,
-    matching_parens: HashMap<usize, usize>,
+    program: Box<[Op]>,
+    tape: Tape,
+    tape_mode: TapeMode,
+    eof: Eof,
+    cell_overflow: CellOverflow,
+    stats: Stats,
+    max_steps: Option<u64>,
+    /// What's left of the fuel given with `set_fuel`, if the run is metered.
+    fuel: Option<u64>,
+    /// Execution count per instruction, if profiling.
+    profile: Option<Box<[u64]>>,
+    /// Whether a `,` is waiting for `set_input` or `set_eof`.
+    pending_input: bool,
+    /// Where the body of each pbrain procedure defined so far starts, by
+    /// number.
+    procedures: HashMap<u32, usize>,
+    /// Where to return to from each pbrain procedure being run.
+    call_stack: Vec<usize>,
+    /// The storage cell of Extended Brainfuck Type I.
+    storage: u32,
+    /// Commands added by plugins.
+    plugins: Registry,
+    /// Whether the program runs from the tape, see `build_self_modifying`.
+    self_modifying: bool,
+    /// The handler of each instruction of `program`, with
+    /// `Dispatch::Threaded`.
+    handlers: Option<Box<[Handler]>>,
+    /// With `BfInterpreterBuilder::unsafe_fast`, the highest cell
+    /// `bounds::max_index` proved the program can reach, if it's on the
+    /// tape. Moves and the cell at the data pointer aren't checked then.
+    unchecked: Option<usize>,
 }
 
-impl BfInterpreter {
-    pub(crate) fn new(program: &[u8]) -> Result<Self, String> {
-        let program = Self::parse_program(program);
-        let matching_parens = Self::find_matching_parens(&program)?;
-        Ok(Self {
-            pc: 0,
-            data_ptr: 0,
-            program,
-            cells: vec![0u8; 30_000],
-            matching_parens,
-        })
+/// Runs an instruction, the one given, which is the one at the program
+/// counter.
+type Handler = fn(&mut BfInterpreter, Op) -> Result<Ret, String>;
+
+/// Execution counters, updated as the program runs.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// Instructions of the optimized program executed so far.
+    pub steps: u64,
+    pub adds: u64,
+    pub moves: u64,
+    pub sets: u64,
+    pub outputs: u64,
+    pub inputs: u64,
+    /// Loop instructions, whether or not they jumped.
+    pub loop_starts: u64,
+    pub loop_ends: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// The largest cell index the data pointer has reached.
+    pub peak_index: usize,
+}
+
+/// What `+` and `-` do when a cell goes past its largest value or below 0.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum CellOverflow {
+    /// Wrap around at the cell width, as usual.
+    #[default]
+    Wrap,
+    /// Stop with an error. Runs of `+` or of `-` are checked as a whole,
+    /// after they have been folded, which needs the program compiled with
+    /// `ir::compile_strict`.
+    Error,
+}
+
+impl FromStr for CellOverflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrap" => Ok(CellOverflow::Wrap),
+            "error" => Ok(CellOverflow::Error),
+            _ => Err(format!(
+                "Invalid cell overflow behavior '{}', expected wrap or error",
+                s
+            )),
+        }
     }
+}
 
-    fn parse_program(program: &[u8]) -> Box<[Token]> {
-        use Token::*;
-        program
-            .iter()
-            .flat_map(|b| {
-                match b {
-                    b'>' => Some(IncDataPtr),
-                    b'<' => Some(DecDataPtr),
-                    b'+' => Some(IncByte),
-                    b'-' => Some(DecByte),
-                    b'.' => Some(WriteByte),
-                    b',' => Some(ReadByte),
-                    b'[' => Some(BeginLoop),
-                    b']' => Some(EndLoop),
-                    _ => {
-                        // Ignore all other bytes.
-                        None
-                    }
-                }
-            })
-            .collect::<Vec<_>>()
-            .into_boxed_slice()
+/// How `step` gets to the code of an instruction.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Dispatch {
+    /// A `match` on the instruction.
+    #[default]
+    Match,
+    /// A call through a table of function pointers built with the program,
+    /// one per instruction, like threaded code.
+    Threaded,
+}
+
+impl FromStr for Dispatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "match" => Ok(Dispatch::Match),
+            "threaded" => Ok(Dispatch::Threaded),
+            _ => Err(format!(
+                "Invalid dispatch '{}', expected match or threaded",
+                s
+            )),
+        }
     }
+}
 
-    fn find_matching_parens(program: &[Token]) -> Result<HashMap<usize, usize>, String> {
-        let mut map = HashMap::new();
-        let mut stack = vec![];
+/// How much fuel `op` burns, see `BfInterpreter::set_fuel`. Superinstructions
+/// cost as much as the instructions they replace. I/O, calls, forks and
+/// plugins cost more, since they make the host or the interpreter do more
+/// work than a change to a cell.
+fn fuel_cost(op: Op) -> u64 {
+    match op {
+        Op::AddMove(..) | Op::MoveAdd(..) => 2,
+        Op::AddAt(..) => 3,
+        Op::Output | Op::Input | Op::OutputNumber | Op::InputNumber | Op::Call => 4,
+        Op::Fork | Op::Plugin(_) => 16,
+        _ => 1,
+    }
+}
 
-        for (i, b) in program.iter().copied().enumerate() {
-            if b == Token::BeginLoop {
-                stack.push((i, b));
-            } else if b == Token::EndLoop {
-                let (matching_index, _) = stack.pop().ok_or_else(|| "Missing '['".to_owned())?;
-                map.insert(i, matching_index);
-                map.insert(matching_index, i);
+/// The handlers of `Dispatch::Threaded` for `program`. The instructions that
+/// run the most get their own, the others go through `execute`.
+fn handlers(program: &[Op]) -> Box<[Handler]> {
+    program
+        .iter()
+        .map(|op| -> Handler {
+            match op {
+                Op::Add(_) => |bf, op| {
+                    let Op::Add(n) = op else { unreachable!() };
+                    bf.add(n)?;
+                    bf.pc += 1;
+                    Ok(Ret::Continue)
+                },
+                Op::Move(_) => |bf, op| {
+                    let Op::Move(n) = op else { unreachable!() };
+                    bf.move_by(n)?;
+                    bf.pc += 1;
+                    Ok(Ret::Continue)
+                },
+                Op::Set(_) => |bf, op| {
+                    let Op::Set(n) = op else { unreachable!() };
+                    bf.set(n)?;
+                    Ok(Ret::Continue)
+                },
+                Op::LoopStart(_) => |bf, op| {
+                    let Op::LoopStart(end) = op else {
+                        unreachable!()
+                    };
+                    bf.loop_start(end);
+                    Ok(Ret::Continue)
+                },
+                Op::LoopEnd(_) => |bf, op| {
+                    let Op::LoopEnd(start) = op else {
+                        unreachable!()
+                    };
+                    bf.loop_end(start);
+                    Ok(Ret::Continue)
+                },
+                _ => |bf, op| bf.execute(op),
             }
+        })
+        .collect()
+}
+
+/// Compiles `program` folding only what `cell_overflow` allows.
+fn compile(program: &[u8], cell_overflow: CellOverflow) -> Result<Box<[Op]>, String> {
+    match cell_overflow {
+        CellOverflow::Wrap => ir::compile(program),
+        CellOverflow::Error => ir::compile_strict(program, false, &[]).map(|(ops, _)| ops),
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BfInterpreterBuilder {
+    config: Config,
+    max_steps: Option<u64>,
+    profile: bool,
+    cell_overflow: CellOverflow,
+    plugins: Registry,
+    dispatch: Dispatch,
+    tape_file: Option<String>,
+    unsafe_fast: bool,
+}
+
+impl BfInterpreterBuilder {
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Makes `step` fail once the program has executed `max_steps`
+    /// instructions of the optimized program.
+    pub fn max_steps(mut self, max_steps: Option<u64>) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// Only supported by the interpreter, so not part of `Config`.
+    pub fn cell_overflow(mut self, cell_overflow: CellOverflow) -> Self {
+        self.cell_overflow = cell_overflow;
+        self
+    }
+
+    /// What the commands compiled to `Op::Plugin` do.
+    pub fn plugins(mut self, plugins: Registry) -> Self {
+        self.plugins = plugins;
+        self
+    }
+
+    /// How `step` runs instructions, see `Dispatch`.
+    pub fn dispatch(mut self, dispatch: Dispatch) -> Self {
+        self.dispatch = dispatch;
+        self
+    }
+
+    /// Keeps the tape in a file instead of memory, see `Tape::mapped`.
+    pub fn tape_file(mut self, path: Option<String>) -> Self {
+        self.tape_file = path;
+        self
+    }
+
+    /// Counts how often each instruction runs, see `BfInterpreter::profile`.
+    pub fn profile(mut self, profile: bool) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Skips the bounds checks on the data pointer if static analysis
+    /// proves it stays on the tape, see `bounds::max_index`. Programs it
+    /// can't prove anything about run with the checks.
+    pub fn unsafe_fast(mut self, unsafe_fast: bool) -> Self {
+        self.unsafe_fast = unsafe_fast;
+        self
+    }
+
+    pub(crate) fn build(self, program: &[u8]) -> Result<BfInterpreter, String> {
+        let ops = compile(program, self.cell_overflow)?;
+        self.build_ops(ops)
+    }
+
+    /// Creates an interpreter for an already compiled program, e.g. one loaded
+    /// from bytecode. Loop targets are trusted to be valid.
+    pub fn build_ops(self, program: Box<[Op]>) -> Result<BfInterpreter, String> {
+        let profile = self
+            .profile
+            .then(|| vec![0; program.len()].into_boxed_slice());
+        let mut tape = match &self.tape_file {
+            Some(path) => Tape::mapped(self.config.cell_size, self.config.tape_size, path)?,
+            None => Tape::new(self.config.cell_size, self.config.tape_size),
+        };
+        // Wrapping around the tape has no checks to skip.
+        let unchecked = match self.config.tape_mode {
+            TapeMode::Wrap => None,
+            _ if !self.unsafe_fast => None,
+            _ => bounds::max_index(&program).filter(|&max| max < tape.len()),
+        };
+        if let Some(max) = unchecked {
+            tape.reserve(max);
         }
+        Ok(BfInterpreter {
+            pc: 0,
+            data_ptr: 0,
+            tape,
+            tape_mode: self.config.tape_mode,
+            eof: self.config.eof,
+            cell_overflow: self.cell_overflow,
+            stats: Stats::default(),
+            max_steps: self.max_steps,
+            fuel: None,
+            profile,
+            pending_input: false,
+            procedures: HashMap::new(),
+            call_stack: vec![],
+            storage: 0,
+            handlers: (self.dispatch == Dispatch::Threaded).then(|| handlers(&program)),
+            program,
+            plugins: self.plugins,
+            self_modifying: false,
+            unchecked,
+        })
+    }
 
-        if !stack.is_empty() {
-            return Err("Missing ']'".to_owned());
+    /// Creates an interpreter that runs the program from the tape, where it
+    /// is loaded at the start, so it can change its own code. The data
+    /// pointer starts on the cell right after it, and the program ends at
+    /// the first cell that is 0. The program counter is an index into the
+    /// tape.
+    pub fn build_self_modifying(self, program: &[u8]) -> Result<BfInterpreter, String> {
+        let tape_mode = self.config.tape_mode;
+        let mut interpreter = self.build_ops(Box::new([]))?;
+        if program.len() >= interpreter.tape.len() {
+            if tape_mode != TapeMode::Grow {
+                return Err(format!(
+                    "The program needs a tape of more than {} cells",
+                    program.len()
+                ));
+            }
+            interpreter.grow_tape(program.len());
         }
+        for (i, &b) in program.iter().enumerate() {
+            interpreter.tape.set(i, b as u32);
+        }
+        interpreter.data_ptr = program.len();
+        interpreter.self_modifying = true;
+        // The program isn't known in advance.
+        interpreter.unchecked = None;
+        Ok(interpreter)
+    }
+}
 
-        Ok(map)
+impl BfInterpreter {
+    pub fn builder() -> BfInterpreterBuilder {
+        BfInterpreterBuilder::default()
     }
 
     pub(crate) fn step(&mut self) -> Result<Ret, String> {
-        if self.pc >= self.program.len() {
+        let p = if self.self_modifying {
+            match self.fetch()? {
+                Some(op) => op,
+                None => return Ok(Ret::Finished),
+            }
+        } else if self.pc >= self.program.len() {
             return Ok(Ret::Finished);
+        } else {
+            self.program[self.pc]
+        };
+
+        if self.max_steps == Some(self.stats.steps) {
+            return Err(format!("Step limit of {} exceeded", self.stats.steps));
+        }
+        if let Some(fuel) = self.fuel {
+            let cost = fuel_cost(p);
+            if cost > fuel {
+                return Ok(Ret::OutOfFuel);
+            }
+            self.fuel = Some(fuel - cost);
+        }
+        self.stats.steps += 1;
+        if let Some(profile) = &mut self.profile {
+            profile[self.pc] += 1;
         }
 
-        let p = self.program[self.pc];
+        match &self.handlers {
+            // A self-modifying program isn't known in advance.
+            Some(handlers) if !self.self_modifying => {
+                let handler = handlers[self.pc];
+                handler(self, p)
+            }
+            _ => self.execute(p),
+        }
+    }
 
-        use Token::*;
+    /// Runs `p`, the instruction at the program counter.
+    fn execute(&mut self, p: Op) -> Result<Ret, String> {
         match p {
-            IncDataPtr => {
-                // Increment the data pointer by one (to point to the next cell to the right).
-                if self.data_ptr == self.cells.len() - 1 {
-                    return Err("Memory overflow".to_owned());
-                }
-                self.data_ptr += 1;
+            Op::Add(n) => {
+                self.add(n)?;
                 self.pc += 1;
             }
-            DecDataPtr => {
-                // Decrement the data pointer by one (to point to the next cell to the left).
-                if self.data_ptr == 0 {
-                    return Err("Memory underflow".to_owned());
-                }
-
-                self.data_ptr -= 1;
+            Op::Move(n) => {
+                self.move_by(n)?;
                 self.pc += 1;
             }
-            IncByte => {
-                // Increment the byte at the data pointer by one.
-                self.cells[self.data_ptr] = self.cells[self.data_ptr].wrapping_add(1);
+            // Fused instructions count as the ones they replace in the
+            // stats, but as one step.
+            Op::AddMove(n, m) => {
+                self.add(n)?;
+                self.move_by(m)?;
                 self.pc += 1;
             }
-            DecByte => {
-                // Decrement the byte at the data pointer by one.
-                self.cells[self.data_ptr] = self.cells[self.data_ptr].wrapping_sub(1);
+            Op::MoveAdd(m, n) => {
+                self.move_by(m)?;
+                self.add(n)?;
                 self.pc += 1;
             }
-
-            WriteByte => {
-                // Output the byte at the data pointer.
+            Op::AddAt(offset, n) => {
+                self.move_by(offset)?;
+                self.add(n)?;
+                self.move_by(-offset)?;
                 self.pc += 1;
-                return Ok(Ret::Output(self.cells[self.data_ptr]));
             }
-            ReadByte => {
+            Op::Set(n) => self.set(n)?,
+            Op::Output => {
+                // Output the low byte of the cell at the data pointer.
+                self.stats.outputs += 1;
+                self.stats.bytes_written += 1;
+                self.pc += 1;
+                return Ok(Ret::Output(self.tape.get(self.data_ptr) as u8));
+            }
+            Op::Input => {
                 // Accept one byte of input, storing its value in the byte at the data pointer.
+                self.stats.inputs += 1;
                 self.pc += 1;
+                self.pending_input = true;
                 return Ok(Ret::Input);
             }
-            BeginLoop => {
-                // If the byte at the data pointer is zero, then instead of moving
-                // the instruction pointer forward to the next command, jump it
-                // forward to the command after the matching ] command.
-                if self.cells[self.data_ptr] == 0 {
-                    self.pc = self.matching_parens[&self.pc] + 1;
-                } else {
-                    self.pc += 1;
+            Op::LoopStart(end) => self.loop_start(end),
+            Op::LoopEnd(start) => self.loop_end(start),
+            Op::Debug => {
+                self.pc += 1;
+                return Ok(Ret::Debug);
+            }
+            Op::ProcStart(end) => {
+                // Defining a procedure replaces any earlier one with the same
+                // number. The body only runs when called.
+                self.procedures
+                    .insert(self.tape.get(self.data_ptr), self.pc + 1);
+                self.pc = end + 1;
+            }
+            Op::ProcEnd => {
+                self.pc = self
+                    .call_stack
+                    .pop()
+                    .ok_or_else(|| "Return outside a procedure".to_owned())?;
+            }
+            Op::Call => {
+                let number = self.tape.get(self.data_ptr);
+                let body = *self
+                    .procedures
+                    .get(&number)
+                    .ok_or_else(|| format!("Call to undefined procedure {}", number))?;
+                if self.call_stack.len() == MAX_CALL_DEPTH {
+                    return Err("Call stack overflow".to_owned());
                 }
+                self.call_stack.push(self.pc + 1);
+                self.pc = body;
+            }
+            Op::Fork => {
+                // The new thread is made by `fork`.
+                self.tape.set(self.data_ptr, 0);
+                self.pc += 1;
+                return Ok(Ret::Fork);
+            }
+            Op::Extended(command) => {
+                let value = self.tape.get(self.data_ptr);
+                let value = match command {
+                    Extended::End => {
+                        self.pc = self.program.len();
+                        return Ok(Ret::Finished);
+                    }
+                    Extended::Store => {
+                        self.storage = value;
+                        value
+                    }
+                    Extended::Load => self.storage,
+                    // Bits shifted out of the cell are lost.
+                    Extended::ShiftLeft => value << 1,
+                    Extended::ShiftRight => value >> 1,
+                    Extended::Not => !value,
+                    Extended::Xor => value ^ self.storage,
+                    Extended::And => value & self.storage,
+                    Extended::Or => value | self.storage,
+                };
+                self.tape.set(self.data_ptr, value);
+                self.pc += 1;
+            }
+            Op::Plugin(command) => {
+                self.pc += 1;
+                let mut state = InterpreterState {
+                    tape: &mut self.tape,
+                    data_ptr: &mut self.data_ptr,
+                };
+                self.plugins.run(command, &mut state)?;
             }
+            Op::OutputNumber => {
+                self.stats.outputs += 1;
+                self.stats.bytes_written += 1;
+                self.pc += 1;
+                return Ok(Ret::OutputNumber(self.tape.get(self.data_ptr)));
+            }
+            Op::InputNumber => {
+                // Stored with `set_input_number`, like `--numeric-io` input.
+                self.stats.inputs += 1;
+                self.pc += 1;
+                self.pending_input = true;
+                return Ok(Ret::InputNumber);
+            }
+        }
 
-            EndLoop => {
-                // If the byte at the data pointer is nonzero, then instead of moving
-                // the instruction pointer forward to the next command, jump it
-                // back to the command after the matching [ command.
-                if self.cells[self.data_ptr] != 0 {
-                    self.pc = self.matching_parens[&self.pc] + 1;
-                } else {
+        Ok(Ret::Continue)
+    }
+
+    /// Runs up to `steps` instructions, returning early for anything the
+    /// caller has to handle: I/O, `#`, a fork or the end of the program.
+    /// Returns `Ret::Continue` if all of them ran.
+    pub(crate) fn run_batch(&mut self, steps: u32) -> Result<Ret, String> {
+        for _ in 0..steps {
+            match self.step()? {
+                Ret::Continue => {}
+                ret => return Ok(ret),
+            }
+        }
+        Ok(Ret::Continue)
+    }
+
+    /// Decodes the command on the tape at the program counter, skipping
+    /// cells that aren't one. Returns None at the end of the program. Brackets
+    /// are matched as they run, since the code may have changed.
+    fn fetch(&mut self) -> Result<Option<Op>, String> {
+        let zero = self.tape.get(self.data_ptr) == 0;
+        while self.pc < self.tape.len() {
+            let op = match u8::try_from(self.tape.get(self.pc)) {
+                Ok(0) => break,
+                Ok(b'+') => Op::Add(1),
+                Ok(b'-') => Op::Add(u32::MAX),
+                Ok(b'>') => Op::Move(1),
+                Ok(b'<') => Op::Move(-1),
+                Ok(b'.') => Op::Output,
+                Ok(b',') => Op::Input,
+                Ok(b'[') if zero => Op::LoopStart(self.matching_bracket(true)?),
+                Ok(b']') if !zero => Op::LoopEnd(self.matching_bracket(false)?),
+                // Brackets that don't jump don't need their match.
+                Ok(b'[') => Op::LoopStart(self.pc),
+                Ok(b']') => Op::LoopEnd(self.pc),
+                _ => {
                     self.pc += 1;
+                    continue;
+                }
+            };
+            return Ok(Some(op));
+        }
+        Ok(None)
+    }
+
+    /// Finds the bracket on the tape that matches the one at the program
+    /// counter, looking forward from a `[` up to the end of the program, or
+    /// back from a `]`.
+    fn matching_bracket(&self, forward: bool) -> Result<usize, String> {
+        let (open, close) = if forward { (b'[', b']') } else { (b']', b'[') };
+        let mut depth = 0;
+        let mut i = self.pc;
+        loop {
+            let cell = self.tape.get(i);
+            if cell == open as u32 {
+                depth += 1;
+            } else if cell == close as u32 {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(i);
+                }
+            }
+            if forward {
+                i += 1;
+                if i == self.tape.len() || self.tape.get(i) == 0 {
+                    return Err("Missing ']'".to_owned());
                 }
+            } else if i == 0 {
+                return Err("Missing '['".to_owned());
+            } else {
+                i -= 1;
             }
         }
+    }
 
-        Ok(Ret::Continue)
+    /// Starts a Brainfork thread after `step` returned `Ret::Fork`: a copy of
+    /// this one with the data pointer moved one cell to the right, onto a 1.
+    pub(crate) fn fork(&self) -> Result<BfInterpreter, String> {
+        let mut thread = self.clone();
+        thread.data_ptr = thread.moved_data_ptr(1)?;
+        thread.tape.set(thread.data_ptr, 1);
+        Ok(thread)
     }
 
     pub(crate) fn set_input(&mut self, input: u8) {
-        self.cells[self.data_ptr] = input;
+        self.pending_input = false;
+        self.stats.bytes_read += 1;
+        self.tape.set(self.data_ptr, input as u32);
+    }
+
+    /// Replaces the program and starts it from the beginning, keeping the
+    /// tape and the data pointer as they are. Used by the REPL to run each
+    /// line on the same tape. Statistics and the step limit start over too.
+    pub(crate) fn load(&mut self, program: &[u8]) -> Result<(), String> {
+        self.program = compile(program, self.cell_overflow)?;
+        self.unchecked = None;
+        if self.handlers.is_some() {
+            self.handlers = Some(handlers(&self.program));
+        }
+        self.pc = 0;
+        self.pending_input = false;
+        self.procedures.clear();
+        self.call_stack.clear();
+        self.storage = 0;
+        self.stats = Stats::default();
+        if let Some(profile) = &mut self.profile {
+            *profile = vec![0; self.program.len()].into_boxed_slice();
+        }
+        Ok(())
+    }
+
+    /// Like `set_input`, for a whole number read with `--numeric-io`. It
+    /// counts as a single byte read.
+    pub(crate) fn set_input_number(&mut self, value: u32) {
+        self.pending_input = false;
+        self.stats.bytes_read += 1;
+        self.tape.set(self.data_ptr, value);
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn program(&self) -> &[Op] {
+        &self.program
+    }
+
+    /// Index of the next instruction in `program`.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn data_ptr(&self) -> usize {
+        self.data_ptr
+    }
+
+    /// Index of the cell `offset` cells from the data pointer, or `None` if
+    /// that is off the tape. On a tape that grows it may be past the end.
+    pub(crate) fn cell_index(&self, offset: isize) -> Option<usize> {
+        if self.unchecked.is_some() {
+            return Some(self.data_ptr.wrapping_add_signed(offset));
+        }
+        let len = self.tape.len();
+        if self.tape_mode == TapeMode::Wrap {
+            return Some((self.data_ptr + offset.rem_euclid(len as isize) as usize) % len);
+        }
+        self.data_ptr
+            .checked_add_signed(offset)
+            .filter(|&i| i < len || self.tape_mode == TapeMode::Grow)
+    }
+
+    /// Value of the cell at the data pointer.
+    pub(crate) fn current_cell(&self) -> u32 {
+        self.tape.get(self.data_ptr)
+    }
+
+    /// How often each instruction of `program` has run, if the interpreter
+    /// was built with profiling enabled.
+    pub fn profile(&self) -> Option<&[u64]> {
+        self.profile.as_deref()
+    }
+
+    /// Reports that no input is left for the pending `,`, applying the
+    /// configured EOF behavior.
+    pub(crate) fn set_eof(&mut self) {
+        self.pending_input = false;
+        match self.eof {
+            Eof::Zero => self.tape.set(self.data_ptr, 0),
+            Eof::MinusOne => self.tape.set(self.data_ptr, u32::MAX),
+            Eof::Unchanged => {}
+            // A self-modifying program ends at the end of the tape.
+            Eof::Halt if self.self_modifying => self.pc = self.tape.len(),
+            Eof::Halt => self.pc = self.program.len(),
+        }
+    }
+
+    /// Describes the final state for `--dump-tape`: the program counter, the
+    /// data pointer and a table of every non-zero cell plus the current one.
+    pub fn dump_tape(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "pc: {} of {}", self.pc, self.program.len()).unwrap();
+        writeln!(out, "data pointer: {}", self.data_ptr).unwrap();
+        out.push_str(&self.dump_cells(None));
+        out
+    }
+
+    /// What a `#` shows: where it is, the data pointer and the cells around
+    /// it. Meant to be called right after `step` returns `Ret::Debug`.
+    pub(crate) fn debug_dump(&self) -> String {
+        let mut out = String::new();
+        writeln!(
+            out,
+            "# at pc {}, data pointer {}",
+            self.pc - 1,
+            self.data_ptr
+        )
+        .unwrap();
+        let start = self.data_ptr.saturating_sub(DEBUG_CONTEXT);
+        out.push_str(&self.dump_cells(Some(start..self.data_ptr + DEBUG_CONTEXT + 1)));
+        out
+    }
+
+    /// The table of cells in `dump_tape`. With a range, every cell in it is
+    /// listed, zero or not.
+    pub(crate) fn dump_cells(&self, cells: Option<Range<usize>>) -> String {
+        let mut out = String::new();
+        writeln!(out, "{:>10}  {:>10}  {:>10}  char", "cell", "hex", "dec").unwrap();
+        match cells {
+            Some(cells) => {
+                for i in cells.start..cells.end.min(self.tape.len()) {
+                    self.write_cell(&mut out, i);
+                }
+            }
+            None => {
+                let current = (self.tape.get(self.data_ptr) == 0).then_some(self.data_ptr);
+                let mut cells: Vec<usize> =
+                    self.tape.nonzero().map(|(i, _)| i).chain(current).collect();
+                cells.sort_unstable();
+                for i in cells {
+                    self.write_cell(&mut out, i);
+                }
+            }
+        }
+        out
+    }
+
+    fn write_cell(&self, out: &mut String, i: usize) {
+        let value = self.tape.get(i);
+        let c = match char::from_u32(value) {
+            Some(c) if c.is_ascii_graphic() || c == ' ' => format!("'{}'", c),
+            _ => String::new(),
+        };
+        let marker = if i == self.data_ptr {
+            "<- data pointer"
+        } else {
+            ""
+        };
+        let line = format!(
+            "{:>10}  {:>#10x}  {:>10}  {:<4}  {}",
+            i, value, value, c, marker
+        );
+        writeln!(out, "{}", line.trim_end()).unwrap();
+    }
+
+    /// Puts the program counter, the data pointer and the cell at it back to
+    /// earlier values, for the debugger to undo a step.
+    pub(crate) fn rewind(&mut self, pc: usize, data_ptr: usize, cell: u32) {
+        self.pc = pc;
+        self.data_ptr = data_ptr;
+        self.tape.set(data_ptr, cell);
+    }
+
+    /// Whether the last `,` is still waiting for its input, e.g. because
+    /// the read was interrupted.
+    pub(crate) fn pending_input(&self) -> bool {
+        self.pending_input
+    }
+
+    /// The storage cell of Extended Brainfuck Type I.
+    pub(crate) fn storage(&self) -> u32 {
+        self.storage
+    }
+
+    /// Where each pbrain procedure being run returns to, innermost last.
+    pub(crate) fn call_stack(&self) -> &[usize] {
+        &self.call_stack
+    }
+
+    /// Puts the interpreter in the state of a snapshot taken of a run of the
+    /// same program, so that it continues where that run stopped.
+    pub(crate) fn restore(
+        &mut self,
+        pc: usize,
+        data_ptr: usize,
+        tape: Tape,
+        pending_input: bool,
+    ) -> Result<(), String> {
+        if tape.cell_size() != self.tape.cell_size() {
+            return Err(format!(
+                "The snapshot has {}-bit cells, but this run uses {}-bit cells",
+                tape.cell_size().bits(),
+                self.tape.cell_size().bits()
+            ));
+        }
+        if pc > self.program.len() || data_ptr >= tape.len() {
+            return Err("The snapshot is corrupt".to_owned());
+        }
+        self.pc = pc;
+        self.data_ptr = data_ptr;
+        self.tape = tape;
+        self.pending_input = pending_input;
+        // Nothing is known about where a snapshot left the data pointer.
+        self.unchecked = None;
+        Ok(())
+    }
+
+    /// Exchanges the tape and the data pointer for the given ones, so the
+    /// REPL can keep earlier states and go back to them without copying.
+    pub(crate) fn swap_tape(&mut self, tape: &mut Tape, data_ptr: &mut usize) {
+        std::mem::swap(&mut self.tape, tape);
+        std::mem::swap(&mut self.data_ptr, data_ptr);
+    }
+
+    /// Zeroes every cell and moves the data pointer back to the first one.
+    pub(crate) fn reset_tape(&mut self) {
+        self.tape = Tape::new(self.tape.cell_size(), self.tape.len());
+        self.data_ptr = 0;
+        if let Some(max) = self.unchecked {
+            self.tape.reserve(max);
+        }
+    }
+
+    pub fn tape(&self) -> &Tape {
+        &self.tape
+    }
+
+    /// Meters the run: every instruction burns some of `fuel`, as
+    /// `fuel_cost` says, and `step` returns `Ret::OutOfFuel` without running
+    /// the next one once there isn't enough left for it. Unlike a step or
+    /// time limit, that happens at the same point on every run of the same
+    /// program and input, and the run can go on with `add_fuel`.
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = Some(fuel);
+    }
+
+    /// Adds to the fuel left, metering the run if it wasn't.
+    pub fn add_fuel(&mut self, fuel: u64) {
+        self.fuel = Some(self.fuel.unwrap_or(0).saturating_add(fuel));
+    }
+
+    /// The fuel left, or `None` if the run isn't metered.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Fails if adding `n` to `value` would wrap around. `n` is negative when
+    /// read as an `i32`, as that's how runs of `-` are folded.
+    fn check_add(&self, value: u32, n: u32) -> Result<(), String> {
+        let result = value as i64 + n as i32 as i64;
+        let error = if result < 0 {
+            "Cell underflow"
+        } else if result > self.tape.cell_size().max() as i64 {
+            "Cell overflow"
+        } else {
+            return Ok(());
+        };
+        Err(format!(
+            "{} at pc {}, data pointer {}",
+            error, self.pc, self.data_ptr
+        ))
+    }
+
+    /// The cell at the data pointer.
+    fn cell(&self) -> u32 {
+        match self.unchecked {
+            // SAFETY: `bounds::max_index` proved the data pointer never goes
+            // past `max`, which `build_ops` reserved on the tape.
+            Some(max) => {
+                debug_assert!(self.data_ptr <= max);
+                unsafe { self.tape.get_unchecked(self.data_ptr) }
+            }
+            None => self.tape.get(self.data_ptr),
+        }
+    }
+
+    fn set_cell(&mut self, value: u32) {
+        match self.unchecked {
+            // SAFETY: as in `cell`.
+            Some(max) => {
+                debug_assert!(self.data_ptr <= max);
+                unsafe { self.tape.set_unchecked(self.data_ptr, value) }
+            }
+            None => self.tape.set(self.data_ptr, value),
+        }
+    }
+
+    /// Overwrites the cell at the data pointer.
+    /// Sets the cell at the data pointer to `n`. `fuse` folds an add after a
+    /// clear into the `Set`, so it's checked as that add to 0.
+    fn set(&mut self, n: u32) -> Result<(), String> {
+        self.stats.sets += 1;
+        if self.cell_overflow == CellOverflow::Error {
+            self.check_add(0, n)?;
+        }
+        self.set_cell(n);
+        self.pc += 1;
+        Ok(())
+    }
+
+    fn loop_start(&mut self, end: usize) {
+        self.stats.loop_starts += 1;
+        // If the byte at the data pointer is zero, then instead of moving
+        // the instruction pointer forward to the next command, jump it
+        // forward to the command after the matching ] command.
+        if self.cell() == 0 {
+            self.pc = end + 1;
+        } else {
+            self.pc += 1;
+        }
+    }
+
+    fn loop_end(&mut self, start: usize) {
+        self.stats.loop_ends += 1;
+        // If the byte at the data pointer is nonzero, then instead of moving
+        // the instruction pointer forward to the next command, jump it
+        // back to the command after the matching [ command.
+        if self.cell() != 0 {
+            self.pc = start + 1;
+        } else {
+            self.pc += 1;
+        }
+    }
+
+    /// Adds `n` to the cell at the data pointer, wrapping around.
+    fn add(&mut self, n: u32) -> Result<(), String> {
+        self.stats.adds += 1;
+        let value = self.cell();
+        if self.cell_overflow == CellOverflow::Error {
+            self.check_add(value, n)?;
+        }
+        self.set_cell(value.wrapping_add(n));
+        Ok(())
+    }
+
+    /// Moves the data pointer by `n` cells to the right (or to the left if
+    /// negative).
+    fn move_by(&mut self, n: isize) -> Result<(), String> {
+        self.stats.moves += 1;
+        let moved = self.moved_data_ptr(n);
+        // A move off the tape stops at its end, where moving a cell at a
+        // time would have.
+        self.data_ptr = match moved {
+            Ok(data_ptr) => data_ptr,
+            Err(_) if n < 0 => 0,
+            Err(_) => self.tape.len() - 1,
+        };
+        self.stats.peak_index = self.stats.peak_index.max(self.data_ptr);
+        moved.map(drop)
+    }
+
+    /// Where the data pointer ends up after moving by `n` cells, growing the
+    /// tape if needed.
+    fn moved_data_ptr(&mut self, n: isize) -> Result<usize, String> {
+        if self.unchecked.is_some() {
+            return Ok(self.data_ptr.wrapping_add_signed(n));
+        }
+        let len = self.tape.len();
+        if self.tape_mode == TapeMode::Wrap {
+            return Ok((self.data_ptr + n.rem_euclid(len as isize) as usize) % len);
+        }
+
+        let data_ptr = self.data_ptr as isize + n;
+        if data_ptr < 0 {
+            return Err("Memory underflow".to_owned());
+        }
+        let data_ptr = data_ptr as usize;
+        if data_ptr >= len {
+            if self.tape_mode == TapeMode::Fixed {
+                return Err("Memory overflow".to_owned());
+            }
+            self.grow_tape(data_ptr);
+        }
+        Ok(data_ptr)
+    }
+
+    /// Doubles the tape until `index` is in bounds.
+    fn grow_tape(&mut self, index: usize) {
+        let mut len = self.tape.len();
+        while len <= index {
+            len = len.saturating_mul(2);
+        }
+        self.tape.resize(len);
     }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
-pub(crate) enum Ret {
+pub enum Ret {
     Input,
     Output(u8),
+    /// A `#` ran, see `BfInterpreter::debug_dump`.
+    Debug,
+    /// A Brainfork `Y` ran, see `BfInterpreter::fork`.
+    Fork,
+    /// The decimal dialect's `:` and `;`.
+    OutputNumber(u32),
+    InputNumber,
+    /// The fuel given with `BfInterpreter::set_fuel` isn't enough for the
+    /// next instruction, which runs once `add_fuel` adds enough.
+    OutOfFuel,
     Continue,
     Finished,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
-enum Token {
-    IncDataPtr,
-    DecDataPtr,
-    IncByte,
-    DecByte,
-    WriteByte,
-    ReadByte,
-    BeginLoop,
-    EndLoop,
-}
-
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::config::{CellSize, TapeSize};
+    use crate::parser::{DECIMAL, EXTENDED1, PBRAIN};
+
+    impl BfInterpreter {
+        fn new(program: &[u8]) -> Result<Self, String> {
+            Self::builder().build(program)
+        }
+
+        /// Runs to the end for a test, with `,` reading the bytes of `input`
+        /// and then the end of input, and returns what `.` printed.
+        pub(crate) fn run_to_end(&mut self, input: &[u8]) -> Result<Vec<u8>, String> {
+            let mut input = input.iter();
+            let mut output = vec![];
+            loop {
+                match self.step()? {
+                    Ret::Finished => return Ok(output),
+                    Ret::Output(o) => output.push(o),
+                    Ret::Input => match input.next() {
+                        Some(&byte) => self.set_input(byte),
+                        None => self.set_eof(),
+                    },
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn with_tape_size(size: TapeSize) -> BfInterpreterBuilder {
+        let mut config = Config::default();
+        config.set_tape_size(size);
+        BfInterpreter::builder().config(config)
+    }
 
     #[test]
     fn hello_world() {
@@ -189,6 +1051,139 @@ mod tests {
         assert_eq!(result, b"Hello World!\n")
     }
 
+    #[test]
+    fn pbrain_procedures() {
+        let run = |program: &[u8]| -> Result<Vec<u8>, String> {
+            let (ops, _) = ir::compile_extended(program, false, PBRAIN).unwrap();
+            BfInterpreter::builder()
+                .build_ops(ops)
+                .unwrap()
+                .run_to_end(b"")
+        };
+        // Procedure 1 adds 2, procedure 2 calls it twice, going 1, 3, 1, 3.
+        assert_eq!(run(b"+(++)+(-:--:).:.").unwrap(), [2, 3]);
+        assert_eq!(run(b"(+)+:").unwrap_err(), "Call to undefined procedure 1");
+        assert_eq!(run(b"+(:):").unwrap_err(), "Call stack overflow");
+    }
+
+    #[test]
+    fn extended_type_1() {
+        let (ops, _) = ir::compile_extended(b"+++{$>!}~.^.|&.@.", false, EXTENDED1).unwrap();
+        let mut bf = BfInterpreter::builder().build_ops(ops).unwrap();
+        let output = bf.run_to_end(b"").unwrap();
+        // 3 shifted left is stored, then shifted right and flipped, and the
+        // `.` after `@` never runs.
+        assert_eq!(output, [!3, !3 ^ 6, (!3 | 6) & 6]);
+    }
+
+    #[test]
+    fn decimal_io() {
+        let (ops, _) = ir::compile_extended(b";+:,:", false, DECIMAL).unwrap();
+        let mut bf = BfInterpreter::builder().build_ops(ops).unwrap();
+        let mut output = vec![];
+        loop {
+            match bf.step().unwrap() {
+                Ret::Finished => break,
+                Ret::InputNumber => bf.set_input_number(299),
+                Ret::Input => bf.set_input(7),
+                Ret::OutputNumber(n) => output.push(n),
+                _ => {}
+            }
+        }
+        // The number read is stored like `+` would, wrapping at the cell size.
+        assert_eq!(output, [44, 7]);
+        assert_eq!((bf.stats().bytes_read, bf.stats().bytes_written), (2, 2));
+    }
+
+    #[test]
+    fn self_modifying() {
+        let run = |program: &[u8]| -> Result<Vec<u8>, String> {
+            BfInterpreter::builder()
+                .build_self_modifying(program)
+                .unwrap()
+                .run_to_end(b"")
+        };
+        // Reads its own code.
+        assert_eq!(run(b"<.").unwrap(), b".");
+        // Turns its `.` into a `/`, which is a comment.
+        assert_eq!(run(b"<+.").unwrap(), b"");
+        // Writes a `.` right after itself, which then runs.
+        assert_eq!(run(b">++++++[<+++++++>-]<++++").unwrap(), b".");
+        assert_eq!(run(b"[").unwrap_err(), "Missing ']'");
+        assert_eq!(run(b"+]").unwrap_err(), "Missing '['");
+
+        // Halting on EOF stops at the end of the tape, not of the program.
+        let config = Config {
+            eof: Eof::Halt,
+            ..Config::default()
+        };
+        let mut bf = BfInterpreter::builder()
+            .config(config)
+            .build_self_modifying(b",+.")
+            .unwrap();
+        assert_eq!(bf.step(), Ok(Ret::Input));
+        bf.set_eof();
+        assert_eq!(bf.step(), Ok(Ret::Finished));
+    }
+
+    #[test]
+    fn threaded_dispatch() {
+        let run = |dispatch, program: &[u8]| {
+            let mut bf = BfInterpreter::builder()
+                .dispatch(dispatch)
+                .build(program)
+                .unwrap();
+            let output = bf.run_to_end(&[3]).unwrap();
+            (output, bf.stats().clone())
+        };
+        for program in [&b"++++++++[>++++++++<-]>+."[..], b",[->+>++<<]>>.<[-]#."] {
+            assert_eq!(
+                run(Dispatch::Threaded, program),
+                run(Dispatch::Match, program)
+            );
+        }
+
+        // A new program gets new handlers.
+        let mut bf = BfInterpreter::builder()
+            .dispatch(Dispatch::Threaded)
+            .build(b"+")
+            .unwrap();
+        bf.load(b">+.").unwrap();
+        assert_eq!(bf.run_batch(10), Ok(Ret::Output(1)));
+    }
+
+    #[test]
+    fn unsafe_fast() {
+        let build = |program: &[u8], tape_size| {
+            BfInterpreter::builder()
+                .config(Config {
+                    tape_size,
+                    ..Config::default()
+                })
+                .unsafe_fast(true)
+                .build(program)
+                .unwrap()
+        };
+        let mut bf = build(b"++++++++[>++++++++<-]>+.", 2);
+        assert_eq!(bf.unchecked, Some(1));
+        assert_eq!(bf.run_batch(1000), Ok(Ret::Output(65)));
+
+        // Programs that may leave the tape keep the checks.
+        assert_eq!(build(b"+>>", 2).unchecked, None);
+        let mut bf = build(b"+[>+]", 2);
+        assert_eq!(bf.unchecked, None);
+        assert_eq!(bf.run_batch(1000).unwrap_err(), "Memory overflow");
+    }
+
+    #[test]
+    fn run_batches() {
+        let mut bf = BfInterpreter::new(b"+++[>+<-].").unwrap();
+        assert_eq!(bf.run_batch(5), Ok(Ret::Continue));
+        assert_eq!(bf.stats().steps, 5);
+        assert_eq!(bf.run_batch(100), Ok(Ret::Output(0)));
+        assert_eq!(bf.run_batch(100), Ok(Ret::Finished));
+    }
+
     #[test]
     fn memory_overflow() {
         let program = ">".repeat(30_001);
@@ -204,6 +1199,282 @@ mod tests {
         }
     }
 
+    #[test]
+    fn custom_tape_size() {
+        let program = ">".repeat(10);
+        let mut bf = with_tape_size(TapeSize::Cells(10))
+            .build(program.as_bytes())
+            .unwrap();
+        assert_eq!(bf.step().unwrap_err(), "Memory overflow");
+
+        let mut bf = with_tape_size(TapeSize::Cells(11))
+            .build(program.as_bytes())
+            .unwrap();
+        assert_eq!(bf.step().unwrap(), Ret::Continue);
+    }
+
+    #[test]
+    fn moves_stop_at_the_tape_ends() {
+        // `<>` leaves the tape before it comes back.
+        let mut bf = BfInterpreter::new(b"<>+.").unwrap();
+        assert_eq!(bf.run_to_end(b"").unwrap_err(), "Memory underflow");
+
+        // A folded move fails at the end it passes, as a `<` at a time would.
+        let mut bf = BfInterpreter::new(b">>+<<<<<").unwrap();
+        assert_eq!(bf.run_to_end(b"").unwrap_err(), "Memory underflow");
+        assert_eq!(bf.data_ptr, 0);
+
+        let mut bf = with_tape_size(TapeSize::Cells(4))
+            .build(b">+>>>>>")
+            .unwrap();
+        assert_eq!(bf.run_to_end(b"").unwrap_err(), "Memory overflow");
+        assert_eq!(bf.data_ptr, 3);
+
+        // So does the move of a fused add to the next cell.
+        let ops = crate::fuse::fuse(&ir::compile(b">>>+>+<").unwrap(), None).ops;
+        assert_eq!(ops[1], Op::AddAt(1, 1));
+        let mut bf = with_tape_size(TapeSize::Cells(4)).build_ops(ops).unwrap();
+        assert_eq!(bf.run_to_end(b"").unwrap_err(), "Memory overflow");
+        assert_eq!(bf.data_ptr, 3);
+    }
+
+    #[test]
+    fn unlimited_tape() {
+        let program = format!("{}+.", ">".repeat(100_000));
+        let mut bf = with_tape_size(TapeSize::Unlimited)
+            .build(program.as_bytes())
+            .unwrap();
+        assert_eq!(bf.step().unwrap(), Ret::Continue);
+        assert_eq!(bf.step().unwrap(), Ret::Continue);
+        assert_eq!(bf.step().unwrap(), Ret::Output(1));
+        assert_eq!(bf.tape.len(), 120_000);
+    }
+
+    #[test]
+    fn wrapping_tape() {
+        let config = Config {
+            tape_size: 4,
+            tape_mode: TapeMode::Wrap,
+            ..Config::default()
+        };
+        let mut bf = BfInterpreter::builder()
+            .config(config)
+            .build(b"<+>>>>>>+<<.")
+            .unwrap();
+        let output = bf.run_to_end(b"").unwrap();
+        // `<` from cell 0 lands on cell 3, six `>` from there on cell 1 and
+        // `<<` back on cell 3.
+        assert_eq!(output, [1]);
+        assert_eq!(bf.data_ptr, 3);
+    }
+
+    #[test]
+    fn cell_sizes() {
+        // 256 increments wrap an 8-bit cell back to zero, skipping the loop.
+        let program = format!("{}[[-]+++.]", "+".repeat(256));
+        for (cell_size, expected) in [
+            (CellSize::Bits8, None),
+            (CellSize::Bits16, Some(3)),
+            (CellSize::Bits32, Some(3)),
+        ] {
+            let config = Config {
+                cell_size,
+                ..Config::default()
+            };
+            let mut bf = BfInterpreter::builder()
+                .config(config)
+                .build(program.as_bytes())
+                .unwrap();
+            let mut output = None;
+            loop {
+                match bf.step().unwrap() {
+                    Ret::Output(o) => {
+                        output = Some(o);
+                        break;
+                    }
+                    Ret::Finished => break,
+                    _ => {}
+                }
+            }
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn eof_behavior() {
+        for (eof, expected) in [
+            (Eof::Zero, vec![0]),
+            (Eof::MinusOne, vec![255]),
+            (Eof::Unchanged, vec![7]),
+            (Eof::Halt, vec![]),
+        ] {
+            let config = Config {
+                eof,
+                ..Config::default()
+            };
+            let mut bf = BfInterpreter::builder()
+                .config(config)
+                .build(b"+++++++,.")
+                .unwrap();
+            assert_eq!(bf.run_to_end(b"").unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn step_limit() {
+        let mut bf = BfInterpreter::builder()
+            .max_steps(Some(3))
+            .build(b"+[]")
+            .unwrap();
+        for _ in 0..3 {
+            assert_eq!(bf.step(), Ok(Ret::Continue));
+        }
+        assert_eq!(bf.step().unwrap_err(), "Step limit of 3 exceeded");
+
+        let mut bf = BfInterpreter::builder()
+            .max_steps(Some(1))
+            .build(b"+")
+            .unwrap();
+        assert_eq!(bf.step(), Ok(Ret::Continue));
+        assert_eq!(bf.step(), Ok(Ret::Finished));
+    }
+
+    #[test]
+    fn cell_overflow_error() {
+        let mut bf = BfInterpreter::builder()
+            .cell_overflow(CellOverflow::Error)
+            .build(b"+>-")
+            .unwrap();
+        assert_eq!(bf.step(), Ok(Ret::Continue));
+        assert_eq!(bf.step(), Ok(Ret::Continue));
+        assert_eq!(
+            bf.step().unwrap_err(),
+            "Cell underflow at pc 2, data pointer 1"
+        );
+
+        let program = "+".repeat(256);
+        let mut bf = BfInterpreter::builder()
+            .cell_overflow(CellOverflow::Error)
+            .build(program.as_bytes())
+            .unwrap();
+        assert_eq!(
+            bf.step().unwrap_err(),
+            "Cell overflow at pc 0, data pointer 0"
+        );
+
+        // Neither folding nor clear loops hide an overflow.
+        for (program, error) in [
+            ("-+", "Cell underflow at pc 0"),
+            ("+++++[+]", "Cell overflow at pc 2"),
+            ("+[-]-", "Cell underflow at pc 2"),
+        ] {
+            let mut bf = BfInterpreter::builder()
+                .cell_overflow(CellOverflow::Error)
+                .build(program.as_bytes())
+                .unwrap();
+            let e = loop {
+                match bf.step() {
+                    Ok(Ret::Finished) => panic!("{} finished", program),
+                    Ok(_) => {}
+                    Err(e) => break e,
+                }
+            };
+            assert!(e.starts_with(error), "{}: {}", program, e);
+        }
+    }
+
+    #[test]
+    fn dump_tape() {
+        let mut bf = BfInterpreter::new(b"+++++++++++++++++++++++++++++++++>>+").unwrap();
+        while bf.step().unwrap() != Ret::Finished {}
+        assert_eq!(
+            bf.dump_tape(),
+            "pc: 3 of 3
+data pointer: 2
+      cell         hex         dec  char
+         0        0x21          33  '!'
+         2         0x1           1        <- data pointer
+"
+        );
+    }
+
+    #[test]
+    fn dump_cells_and_reset() {
+        let mut bf = BfInterpreter::new(b"+>+++>").unwrap();
+        while bf.step().unwrap() != Ret::Finished {}
+        assert_eq!(
+            bf.dump_cells(Some(1..3)),
+            "      cell         hex         dec  char
+         1         0x3           3
+         2         0x0           0        <- data pointer
+"
+        );
+
+        bf.reset_tape();
+        assert_eq!(bf.data_ptr, 0);
+        assert_eq!(bf.tape.get(1), 0);
+    }
+
+    #[test]
+    fn debug_dump() {
+        let (ops, _) = ir::compile_with_offsets(b">>>>>>+#", true).unwrap();
+        let mut bf = BfInterpreter::builder().build_ops(ops).unwrap();
+        assert_eq!(bf.step(), Ok(Ret::Continue));
+        assert_eq!(bf.step(), Ok(Ret::Continue));
+        assert_eq!(bf.step(), Ok(Ret::Debug));
+        assert_eq!(
+            bf.debug_dump(),
+            "# at pc 2, data pointer 6
+      cell         hex         dec  char
+         2         0x0           0
+         3         0x0           0
+         4         0x0           0
+         5         0x0           0
+         6         0x1           1        <- data pointer
+         7         0x0           0
+         8         0x0           0
+         9         0x0           0
+        10         0x0           0
+"
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let mut bf = BfInterpreter::new(b"++[>+<-]>>.,").unwrap();
+        bf.run_to_end(b"x").unwrap();
+        assert_eq!(
+            bf.stats(),
+            &Stats {
+                steps: 15,
+                adds: 5,
+                moves: 5,
+                sets: 0,
+                outputs: 1,
+                inputs: 1,
+                loop_starts: 1,
+                loop_ends: 2,
+                bytes_read: 1,
+                bytes_written: 1,
+                peak_index: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn load_keeps_tape() {
+        let mut bf = BfInterpreter::new(b">+++").unwrap();
+        while bf.step().unwrap() != Ret::Finished {}
+
+        bf.load(b"+.").unwrap();
+        assert_eq!(bf.stats().steps, 0);
+        assert_eq!(bf.step(), Ok(Ret::Continue));
+        assert_eq!(bf.step(), Ok(Ret::Output(4)));
+        assert_eq!(bf.data_ptr, 1);
+
+        assert_eq!(bf.load(b"[").unwrap_err(), "Missing ']'");
+    }
+
     #[test]
     fn memory_underflow() {
         let program = "<";
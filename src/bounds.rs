@@ -0,0 +1,258 @@
+//! Static analysis of where the data pointer can be, to catch a program
+//! moving off the left end of the tape before it runs, to tell how much of
+//! the tape it needs at least, and to prove it never leaves a part of the
+//! tape so `--unsafe-fast` can skip the checks.
+//!
+//! The pointer is tracked as a range of cells. Loops are followed until the
+//! range stops changing, with an upper bound that keeps growing dropped, so
+//! the range always contains every place the pointer can really be. A move
+//! to the left is only reported if it leaves the tape from all of them.
+
+use crate::ir::Op;
+
+/// Where the data pointer can be.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Range {
+    min: usize,
+    /// `None` if unbounded.
+    max: Option<usize>,
+}
+
+impl Range {
+    fn join(self, other: Range) -> Range {
+        Range {
+            min: self.min.min(other.min),
+            max: self.max.zip(other.max).map(|(a, b)| a.max(b)),
+        }
+    }
+}
+
+/// The index of a `Move` in `ops` that underflows the tape whenever it
+/// runs, if there is one.
+pub fn underflow(ops: &[Op]) -> Option<usize> {
+    let start = Range {
+        min: 0,
+        max: Some(0),
+    };
+    block(ops, 0, ops.len(), start, true, None, None).err()
+}
+
+/// The number of cells a program certainly uses if it runs to the end,
+/// counting only moves outside loops and where loops leave the pointer.
+pub(crate) fn min_cells(ops: &[Op]) -> usize {
+    let start = Range {
+        min: 0,
+        max: Some(0),
+    };
+    let mut peak = 0;
+    // An underflow stops the program, but the cells up to it were used.
+    let _ = block(ops, 0, ops.len(), start, true, Some(&mut peak), None);
+    peak + 1
+}
+
+/// The highest cell the data pointer can ever be on, or that `AddAt` can
+/// reach, if that's known and the pointer can never move off the left end
+/// of the tape.
+pub(crate) fn max_index(ops: &[Op]) -> Option<usize> {
+    let start = Range {
+        min: 0,
+        max: Some(0),
+    };
+    let mut reach = Reach::default();
+    block(ops, 0, ops.len(), start, true, None, Some(&mut reach)).ok()?;
+    (!reach.unbounded).then_some(reach.max)
+}
+
+/// Everywhere the pointer went, for `max_index`.
+#[derive(Default)]
+struct Reach {
+    max: usize,
+    /// Whether the highest cell isn't known, or a move may underflow.
+    unbounded: bool,
+}
+
+/// Takes a move by `n` from anywhere in `range` into `reach`, if given.
+fn track(reach: Option<&mut Reach>, range: Range, n: isize) {
+    let Some(reach) = reach else { return };
+    if n < 0 && range.min < n.unsigned_abs() {
+        reach.unbounded = true;
+    }
+    match range.max {
+        Some(max) => reach.max = reach.max.max(max.saturating_add_signed(n)),
+        None => reach.unbounded = true,
+    }
+}
+
+/// Follows `ops[start..end]` with the pointer in `range`. `tape_zero` says
+/// whether every cell is still 0. Fails with the index of a move that
+/// underflows. If given, `peak` is raised to the lowest index the pointer
+/// certainly reaches, and `reach` takes in every move.
+fn block(
+    ops: &[Op],
+    start: usize,
+    end: usize,
+    mut range: Range,
+    mut tape_zero: bool,
+    mut peak: Option<&mut usize>,
+    mut reach: Option<&mut Reach>,
+) -> Result<Range, usize> {
+    // Whether the current cell is certainly 0.
+    let mut cell_zero = tape_zero;
+    let mut i = start;
+    while i < end {
+        match ops[i] {
+            Op::Move(n) => {
+                track(reach.as_deref_mut(), range, n);
+                range = moved(range, n).ok_or(i)?;
+                cell_zero = tape_zero;
+            }
+            Op::AddMove(n, m) => {
+                tape_zero &= n == 0;
+                track(reach.as_deref_mut(), range, m);
+                range = moved(range, m).ok_or(i)?;
+                cell_zero = tape_zero;
+            }
+            Op::MoveAdd(m, n) => {
+                track(reach.as_deref_mut(), range, m);
+                range = moved(range, m).ok_or(i)?;
+                cell_zero = tape_zero && n == 0;
+                tape_zero &= n == 0;
+            }
+            Op::AddAt(offset, n) => {
+                // The pointer comes back, if it doesn't underflow.
+                track(reach.as_deref_mut(), range, offset);
+                moved(range, offset).ok_or(i)?;
+                tape_zero &= n == 0;
+            }
+            Op::Add(n) => {
+                cell_zero &= n == 0;
+                tape_zero &= n == 0;
+            }
+            Op::Set(n) => {
+                cell_zero = n == 0;
+                tape_zero &= n == 0;
+            }
+            Op::Input | Op::InputNumber => {
+                cell_zero = false;
+                tape_zero = false;
+            }
+            Op::Output | Op::OutputNumber | Op::Debug | Op::LoopEnd(_) | Op::ProcEnd => {}
+            // A procedure body only runs when it's called.
+            Op::ProcStart(end) => i = end,
+            // A new thread starts one cell to the right, on a 1, and isn't
+            // followed.
+            Op::Fork => {
+                track(reach.as_deref_mut(), Range { min: 0, max: None }, 0);
+                cell_zero = false;
+                tape_zero = false;
+            }
+            // The extended commands may change the cell in any way.
+            Op::Extended(_) => {
+                cell_zero = false;
+                tape_zero = false;
+            }
+            Op::Call | Op::Plugin(_) => {
+                // The procedure or plugin may have gone anywhere and changed
+                // anything.
+                range = Range { min: 0, max: None };
+                track(reach.as_deref_mut(), range, 0);
+                cell_zero = false;
+                tape_zero = false;
+            }
+            Op::LoopStart(close) => {
+                // A loop whose cell is 0 never runs, like a comment at the
+                // start of the program.
+                if !cell_zero {
+                    let mut entry = range;
+                    loop {
+                        let exit =
+                            block(ops, i + 1, close, entry, false, None, reach.as_deref_mut())?;
+                        let joined = entry.join(exit);
+                        if joined == entry {
+                            break;
+                        }
+                        entry = Range {
+                            min: joined.min,
+                            max: if joined.max > entry.max {
+                                None
+                            } else {
+                                joined.max
+                            },
+                        };
+                    }
+                    range = entry;
+                    tape_zero = false;
+                }
+                // The loop ended on a 0 cell either way.
+                cell_zero = true;
+                i = close;
+            }
+        }
+        if let Some(peak) = peak.as_deref_mut() {
+            *peak = (*peak).max(range.min);
+        }
+        i += 1;
+    }
+    Ok(range)
+}
+
+/// `range` after a move by `n`, or None if the move underflows from all of
+/// it.
+fn moved(range: Range, n: isize) -> Option<Range> {
+    if n < 0 && range.max.is_some_and(|max| max < n.unsigned_abs()) {
+        return None;
+    }
+    // Runs that survive the move have the pointer on the tape.
+    Some(Range {
+        min: range.min.saturating_add_signed(n),
+        max: range.max.map(|max| max.saturating_add_signed(n)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::ir;
+
+    fn find(program: &[u8]) -> Option<usize> {
+        underflow(&ir::compile(program).unwrap())
+    }
+
+    #[test]
+    fn find_underflows() {
+        assert_eq!(find(b"+>>+<<."), None);
+        assert_eq!(find(b"+>+<<"), Some(3));
+        // Loops that always return the pointer keep the range exact.
+        assert_eq!(find(b"+[>+<-]<"), Some(7));
+        assert_eq!(find(b",[>+<-]>[<<]"), Some(9));
+        // After a loop that can move right, moving left may be fine.
+        assert_eq!(find(b",[>,]<<<"), None);
+        // Loops that never run are skipped.
+        assert_eq!(find(b"[<<] +[-]>[<]"), None);
+    }
+
+    #[test]
+    fn prove_max_index() {
+        let max_index = |program: &[u8]| max_index(&ir::compile(program).unwrap());
+        assert_eq!(max_index(b""), Some(0));
+        assert_eq!(max_index(b">>+<[->+<]>>"), Some(3));
+        // Loops that always come back keep the range exact.
+        assert_eq!(max_index(b",[>>+<<-]"), Some(2));
+        // A loop that moves right may go anywhere.
+        assert_eq!(max_index(b",[>,]"), None);
+        // A move to the left may underflow, even if it doesn't always.
+        assert_eq!(max_index(b">,[<]"), None);
+        assert_eq!(max_index(b"+>+<<"), None);
+    }
+
+    #[test]
+    fn count_min_cells() {
+        let min_cells = |program: &[u8]| min_cells(&ir::compile(program).unwrap());
+        assert_eq!(min_cells(b""), 1);
+        assert_eq!(min_cells(b">>+<"), 3);
+        // Where a loop that moves right stops isn't known.
+        assert_eq!(min_cells(b">,[>,]<"), 2);
+        assert_eq!(min_cells(b">>>[-]>+[<]"), 5);
+    }
+}
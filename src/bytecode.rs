@@ -0,0 +1,294 @@
+//! The `.bfc` bytecode format: a serialized copy of the optimized IR so that
+//! programs can be run without parsing and optimizing them again.
+//!
+//! Layout (integers are LEB128 unless noted otherwise):
+//!
+//! - magic `BFC\0`
+//! - format version, `u16` little endian
+//! - number of metadata entries, then each entry as a length-prefixed key
+//!   and a length-prefixed value (both UTF-8)
+//! - number of instructions, then each instruction as an opcode byte
+//!   followed by its operand, if any
+
+use crate::ir::Op;
+use crate::leb128;
+use crate::parser::{Token, EXTENDED1};
+
+const MAGIC: &[u8; 4] = b"BFC\0";
+const VERSION: u16 = 2;
+
+const ADD: u8 = 0x01;
+const MOVE: u8 = 0x02;
+const SET: u8 = 0x03;
+const OUTPUT: u8 = 0x04;
+const INPUT: u8 = 0x05;
+const LOOP_START: u8 = 0x06;
+const LOOP_END: u8 = 0x07;
+const DEBUG: u8 = 0x08;
+const PROC_START: u8 = 0x09;
+const PROC_END: u8 = 0x0a;
+const CALL: u8 = 0x0b;
+const FORK: u8 = 0x0c;
+/// Followed by the command character.
+const EXTENDED: u8 = 0x0d;
+/// Followed by the command character.
+const PLUGIN: u8 = 0x0e;
+const OUTPUT_NUMBER: u8 = 0x0f;
+const INPUT_NUMBER: u8 = 0x10;
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub(crate) fn encode(ops: &[Op], metadata: &[(&str, &str)]) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&VERSION.to_le_bytes());
+
+    leb128::write_unsigned(&mut out, metadata.len() as u64);
+    for (key, value) in metadata {
+        write_str(&mut out, key);
+        write_str(&mut out, value);
+    }
+
+    leb128::write_unsigned(&mut out, ops.len() as u64);
+    for op in ops.iter().copied() {
+        match op {
+            Op::Add(n) => {
+                out.push(ADD);
+                leb128::write_unsigned(&mut out, n as u64);
+            }
+            Op::Move(n) => {
+                out.push(MOVE);
+                leb128::write_signed(&mut out, n as i64);
+            }
+            Op::Set(n) => {
+                out.push(SET);
+                leb128::write_unsigned(&mut out, n as u64);
+            }
+            Op::Output => out.push(OUTPUT),
+            Op::Input => out.push(INPUT),
+            Op::LoopStart(end) => {
+                out.push(LOOP_START);
+                leb128::write_unsigned(&mut out, end as u64);
+            }
+            Op::LoopEnd(start) => {
+                out.push(LOOP_END);
+                leb128::write_unsigned(&mut out, start as u64);
+            }
+            Op::Debug => out.push(DEBUG),
+            Op::ProcStart(end) => {
+                out.push(PROC_START);
+                leb128::write_unsigned(&mut out, end as u64);
+            }
+            Op::ProcEnd => out.push(PROC_END),
+            Op::Call => out.push(CALL),
+            Op::Fork => out.push(FORK),
+            Op::Plugin(command) => {
+                out.push(PLUGIN);
+                out.push(command);
+            }
+            Op::Extended(command) => {
+                out.push(EXTENDED);
+                out.extend_from_slice(command.command().as_bytes());
+            }
+            Op::OutputNumber => out.push(OUTPUT_NUMBER),
+            Op::InputNumber => out.push(INPUT_NUMBER),
+            Op::AddMove(..) | Op::MoveAdd(..) | Op::AddAt(..) => {
+                unreachable!("fused instructions are only interpreted")
+            }
+        }
+    }
+
+    out
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Box<[Op]>, String> {
+    if !is_bytecode(bytes) {
+        return Err("Not a bytecode file".to_owned());
+    }
+    let version = bytes
+        .get(4..6)
+        .map(|v| u16::from_le_bytes([v[0], v[1]]))
+        .ok_or_else(truncated)?;
+    if version != VERSION {
+        return Err(format!("Unsupported bytecode version {}", version));
+    }
+
+    let mut pos = 6;
+    let entries = read_unsigned(bytes, &mut pos)?;
+    for _ in 0..entries {
+        // Metadata is informational only; make sure it's well formed.
+        read_str(bytes, &mut pos)?;
+        read_str(bytes, &mut pos)?;
+    }
+
+    let count = read_unsigned(bytes, &mut pos)?;
+    let mut ops = Vec::with_capacity(count.min(bytes.len()));
+    for _ in 0..count {
+        let opcode = *bytes.get(pos).ok_or_else(truncated)?;
+        pos += 1;
+        let op = match opcode {
+            ADD | SET => {
+                let n = leb128::read_unsigned(bytes, &mut pos).ok_or_else(truncated)?;
+                let n = n.try_into().map_err(|_| "Value out of range".to_owned())?;
+                if opcode == ADD {
+                    Op::Add(n)
+                } else {
+                    Op::Set(n)
+                }
+            }
+            MOVE => {
+                let n = leb128::read_signed(bytes, &mut pos).ok_or_else(truncated)?;
+                Op::Move(n.try_into().map_err(|_| "Move out of range".to_owned())?)
+            }
+            OUTPUT => Op::Output,
+            INPUT => Op::Input,
+            LOOP_START => Op::LoopStart(read_unsigned(bytes, &mut pos)?),
+            LOOP_END => Op::LoopEnd(read_unsigned(bytes, &mut pos)?),
+            DEBUG => Op::Debug,
+            PROC_START => Op::ProcStart(read_unsigned(bytes, &mut pos)?),
+            PROC_END => Op::ProcEnd,
+            CALL => Op::Call,
+            FORK => Op::Fork,
+            PLUGIN => {
+                let command = *bytes.get(pos).ok_or_else(truncated)?;
+                pos += 1;
+                Op::Plugin(command)
+            }
+            EXTENDED => {
+                let command = *bytes.get(pos).ok_or_else(truncated)?;
+                pos += 1;
+                match EXTENDED1.iter().find(|(c, _)| *c == command) {
+                    Some(&(_, Token::Extended(command))) => Op::Extended(command),
+                    _ => return Err(format!("Unknown extended command 0x{:02x}", command)),
+                }
+            }
+            OUTPUT_NUMBER => Op::OutputNumber,
+            INPUT_NUMBER => Op::InputNumber,
+            _ => return Err(format!("Unknown opcode 0x{:02x}", opcode)),
+        };
+        ops.push(op);
+    }
+
+    if pos != bytes.len() {
+        return Err("Trailing bytes after instructions".to_owned());
+    }
+    validate_loops(&ops)?;
+
+    Ok(ops.into_boxed_slice())
+}
+
+/// Checks that loop instructions point at each other, since the interpreter
+/// trusts the jump targets, and that loops and procedures nest like the
+/// parser makes them, since the bounds analysis of `--unsafe-fast` trusts
+/// that.
+fn validate_loops(ops: &[Op]) -> Result<(), String> {
+    // Where each loop or procedure still open ends.
+    let mut open = vec![];
+    for (i, op) in ops.iter().copied().enumerate() {
+        let valid = match op {
+            Op::LoopStart(end) => end > i && ops.get(end) == Some(&Op::LoopEnd(i)),
+            Op::LoopEnd(start) => start < i && ops.get(start) == Some(&Op::LoopStart(i)),
+            Op::ProcStart(end) => end > i && ops.get(end) == Some(&Op::ProcEnd),
+            _ => true,
+        };
+        if !valid {
+            return Err(format!("Invalid loop target at instruction {}", i));
+        }
+        match op {
+            Op::LoopStart(end) | Op::ProcStart(end) => open.push(end),
+            Op::LoopEnd(_) | Op::ProcEnd if open.pop() != Some(i) => {
+                return Err(format!("Loops cross at instruction {}", i));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    leb128::write_unsigned(out, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn read_str<'a>(bytes: &'a [u8], pos: &mut usize) -> Result<&'a str, String> {
+    let len = read_unsigned(bytes, pos)?;
+    let end = pos.checked_add(len).ok_or_else(truncated)?;
+    let s = bytes.get(*pos..end).ok_or_else(truncated)?;
+    *pos = end;
+    std::str::from_utf8(s).map_err(|_| "Invalid metadata".to_owned())
+}
+
+fn read_unsigned(bytes: &[u8], pos: &mut usize) -> Result<usize, String> {
+    let n = leb128::read_unsigned(bytes, pos).ok_or_else(truncated)?;
+    n.try_into().map_err(|_| truncated())
+}
+
+fn truncated() -> String {
+    "Truncated bytecode".to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::ir;
+
+    #[test]
+    fn round_trip() {
+        let ops = ir::compile(b"+++[>,.<-]>[-]<<<<").unwrap();
+        let bytes = encode(&ops, &[("generator", "test")]);
+
+        assert!(is_bytecode(&bytes));
+        assert_eq!(decode(&bytes).unwrap(), ops);
+    }
+
+    #[test]
+    fn reject_invalid() {
+        let bytes = encode(&ir::compile(b"[.]").unwrap(), &[]);
+
+        assert_eq!(decode(b"nope").unwrap_err(), "Not a bytecode file");
+        assert_eq!(
+            decode(&bytes[..bytes.len() - 1]).unwrap_err(),
+            "Truncated bytecode"
+        );
+
+        let mut bad_version = bytes.clone();
+        bad_version[4] = 9;
+        assert_eq!(
+            decode(&bad_version).unwrap_err(),
+            "Unsupported bytecode version 9"
+        );
+
+        let mut bad_target = bytes.clone();
+        bad_target[9] = 1;
+        assert_eq!(
+            decode(&bad_target).unwrap_err(),
+            "Invalid loop target at instruction 0"
+        );
+
+        let crossing = [
+            Op::LoopStart(4),
+            Op::Move(1),
+            Op::Add(1),
+            Op::LoopStart(6),
+            Op::LoopEnd(0),
+            Op::Move(1),
+            Op::LoopEnd(3),
+        ];
+        assert_eq!(
+            decode(&encode(&crossing, &[])).unwrap_err(),
+            "Loops cross at instruction 4"
+        );
+        let crossing_procedure = [
+            Op::ProcStart(2),
+            Op::LoopStart(3),
+            Op::ProcEnd,
+            Op::LoopEnd(1),
+        ];
+        assert_eq!(
+            decode(&encode(&crossing_procedure, &[])).unwrap_err(),
+            "Loops cross at instruction 2"
+        );
+    }
+}
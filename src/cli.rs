@@ -0,0 +1,2678 @@
+use crate::bf_interpreter::{CellOverflow, Dispatch};
+use crate::codegen::{Language, Target};
+use crate::config::{CellSize, Config, TapeMode};
+use crate::dialect::Dialect;
+use crate::difftest::Backend;
+use crate::formatter;
+use crate::native;
+use crate::trace::TraceFormat;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Help for the options accepted by `is_config_flag`, shared by every command
+/// that runs or compiles programs.
+macro_rules! config_usage {
+    () => {
+        "  --tape <mode>    What moving past the end of the tape does: fixed stops
+                   with an error, grow extends the tape to the right and wrap
+                   wraps around to the other end (default: fixed)
+  --tape-size <n>  Number of cells, or the initial number for a growing
+                   tape; `unlimited` is short for --tape grow (default: 30000)
+  --cell-size <n>  Cell width in bits: 8, 16 or 32 (default: 8)
+  --eof <mode>     What `,` does at the end of input: zero, minus-one,
+                   unchanged or halt (default: halt)
+"
+    };
+}
+
+const USAGE: &str = "\
+Usage: bf <command> [options]
+
+Commands:
+  run      Run a program
+  repl     Start an interactive session
+  debug    Step through a program with breakpoints
+  dap      Run a Debug Adapter Protocol server for editors
+  lsp      Run a Language Server Protocol server for editors
+  kernel   Run a Jupyter kernel
+  serve    Run an HTTP server for running programs
+  visualize
+           Watch a program run in a full-screen terminal view
+  compile  Compile a program to another language
+  build    Compile a program to a native executable
+  check    Check programs for syntax errors
+  lint     Warn about loops that are probably mistakes
+  stats    Show what a program is made of, without running it
+  parse    List the commands or optimized instructions of a program
+  graph    Draw the loops of a program as a GraphViz graph
+  verify   Check that the optimized interpreter runs a program correctly
+  difftest Check that the interpreters and compiled targets agree on a program
+  test     Run programs on .in files and compare their output to .out files
+  grade    Run student submissions against test cases and report the results
+  bench    Time a program in one or more interpreter configurations
+  fmt      Format a program
+  gen      Generate a program from an arithmetic expression, or at random
+
+`bf <file>` is short for `bf run <file>`, and `bf -e <program>` for
+`bf run -e <program>`. A <file> of `-` reads the program from stdin.
+Run `bf <command> --help` for more information on a command.
+";
+
+const RUN_USAGE: &str = concat!(
+    "\
+Usage: bf run [options] <file>
+       bf run [options] -e <program>
+
+Runs a Brainfuck program or a compiled .bfc bytecode file. With `-` as the
+file, the program is read from stdin and `,` sees the end of input unless
+--input or --input-string is given.
+
+Options:
+  -e, --eval <program>
+                   Run <program> itself instead of reading a file
+  --input <file>   Read the input for `,` from <file> instead of stdin
+  --input-string <text>
+                   Use <text> as the input for `,`
+  -o, --output <file>
+                   Write the output of `.` to <file> instead of stdout
+  --io-in <path>   Have `,` read from <path> as it goes, e.g. a named pipe,
+                   or from an inherited file descriptor given as fd:<n>
+  --io-out <path>  Have `.` write to <path> as it goes, or to fd:<n>
+  --max-steps <n>  Stop with an error after executing <n> instructions
+  --timeout <time> Stop with an error after running for <time>, e.g. 5s,
+                   500ms or 2m
+  --detect-hangs   Stop with an error when a loop comes back to a state it
+                   was in before without any I/O in between, as it would
+                   probably run forever
+  --dump-tape      Print the non-zero cells, the data pointer and the
+                   program counter to stderr when the program stops
+  --dump-tape-file <file>
+                   Like --dump-tape, but write to <file>
+  --tape-program <file>
+                   Write a Brainfuck program to <file> when the program
+                   stops that recreates the tape and the data pointer
+  --stats          Print execution statistics to stderr when the program
+                   stops
+  --profile        Print the 10 most executed instructions and loops with
+                   their source locations to stderr when the program stops
+  --profile-top <n>
+                   Like --profile, but show the <n> most executed ones
+  --profile-out <file>
+                   Save how often each instruction ran to <file>, for
+                   `bf compile --profile`
+  --coverage       Print the source to stderr when the program stops, with
+                   the commands that never ran highlighted, and how many ran
+  --cell-overflow <mode>
+                   What `+` and `-` do past the largest cell value or 0:
+                   wrap around, or stop with an error (default: wrap)
+  --dispatch <mode>
+                   How the interpreter gets to the code of each
+                   instruction: match or threaded (default: match)
+  --backend <name> What runs the program: interp, the interpreter, jit,
+                   native code compiled with Cranelift as it starts, or asm,
+                   x86-64 code assembled as it starts; jit and asm run
+                   Brainfuck without the options that watch or limit the
+                   run, and builds without their feature fall back to the
+                   interpreter (default: interp)
+  --unsafe-fast    Skip the checks that the data pointer stays on the tape
+                   if static analysis proves it does; programs that move
+                   it in ways the analysis can't follow run as usual
+  --numeric-io     Make `.` print the cell as a decimal number and a newline,
+                   and `,` read a decimal number
+  --raw-input      Pass each keypress to `,` right away, without waiting for
+                   Enter; Ctrl-D ends the input and Ctrl-C stops the program
+  --listen <address>
+                   Wait for a TCP connection on <address>, e.g.
+                   127.0.0.1:4000, and have `,` read from it and `.` write
+                   to it instead of stdin and stdout
+  --record <file>  Save everything `,` reads to <file>, to rerun the program
+                   the same way with --replay
+  --replay <file>  Read input from a file saved with --record instead of
+                   stdin
+  --snapshot-out <file>
+                   Save the state of the run to <file> when it stops, also
+                   on an error or Ctrl-C
+  --resume <file>  Continue the run saved with --snapshot-out in <file>
+  --tape-file <file>
+                   Keep the tape in <file>, created with every cell 0, so
+                   it can be far larger than memory and is left there for
+                   inspection however the run ends
+  --escape-output  Write non-printable output bytes as \\xNN, dimmed on a
+                   terminal
+  --show-fusions   Print which instructions were fused into
+                   superinstructions, and how often
+  --flush-every-byte
+                   Pass each output byte on right away instead of buffering
+                   output that doesn't go to a terminal
+  --debug-char     Make `#` print the program counter, the data pointer and
+                   the cells around it to stderr
+  --random         Make `?` store a random byte in the current cell
+  --seed <n>       Seed for `?`, to get the same bytes on every run
+  --self-modifying Load the program onto the tape and run it from there, so
+                   it can read and change its own code
+  --dialect <name> Language the program is written in: bf, ook, pbrain,
+                   brainfork, extended1 or decimal (default: ook for .ook
+                   files, bf otherwise)
+  --dialect-file <file>
+                   Read the program in a language that spells the commands
+                   differently, with the word for each command in <file>
+  --trace          Print every executed instruction with its location, the
+                   data pointer and the current cell value to stderr
+  --trace-file <file>
+                   Like --trace, but write to <file>
+  --trace-limit <n>
+                   Stop tracing after <n> instructions
+  --trace-format <format>
+                   Write the trace as text, a line per instruction, as a
+                   Chrome trace of loops and I/O with timestamps, to view in
+                   chrome://tracing or Perfetto, or as folded stacks of
+                   loops for flamegraph.pl or inferno (default: text)
+",
+    config_usage!(),
+    "  -h, --help       Print this help
+"
+);
+
+const REPL_USAGE: &str = concat!(
+    "\
+Usage: bf repl [options] [file]
+
+Starts an interactive session. Every line runs on the same tape, starting at
+the data pointer the previous line left. Lines with an unclosed `[` continue
+on the next line. Type `exit` to quit.
+
+With a <file>, the program is run first and the session starts on the tape
+it leaves behind.
+
+Commands:
+  :reset          Clear the tape and move the data pointer to cell 0
+  :undo           Undo the last line, :load, :time or :reset
+  :dump [range]   Show the non-zero cells, or the cells in e.g. 0..10
+  :ptr            Show the data pointer
+  :load <file>    Run a program file on the current tape
+  :save <file>    Write the data pointer and tape to a JSON file
+  :export <file>  Write a Brainfuck program that recreates the tape
+  :time <code>    Run <code> and show the time and instructions it took
+
+Options:
+  --script <file>  Run the lines in <file> instead of reading them from
+                   stdin, echoing each one after the prompt
+  --max-steps <n>  Stop a line with an error after executing <n> instructions,
+                   or `unlimited` (default: 500000000)
+",
+    config_usage!(),
+    "  -h, --help       Print this help
+"
+);
+
+const DEBUG_USAGE: &str = concat!(
+    "\
+Usage: bf debug [options] <file>
+
+Runs a program under a debugger. The program starts stopped before its first
+instruction, and commands are read from stdin.
+
+Commands:
+  break <location>  Stop right before the instruction at <location>, given
+                    as <line>:<column> in the source or as @<index>
+  break <location> if <condition>
+                    Only stop there when <condition> holds, e.g. cell[3] == 65
+  watch <cell>      Stop whenever the value of cell <cell> changes
+  delete <n>        Remove breakpoint or watchpoint <n>
+  info              List the breakpoints and watchpoints
+  step [n]          Execute the next instruction, or the next <n>
+  continue          Run until a breakpoint or the end of the program
+  step-back [n]     Undo the last instruction, or the last <n>
+  reverse-continue  Undo instructions until a breakpoint or watchpoint
+  print             Show the next instruction, the data pointer and the cell
+  tape [range]      Show the non-zero cells, or the cells in e.g. 0..10
+  quit              Exit the debugger
+
+Options:
+  --input <file>   Read the input for `,` from <file> instead of stdin
+  --input-string <text>
+                   Use <text> as the input for `,`
+",
+    config_usage!(),
+    "  -h, --help       Print this help
+"
+);
+
+const DAP_USAGE: &str = "\
+Usage: bf dap [options]
+
+Runs a Debug Adapter Protocol server on stdin and stdout, for debugging
+programs from an editor. The launch request takes the path of the program
+as `program`, and optionally `stopOnEntry`, the input for `,` as `input`, and
+`tape`, `tapeSize`, `cellSize` and `eof` with the same values as the options
+of `bf run`.
+
+Options:
+  -h, --help  Print this help
+";
+
+const LSP_USAGE: &str = "\
+Usage: bf lsp [options]
+
+Runs a Language Server Protocol server on stdin and stdout, for editing
+programs in an editor. It reports unmatched brackets and the warnings of
+`bf lint`, shows the loop depth on hover, goes from a bracket to its match
+and formats documents as `bf fmt` does.
+
+Options:
+  -h, --help  Print this help
+";
+
+const KERNEL_USAGE: &str = concat!(
+    "\
+Usage: bf kernel [options] <connection-file>
+
+Runs a Jupyter kernel. Every cell runs on the tape the previous cells left,
+with the commands of `bf repl`. A cell whose first line is `%%input` makes the
+rest of the cell the input for `,`. Jupyter starts the kernel with the
+connection file, given a kernel.json like:
+
+  {\"argv\": [\"bf\", \"kernel\", \"{connection_file}\"],
+   \"display_name\": \"Brainfuck\", \"language\": \"brainfuck\"}
+
+Options:
+  --max-steps <n>  Stop a cell with an error after executing <n> instructions,
+                   or `unlimited` (default: 500000000)
+",
+    config_usage!(),
+    "  -h, --help       Print this help
+"
+);
+
+const SERVE_USAGE: &str = "\
+Usage: bf serve [options]
+
+Runs an HTTP server for running programs, e.g. behind an online playground.
+`POST /run` takes a JSON object with the program as `program`, and optionally
+the input for `,` as `input`, `tape`, `tapeSize`, `cellSize` and `eof` with
+the same values as the options of `bf run`, and lower limits than the
+server's as `maxSteps`, `timeout` and `maxMemory`. It returns the output, the
+error the program stopped with if any, and the steps and time it took.
+
+Options:
+  --host <address>  Listen on <address> (default: 127.0.0.1)
+  --port <n>        Listen on port <n> (default: 8080)
+  --max-steps <n>   Stop a program with an error after executing <n>
+                    instructions (default: 100000000)
+  --timeout <time>  Stop a program with an error after running for <time>,
+                    e.g. 5s or 500ms (default: 5s)
+  --max-memory <size>
+                    Limit the tape to <size> bytes, and the output too, e.g.
+                    64K or 16M (default: 16M)
+  -h, --help        Print this help
+";
+
+const VISUALIZE_USAGE: &str = concat!(
+    "\
+Usage: bf visualize [options] <file>
+
+Shows a program running in a full-screen terminal view, with the next
+instruction highlighted in the source, the cells around the data pointer and
+the output. It starts paused. The output is printed again on exit.
+
+Keys:
+  space  Run or pause
+  s      Execute the next instruction
+  + -    Run faster or slower: 1, 10, 100 or 1000 instructions per frame
+  q      Quit
+
+Options:
+  --input <file>   Read the input for `,` from <file>; without it or
+                   --input-string, `,` sees the end of input
+  --input-string <text>
+                   Use <text> as the input for `,`
+",
+    config_usage!(),
+    "  -h, --help       Print this help
+"
+);
+
+const COMPILE_USAGE: &str = concat!(
+    "\
+Usage: bf compile --target <target> [options] <file>
+
+Compiles a program to another language. Programs can also be written in
+Mini, a small structured language that compiles to Brainfuck, in files
+ending in .mini or with --from mini.
+
+Targets:
+  c     Portable C
+  rust  A standalone main.rs
+  llvm  LLVM IR text (.ll)
+  wat   WebAssembly text format, with JavaScript glue
+  wasm  WebAssembly binary format, with JavaScript glue
+  js    A JavaScript function
+  bfc   Bytecode that `bf run` executes directly
+  bf    Normalized Brainfuck
+
+Options:
+  --target <target>
+                   Target to compile to
+  --from <language>
+                   Language of the program: bf or mini (default: going by the
+                   file name, else bf)
+  -o <file>        Write the output to <file> instead of stdout
+  --profile <file> Unroll the loops the program spends its time in, going by
+                   a profile saved with `bf run --profile-out`; c and llvm
+                   only
+",
+    config_usage!(),
+    "  -h, --help       Print this help
+"
+);
+
+const BUILD_USAGE: &str = concat!(
+    "\
+Usage: bf build [options] <file>
+
+Compiles a program to a native executable, with the C backend and the
+system C compiler, or with Cranelift and the C compiler as the linker.
+
+Options:
+  -o <file>        Output executable, defaults to <file> without extension
+  --backend <name> What compiles the program: c, the C backend, or
+                   cranelift, which needs the jit feature (default: c)
+  -O0|-O1|-O2|-O3|-Os
+                   Optimization level passed to the C compiler, and to
+                   Cranelift as none, speed or speed_and_size (default: -O2)
+  --cc <compiler>  C compiler to use (default: $CC or cc)
+",
+    config_usage!(),
+    "  -h, --help       Print this help
+"
+);
+
+const CHECK_USAGE: &str = "\
+Usage: bf check [options] <file>...
+
+Checks programs for syntax errors without running them.
+
+Options:
+  -h, --help  Print this help
+";
+
+const LINT_USAGE: &str = "\
+Usage: bf lint [options] <file>...
+
+Warns about loops that are probably mistakes, without running the programs:
+loops that never run because the cell is always 0, empty loops that never
+end, code after a loop that never ends and loops that move the data pointer
+on every iteration. Fails if there are any warnings.
+
+Options:
+  -h, --help  Print this help
+";
+
+const STATS_USAGE: &str = "\
+Usage: bf stats [options] <file>
+
+Prints statistics about a program without running it: its size, how often
+each command appears, the number of instructions after optimization, the
+number of loops, the deepest loop nesting and the number of cells it uses at
+least if it runs to the end.
+
+Options:
+  -h, --help  Print this help
+";
+
+const PARSE_USAGE: &str = "\
+Usage: bf parse [options] <file>
+
+Lists the commands of a program with their index, line and column, and the
+index of the matching bracket for `[` and `]`. With --ir, lists the
+instructions the program is optimized into instead, which is what the
+indices in `--trace` logs and `--profile` reports refer to.
+
+Options:
+  --ir               List the optimized instructions
+  --json             Print a JSON object with the kind of listing, `tokens`
+                     or `ir`, and an array of items instead
+  --debug-char       Treat `#` as a command
+  -h, --help         Print this help
+";
+
+const GRAPH_USAGE: &str = "\
+Usage: bf graph [options] <file>
+
+Prints the control flow of a program as a GraphViz graph in the DOT
+language: straight runs of commands are boxes, `[` and `]` are the branches
+between them and every loop is a box around its body. Render it with e.g.
+`dot -Tsvg program.dot -o program.svg`.
+
+Options:
+  -o <file>          Write the graph to <file> instead of stdout
+  --trace <file>     Show how often each part ran according to a log made
+                     with `bf run --trace-file <file>`, and shade hot parts
+  -h, --help         Print this help
+";
+
+const VERIFY_USAGE: &str = concat!(
+    "\
+Usage: bf verify [options] <file>
+
+Runs a program with a naive interpreter that executes one command at a time
+and with the optimized one, on the same input, and fails unless both produce
+the same output and leave the data pointer and the tape in the same state.
+
+Options:
+  --input <file>   Read the input for `,` from <file> instead of stdin
+  --input-string <text>
+                   Use <text> as the input for `,`
+  --max-steps <n>  Give up after the naive interpreter executes <n> commands
+",
+    config_usage!(),
+    "  -h, --help       Print this help
+"
+);
+
+const DIFFTEST_USAGE: &str = concat!(
+    "\
+Usage: bf difftest [options] <file>
+
+Runs a program on the same input through several backends and fails unless
+they all agree with the first: they must print the same output and finish,
+or fail with the same error. Backends that run in this process must also
+leave the data pointer and the tape in the same state.
+
+Backends:
+  interp  The optimized interpreter, as in `bf run`
+  naive   An interpreter that executes one command at a time
+  bfc     The optimized interpreter on the program compiled to bytecode
+  bf      The optimized interpreter on the program compiled to Brainfuck
+  jit     Native code from Cranelift, as in `bf run --backend jit`
+  asm     x86-64 code, as in `bf run --backend asm`
+  c       The program compiled to C, built with cc
+  rust    The program compiled to Rust, built with rustc
+  js      The program compiled to JavaScript, run with node
+
+jit and asm have no step limit or timeout, so they run after the other
+backends, and are reported as unavailable in builds without their feature.
+
+Options:
+  --backends <list>
+                   Comma-separated backends to compare
+                   (default: interp,naive,bfc,bf,c)
+  --input <file>   Read the input for `,` from <file> instead of stdin
+  --input-string <text>
+                   Use <text> as the input for `,`
+  --max-steps <n>  Give up after a backend in this process executes <n>
+                   instructions, or `unlimited` (default: 100000000)
+  --timeout <duration>
+                   Give up on a compiled program after <duration>
+                   (default: 10s)
+",
+    config_usage!(),
+    "  -h, --help       Print this help
+"
+);
+
+const TEST_USAGE: &str = concat!(
+    "\
+Usage: bf test [options] <path>...
+
+Runs golden tests: every .b file in the directories, searched recursively,
+or among the files given, that has a .out file with the same name next to it.
+Each one runs on the .in file with the same name, or on empty input if there
+isn't one, and passes if it finishes and prints exactly what the .out file
+holds. Failures show a diff of the expected output against the actual one.
+
+Options:
+  --max-steps <n>  Fail a test after it executes <n> instructions, or
+                   `unlimited` (default: 100000000)
+  --timeout <duration>
+                   Fail a test that runs longer than <duration>, e.g. 5s
+",
+    config_usage!(),
+    "  -h, --help       Print this help
+"
+);
+
+const GRADE_USAGE: &str = concat!(
+    "\
+Usage: bf grade --spec <file> [options] <directory>
+
+Runs every submission in <directory> against the cases in the spec, and
+prints a report with, for each student, the cases passed and failed and the
+instructions, time and cells each run took. A submission is a .b file, named
+after the student, or a directory named after the student holding one.
+
+The spec is a TOML file with the limits for each run and a [[case]] table
+for each case, giving its input and expected output as strings or files
+relative to the spec:
+
+  max_steps = 1000000   # the default is 100000000
+  timeout = \"2s\"        # the default is none
+
+  [[case]]
+  name = \"echo\"
+  input = \"hello\"
+  output = \"hello\"
+
+  [[case]]
+  input_file = \"cases/long.in\"
+  output_file = \"cases/long.out\"
+
+Options:
+  --spec <file>    The cases to run
+  --csv            Print CSV, one line per student, instead of JSON
+  -o <file>        Write the report to <file> instead of stdout
+",
+    config_usage!(),
+    "  -h, --help       Print this help
+"
+);
+
+const BENCH_USAGE: &str = concat!(
+    "\
+Usage: bf bench [options] <file>
+
+Runs a program a number of times on the same input, throwing the output
+away, and prints the fastest, mean and standard deviation of the wall time
+and the instructions executed per second. With several --config options,
+each configuration is benchmarked in turn.
+
+Options:
+  --iterations <n> Run the program <n> times per configuration
+                   (default: 10)
+  --input <file>   Read the input for `,` from <file> instead of stdin
+  --input-string <text>
+                   Use <text> as the input for `,`
+  --config <options>
+                   Benchmark the interpreter with <options>, e.g.
+                   \"-O0 --dispatch threaded\"; -O0 runs without
+                   superinstructions and -O1 with them, and --dispatch and
+                   --unsafe-fast are as for `bf run` (default: -O1)
+",
+    config_usage!(),
+    "  -h, --help       Print this help
+"
+);
+
+const FMT_USAGE: &str = "\
+Usage: bf fmt [options] <file>
+
+Prints a program with every `[` and `]` on a line of its own and loop bodies
+indented by nesting depth. Runs of the same command are separated by spaces,
+long lines are wrapped and comments are kept on lines of their own.
+
+Options:
+  --width <n>        Wrap lines at <n> columns (default: 80)
+  --check            Print nothing, but fail if the file isn't formatted
+  --minify           Print only the commands, on a single line, and the size
+                     before and after to stderr
+  --remove-no-ops    With --minify, also remove commands that cancel each
+                     other out: +- -+ <> ><
+  -h, --help         Print this help
+";
+
+const GEN_USAGE: &str = "\
+Usage: bf gen expr [options] <expression>
+       bf gen random [options]
+
+`bf gen expr` generates a program that computes an integer expression into
+cell 0, e.g. `bf gen expr \"3*7+2\"`. Expressions are made of whole numbers,
+`+`, `-`, `*`, `/` (rounding down), `%` and parentheses. Arithmetic wraps
+around at the cell width, as it does in Brainfuck, so `2-5` is 253 with 8-bit
+cells. Every step of the computation is on a line of its own, commented with
+what it does.
+
+`bf gen random` generates a random program with balanced brackets, for
+fuzzing interpreters. It may never finish, so run it with --max-steps.
+
+Options for expr:
+  --print            Also print the result in decimal, followed by a newline
+  --cell-size <n>    Cell width in bits the program is for: 8, 16 or 32
+                     (default: 8)
+
+Options for random:
+  --size <n>         Number of commands (default: 100)
+  --seed <n>         Seed, to get the same program every time (default: the
+                     current time)
+  --loops <p>        Chance of each command being a bracket, from 0 to 1
+                     (default: 0.1)
+
+  -h, --help         Print this help
+";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run(Box<RunOptions>),
+    Repl(ReplOptions),
+    Debug(DebugOptions),
+    Dap,
+    Lsp,
+    Kernel(KernelOptions),
+    Serve(ServeOptions),
+    Visualize(VisualizeOptions),
+    Compile(CompileOptions),
+    Build(BuildOptions),
+    Check(CheckOptions),
+    Lint(LintOptions),
+    Stats(StatsOptions),
+    Parse(ParseOptions),
+    Graph(GraphOptions),
+    Verify(VerifyOptions),
+    Difftest(DifftestOptions),
+    Test(TestOptions),
+    Grade(GradeOptions),
+    Bench(BenchOptions),
+    Fmt(FmtOptions),
+    Gen(GenOptions),
+    Help(&'static str),
+}
+
+/// Where `bf run` gets its program from.
+#[derive(Debug, PartialEq)]
+pub enum Source {
+    File(String),
+    /// Program text given with `-e`.
+    Eval(String),
+}
+
+/// Stdin, like `bf run -`.
+impl Default for Source {
+    fn default() -> Self {
+        Source::File("-".to_owned())
+    }
+}
+
+/// A file, named pipe or device to stream to or from, or an inherited file
+/// descriptor, for `--io-in` and `--io-out`.
+#[derive(Debug, PartialEq)]
+pub enum IoPath {
+    Path(String),
+    Fd(i32),
+}
+
+impl FromStr for IoPath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("fd:") {
+            Some(fd) => fd
+                .parse()
+                .map(IoPath::Fd)
+                .map_err(|_| format!("Invalid file descriptor '{}'", fd)),
+            None => Ok(IoPath::Path(s.to_owned())),
+        }
+    }
+}
+
+/// Where `,` reads from.
+#[derive(Debug, Default, PartialEq)]
+pub enum Input {
+    #[default]
+    Stdin,
+    File(String),
+    /// Text given with `--input-string`.
+    String(String),
+}
+
+const DEFAULT_PROFILE_TOP: usize = 10;
+
+/// Enough for any reasonable line, while a runaway loop gives up within
+/// seconds.
+const DEFAULT_REPL_MAX_STEPS: u64 = 500_000_000;
+const DEFAULT_TEST_MAX_STEPS: u64 = 100_000_000;
+const DEFAULT_DIFFTEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a diagnostic such as `--dump-tape` or `--trace` is written.
+#[derive(Debug, PartialEq)]
+pub enum Destination {
+    Stderr,
+    File(String),
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct RunOptions {
+    pub program: Source,
+    pub input: Input,
+    pub output: Option<String>,
+    pub max_steps: Option<u64>,
+    pub timeout: Option<Duration>,
+    /// Stop loops that probably never end.
+    pub detect_hangs: bool,
+    pub dump_tape: Option<Destination>,
+    /// File to write a program that recreates the tape to.
+    pub tape_program: Option<String>,
+    pub stats: bool,
+    /// Number of entries to show in the `--profile` report.
+    pub profile: Option<usize>,
+    /// File to save the execution counts to.
+    pub profile_out: Option<String>,
+    pub coverage: bool,
+    pub trace: Option<Destination>,
+    /// Number of instructions to trace at most.
+    pub trace_limit: Option<u64>,
+    pub trace_format: TraceFormat,
+    pub cell_overflow: CellOverflow,
+    pub dispatch: Dispatch,
+    pub backend: native::Backend,
+    pub unsafe_fast: bool,
+    pub numeric_io: bool,
+    pub escape_output: bool,
+    pub flush_every_byte: bool,
+    pub show_fusions: bool,
+    pub raw_input: bool,
+    /// Address to accept a connection on, for the input and output.
+    pub listen: Option<String>,
+    pub io_in: Option<IoPath>,
+    pub io_out: Option<IoPath>,
+    /// File to save the input to.
+    pub record: Option<String>,
+    /// File saved with `--record` to read the input from.
+    pub replay: Option<String>,
+    /// File to save the state to when the run stops.
+    pub snapshot_out: Option<String>,
+    /// File saved with `--snapshot-out` to continue from.
+    pub resume: Option<String>,
+    /// File to map the tape to.
+    pub tape_file: Option<String>,
+    /// Treat `#` as a command that prints the interpreter state.
+    pub debug_char: bool,
+    /// Treat `?` as a command that stores a random byte.
+    pub random: bool,
+    /// Seed for `?`, to get the same bytes on every run.
+    pub seed: Option<u64>,
+    /// Run the program from the tape, where it can change itself.
+    pub self_modifying: bool,
+    /// `None` to go by the file extension.
+    pub dialect: Option<Dialect>,
+    /// TOML file of words for the commands, for a custom dialect.
+    pub dialect_file: Option<String>,
+    pub config: Config,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ReplOptions {
+    /// Program to run before the session starts.
+    pub(crate) program: Option<String>,
+    /// File of lines to run instead of reading them from stdin.
+    pub(crate) script: Option<String>,
+    /// Step limit for each line.
+    pub(crate) max_steps: Option<u64>,
+    pub(crate) config: Config,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct KernelOptions {
+    /// The JSON file from Jupyter with the ports to listen on and the key to
+    /// sign messages with.
+    pub(crate) connection_file: String,
+    /// Step limit for each cell.
+    pub(crate) max_steps: Option<u64>,
+    pub(crate) config: Config,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ServeOptions {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) limits: Limits,
+}
+
+/// Limits on a program run by `bf serve`. Requests can lower them.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct Limits {
+    pub(crate) max_steps: u64,
+    pub(crate) timeout: Duration,
+    /// Bytes of tape, and bytes of output.
+    pub(crate) max_memory: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_steps: 100_000_000,
+            timeout: Duration::from_secs(5),
+            max_memory: 16 << 20,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DebugOptions {
+    pub(crate) program: String,
+    pub(crate) input: Input,
+    pub(crate) config: Config,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct VisualizeOptions {
+    pub(crate) program: String,
+    pub(crate) input: Input,
+    pub(crate) config: Config,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CompileOptions {
+    pub program: String,
+    /// `None` to go by the file name.
+    pub from: Option<Language>,
+    pub target: Target,
+    pub output: Option<String>,
+    /// File saved with `bf run --profile-out`.
+    pub profile: Option<String>,
+    pub config: Config,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BuildOptions {
+    pub program: String,
+    pub output: Option<String>,
+    pub backend: BuildBackend,
+    pub opt_level: String,
+    pub cc: String,
+    pub config: Config,
+}
+
+/// What compiles the program in `bf build`, before the C compiler builds
+/// or links the executable.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum BuildBackend {
+    /// The C backend of `bf compile`.
+    #[default]
+    C,
+    /// Cranelift, to an object file with a runtime in C.
+    Cranelift,
+}
+
+impl FromStr for BuildBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "c" => Ok(BuildBackend::C),
+            "cranelift" => Ok(BuildBackend::Cranelift),
+            _ => Err(format!("Invalid backend '{}', expected c or cranelift", s)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CheckOptions {
+    pub programs: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct LintOptions {
+    pub programs: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct StatsOptions {
+    pub program: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseOptions {
+    pub program: String,
+    /// List optimized instructions instead of commands.
+    pub ir: bool,
+    pub json: bool,
+    pub debug_char: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct GraphOptions {
+    pub program: String,
+    pub output: Option<String>,
+    /// `--trace-file` log to count executions from.
+    pub trace: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct VerifyOptions {
+    pub(crate) program: String,
+    pub(crate) input: Input,
+    pub(crate) max_steps: Option<u64>,
+    pub(crate) config: Config,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DifftestOptions {
+    pub(crate) program: String,
+    /// At least two, the first being the one the others are compared to.
+    pub(crate) backends: Vec<Backend>,
+    pub(crate) input: Input,
+    pub(crate) max_steps: Option<u64>,
+    pub(crate) timeout: Duration,
+    pub(crate) config: Config,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TestOptions {
+    /// Directories to search for tests, or test programs.
+    pub(crate) paths: Vec<String>,
+    pub(crate) max_steps: Option<u64>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) config: Config,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct GradeOptions {
+    pub(crate) spec: String,
+    /// The directory with a submission for each student.
+    pub(crate) submissions: String,
+    /// Report in CSV instead of JSON.
+    pub(crate) csv: bool,
+    pub(crate) output: Option<String>,
+    pub(crate) config: Config,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BenchOptions {
+    pub(crate) program: String,
+    pub(crate) input: Input,
+    pub(crate) iterations: usize,
+    pub(crate) configs: Vec<BenchConfig>,
+    pub(crate) config: Config,
+}
+
+/// An interpreter configuration for `bf bench`.
+#[derive(Debug, PartialEq)]
+pub(crate) struct BenchConfig {
+    /// The options as given, to show in the report.
+    pub(crate) name: String,
+    /// Whether to fuse superinstructions, see `fuse::fuse`.
+    pub(crate) fuse: bool,
+    pub(crate) dispatch: Dispatch,
+    pub(crate) unsafe_fast: bool,
+}
+
+impl FromStr for BenchConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let args: Vec<String> = s.split_whitespace().map(str::to_owned).collect();
+        let mut config = BenchConfig {
+            name: args.join(" "),
+            fuse: true,
+            dispatch: Dispatch::Match,
+            unsafe_fast: false,
+        };
+        let mut parser = ArgParser::new(&args);
+        while let Some(arg) = parser.next() {
+            match arg {
+                Arg::Flag("-O0") => config.fuse = false,
+                Arg::Flag("-O1") => config.fuse = true,
+                Arg::Flag("--dispatch") => config.dispatch = parser.value("--dispatch")?.parse()?,
+                Arg::Flag("--unsafe-fast") => config.unsafe_fast = true,
+                _ => return Err(format!("Invalid --config '{}': {}", s, arg.unexpected())),
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FmtOptions {
+    pub program: String,
+    pub width: usize,
+    /// Only check that the file is formatted.
+    pub check: bool,
+    pub minify: bool,
+    /// Let `--minify` remove commands that cancel each other out.
+    pub remove_no_ops: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GenOptions {
+    Expr {
+        expression: String,
+        /// Make the program print the result too.
+        print: bool,
+        cell_size: CellSize,
+    },
+    Random {
+        size: usize,
+        /// `None` to seed with the current time.
+        seed: Option<u64>,
+        /// The chance of each command being a bracket.
+        loops: f64,
+    },
+}
+
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (command.as_str(), rest),
+        None => return Err(format!("Missing command\n\n{}", USAGE)),
+    };
+
+    match command {
+        "run" => parse_run(rest),
+        "repl" => parse_repl(rest),
+        "debug" => parse_debug(rest),
+        "dap" => parse_dap(rest),
+        "lsp" => parse_lsp(rest),
+        "kernel" => parse_kernel(rest),
+        "serve" => parse_serve(rest),
+        "visualize" => parse_visualize(rest),
+        "compile" => parse_compile(rest),
+        "build" => parse_build(rest),
+        "check" => parse_check(rest),
+        "lint" => parse_lint(rest),
+        "stats" => parse_stats(rest),
+        "parse" => parse_parse(rest),
+        "graph" => parse_graph(rest),
+        "verify" => parse_verify(rest),
+        "difftest" => parse_difftest(rest),
+        "test" => parse_test(rest),
+        "grade" => parse_grade(rest),
+        "bench" => parse_bench(rest),
+        "fmt" => parse_fmt(rest),
+        "gen" => parse_gen(rest),
+        "help" | "-h" | "--help" => Ok(Command::Help(USAGE)),
+        _ if !is_flag(command) || is_eval_flag(command) => parse_run(args),
+        _ => Err(format!("Unknown command '{}'\n\n{}", command, USAGE)),
+    }
+}
+
+fn parse_run(args: &[String]) -> Result<Command, String> {
+    let mut program = None;
+    let mut input = Input::Stdin;
+    let mut output = None;
+    let mut max_steps = None;
+    let mut timeout = None;
+    let mut detect_hangs = false;
+    let mut dump_tape = None;
+    let mut tape_program = None;
+    let mut stats = false;
+    let mut profile = None;
+    let mut profile_out = None;
+    let mut coverage = false;
+    let mut trace = None;
+    let mut trace_limit = None;
+    let mut trace_format = None;
+    let mut cell_overflow = CellOverflow::Wrap;
+    let mut dispatch = Dispatch::Match;
+    let mut backend = native::Backend::Interp;
+    let mut unsafe_fast = false;
+    let mut numeric_io = false;
+    let mut escape_output = false;
+    let mut flush_every_byte = false;
+    let mut show_fusions = false;
+    let mut raw_input = false;
+    let mut listen = None;
+    let mut io_in = None;
+    let mut io_out = None;
+    let mut record = None;
+    let mut replay = None;
+    let mut snapshot_out = None;
+    let mut resume = None;
+    let mut tape_file = None;
+    let mut debug_char = false;
+    let mut random = false;
+    let mut seed = None;
+    let mut self_modifying = false;
+    let mut dialect = None;
+    let mut dialect_file = None;
+    let mut config = Config::default();
+
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(RUN_USAGE)),
+            Arg::Flag(flag) if is_config_flag(flag) => {
+                parse_config_flag(flag, &mut parser, &mut config)?
+            }
+            Arg::Flag(flag @ ("-e" | "--eval")) => match program {
+                None => program = Some(Source::Eval(parser.value(flag)?.to_owned())),
+                Some(Source::Eval(_)) => return Err(format!("Duplicate option '{}'", flag)),
+                Some(Source::File(_)) => {
+                    return Err(format!("{} can't be combined with a program file", flag))
+                }
+            },
+            Arg::Flag("--input") => input = Input::File(parser.value("--input")?.to_owned()),
+            Arg::Flag("--input-string") => {
+                input = Input::String(parser.value("--input-string")?.to_owned())
+            }
+            Arg::Flag(flag @ ("-o" | "--output")) => output = Some(parser.value(flag)?.to_owned()),
+            Arg::Flag("--io-in") => io_in = Some(parser.value("--io-in")?.parse()?),
+            Arg::Flag("--io-out") => io_out = Some(parser.value("--io-out")?.parse()?),
+            Arg::Flag("--max-steps") => {
+                max_steps = Some(parse_step_count(parser.value("--max-steps")?)?)
+            }
+            Arg::Flag("--timeout") => timeout = Some(parse_duration(parser.value("--timeout")?)?),
+            Arg::Flag("--detect-hangs") => detect_hangs = true,
+            Arg::Flag("--dump-tape") => dump_tape = Some(Destination::Stderr),
+            Arg::Flag("--stats") => stats = true,
+            Arg::Flag("--profile") => profile = Some(DEFAULT_PROFILE_TOP),
+            Arg::Flag("--profile-top") => {
+                let value = parser.value("--profile-top")?;
+                let top = value
+                    .parse()
+                    .map_err(|_| format!("Invalid count '{}'", value))?;
+                profile = Some(top);
+            }
+            Arg::Flag("--profile-out") => {
+                profile_out = Some(parser.value("--profile-out")?.to_owned())
+            }
+            Arg::Flag("--coverage") => coverage = true,
+            Arg::Flag("--dump-tape-file") => {
+                dump_tape = Some(Destination::File(
+                    parser.value("--dump-tape-file")?.to_owned(),
+                ))
+            }
+            Arg::Flag("--tape-program") => {
+                tape_program = Some(parser.value("--tape-program")?.to_owned())
+            }
+            Arg::Flag("--cell-overflow") => {
+                cell_overflow = parser.value("--cell-overflow")?.parse()?
+            }
+            Arg::Flag("--dispatch") => dispatch = parser.value("--dispatch")?.parse()?,
+            Arg::Flag("--backend") => backend = parser.value("--backend")?.parse()?,
+            Arg::Flag("--unsafe-fast") => unsafe_fast = true,
+            Arg::Flag("--numeric-io") => numeric_io = true,
+            Arg::Flag("--escape-output") => escape_output = true,
+            Arg::Flag("--flush-every-byte") => flush_every_byte = true,
+            Arg::Flag("--show-fusions") => show_fusions = true,
+            Arg::Flag("--raw-input") => raw_input = true,
+            Arg::Flag("--listen") => listen = Some(parser.value("--listen")?.to_owned()),
+            Arg::Flag("--record") => record = Some(parser.value("--record")?.to_owned()),
+            Arg::Flag("--replay") => replay = Some(parser.value("--replay")?.to_owned()),
+            Arg::Flag("--snapshot-out") => {
+                snapshot_out = Some(parser.value("--snapshot-out")?.to_owned())
+            }
+            Arg::Flag("--resume") => resume = Some(parser.value("--resume")?.to_owned()),
+            Arg::Flag("--tape-file") => tape_file = Some(parser.value("--tape-file")?.to_owned()),
+            Arg::Flag("--debug-char") => debug_char = true,
+            Arg::Flag("--random") => random = true,
+            Arg::Flag("--self-modifying") => self_modifying = true,
+            Arg::Flag("--seed") => {
+                let value = parser.value("--seed")?;
+                let value = value
+                    .parse()
+                    .map_err(|_| format!("Invalid seed '{}'", value))?;
+                seed = Some(value);
+            }
+            Arg::Flag("--dialect") => dialect = Some(parser.value("--dialect")?.parse()?),
+            Arg::Flag("--dialect-file") => {
+                dialect_file = Some(parser.value("--dialect-file")?.to_owned())
+            }
+            Arg::Flag("--trace") => trace = Some(Destination::Stderr),
+            Arg::Flag("--trace-file") => {
+                trace = Some(Destination::File(parser.value("--trace-file")?.to_owned()))
+            }
+            Arg::Flag("--trace-limit") => {
+                let value = parser.value("--trace-limit")?;
+                let limit = value
+                    .parse()
+                    .map_err(|_| format!("Invalid count '{}'", value))?;
+                trace_limit = Some(limit);
+            }
+            Arg::Flag("--trace-format") => {
+                trace_format = Some(parser.value("--trace-format")?.parse()?)
+            }
+            Arg::Positional(p) if program.is_none() => program = Some(Source::File(p.to_owned())),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+    if raw_input && input != Input::Stdin {
+        return Err("--raw-input can't be combined with --input or --input-string".to_owned());
+    }
+    if listen.is_some()
+        && (input != Input::Stdin || output.is_some() || raw_input || replay.is_some())
+    {
+        return Err(
+            "--listen can't be combined with --input, --input-string, -o, --raw-input or --replay"
+                .to_owned(),
+        );
+    }
+    if io_in.is_some()
+        && (input != Input::Stdin || raw_input || replay.is_some() || listen.is_some())
+    {
+        return Err("--io-in can't be combined with --input, --input-string, --raw-input, --replay or --listen".to_owned());
+    }
+    if io_out.is_some() && (output.is_some() || listen.is_some()) {
+        return Err("--io-out can't be combined with -o or --listen".to_owned());
+    }
+    if self_modifying
+        && (trace.is_some()
+            || profile.is_some()
+            || profile_out.is_some()
+            || coverage
+            || detect_hangs
+            || snapshot_out.is_some()
+            || resume.is_some()
+            || random)
+    {
+        return Err("--self-modifying can't be combined with --trace, --profile, --profile-out, --coverage, --detect-hangs, --snapshot-out, --resume or --random".to_owned());
+    }
+    if tape_file.is_some() && (config.tape_mode == TapeMode::Grow || resume.is_some()) {
+        return Err(
+            "--tape-file needs a tape of fixed size and can't be combined with --resume".to_owned(),
+        );
+    }
+    if seed.is_some() && !random {
+        return Err("--seed needs --random".to_owned());
+    }
+    if trace_format.is_some() && trace.is_none() {
+        return Err("--trace-format needs --trace or --trace-file".to_owned());
+    }
+    if dialect.is_some() && dialect_file.is_some() {
+        return Err("--dialect can't be combined with --dialect-file".to_owned());
+    }
+    if replay.is_some() && (raw_input || input != Input::Stdin) {
+        return Err(
+            "--replay can't be combined with --input, --input-string or --raw-input".to_owned(),
+        );
+    }
+    // Compiled code only does I/O; everything else is the interpreter's.
+    let needs_interpreter = [
+        ("--max-steps", max_steps.is_some()),
+        ("--timeout", timeout.is_some()),
+        ("--detect-hangs", detect_hangs),
+        ("--dump-tape", dump_tape.is_some()),
+        ("--tape-program", tape_program.is_some()),
+        ("--stats", stats),
+        ("--profile", profile.is_some()),
+        ("--profile-out", profile_out.is_some()),
+        ("--coverage", coverage),
+        ("--trace", trace.is_some()),
+        ("--cell-overflow", cell_overflow != CellOverflow::Wrap),
+        ("--dispatch", dispatch != Dispatch::Match),
+        ("--unsafe-fast", unsafe_fast),
+        ("--numeric-io", numeric_io),
+        ("--show-fusions", show_fusions),
+        ("--snapshot-out", snapshot_out.is_some()),
+        ("--resume", resume.is_some()),
+        ("--tape-file", tape_file.is_some()),
+        ("--debug-char", debug_char),
+        ("--random", random),
+        ("--self-modifying", self_modifying),
+    ];
+    if let Some((flag, _)) = needs_interpreter.iter().find(|(_, given)| *given) {
+        if backend != native::Backend::Interp {
+            return Err(format!(
+                "{} can't be combined with --backend {}",
+                flag,
+                backend.name()
+            ));
+        }
+    }
+
+    Ok(Command::Run(Box::new(RunOptions {
+        program: program.ok_or("Missing program file or -e")?,
+        input,
+        output,
+        max_steps,
+        timeout,
+        detect_hangs,
+        dump_tape,
+        tape_program,
+        stats,
+        profile,
+        profile_out,
+        coverage,
+        trace,
+        trace_limit,
+        trace_format: trace_format.unwrap_or_default(),
+        cell_overflow,
+        dispatch,
+        backend,
+        unsafe_fast,
+        numeric_io,
+        escape_output,
+        flush_every_byte,
+        show_fusions,
+        raw_input,
+        listen,
+        io_in,
+        io_out,
+        record,
+        replay,
+        snapshot_out,
+        resume,
+        tape_file,
+        debug_char,
+        random,
+        seed,
+        self_modifying,
+        dialect,
+        dialect_file,
+        config,
+    })))
+}
+
+fn parse_repl(args: &[String]) -> Result<Command, String> {
+    let mut program = None;
+    let mut script = None;
+    let mut max_steps = Some(DEFAULT_REPL_MAX_STEPS);
+    let mut config = Config::default();
+
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(REPL_USAGE)),
+            Arg::Flag(flag) if is_config_flag(flag) => {
+                parse_config_flag(flag, &mut parser, &mut config)?
+            }
+            Arg::Flag("--max-steps") => {
+                max_steps = match parser.value("--max-steps")? {
+                    "unlimited" => None,
+                    value => Some(parse_step_count(value)?),
+                }
+            }
+            Arg::Flag("--script") => script = Some(parser.value("--script")?.to_owned()),
+            Arg::Positional(p) if program.is_none() => program = Some(p.to_owned()),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    Ok(Command::Repl(ReplOptions {
+        program,
+        script,
+        max_steps,
+        config,
+    }))
+}
+
+fn parse_debug(args: &[String]) -> Result<Command, String> {
+    let mut program = None;
+    let mut input = Input::Stdin;
+    let mut config = Config::default();
+
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(DEBUG_USAGE)),
+            Arg::Flag(flag) if is_config_flag(flag) => {
+                parse_config_flag(flag, &mut parser, &mut config)?
+            }
+            Arg::Flag("--input") => input = Input::File(parser.value("--input")?.to_owned()),
+            Arg::Flag("--input-string") => {
+                input = Input::String(parser.value("--input-string")?.to_owned())
+            }
+            Arg::Positional(p) if program.is_none() => program = Some(p.to_owned()),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    Ok(Command::Debug(DebugOptions {
+        program: program.ok_or("Missing program file")?,
+        input,
+        config,
+    }))
+}
+
+fn parse_dap(args: &[String]) -> Result<Command, String> {
+    let mut parser = ArgParser::new(args);
+    if let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(DAP_USAGE)),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+    Ok(Command::Dap)
+}
+
+fn parse_lsp(args: &[String]) -> Result<Command, String> {
+    let mut parser = ArgParser::new(args);
+    if let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(LSP_USAGE)),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+    Ok(Command::Lsp)
+}
+
+fn parse_kernel(args: &[String]) -> Result<Command, String> {
+    let mut connection_file = None;
+    let mut max_steps = Some(DEFAULT_REPL_MAX_STEPS);
+    let mut config = Config::default();
+
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(KERNEL_USAGE)),
+            Arg::Flag(flag) if is_config_flag(flag) => {
+                parse_config_flag(flag, &mut parser, &mut config)?
+            }
+            Arg::Flag("--max-steps") => {
+                max_steps = match parser.value("--max-steps")? {
+                    "unlimited" => None,
+                    value => Some(parse_step_count(value)?),
+                }
+            }
+            Arg::Positional(p) if connection_file.is_none() => connection_file = Some(p.to_owned()),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    Ok(Command::Kernel(KernelOptions {
+        connection_file: connection_file.ok_or("Missing connection file")?,
+        max_steps,
+        config,
+    }))
+}
+
+fn parse_serve(args: &[String]) -> Result<Command, String> {
+    let mut host = "127.0.0.1".to_owned();
+    let mut port = 8080;
+    let mut limits = Limits::default();
+
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(SERVE_USAGE)),
+            Arg::Flag("--host") => host = parser.value("--host")?.to_owned(),
+            Arg::Flag("--port") => {
+                let value = parser.value("--port")?;
+                port = value
+                    .parse()
+                    .map_err(|_| format!("Invalid port '{}'", value))?;
+            }
+            Arg::Flag("--max-steps") => {
+                limits.max_steps = parse_step_count(parser.value("--max-steps")?)?
+            }
+            Arg::Flag("--timeout") => limits.timeout = parse_duration(parser.value("--timeout")?)?,
+            Arg::Flag("--max-memory") => {
+                limits.max_memory = parse_memory(parser.value("--max-memory")?)?
+            }
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    Ok(Command::Serve(ServeOptions { host, port, limits }))
+}
+
+fn parse_visualize(args: &[String]) -> Result<Command, String> {
+    let mut program = None;
+    let mut input = Input::Stdin;
+    let mut config = Config::default();
+
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(VISUALIZE_USAGE)),
+            Arg::Flag(flag) if is_config_flag(flag) => {
+                parse_config_flag(flag, &mut parser, &mut config)?
+            }
+            Arg::Flag("--input") => input = Input::File(parser.value("--input")?.to_owned()),
+            Arg::Flag("--input-string") => {
+                input = Input::String(parser.value("--input-string")?.to_owned())
+            }
+            Arg::Positional(p) if program.is_none() => program = Some(p.to_owned()),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    Ok(Command::Visualize(VisualizeOptions {
+        program: program.ok_or("Missing program file")?,
+        input,
+        config,
+    }))
+}
+
+fn parse_compile(args: &[String]) -> Result<Command, String> {
+    let mut program = None;
+    let mut from = None;
+    let mut target = None;
+    let mut output = None;
+    let mut profile = None;
+    let mut config = Config::default();
+
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(COMPILE_USAGE)),
+            Arg::Flag(flag) if is_config_flag(flag) => {
+                parse_config_flag(flag, &mut parser, &mut config)?
+            }
+            Arg::Flag("--target") => target = Some(parser.value("--target")?.parse()?),
+            Arg::Flag("--from") => from = Some(parser.value("--from")?.parse()?),
+            Arg::Flag("-o") => output = Some(parser.value("-o")?.to_owned()),
+            Arg::Flag("--profile") => profile = Some(parser.value("--profile")?.to_owned()),
+            Arg::Positional(p) if program.is_none() => program = Some(p.to_owned()),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    Ok(Command::Compile(CompileOptions {
+        program: program.ok_or("Missing program file")?,
+        from,
+        target: target.ok_or("Missing --target")?,
+        output,
+        profile,
+        config,
+    }))
+}
+
+fn parse_build(args: &[String]) -> Result<Command, String> {
+    let mut program = None;
+    let mut output = None;
+    let mut backend = BuildBackend::C;
+    let mut opt_level = "-O2".to_owned();
+    let mut cc = None;
+    let mut config = Config::default();
+
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(BUILD_USAGE)),
+            Arg::Flag(flag) if is_config_flag(flag) => {
+                parse_config_flag(flag, &mut parser, &mut config)?
+            }
+            Arg::Flag("-o") => output = Some(parser.value("-o")?.to_owned()),
+            Arg::Flag("--backend") => backend = parser.value("--backend")?.parse()?,
+            Arg::Flag("--cc") => cc = Some(parser.value("--cc")?.to_owned()),
+            Arg::Flag(level @ ("-O0" | "-O1" | "-O2" | "-O3" | "-Os")) => {
+                opt_level = level.to_owned()
+            }
+            Arg::Positional(p) if program.is_none() => program = Some(p.to_owned()),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    Ok(Command::Build(BuildOptions {
+        program: program.ok_or("Missing program file")?,
+        output,
+        backend,
+        opt_level,
+        cc: cc.unwrap_or_else(|| std::env::var("CC").unwrap_or_else(|_| "cc".to_owned())),
+        config,
+    }))
+}
+
+fn parse_check(args: &[String]) -> Result<Command, String> {
+    let mut programs = vec![];
+
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(CHECK_USAGE)),
+            Arg::Positional(p) => programs.push(p.to_owned()),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    if programs.is_empty() {
+        return Err("Missing program file".to_owned());
+    }
+    Ok(Command::Check(CheckOptions { programs }))
+}
+
+fn parse_lint(args: &[String]) -> Result<Command, String> {
+    let mut programs = vec![];
+
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(LINT_USAGE)),
+            Arg::Positional(p) => programs.push(p.to_owned()),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    if programs.is_empty() {
+        return Err("Missing program file".to_owned());
+    }
+    Ok(Command::Lint(LintOptions { programs }))
+}
+
+fn parse_stats(args: &[String]) -> Result<Command, String> {
+    let mut program = None;
+
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(STATS_USAGE)),
+            Arg::Positional(p) if program.is_none() => program = Some(p.to_owned()),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    Ok(Command::Stats(StatsOptions {
+        program: program.ok_or("Missing program file")?,
+    }))
+}
+
+fn parse_parse(args: &[String]) -> Result<Command, String> {
+    let mut program = None;
+    let mut ir = false;
+    let mut json = false;
+    let mut debug_char = false;
+
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(PARSE_USAGE)),
+            Arg::Flag("--ir") => ir = true,
+            Arg::Flag("--json") => json = true,
+            Arg::Flag("--debug-char") => debug_char = true,
+            Arg::Positional(p) if program.is_none() => program = Some(p.to_owned()),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    Ok(Command::Parse(ParseOptions {
+        program: program.ok_or("Missing program file")?,
+        ir,
+        json,
+        debug_char,
+    }))
+}
+
+fn parse_graph(args: &[String]) -> Result<Command, String> {
+    let mut program = None;
+    let mut output = None;
+    let mut trace = None;
+
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(GRAPH_USAGE)),
+            Arg::Flag("-o") => output = Some(parser.value("-o")?.to_owned()),
+            Arg::Flag("--trace") => trace = Some(parser.value("--trace")?.to_owned()),
+            Arg::Positional(p) if program.is_none() => program = Some(p.to_owned()),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    Ok(Command::Graph(GraphOptions {
+        program: program.ok_or("Missing program file")?,
+        output,
+        trace,
+    }))
+}
+
+fn parse_verify(args: &[String]) -> Result<Command, String> {
+    let mut program = None;
+    let mut input = Input::Stdin;
+    let mut max_steps = None;
+    let mut config = Config::default();
+
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(VERIFY_USAGE)),
+            Arg::Flag(flag) if is_config_flag(flag) => {
+                parse_config_flag(flag, &mut parser, &mut config)?
+            }
+            Arg::Flag("--input") => input = Input::File(parser.value("--input")?.to_owned()),
+            Arg::Flag("--input-string") => {
+                input = Input::String(parser.value("--input-string")?.to_owned())
+            }
+            Arg::Flag("--max-steps") => {
+                max_steps = Some(parse_step_count(parser.value("--max-steps")?)?)
+            }
+            Arg::Positional(p) if program.is_none() => program = Some(p.to_owned()),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    Ok(Command::Verify(VerifyOptions {
+        program: program.ok_or("Missing program file")?,
+        input,
+        max_steps,
+        config,
+    }))
+}
+
+fn parse_difftest(args: &[String]) -> Result<Command, String> {
+    let mut program = None;
+    let mut backends = vec![
+        Backend::Interp,
+        Backend::Naive,
+        Backend::Bfc,
+        Backend::Bf,
+        Backend::C,
+    ];
+    let mut input = Input::Stdin;
+    let mut max_steps = Some(DEFAULT_TEST_MAX_STEPS);
+    let mut timeout = DEFAULT_DIFFTEST_TIMEOUT;
+    let mut config = Config::default();
+
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(DIFFTEST_USAGE)),
+            Arg::Flag(flag) if is_config_flag(flag) => {
+                parse_config_flag(flag, &mut parser, &mut config)?
+            }
+            Arg::Flag("--backends") => {
+                backends = parser
+                    .value("--backends")?
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()?;
+                if backends.len() < 2 {
+                    return Err("--backends needs at least two backends to compare".to_owned());
+                }
+            }
+            Arg::Flag("--input") => input = Input::File(parser.value("--input")?.to_owned()),
+            Arg::Flag("--input-string") => {
+                input = Input::String(parser.value("--input-string")?.to_owned())
+            }
+            Arg::Flag("--max-steps") => {
+                max_steps = match parser.value("--max-steps")? {
+                    "unlimited" => None,
+                    value => Some(parse_step_count(value)?),
+                }
+            }
+            Arg::Flag("--timeout") => timeout = parse_duration(parser.value("--timeout")?)?,
+            Arg::Positional(p) if program.is_none() => program = Some(p.to_owned()),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    Ok(Command::Difftest(DifftestOptions {
+        program: program.ok_or("Missing program file")?,
+        backends,
+        input,
+        max_steps,
+        timeout,
+        config,
+    }))
+}
+
+fn parse_test(args: &[String]) -> Result<Command, String> {
+    let mut paths = vec![];
+    let mut max_steps = Some(DEFAULT_TEST_MAX_STEPS);
+    let mut timeout = None;
+    let mut config = Config::default();
+
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(TEST_USAGE)),
+            Arg::Flag(flag) if is_config_flag(flag) => {
+                parse_config_flag(flag, &mut parser, &mut config)?
+            }
+            Arg::Flag("--max-steps") => {
+                max_steps = match parser.value("--max-steps")? {
+                    "unlimited" => None,
+                    value => Some(parse_step_count(value)?),
+                }
+            }
+            Arg::Flag("--timeout") => timeout = Some(parse_duration(parser.value("--timeout")?)?),
+            Arg::Positional(p) => paths.push(p.to_owned()),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    if paths.is_empty() {
+        return Err(format!("Missing test directory\n\n{}", TEST_USAGE));
+    }
+    Ok(Command::Test(TestOptions {
+        paths,
+        max_steps,
+        timeout,
+        config,
+    }))
+}
+
+fn parse_grade(args: &[String]) -> Result<Command, String> {
+    let mut spec = None;
+    let mut submissions = None;
+    let mut csv = false;
+    let mut output = None;
+    let mut config = Config::default();
+
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(GRADE_USAGE)),
+            Arg::Flag(flag) if is_config_flag(flag) => {
+                parse_config_flag(flag, &mut parser, &mut config)?
+            }
+            Arg::Flag("--spec") => spec = Some(parser.value("--spec")?.to_owned()),
+            Arg::Flag("--csv") => csv = true,
+            Arg::Flag("-o") => output = Some(parser.value("-o")?.to_owned()),
+            Arg::Positional(p) if submissions.is_none() => submissions = Some(p.to_owned()),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    Ok(Command::Grade(GradeOptions {
+        spec: spec.ok_or("Missing --spec")?,
+        submissions: submissions.ok_or("Missing submissions directory")?,
+        csv,
+        output,
+        config,
+    }))
+}
+
+fn parse_bench(args: &[String]) -> Result<Command, String> {
+    let mut program = None;
+    let mut input = Input::Stdin;
+    let mut iterations = 10;
+    let mut configs = vec![];
+    let mut config = Config::default();
+
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(BENCH_USAGE)),
+            Arg::Flag(flag) if is_config_flag(flag) => {
+                parse_config_flag(flag, &mut parser, &mut config)?
+            }
+            Arg::Flag("--input") => input = Input::File(parser.value("--input")?.to_owned()),
+            Arg::Flag("--input-string") => {
+                input = Input::String(parser.value("--input-string")?.to_owned())
+            }
+            Arg::Flag("--iterations") => {
+                let value = parser.value("--iterations")?;
+                iterations = match value.parse() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(format!("Invalid count '{}'", value)),
+                };
+            }
+            Arg::Flag("--config") => configs.push(parser.value("--config")?.parse()?),
+            Arg::Positional(p) if program.is_none() => program = Some(p.to_owned()),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+    if configs.is_empty() {
+        configs.push("-O1".parse()?);
+    }
+
+    Ok(Command::Bench(BenchOptions {
+        program: program.ok_or("Missing program file")?,
+        input,
+        iterations,
+        configs,
+        config,
+    }))
+}
+
+fn parse_fmt(args: &[String]) -> Result<Command, String> {
+    let mut program = None;
+    let mut width = formatter::DEFAULT_WIDTH;
+    let mut check = false;
+    let mut minify = false;
+    let mut remove_no_ops = false;
+
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(FMT_USAGE)),
+            Arg::Flag("--width") => {
+                let value = parser.value("--width")?;
+                width = value
+                    .parse()
+                    .ok()
+                    .filter(|&width| width > 0)
+                    .ok_or_else(|| format!("Invalid width '{}'", value))?;
+            }
+            Arg::Flag("--check") => check = true,
+            Arg::Flag("--minify") => minify = true,
+            Arg::Flag("--remove-no-ops") => remove_no_ops = true,
+            Arg::Positional(p) if program.is_none() => program = Some(p.to_owned()),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    if minify && check {
+        return Err("--minify can't be combined with --check".to_owned());
+    }
+    if remove_no_ops && !minify {
+        return Err("--remove-no-ops requires --minify".to_owned());
+    }
+
+    Ok(Command::Fmt(FmtOptions {
+        program: program.ok_or("Missing program file")?,
+        width,
+        check,
+        minify,
+        remove_no_ops,
+    }))
+}
+
+fn parse_gen(args: &[String]) -> Result<Command, String> {
+    let mut parser = ArgParser::new(args);
+    match parser.next() {
+        Some(Arg::Flag("-h" | "--help")) => Ok(Command::Help(GEN_USAGE)),
+        Some(Arg::Positional("expr")) => parse_gen_expr(parser),
+        Some(Arg::Positional("random")) => parse_gen_random(parser),
+        Some(Arg::Positional(kind)) => Err(format!("Unknown generator '{}'", kind)),
+        Some(arg) => Err(arg.unexpected()),
+        None => Err(format!("Missing generator\n\n{}", GEN_USAGE)),
+    }
+}
+
+fn parse_gen_expr(mut parser: ArgParser) -> Result<Command, String> {
+    let mut expression = None;
+    let mut print = false;
+    let mut cell_size = CellSize::Bits8;
+
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(GEN_USAGE)),
+            Arg::Flag("--print") => print = true,
+            Arg::Flag("--cell-size") => cell_size = parser.value("--cell-size")?.parse()?,
+            Arg::Positional(p) if expression.is_none() => expression = Some(p.to_owned()),
+            // An expression can start with a minus, as in `-3+4`.
+            Arg::Flag(p) if expression.is_none() && !p.starts_with("--") => {
+                expression = Some(p.to_owned())
+            }
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    Ok(Command::Gen(GenOptions::Expr {
+        expression: expression.ok_or("Missing expression")?,
+        print,
+        cell_size,
+    }))
+}
+
+fn parse_gen_random(mut parser: ArgParser) -> Result<Command, String> {
+    let mut size = 100;
+    let mut seed = None;
+    let mut loops = 0.1;
+
+    while let Some(arg) = parser.next() {
+        match arg {
+            Arg::Flag("-h" | "--help") => return Ok(Command::Help(GEN_USAGE)),
+            Arg::Flag("--size") => {
+                let value = parser.value("--size")?;
+                size = value
+                    .parse()
+                    .map_err(|_| format!("Invalid size '{}'", value))?;
+            }
+            Arg::Flag("--seed") => {
+                let value = parser.value("--seed")?;
+                let value = value
+                    .parse()
+                    .map_err(|_| format!("Invalid seed '{}'", value))?;
+                seed = Some(value);
+            }
+            Arg::Flag("--loops") => {
+                let value = parser.value("--loops")?;
+                loops = value
+                    .parse()
+                    .ok()
+                    .filter(|loops| (0.0..=1.0).contains(loops))
+                    .ok_or_else(|| format!("Invalid chance '{}', expected 0 to 1", value))?;
+            }
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    Ok(Command::Gen(GenOptions::Random { size, seed, loops }))
+}
+
+fn is_config_flag(flag: &str) -> bool {
+    matches!(flag, "--tape" | "--tape-size" | "--cell-size" | "--eof")
+}
+
+fn parse_config_flag(
+    flag: &str,
+    parser: &mut ArgParser,
+    config: &mut Config,
+) -> Result<(), String> {
+    let value = parser.value(flag)?;
+    match flag {
+        "--tape" => config.tape_mode = value.parse()?,
+        "--tape-size" => config.set_tape_size(value.parse()?),
+        "--cell-size" => config.cell_size = value.parse()?,
+        "--eof" => config.eof = value.parse()?,
+        _ => unreachable!("not a config flag: {}", flag),
+    }
+    Ok(())
+}
+
+pub(crate) fn parse_step_count(s: &str) -> Result<u64, String> {
+    s.parse().map_err(|_| format!("Invalid step count '{}'", s))
+}
+
+/// Parses a duration such as `5s`, `500ms`, `2m` or a plain number of
+/// seconds.
+pub(crate) fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration '{}', expected e.g. 5s or 500ms", s);
+    let (number, unit) = if let Some(n) = s.strip_suffix("ms") {
+        (n, 0.001)
+    } else if let Some(n) = s.strip_suffix('s') {
+        (n, 1.0)
+    } else if let Some(n) = s.strip_suffix('m') {
+        (n, 60.0)
+    } else {
+        (s, 1.0)
+    };
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    Duration::try_from_secs_f64(number * unit).map_err(|_| invalid())
+}
+
+/// Parses a number of bytes such as `4096`, `64K`, `16M` or `1G`.
+pub(crate) fn parse_memory(s: &str) -> Result<usize, String> {
+    let invalid = || format!("Invalid size '{}', expected e.g. 64K or 16M", s);
+    let (number, shift) = match s.char_indices().last() {
+        Some((i, 'K')) => (&s[..i], 10),
+        Some((i, 'M')) => (&s[..i], 20),
+        Some((i, 'G')) => (&s[..i], 30),
+        _ => (s, 0),
+    };
+    let number: usize = number.parse().map_err(|_| invalid())?;
+    number.checked_mul(1 << shift).ok_or_else(invalid)
+}
+
+fn is_eval_flag(arg: &str) -> bool {
+    arg == "-e" || arg == "--eval" || arg.starts_with("--eval=")
+}
+
+fn is_flag(arg: &str) -> bool {
+    arg.len() > 1 && arg.starts_with('-')
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Arg<'a> {
+    Flag(&'a str),
+    Positional(&'a str),
+}
+
+impl Arg<'_> {
+    fn unexpected(self) -> String {
+        match self {
+            Arg::Flag(flag) => format!("Unknown option '{}'", flag),
+            Arg::Positional(p) => format!("Unexpected argument '{}'", p),
+        }
+    }
+}
+
+/// Splits arguments into flags and positionals. Flag values can be given
+/// either as the next argument or inline as `--flag=value`.
+struct ArgParser<'a> {
+    args: std::slice::Iter<'a, String>,
+    inline_value: Option<&'a str>,
+}
+
+impl<'a> ArgParser<'a> {
+    fn new(args: &'a [String]) -> Self {
+        Self {
+            args: args.iter(),
+            inline_value: None,
+        }
+    }
+
+    fn next(&mut self) -> Option<Arg<'a>> {
+        if let Some(value) = self.inline_value.take() {
+            // A flag that doesn't take a value was given one anyway.
+            return Some(Arg::Positional(value));
+        }
+
+        let arg = self.args.next()?.as_str();
+        if !is_flag(arg) {
+            return Some(Arg::Positional(arg));
+        }
+
+        match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                self.inline_value = Some(value);
+                Some(Arg::Flag(flag))
+            }
+            _ => Some(Arg::Flag(arg)),
+        }
+    }
+
+    fn value(&mut self, flag: &str) -> Result<&'a str, String> {
+        self.inline_value
+            .take()
+            .or_else(|| self.args.next().map(String::as_str))
+            .ok_or_else(|| format!("Missing value for {}", flag))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config::{Eof, TapeSize};
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            parse(&args("run hello.b")).unwrap(),
+            Command::Run(Box::new(RunOptions {
+                program: Source::File("hello.b".to_owned()),
+                ..Default::default()
+            }))
+        );
+        assert_eq!(parse(&args("hello.b")), parse(&args("run hello.b")));
+        assert_eq!(
+            parse(&args("-e ++++.")).unwrap(),
+            Command::Run(Box::new(RunOptions {
+                program: Source::Eval("++++.".to_owned()),
+                ..Default::default()
+            }))
+        );
+        assert_eq!(parse(&args("--eval=++++.")), parse(&args("run -e ++++.")));
+        assert_eq!(
+            parse(&args(
+                "-e ,[.,] --input-string hello -o out.bin --max-steps 1000 --timeout 1.5s --detect-hangs --dump-tape --tape-program tape.b --stats --profile-top 5 --trace-file trace.log --trace-limit 100 --trace-format chrome --cell-overflow error --dispatch threaded --unsafe-fast --numeric-io --escape-output --flush-every-byte --show-fusions --tape-file tape.bin --debug-char --random --seed 42 --dialect ook"
+            ))
+            .unwrap(),
+            Command::Run(Box::new(RunOptions {
+                program: Source::Eval(",[.,]".to_owned()),
+                input: Input::String("hello".to_owned()),
+                output: Some("out.bin".to_owned()),
+                max_steps: Some(1000),
+                timeout: Some(Duration::from_millis(1500)),
+                detect_hangs: true,
+                dump_tape: Some(Destination::Stderr),
+                tape_program: Some("tape.b".to_owned()),
+                stats: true,
+                profile: Some(5),
+                trace: Some(Destination::File("trace.log".to_owned())),
+                trace_limit: Some(100),
+                trace_format: TraceFormat::Chrome,
+                cell_overflow: CellOverflow::Error,
+                dispatch: Dispatch::Threaded,
+                unsafe_fast: true,
+                numeric_io: true,
+                escape_output: true,
+                flush_every_byte: true,
+                show_fusions: true,
+                tape_file: Some("tape.bin".to_owned()),
+                debug_char: true,
+                random: true,
+                seed: Some(42),
+                dialect: Some(Dialect::Ook),
+                ..Default::default()
+            }))
+        );
+        assert_eq!(
+            parse(&args("-")).unwrap(),
+            Command::Run(Box::new(RunOptions {
+                program: Source::File("-".to_owned()),
+                ..Default::default()
+            }))
+        );
+        assert_eq!(
+            parse(&args("a.b --backend asm -o out.bin")).unwrap(),
+            Command::Run(Box::new(RunOptions {
+                program: Source::File("a.b".to_owned()),
+                output: Some("out.bin".to_owned()),
+                backend: native::Backend::Asm,
+                ..Default::default()
+            }))
+        );
+        assert_eq!(
+            parse(&args("repl")).unwrap(),
+            Command::Repl(ReplOptions {
+                program: None,
+                script: None,
+                max_steps: Some(DEFAULT_REPL_MAX_STEPS),
+                config: Config::default()
+            })
+        );
+        assert_eq!(
+            parse(&args("repl game.b --max-steps unlimited --script demo.bfs")).unwrap(),
+            Command::Repl(ReplOptions {
+                program: Some("game.b".to_owned()),
+                script: Some("demo.bfs".to_owned()),
+                max_steps: None,
+                config: Config::default()
+            })
+        );
+        assert_eq!(
+            parse(&args("debug --input-string abc hello.b")).unwrap(),
+            Command::Debug(DebugOptions {
+                program: "hello.b".to_owned(),
+                input: Input::String("abc".to_owned()),
+                config: Config::default(),
+            })
+        );
+        assert_eq!(
+            parse(&args(
+                "test tests more/a.b --max-steps unlimited --timeout 2s"
+            ))
+            .unwrap(),
+            Command::Test(TestOptions {
+                paths: vec!["tests".to_owned(), "more/a.b".to_owned()],
+                max_steps: None,
+                timeout: Some(Duration::from_secs(2)),
+                config: Config::default(),
+            })
+        );
+        assert_eq!(
+            parse(&args(
+                "grade submissions --spec spec.toml --csv -o report.csv"
+            ))
+            .unwrap(),
+            Command::Grade(GradeOptions {
+                spec: "spec.toml".to_owned(),
+                submissions: "submissions".to_owned(),
+                csv: true,
+                output: Some("report.csv".to_owned()),
+                config: Config::default(),
+            })
+        );
+        assert_eq!(
+            parse(&args("grade submissions")).unwrap_err(),
+            "Missing --spec"
+        );
+        assert_eq!(
+            parse(&args(
+                "difftest a.b --backends interp,js --input-string hi --timeout 1s"
+            ))
+            .unwrap(),
+            Command::Difftest(DifftestOptions {
+                program: "a.b".to_owned(),
+                backends: vec![Backend::Interp, Backend::Js],
+                input: Input::String("hi".to_owned()),
+                max_steps: Some(DEFAULT_TEST_MAX_STEPS),
+                timeout: Duration::from_secs(1),
+                config: Config::default(),
+            })
+        );
+        assert_eq!(
+            parse(&args("difftest a.b --backends c")).unwrap_err(),
+            "--backends needs at least two backends to compare"
+        );
+        assert_eq!(parse(&args("dap")).unwrap(), Command::Dap);
+        assert_eq!(parse(&args("lsp")).unwrap(), Command::Lsp);
+        assert_eq!(
+            parse(&args("gen expr -3+4 --print --cell-size 16")).unwrap(),
+            Command::Gen(GenOptions::Expr {
+                expression: "-3+4".to_owned(),
+                print: true,
+                cell_size: CellSize::Bits16,
+            })
+        );
+        assert_eq!(
+            parse(&args("gen random --size 50 --seed 7 --loops 0.25")).unwrap(),
+            Command::Gen(GenOptions::Random {
+                size: 50,
+                seed: Some(7),
+                loops: 0.25,
+            })
+        );
+        assert_eq!(
+            parse(&args("gen random --loops 2")).unwrap_err(),
+            "Invalid chance '2', expected 0 to 1"
+        );
+        assert_eq!(
+            parse(&args("gen text hi")).unwrap_err(),
+            "Unknown generator 'text'"
+        );
+        assert_eq!(
+            parse(&args("kernel --max-steps unlimited kernel-1.json")).unwrap(),
+            Command::Kernel(KernelOptions {
+                connection_file: "kernel-1.json".to_owned(),
+                max_steps: None,
+                config: Config::default()
+            })
+        );
+        assert_eq!(
+            parse(&args("kernel")).unwrap_err(),
+            "Missing connection file"
+        );
+        assert_eq!(
+            parse(&args("serve --port 3000 --max-memory 1M --timeout 2s")).unwrap(),
+            Command::Serve(ServeOptions {
+                host: "127.0.0.1".to_owned(),
+                port: 3000,
+                limits: Limits {
+                    timeout: Duration::from_secs(2),
+                    max_memory: 1 << 20,
+                    ..Limits::default()
+                }
+            })
+        );
+        assert_eq!(
+            parse(&args("visualize --tape wrap hello.b")).unwrap(),
+            Command::Visualize(VisualizeOptions {
+                program: "hello.b".to_owned(),
+                input: Input::Stdin,
+                config: Config {
+                    tape_mode: TapeMode::Wrap,
+                    ..Config::default()
+                },
+            })
+        );
+        assert_eq!(
+            parse(&args(
+                "compile --target=c hello.b -o hello.c --profile prof.json"
+            ))
+            .unwrap(),
+            Command::Compile(CompileOptions {
+                program: "hello.b".to_owned(),
+                from: None,
+                target: Target::C,
+                output: Some("hello.c".to_owned()),
+                profile: Some("prof.json".to_owned()),
+                config: Config::default(),
+            })
+        );
+        assert_eq!(
+            parse(&args("build -O3 hello.b --cc clang")).unwrap(),
+            Command::Build(BuildOptions {
+                program: "hello.b".to_owned(),
+                output: None,
+                backend: BuildBackend::C,
+                opt_level: "-O3".to_owned(),
+                cc: "clang".to_owned(),
+                config: Config::default(),
+            })
+        );
+        assert_eq!(
+            parse(&args("build hello.b --backend cranelift -O0 --cc cc")).unwrap(),
+            Command::Build(BuildOptions {
+                program: "hello.b".to_owned(),
+                output: None,
+                backend: BuildBackend::Cranelift,
+                opt_level: "-O0".to_owned(),
+                cc: "cc".to_owned(),
+                config: Config::default(),
+            })
+        );
+        assert_eq!(
+            parse(&args("check a.b b.b")).unwrap(),
+            Command::Check(CheckOptions {
+                programs: vec!["a.b".to_owned(), "b.b".to_owned()]
+            })
+        );
+        assert_eq!(
+            parse(&args("lint a.b")).unwrap(),
+            Command::Lint(LintOptions {
+                programs: vec!["a.b".to_owned()]
+            })
+        );
+        assert_eq!(
+            parse(&args("stats a.b")).unwrap(),
+            Command::Stats(StatsOptions {
+                program: "a.b".to_owned()
+            })
+        );
+        assert_eq!(
+            parse(&args(
+                "verify --input-string abc --max-steps 1000 --eof zero a.b"
+            ))
+            .unwrap(),
+            Command::Verify(VerifyOptions {
+                program: "a.b".to_owned(),
+                input: Input::String("abc".to_owned()),
+                max_steps: Some(1000),
+                config: Config {
+                    eof: Eof::Zero,
+                    ..Config::default()
+                },
+            })
+        );
+        let bench = [
+            "bench",
+            "a.b",
+            "--iterations",
+            "3",
+            "--config",
+            "-O0  --dispatch threaded --unsafe-fast",
+        ];
+        assert_eq!(
+            parse(&bench.map(str::to_owned)).unwrap(),
+            Command::Bench(BenchOptions {
+                program: "a.b".to_owned(),
+                input: Input::Stdin,
+                iterations: 3,
+                configs: vec![BenchConfig {
+                    name: "-O0 --dispatch threaded --unsafe-fast".to_owned(),
+                    fuse: false,
+                    dispatch: Dispatch::Threaded,
+                    unsafe_fast: true,
+                }],
+                config: Config::default(),
+            })
+        );
+        assert_eq!(
+            parse(&args("bench --config=-O2 a.b")).unwrap_err(),
+            "Invalid --config '-O2': Unknown option '-O2'"
+        );
+        assert_eq!(
+            parse(&args("fmt --check --width 40 a.b")).unwrap(),
+            Command::Fmt(FmtOptions {
+                program: "a.b".to_owned(),
+                width: 40,
+                check: true,
+                minify: false,
+                remove_no_ops: false,
+            })
+        );
+        assert_eq!(
+            parse(&args("fmt a.b --minify --remove-no-ops")).unwrap(),
+            Command::Fmt(FmtOptions {
+                program: "a.b".to_owned(),
+                width: formatter::DEFAULT_WIDTH,
+                check: false,
+                minify: true,
+                remove_no_ops: true,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_config() {
+        let config = Config {
+            tape_size: 1_000_000,
+            ..Config::default()
+        };
+        assert_eq!(
+            parse(&args("run --tape-size 1000000 hello.b")).unwrap(),
+            Command::Run(Box::new(RunOptions {
+                program: Source::File("hello.b".to_owned()),
+                config,
+                ..Default::default()
+            }))
+        );
+
+        let mut config = Config::default();
+        config.set_tape_size(TapeSize::Unlimited);
+        assert_eq!(
+            parse(&args("repl --tape-size=unlimited")).unwrap(),
+            Command::Repl(ReplOptions {
+                program: None,
+                script: None,
+                max_steps: Some(DEFAULT_REPL_MAX_STEPS),
+                config
+            })
+        );
+
+        let config = Config {
+            tape_size: 16,
+            tape_mode: TapeMode::Grow,
+            ..Config::default()
+        };
+        assert_eq!(
+            parse(&args(
+                "compile --tape=grow --tape-size 16 --target c hello.b"
+            ))
+            .unwrap(),
+            Command::Compile(CompileOptions {
+                program: "hello.b".to_owned(),
+                from: None,
+                target: Target::C,
+                output: None,
+                profile: None,
+                config,
+            })
+        );
+        assert_eq!(
+            parse(&args("compile --from mini --target bf prog.txt")).unwrap(),
+            Command::Compile(CompileOptions {
+                program: "prog.txt".to_owned(),
+                from: Some(Language::Mini),
+                target: Target::Bf,
+                output: None,
+                profile: None,
+                config: Config::default(),
+            })
+        );
+
+        let config = Config {
+            cell_size: CellSize::Bits16,
+            eof: Eof::Zero,
+            ..Config::default()
+        };
+        assert_eq!(
+            parse(&args("run --cell-size 16 --eof=zero hello.b")).unwrap(),
+            Command::Run(Box::new(RunOptions {
+                program: Source::File("hello.b".to_owned()),
+                config,
+                ..Default::default()
+            }))
+        );
+
+        assert_eq!(
+            parse(&args("run --tape-size 0 hello.b")).unwrap_err(),
+            "Tape size must be at least 1"
+        );
+    }
+
+    #[test]
+    fn parse_help() {
+        assert_eq!(parse(&args("--help")).unwrap(), Command::Help(USAGE));
+        assert_eq!(
+            parse(&args("compile --help")).unwrap(),
+            Command::Help(COMPILE_USAGE)
+        );
+        assert_eq!(parse(&args("fmt -h")).unwrap(), Command::Help(FMT_USAGE));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse(&[]).unwrap_err().starts_with("Missing command"));
+        assert!(parse(&args("--bogus"))
+            .unwrap_err()
+            .starts_with("Unknown command '--bogus'"));
+        assert_eq!(
+            parse(&args("run a.b b.b")).unwrap_err(),
+            "Unexpected argument 'b.b'"
+        );
+        assert_eq!(
+            parse(&args("run --repl")).unwrap_err(),
+            "Unknown option '--repl'"
+        );
+        assert_eq!(
+            parse(&args("-e + -e -")).unwrap_err(),
+            "Duplicate option '-e'"
+        );
+        assert_eq!(
+            parse(&args("run a.b --eval=+")).unwrap_err(),
+            "--eval can't be combined with a program file"
+        );
+        assert_eq!(
+            parse(&args("compile a.b --target")).unwrap_err(),
+            "Missing value for --target"
+        );
+        assert_eq!(
+            parse(&args("compile a.b --target nope")).unwrap_err(),
+            "Unknown target 'nope'"
+        );
+        assert_eq!(
+            parse(&args("run a.b --max-steps -1")).unwrap_err(),
+            "Invalid step count '-1'"
+        );
+        assert_eq!(
+            parse(&args("run a.b --raw-input --input in.txt")).unwrap_err(),
+            "--raw-input can't be combined with --input or --input-string"
+        );
+        assert_eq!(
+            parse(&args("run a.b --listen 127.0.0.1:4000 -o out.txt")).unwrap_err(),
+            "--listen can't be combined with --input, --input-string, -o, --raw-input or --replay"
+        );
+        assert!(matches!(
+            parse(&args("run echo.b --listen 127.0.0.1:4000")).unwrap(),
+            Command::Run(options) if options.listen.as_deref() == Some("127.0.0.1:4000")
+        ));
+        assert!(matches!(
+            parse(&args("run a.b --io-in /tmp/in.fifo --io-out fd:3")).unwrap(),
+            Command::Run(options)
+                if options.io_in == Some(IoPath::Path("/tmp/in.fifo".to_owned()))
+                    && options.io_out == Some(IoPath::Fd(3))
+        ));
+        assert_eq!(
+            parse(&args("run a.b --io-out fd:x")).unwrap_err(),
+            "Invalid file descriptor 'x'"
+        );
+        assert_eq!(
+            parse(&args("run a.b --io-in fd:0 --input-string x")).unwrap_err(),
+            "--io-in can't be combined with --input, --input-string, --raw-input, --replay or --listen"
+        );
+        assert_eq!(
+            parse(&args("run a.b --io-out out.fifo -o out.txt")).unwrap_err(),
+            "--io-out can't be combined with -o or --listen"
+        );
+        assert_eq!(
+            parse(&args("run a.b --replay a.rec --input-string x")).unwrap_err(),
+            "--replay can't be combined with --input, --input-string or --raw-input"
+        );
+        assert_eq!(
+            parse(&args("run a.b --self-modifying --trace")).unwrap_err(),
+            "--self-modifying can't be combined with --trace, --profile, --profile-out, --coverage, --detect-hangs, --snapshot-out, --resume or --random"
+        );
+        assert_eq!(
+            parse(&args("run a.b --tape-file t.bin --tape grow")).unwrap_err(),
+            "--tape-file needs a tape of fixed size and can't be combined with --resume"
+        );
+        assert_eq!(
+            parse(&args("run a.b --seed 1")).unwrap_err(),
+            "--seed needs --random"
+        );
+        assert_eq!(
+            parse(&args("run a.b --trace-format chrome")).unwrap_err(),
+            "--trace-format needs --trace or --trace-file"
+        );
+        assert_eq!(
+            parse(&args("run a.b --trace --trace-format json")).unwrap_err(),
+            "Invalid trace format 'json', expected text, chrome or folded"
+        );
+        assert_eq!(
+            parse(&args("run a.b --random --seed x")).unwrap_err(),
+            "Invalid seed 'x'"
+        );
+        assert_eq!(
+            parse(&args("run a.b --dialect ook --dialect-file words.toml")).unwrap_err(),
+            "--dialect can't be combined with --dialect-file"
+        );
+        assert_eq!(
+            parse(&args("run a.b --dialect cow")).unwrap_err(),
+            "Invalid dialect 'cow', expected bf, ook, pbrain, brainfork, extended1 or decimal"
+        );
+        assert_eq!(
+            parse(&args("run a.b --backend jit --stats")).unwrap_err(),
+            "--stats can't be combined with --backend jit"
+        );
+        assert_eq!(
+            parse(&args("run a.b --backend gpu")).unwrap_err(),
+            "Invalid backend 'gpu', expected interp, jit or asm"
+        );
+        assert_eq!(
+            parse(&args("build a.b --backend llvm")).unwrap_err(),
+            "Invalid backend 'llvm', expected c or cranelift"
+        );
+    }
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(parse_memory("4096"), Ok(4096));
+        assert_eq!(parse_memory("64K"), Ok(65536));
+        assert_eq!(parse_memory("2G"), Ok(2 << 30));
+        assert_eq!(
+            parse_memory("1T").unwrap_err(),
+            "Invalid size '1T', expected e.g. 64K or 16M"
+        );
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("5s"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("3"), Ok(Duration::from_secs(3)));
+        assert_eq!(
+            parse_duration("-1s").unwrap_err(),
+            "Invalid duration '-1s', expected e.g. 5s or 500ms"
+        );
+        assert!(parse_duration("soon").is_err());
+    }
+}
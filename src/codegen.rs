@@ -0,0 +1,162 @@
+mod bf;
+mod c;
+mod js;
+mod llvm;
+mod rust;
+mod wasm;
+
+use crate::config::{Config, TapeMode};
+use crate::pgo::Profile;
+use crate::{bytecode, ir};
+use std::path::Path;
+use std::str::FromStr;
+
+/// The language `bf compile` compiles from.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Language {
+    Bf,
+    /// See `mini`, compiled to Brainfuck first.
+    Mini,
+}
+
+impl FromStr for Language {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bf" => Ok(Language::Bf),
+            "mini" => Ok(Language::Mini),
+            _ => Err(format!("Unknown language '{}'", s)),
+        }
+    }
+}
+
+impl Language {
+    /// The language a file is written in, going by its extension.
+    pub fn from_path(path: &str) -> Language {
+        match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("mini") => Language::Mini,
+            _ => Language::Bf,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Target {
+    C,
+    Rust,
+    Llvm,
+    Wat,
+    Wasm,
+    Js,
+    Bfc,
+    Bf,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "c" => Ok(Target::C),
+            "rust" => Ok(Target::Rust),
+            "llvm" => Ok(Target::Llvm),
+            "wat" => Ok(Target::Wat),
+            "wasm" => Ok(Target::Wasm),
+            "js" => Ok(Target::Js),
+            "bfc" => Ok(Target::Bfc),
+            "bf" => Ok(Target::Bf),
+            _ => Err(format!("Unknown target '{}'", s)),
+        }
+    }
+}
+
+impl Target {
+    fn name(self) -> &'static str {
+        match self {
+            Target::C => "c",
+            Target::Rust => "rust",
+            Target::Llvm => "llvm",
+            Target::Wat => "wat",
+            Target::Wasm => "wasm",
+            Target::Js => "js",
+            Target::Bfc => "bfc",
+            Target::Bf => "bf",
+        }
+    }
+
+    /// Checks that the target can faithfully implement `config`.
+    fn check_supported(self, config: &Config) -> Result<(), String> {
+        let unsupported = |what: &str| {
+            Err(format!(
+                "The '{}' target does not support {}",
+                self.name(),
+                what
+            ))
+        };
+
+        match self {
+            Target::Llvm | Target::Wat | Target::Wasm if config.tape_mode == TapeMode::Grow => {
+                unsupported("a growing tape")
+            }
+            Target::Wat | Target::Wasm
+                if config.tape_size.saturating_mul(config.cell_size.bytes())
+                    > i32::MAX as usize =>
+            {
+                unsupported("tapes larger than 2^31 - 1 bytes")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Compiles `program` for `target`. The bytecode target doesn't encode any
+/// runtime semantics, and the Brainfuck target only uses the cell size.
+/// `profile` is the JSON saved by `bf run --profile-out` for the program,
+/// which only the C and LLVM targets can use.
+pub fn compile(
+    program: &[u8],
+    target: Target,
+    config: &Config,
+    profile: Option<&str>,
+) -> Result<Vec<u8>, String> {
+    target.check_supported(config)?;
+
+    let ops = ir::compile(program)?;
+    let unroll = match profile {
+        Some(_) if !matches!(target, Target::C | Target::Llvm) => {
+            return Err(format!(
+                "The '{}' target does not support --profile",
+                target.name()
+            ))
+        }
+        Some(json) => Profile::load(json, &ops)?.unroll(&ops),
+        None => Box::new([]),
+    };
+    let output = match target {
+        Target::C => c::emit(&ops, config, &unroll).into_bytes(),
+        Target::Rust => rust::emit(&ops, config).into_bytes(),
+        Target::Llvm => llvm::emit(&ops, config, &unroll).into_bytes(),
+        Target::Wat => wasm::emit_text(&ops, config).into_bytes(),
+        Target::Wasm => wasm::emit_binary(&ops, config),
+        Target::Js => js::emit(&ops, config).into_bytes(),
+        Target::Bfc => bytecode::encode(
+            &ops,
+            &[(
+                "generator",
+                concat!("bf-interpreter ", env!("CARGO_PKG_VERSION")),
+            )],
+        ),
+        Target::Bf => bf::emit(&ops, config).into_bytes(),
+    };
+    Ok(output)
+}
+
+/// Host-side code that is written next to the compiled output, if the target
+/// needs any. `module_file` is the file name of the binary module to load.
+pub fn glue(target: Target, module_file: &str) -> Option<String> {
+    match target {
+        Target::Wat | Target::Wasm => Some(wasm::glue(module_file)),
+        _ => None,
+    }
+}
@@ -0,0 +1,70 @@
+use crate::config::{CellSize, Config};
+use crate::ir::Op;
+
+/// Emits plain Brainfuck for the optimized program: canceling `+` and `-` are
+/// gone, additions use whichever of `+`/`-` is shorter at the configured cell
+/// width, clear loops are written as `[-]` and comments are dropped.
+pub(crate) fn emit(ops: &[Op], config: &Config) -> String {
+    let cell_size = config.cell_size;
+    let mut out = String::new();
+    for op in ops.iter().copied() {
+        match op {
+            Op::Add(n) => push_add(&mut out, n, cell_size),
+            Op::Move(n) if n > 0 => out.push_str(&">".repeat(n as usize)),
+            Op::Move(n) => out.push_str(&"<".repeat(n.unsigned_abs())),
+            Op::Set(n) => {
+                out.push_str("[-]");
+                push_add(&mut out, n, cell_size);
+            }
+            Op::Output => out.push('.'),
+            Op::Input => out.push(','),
+            Op::LoopStart(_) => out.push('['),
+            Op::LoopEnd(_) => out.push(']'),
+            Op::Debug => out.push('#'),
+            Op::ProcStart(_) => out.push('('),
+            Op::ProcEnd => out.push(')'),
+            Op::Call => out.push(':'),
+            Op::Fork => out.push('Y'),
+            Op::Extended(command) => out.push_str(command.command()),
+            Op::Plugin(command) => out.push(command as char),
+            Op::OutputNumber => out.push(':'),
+            Op::InputNumber => out.push(';'),
+            Op::AddMove(..) | Op::MoveAdd(..) | Op::AddAt(..) => {
+                unreachable!("fused instructions are only interpreted")
+            }
+        }
+    }
+    out.push('\n');
+    out
+}
+
+fn push_add(out: &mut String, n: u32, cell_size: CellSize) {
+    let n = n & cell_size.max();
+    if n <= cell_size.max() / 2 + 1 {
+        out.push_str(&"+".repeat(n as usize));
+    } else {
+        out.push_str(&"-".repeat((cell_size.max() - n) as usize + 1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::ir;
+
+    #[test]
+    fn normalize() {
+        let ops = ir::compile(b"comment +++-- >><<< [+]\n[->+<]..,").unwrap();
+        assert_eq!(emit(&ops, &Config::default()), "+>><<<[-][->+<]..,\n");
+
+        let ops = ir::compile("+".repeat(250).as_bytes()).unwrap();
+        assert_eq!(emit(&ops, &Config::default()), "------\n");
+
+        let config = Config {
+            cell_size: CellSize::Bits16,
+            ..Config::default()
+        };
+        assert_eq!(emit(&ops, &config), format!("{}\n", "+".repeat(250)));
+    }
+}
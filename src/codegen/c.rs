@@ -0,0 +1,265 @@
+use crate::config::{CellSize, Config, Eof, TapeMode};
+use crate::ir::Op;
+use crate::pgo::Unroll;
+use std::fmt::Write;
+
+const FAIL: &str = r#"static void fail(const char *msg) {
+    fflush(stdout);
+    fprintf(stderr, "ERROR: %s\n", msg);
+    exit(EXIT_FAILURE);
+}
+"#;
+
+const GROW: &str = r#"
+static void grow(size_t ptr) {
+    size_t new_size = tape_size;
+    while (new_size <= ptr) new_size *= 2;
+    tape = realloc(tape, new_size * sizeof *tape);
+    if (!tape) fail("Memory overflow");
+    memset(tape + tape_size, 0, (new_size - tape_size) * sizeof *tape);
+    tape_size = new_size;
+}
+"#;
+
+/// Emits a standalone C program with the same semantics as the interpreter:
+/// a tape of wrapping cells, the configured tape mode and the configured EOF
+/// behavior. `unroll` has the hint for each loop from a profile, if any.
+pub(crate) fn emit(ops: &[Op], config: &Config, unroll: &[Unroll]) -> String {
+    let grow = config.tape_mode == TapeMode::Grow;
+    let wrap = config.tape_mode == TapeMode::Wrap;
+    let cell = match config.cell_size {
+        CellSize::Bits8 => "unsigned char",
+        CellSize::Bits16 => "uint16_t",
+        CellSize::Bits32 => "uint32_t",
+    };
+
+    let mut out = String::new();
+    writeln!(out, "#define TAPE_SIZE {}", config.tape_size).unwrap();
+    if config.cell_size != CellSize::Bits8 {
+        out.push_str("#include <stdint.h>\n");
+    }
+    out.push_str("#include <stdio.h>\n#include <stdlib.h>\n");
+    if grow {
+        out.push_str("#include <string.h>\n\n");
+        writeln!(
+            out,
+            "static {} *tape;\nstatic size_t tape_size = TAPE_SIZE;\n",
+            cell
+        )
+        .unwrap();
+    } else {
+        writeln!(out, "\nstatic {} tape[TAPE_SIZE];\n", cell).unwrap();
+    }
+    out.push_str(FAIL);
+    let on_eof = match config.eof {
+        Eof::Zero => "return 0",
+        // Converts to the largest value of the unsigned cell type.
+        Eof::MinusOne => "return -1",
+        Eof::Unchanged => "return current",
+        Eof::Halt => "exit(EXIT_SUCCESS)",
+    };
+    writeln!(
+        out,
+        "
+static {cell} read_byte({cell} current) {{
+    int c;
+    fflush(stdout);
+    c = getchar();
+    if (c == EOF) {on_eof};
+    return (unsigned char)c;
+}}",
+        cell = cell,
+        on_eof = on_eof
+    )
+    .unwrap();
+    if grow {
+        out.push_str(GROW);
+    }
+    out.push_str("\nint main(void) {\n    size_t ptr = 0;\n\n");
+    if grow {
+        out.push_str("    tape = calloc(TAPE_SIZE, sizeof *tape);\n");
+        out.push_str("    if (!tape) fail(\"Memory overflow\");\n\n");
+    }
+
+    let mut depth = 1;
+    for (i, op) in ops.iter().copied().enumerate() {
+        if let Op::LoopEnd(_) = op {
+            depth -= 1;
+        }
+        let indent = "    ".repeat(depth);
+
+        match op {
+            Op::Add(n) => {
+                let n = n & config.cell_size.max();
+                writeln!(out, "{}tape[ptr] += {};", indent, n).unwrap();
+            }
+            Op::Move(n) if wrap => {
+                let n = config.wrapped_move(n);
+                if n != 0 {
+                    writeln!(out, "{}ptr = (ptr + {}) % TAPE_SIZE;", indent, n).unwrap();
+                }
+            }
+            Op::Move(n) if n > 0 && grow => {
+                writeln!(out, "{}ptr += {};", indent, n).unwrap();
+                writeln!(out, "{}if (ptr >= tape_size) grow(ptr);", indent).unwrap();
+            }
+            Op::Move(n) if n > 0 => {
+                writeln!(
+                    out,
+                    "{}if (ptr + {} >= TAPE_SIZE) fail(\"Memory overflow\");",
+                    indent, n
+                )
+                .unwrap();
+                writeln!(out, "{}ptr += {};", indent, n).unwrap();
+            }
+            Op::Move(n) => {
+                let n = n.unsigned_abs();
+                writeln!(
+                    out,
+                    "{}if (ptr < {}) fail(\"Memory underflow\");",
+                    indent, n
+                )
+                .unwrap();
+                writeln!(out, "{}ptr -= {};", indent, n).unwrap();
+            }
+            Op::Set(n) => {
+                let n = n & config.cell_size.max();
+                writeln!(out, "{}tape[ptr] = {};", indent, n).unwrap();
+            }
+            Op::Output => {
+                // putchar() writes the low byte of wider cells.
+                writeln!(out, "{}putchar(tape[ptr]);", indent).unwrap();
+            }
+            Op::Input => {
+                writeln!(out, "{}tape[ptr] = read_byte(tape[ptr]);", indent).unwrap();
+            }
+            Op::LoopStart(_) => {
+                // Understood by GCC and Clang.
+                match unroll.get(i) {
+                    Some(Unroll::Never) => writeln!(out, "{}#pragma GCC unroll 1", indent).unwrap(),
+                    Some(Unroll::Times(n)) => {
+                        writeln!(out, "{}#pragma GCC unroll {}", indent, n).unwrap()
+                    }
+                    Some(Unroll::Default) | None => {}
+                }
+                writeln!(out, "{}while (tape[ptr]) {{", indent).unwrap();
+                depth += 1;
+            }
+            Op::LoopEnd(_) => {
+                writeln!(out, "{}}}", indent).unwrap();
+            }
+            Op::Debug => {}
+            Op::ProcStart(_)
+            | Op::ProcEnd
+            | Op::Call
+            | Op::Fork
+            | Op::Extended(_)
+            | Op::Plugin(_)
+            | Op::OutputNumber
+            | Op::InputNumber => {
+                unreachable!("dialect extensions are only interpreted")
+            }
+            Op::AddMove(..) | Op::MoveAdd(..) | Op::AddAt(..) => {
+                unreachable!("fused instructions are only interpreted")
+            }
+        }
+    }
+
+    out.push_str("\n    return EXIT_SUCCESS;\n}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config::TapeSize;
+    use crate::ir;
+
+    #[test]
+    fn emit_program() {
+        let ops = ir::compile(b",[->+<]>.").unwrap();
+        let source = emit(&ops, &Config::default(), &[]);
+
+        assert!(source.starts_with("#define TAPE_SIZE 30000\n#include <stdio.h>"));
+        assert!(source.contains(
+            "    tape[ptr] = read_byte(tape[ptr]);
+    while (tape[ptr]) {
+        tape[ptr] += 255;
+        if (ptr + 1 >= TAPE_SIZE) fail(\"Memory overflow\");
+        ptr += 1;
+        tape[ptr] += 1;
+        if (ptr < 1) fail(\"Memory underflow\");
+        ptr -= 1;
+    }
+"
+        ));
+        assert!(source.ends_with("    putchar(tape[ptr]);\n\n    return EXIT_SUCCESS;\n}\n"));
+    }
+
+    #[test]
+    fn emit_growing_tape() {
+        let ops = ir::compile(b">").unwrap();
+        let mut config = Config::default();
+        config.set_tape_size(TapeSize::Unlimited);
+        let source = emit(&ops, &config, &[]);
+
+        assert!(source.contains("static unsigned char *tape;"));
+        assert!(source.contains("    ptr += 1;\n    if (ptr >= tape_size) grow(ptr);\n"));
+    }
+
+    #[test]
+    fn emit_wide_cells() {
+        let ops = ir::compile(b"-").unwrap();
+        let config = Config {
+            cell_size: CellSize::Bits16,
+            ..Config::default()
+        };
+        let source = emit(&ops, &config, &[]);
+
+        assert!(source.contains("#include <stdint.h>\n"));
+        assert!(source.contains("static uint16_t tape[TAPE_SIZE];"));
+        assert!(source.contains("    tape[ptr] += 65535;\n"));
+    }
+
+    #[test]
+    fn emit_eof_behavior() {
+        let ops = ir::compile(b",").unwrap();
+        let config = Config {
+            eof: Eof::Unchanged,
+            ..Config::default()
+        };
+        let source = emit(&ops, &config, &[]);
+
+        assert!(source.contains("    if (c == EOF) return current;\n"));
+    }
+
+    #[test]
+    fn emit_wrapping_tape() {
+        let ops = ir::compile(b"><<>>>>>").unwrap();
+        let config = Config {
+            tape_size: 3,
+            tape_mode: TapeMode::Wrap,
+            ..Config::default()
+        };
+        let source = emit(&ops, &config, &[]);
+
+        // The folded move by 4 is a move by 1 on a ring of 3 cells.
+        assert!(source.contains("    ptr = (ptr + 1) % TAPE_SIZE;\n"));
+    }
+
+    #[test]
+    fn emit_unroll_hints() {
+        let ops = ir::compile(b"[[-]>[<]]").unwrap();
+        let mut unroll = [Unroll::Default; 4];
+        unroll[3] = Unroll::Times(8);
+        let source = emit(&ops, &Config::default(), &unroll);
+
+        assert!(source.contains(
+            "    while (tape[ptr]) {
+        tape[ptr] = 0;
+"
+        ));
+        assert!(source.contains("        #pragma GCC unroll 8\n        while (tape[ptr]) {\n"));
+    }
+}
@@ -0,0 +1,163 @@
+use crate::config::{CellSize, Config, Eof, TapeMode};
+use crate::ir::Op;
+use std::fmt::Write;
+
+const PRELUDE: &str = r#"// `readByte` is an async callback returning the next input byte, or -1 at
+// EOF. `writeByte` is called with every output byte.
+async function run(readByte, writeByte) {
+  let tape = new Tape(TAPE_SIZE);
+  let ptr = 0;
+  let input;
+
+"#;
+
+/// Emits a self-contained JavaScript `run` function with the same semantics
+/// as the interpreter. Errors are thrown as `Error`s, and with the default EOF
+/// behavior running out of input resolves the returned promise early.
+pub(crate) fn emit(ops: &[Op], config: &Config) -> String {
+    let grow = config.tape_mode == TapeMode::Grow;
+    let wrap = config.tape_mode == TapeMode::Wrap;
+    let array = match config.cell_size {
+        CellSize::Bits8 => "Uint8Array",
+        CellSize::Bits16 => "Uint16Array",
+        CellSize::Bits32 => "Uint32Array",
+    };
+
+    let mut out = String::new();
+    writeln!(out, "const TAPE_SIZE = {};", config.tape_size).unwrap();
+    writeln!(out, "const Tape = {};\n", array).unwrap();
+    out.push_str(PRELUDE);
+
+    let mut depth = 1;
+    for op in ops.iter().copied() {
+        if let Op::LoopEnd(_) = op {
+            depth -= 1;
+        }
+        let indent = "  ".repeat(depth);
+
+        match op {
+            Op::Add(n) => {
+                // Typed array stores wrap at the cell width on their own.
+                let n = n & config.cell_size.max();
+                writeln!(out, "{}tape[ptr] += {};", indent, n).unwrap();
+            }
+            Op::Move(n) if wrap => {
+                let n = config.wrapped_move(n);
+                if n != 0 {
+                    writeln!(out, "{}ptr = (ptr + {}) % TAPE_SIZE;", indent, n).unwrap();
+                }
+            }
+            Op::Move(n) if n > 0 && grow => {
+                writeln!(out, "{}ptr += {};", indent, n).unwrap();
+                writeln!(out, "{}while (ptr >= tape.length) {{", indent).unwrap();
+                writeln!(out, "{}  const grown = new Tape(tape.length * 2);", indent).unwrap();
+                writeln!(out, "{}  grown.set(tape);", indent).unwrap();
+                writeln!(out, "{}  tape = grown;", indent).unwrap();
+                writeln!(out, "{}}}", indent).unwrap();
+            }
+            Op::Move(n) if n > 0 => {
+                writeln!(
+                    out,
+                    "{}if (ptr + {} >= TAPE_SIZE) throw new Error(\"Memory overflow\");",
+                    indent, n
+                )
+                .unwrap();
+                writeln!(out, "{}ptr += {};", indent, n).unwrap();
+            }
+            Op::Move(n) => {
+                let n = n.unsigned_abs();
+                writeln!(
+                    out,
+                    "{}if (ptr < {}) throw new Error(\"Memory underflow\");",
+                    indent, n
+                )
+                .unwrap();
+                writeln!(out, "{}ptr -= {};", indent, n).unwrap();
+            }
+            Op::Set(n) => {
+                let n = n & config.cell_size.max();
+                writeln!(out, "{}tape[ptr] = {};", indent, n).unwrap();
+            }
+            Op::Output if config.cell_size == CellSize::Bits8 => {
+                writeln!(out, "{}writeByte(tape[ptr]);", indent).unwrap();
+            }
+            Op::Output => {
+                writeln!(out, "{}writeByte(tape[ptr] & 255);", indent).unwrap();
+            }
+            Op::Input => {
+                writeln!(out, "{}input = await readByte();", indent).unwrap();
+                match config.eof {
+                    Eof::Zero => {
+                        writeln!(out, "{}tape[ptr] = input < 0 ? 0 : input;", indent).unwrap()
+                    }
+                    // Typed arrays store -1 as the largest cell value.
+                    Eof::MinusOne => writeln!(out, "{}tape[ptr] = input;", indent).unwrap(),
+                    Eof::Unchanged => {
+                        writeln!(out, "{}if (input >= 0) tape[ptr] = input;", indent).unwrap()
+                    }
+                    Eof::Halt => {
+                        writeln!(out, "{}if (input < 0) return;", indent).unwrap();
+                        writeln!(out, "{}tape[ptr] = input;", indent).unwrap();
+                    }
+                }
+            }
+            Op::LoopStart(_) => {
+                writeln!(out, "{}while (tape[ptr] !== 0) {{", indent).unwrap();
+                depth += 1;
+            }
+            Op::LoopEnd(_) => {
+                writeln!(out, "{}}}", indent).unwrap();
+            }
+            Op::Debug => {}
+            Op::ProcStart(_)
+            | Op::ProcEnd
+            | Op::Call
+            | Op::Fork
+            | Op::Extended(_)
+            | Op::Plugin(_)
+            | Op::OutputNumber
+            | Op::InputNumber => {
+                unreachable!("dialect extensions are only interpreted")
+            }
+            Op::AddMove(..) | Op::MoveAdd(..) | Op::AddAt(..) => {
+                unreachable!("fused instructions are only interpreted")
+            }
+        }
+    }
+
+    out.push_str("}\n\nif (typeof module !== \"undefined\") {\n  module.exports = { run };\n}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::ir;
+
+    #[test]
+    fn emit_program() {
+        let ops = ir::compile(b",[->+<]>.").unwrap();
+        let source = emit(&ops, &Config::default());
+
+        assert!(source.starts_with("const TAPE_SIZE = 30000;\nconst Tape = Uint8Array;\n"));
+        assert!(source.contains(
+            "  input = await readByte();
+  if (input < 0) return;
+  tape[ptr] = input;
+  while (tape[ptr] !== 0) {
+    tape[ptr] += 255;
+    if (ptr + 1 >= TAPE_SIZE) throw new Error(\"Memory overflow\");
+    ptr += 1;
+    tape[ptr] += 1;
+    if (ptr < 1) throw new Error(\"Memory underflow\");
+    ptr -= 1;
+  }
+  if (ptr + 1 >= TAPE_SIZE) throw new Error(\"Memory overflow\");
+  ptr += 1;
+  writeByte(tape[ptr]);
+}
+"
+        ));
+    }
+}
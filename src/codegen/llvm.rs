@@ -0,0 +1,424 @@
+use crate::config::{CellSize, Config, Eof, TapeMode};
+use crate::ir::Op;
+use crate::pgo::Unroll;
+use std::fmt::Write;
+
+const OVERFLOW_MSG: &str = "ERROR: Memory overflow\n";
+const UNDERFLOW_MSG: &str = "ERROR: Memory underflow\n";
+
+const PRELUDE: &str = r#"declare i32 @putchar(i32)
+declare i32 @getchar()
+declare i32 @fflush(ptr)
+declare i64 @write(i32, ptr, i64)
+declare void @exit(i32) noreturn
+
+define internal void @fail(ptr %msg, i64 %len) noreturn {
+  call i32 @fflush(ptr null)
+  call i64 @write(i32 2, ptr %msg, i64 %len)
+  call void @exit(i32 1)
+  unreachable
+}
+
+"#;
+
+const MAIN: &str = r#"
+define i32 @main() {
+entry:
+  %p = alloca i64
+  store i64 0, ptr %p
+"#;
+
+/// Emits textual LLVM IR (`.ll`) with the same semantics as the interpreter.
+/// Nothing links against LLVM; the output is meant to be fed to `clang` or
+/// `llc`, which promote the pointer slot to a register during optimization.
+/// `unroll` has the hint for each loop from a profile, if any, which goes
+/// into the loop's `!llvm.loop` metadata.
+pub(crate) fn emit(ops: &[Op], config: &Config, unroll: &[Unroll]) -> String {
+    let tape_size = config.tape_size;
+    let cell = cell_type(config.cell_size);
+    let mut out = String::new();
+    writeln!(
+        out,
+        "@tape = internal global [{} x {}] zeroinitializer",
+        tape_size, cell
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "@overflow_msg = private constant [{} x i8] c\"{}\"",
+        OVERFLOW_MSG.len(),
+        escape(OVERFLOW_MSG)
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "@underflow_msg = private constant [{} x i8] c\"{}\"\n",
+        UNDERFLOW_MSG.len(),
+        escape(UNDERFLOW_MSG)
+    )
+    .unwrap();
+    out.push_str(PRELUDE);
+    emit_read_byte(&mut out, config);
+    out.push_str(MAIN);
+
+    let mut emitter = Emitter {
+        out,
+        next_id: 0,
+        tape_size,
+        wrap: config.tape_mode == TapeMode::Wrap,
+        cell,
+        cell_size: config.cell_size,
+        unroll,
+        metadata: String::new(),
+        next_metadata: 0,
+    };
+    for (i, op) in ops.iter().copied().enumerate() {
+        emitter.emit_op(i, op);
+    }
+
+    let mut out = emitter.out;
+    writeln!(out, "  ret i32 0").unwrap();
+    writeln!(out, "overflow:").unwrap();
+    writeln!(
+        out,
+        "  call void @fail(ptr @overflow_msg, i64 {})",
+        OVERFLOW_MSG.len()
+    )
+    .unwrap();
+    writeln!(out, "  unreachable").unwrap();
+    writeln!(out, "underflow:").unwrap();
+    writeln!(
+        out,
+        "  call void @fail(ptr @underflow_msg, i64 {})",
+        UNDERFLOW_MSG.len()
+    )
+    .unwrap();
+    writeln!(out, "  unreachable").unwrap();
+    writeln!(out, "}}").unwrap();
+    out.push_str(&emitter.metadata);
+    out
+}
+
+struct Emitter<'a> {
+    out: String,
+    next_id: usize,
+    tape_size: usize,
+    wrap: bool,
+    /// LLVM type of a cell, e.g. `i8`.
+    cell: &'static str,
+    cell_size: CellSize,
+    unroll: &'a [Unroll],
+    /// Metadata nodes, written after the code.
+    metadata: String,
+    next_metadata: usize,
+}
+
+impl Emitter<'_> {
+    fn emit_op(&mut self, i: usize, op: Op) {
+        match op {
+            Op::Add(n) => {
+                let ty = self.cell;
+                let cell = self.cell_ptr();
+                let value = self.temp();
+                let sum = self.temp();
+                writeln!(self.out, "  {} = load {}, ptr {}", value, ty, cell).unwrap();
+                writeln!(
+                    self.out,
+                    "  {} = add {} {}, {}",
+                    sum,
+                    ty,
+                    value,
+                    n & self.cell_size.max()
+                )
+                .unwrap();
+                writeln!(self.out, "  store {} {}, ptr {}", ty, sum, cell).unwrap();
+            }
+            Op::Move(n) if self.wrap => {
+                let n = n.rem_euclid(self.tape_size as isize);
+                if n == 0 {
+                    return;
+                }
+                let index = self.temp();
+                let moved = self.temp();
+                let check = self.temp();
+                let wrapped = self.temp();
+                let result = self.temp();
+                writeln!(self.out, "  {} = load i64, ptr %p", index).unwrap();
+                writeln!(self.out, "  {} = add i64 {}, {}", moved, index, n).unwrap();
+                writeln!(
+                    self.out,
+                    "  {} = icmp uge i64 {}, {}",
+                    check, moved, self.tape_size
+                )
+                .unwrap();
+                writeln!(
+                    self.out,
+                    "  {} = sub i64 {}, {}",
+                    wrapped, moved, self.tape_size
+                )
+                .unwrap();
+                writeln!(
+                    self.out,
+                    "  {} = select i1 {}, i64 {}, i64 {}",
+                    result, check, wrapped, moved
+                )
+                .unwrap();
+                writeln!(self.out, "  store i64 {}, ptr %p", result).unwrap();
+            }
+            Op::Move(n) => {
+                let index = self.temp();
+                let moved = self.temp();
+                let check = self.temp();
+                let ok = format!("move_{}", i);
+                writeln!(self.out, "  {} = load i64, ptr %p", index).unwrap();
+                if n > 0 {
+                    writeln!(self.out, "  {} = add i64 {}, {}", moved, index, n).unwrap();
+                    writeln!(
+                        self.out,
+                        "  {} = icmp uge i64 {}, {}",
+                        check, moved, self.tape_size
+                    )
+                    .unwrap();
+                    writeln!(
+                        self.out,
+                        "  br i1 {}, label %overflow, label %{}",
+                        check, ok
+                    )
+                    .unwrap();
+                } else {
+                    let n = n.unsigned_abs();
+                    writeln!(self.out, "  {} = sub i64 {}, {}", moved, index, n).unwrap();
+                    writeln!(self.out, "  {} = icmp ult i64 {}, {}", check, index, n).unwrap();
+                    writeln!(
+                        self.out,
+                        "  br i1 {}, label %underflow, label %{}",
+                        check, ok
+                    )
+                    .unwrap();
+                }
+                writeln!(self.out, "{}:", ok).unwrap();
+                writeln!(self.out, "  store i64 {}, ptr %p", moved).unwrap();
+            }
+            Op::Set(n) => {
+                let ty = self.cell;
+                let cell = self.cell_ptr();
+                writeln!(
+                    self.out,
+                    "  store {} {}, ptr {}",
+                    ty,
+                    n & self.cell_size.max(),
+                    cell
+                )
+                .unwrap();
+            }
+            Op::Output => {
+                let ty = self.cell;
+                let cell = self.cell_ptr();
+                let value = self.temp();
+                writeln!(self.out, "  {} = load {}, ptr {}", value, ty, cell).unwrap();
+                // putchar() only looks at the low byte, so wider cells can be
+                // passed through as they are.
+                let c = match self.cell_size {
+                    CellSize::Bits32 => value,
+                    _ => {
+                        let c = self.temp();
+                        writeln!(self.out, "  {} = zext {} {} to i32", c, ty, value).unwrap();
+                        c
+                    }
+                };
+                writeln!(self.out, "  call i32 @putchar(i32 {})", c).unwrap();
+            }
+            Op::Input => {
+                let ty = self.cell;
+                let cell = self.cell_ptr();
+                let current = self.temp();
+                let value = self.temp();
+                writeln!(self.out, "  {} = load {}, ptr {}", current, ty, cell).unwrap();
+                writeln!(
+                    self.out,
+                    "  {} = call {} @read_byte({} {})",
+                    value, ty, ty, current
+                )
+                .unwrap();
+                writeln!(self.out, "  store {} {}, ptr {}", ty, value, cell).unwrap();
+            }
+            Op::LoopStart(_) => {
+                writeln!(self.out, "  br label %loop_{}", i).unwrap();
+                writeln!(self.out, "loop_{}:", i).unwrap();
+                let ty = self.cell;
+                let cell = self.cell_ptr();
+                let value = self.temp();
+                let check = self.temp();
+                writeln!(self.out, "  {} = load {}, ptr {}", value, ty, cell).unwrap();
+                writeln!(self.out, "  {} = icmp ne {} {}, 0", check, ty, value).unwrap();
+                writeln!(
+                    self.out,
+                    "  br i1 {}, label %body_{}, label %end_{}",
+                    check, i, i
+                )
+                .unwrap();
+                writeln!(self.out, "body_{}:", i).unwrap();
+            }
+            Op::LoopEnd(start) => {
+                let hint = match self.unroll.get(start) {
+                    Some(Unroll::Never) => Some("!\"llvm.loop.unroll.disable\"".to_owned()),
+                    Some(Unroll::Times(n)) => {
+                        Some(format!("!\"llvm.loop.unroll.count\", i32 {}", n))
+                    }
+                    Some(Unroll::Default) | None => None,
+                };
+                match hint {
+                    Some(hint) => {
+                        let id = self.next_metadata;
+                        self.next_metadata += 2;
+                        writeln!(self.out, "  br label %loop_{}, !llvm.loop !{}", start, id)
+                            .unwrap();
+                        writeln!(
+                            self.metadata,
+                            "!{} = distinct !{{!{}, !{}}}\n!{} = !{{{}}}",
+                            id,
+                            id,
+                            id + 1,
+                            id + 1,
+                            hint
+                        )
+                        .unwrap();
+                    }
+                    None => writeln!(self.out, "  br label %loop_{}", start).unwrap(),
+                }
+                writeln!(self.out, "end_{}:", start).unwrap();
+            }
+            Op::Debug => {}
+            Op::ProcStart(_)
+            | Op::ProcEnd
+            | Op::Call
+            | Op::Fork
+            | Op::Extended(_)
+            | Op::Plugin(_)
+            | Op::OutputNumber
+            | Op::InputNumber => {
+                unreachable!("dialect extensions are only interpreted")
+            }
+            Op::AddMove(..) | Op::MoveAdd(..) | Op::AddAt(..) => {
+                unreachable!("fused instructions are only interpreted")
+            }
+        }
+    }
+
+    /// Loads the data pointer and returns the address of the current cell.
+    fn cell_ptr(&mut self) -> String {
+        let index = self.temp();
+        let cell = self.temp();
+        writeln!(self.out, "  {} = load i64, ptr %p", index).unwrap();
+        writeln!(
+            self.out,
+            "  {} = getelementptr inbounds [{} x {}], ptr @tape, i64 0, i64 {}",
+            cell, self.tape_size, self.cell, index
+        )
+        .unwrap();
+        cell
+    }
+
+    fn temp(&mut self) -> String {
+        self.next_id += 1;
+        format!("%t{}", self.next_id)
+    }
+}
+
+/// Defines `@read_byte`, which takes the current cell value and returns the
+/// new one.
+fn emit_read_byte(out: &mut String, config: &Config) {
+    let cell = cell_type(config.cell_size);
+    writeln!(
+        out,
+        "define internal {} @read_byte({} %current) {{
+  call i32 @fflush(ptr null)
+  %c = call i32 @getchar()
+  %eof = icmp eq i32 %c, -1
+  br i1 %eof, label %done, label %ok
+done:",
+        cell, cell
+    )
+    .unwrap();
+    match config.eof {
+        Eof::Zero => writeln!(out, "  ret {} 0", cell).unwrap(),
+        Eof::MinusOne => writeln!(out, "  ret {} -1", cell).unwrap(),
+        Eof::Unchanged => writeln!(out, "  ret {} %current", cell).unwrap(),
+        Eof::Halt => out.push_str("  call void @exit(i32 0)\n  unreachable\n"),
+    }
+    out.push_str("ok:\n");
+    if config.cell_size == CellSize::Bits32 {
+        out.push_str("  ret i32 %c\n}\n");
+    } else {
+        writeln!(
+            out,
+            "  %b = trunc i32 %c to {}\n  ret {} %b\n}}",
+            cell, cell
+        )
+        .unwrap();
+    }
+}
+
+fn cell_type(cell_size: CellSize) -> &'static str {
+    match cell_size {
+        CellSize::Bits8 => "i8",
+        CellSize::Bits16 => "i16",
+        CellSize::Bits32 => "i32",
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.bytes()
+        .map(|b| {
+            if (b.is_ascii_graphic() || b == b' ') && b != b'"' && b != b'\\' {
+                (b as char).to_string()
+            } else {
+                format!("\\{:02X}", b)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::ir;
+
+    #[test]
+    fn emit_program() {
+        let ops = ir::compile(b"+[<]").unwrap();
+        let source = emit(&ops, &Config::default(), &[]);
+
+        assert!(source.starts_with(
+            "@tape = internal global [30000 x i8] zeroinitializer
+@overflow_msg = private constant [23 x i8] c\"ERROR: Memory overflow\\0A\"
+"
+        ));
+        assert!(source.contains(
+            "  br label %loop_1
+loop_1:
+  %t5 = load i64, ptr %p
+  %t6 = getelementptr inbounds [30000 x i8], ptr @tape, i64 0, i64 %t5
+  %t7 = load i8, ptr %t6
+  %t8 = icmp ne i8 %t7, 0
+  br i1 %t8, label %body_1, label %end_1
+body_1:
+  %t9 = load i64, ptr %p
+  %t10 = sub i64 %t9, 1
+  %t11 = icmp ult i64 %t9, 1
+  br i1 %t11, label %underflow, label %move_2
+move_2:
+  store i64 %t10, ptr %p
+  br label %loop_1
+end_1:
+  ret i32 0
+"
+        ));
+    }
+
+    #[test]
+    fn escape_string() {
+        assert_eq!(escape("a \"b\"\\\n"), "a \\22b\\22\\5C\\0A");
+    }
+}
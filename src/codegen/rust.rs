@@ -0,0 +1,179 @@
+use crate::config::{CellSize, Config, Eof, TapeMode};
+use crate::ir::Op;
+use std::fmt::Write;
+
+const PRELUDE: &str = r#"use std::io::{BufWriter, Read, Stdout, Write};
+
+fn fail(out: &mut BufWriter<Stdout>, msg: &str) -> ! {
+    out.flush().unwrap();
+    eprintln!("ERROR: {}", msg);
+    std::process::exit(1);
+}
+
+"#;
+
+const MAIN: &str = r#"
+fn main() {
+    let mut tape: Vec<Cell> = vec![0; TAPE_SIZE];
+    let mut ptr = 0usize;
+    let mut out = BufWriter::new(std::io::stdout());
+
+"#;
+
+/// Emits a standalone `main.rs` with the same semantics as the interpreter.
+/// The result only depends on `std`, so it builds with a plain `rustc`.
+pub(crate) fn emit(ops: &[Op], config: &Config) -> String {
+    let grow = config.tape_mode == TapeMode::Grow;
+    let wrap = config.tape_mode == TapeMode::Wrap;
+
+    let mut out = String::new();
+    // Not every program reads input or moves the pointer.
+    out.push_str("#![allow(unused)]\n\n");
+    writeln!(out, "const TAPE_SIZE: usize = {};", config.tape_size).unwrap();
+    let cell = match config.cell_size {
+        CellSize::Bits8 => "u8",
+        CellSize::Bits16 => "u16",
+        CellSize::Bits32 => "u32",
+    };
+    writeln!(out, "type Cell = {};\n", cell).unwrap();
+    let on_eof = match config.eof {
+        Eof::Zero => "0",
+        Eof::MinusOne => "Cell::MAX",
+        Eof::Unchanged => "current",
+        Eof::Halt => "std::process::exit(0)",
+    };
+    out.push_str(PRELUDE);
+    writeln!(
+        out,
+        "fn read_byte(out: &mut BufWriter<Stdout>, current: Cell) -> Cell {{
+    out.flush().unwrap();
+    let mut buf = [0u8; 1];
+    match std::io::stdin().read_exact(&mut buf) {{
+        Ok(_) => buf[0].into(),
+        Err(_) => {},
+    }}
+}}",
+        on_eof
+    )
+    .unwrap();
+    out.push_str(MAIN);
+
+    let mut depth = 1;
+    for op in ops.iter().copied() {
+        if let Op::LoopEnd(_) = op {
+            depth -= 1;
+        }
+        let indent = "    ".repeat(depth);
+
+        match op {
+            Op::Add(n) => {
+                let n = n & config.cell_size.max();
+                writeln!(out, "{}tape[ptr] = tape[ptr].wrapping_add({});", indent, n).unwrap();
+            }
+            Op::Move(n) if wrap => {
+                let n = config.wrapped_move(n);
+                if n != 0 {
+                    writeln!(out, "{}ptr = (ptr + {}) % TAPE_SIZE;", indent, n).unwrap();
+                }
+            }
+            Op::Move(n) if n > 0 && grow => {
+                writeln!(out, "{}ptr += {};", indent, n).unwrap();
+                writeln!(out, "{}while ptr >= tape.len() {{", indent).unwrap();
+                writeln!(out, "{}    tape.resize(tape.len() * 2, 0);", indent).unwrap();
+                writeln!(out, "{}}}", indent).unwrap();
+            }
+            Op::Move(n) if n > 0 => {
+                writeln!(out, "{}if ptr + {} >= TAPE_SIZE {{", indent, n).unwrap();
+                writeln!(out, "{}    fail(&mut out, \"Memory overflow\");", indent).unwrap();
+                writeln!(out, "{}}}", indent).unwrap();
+                writeln!(out, "{}ptr += {};", indent, n).unwrap();
+            }
+            Op::Move(n) => {
+                let n = n.unsigned_abs();
+                writeln!(out, "{}if ptr < {} {{", indent, n).unwrap();
+                writeln!(out, "{}    fail(&mut out, \"Memory underflow\");", indent).unwrap();
+                writeln!(out, "{}}}", indent).unwrap();
+                writeln!(out, "{}ptr -= {};", indent, n).unwrap();
+            }
+            Op::Set(n) => {
+                let n = n & config.cell_size.max();
+                writeln!(out, "{}tape[ptr] = {};", indent, n).unwrap();
+            }
+            Op::Output => {
+                writeln!(out, "{}out.write_all(&[tape[ptr] as u8]).unwrap();", indent).unwrap();
+            }
+            Op::Input => {
+                writeln!(out, "{}tape[ptr] = read_byte(&mut out, tape[ptr]);", indent).unwrap();
+            }
+            Op::LoopStart(_) => {
+                writeln!(out, "{}while tape[ptr] != 0 {{", indent).unwrap();
+                depth += 1;
+            }
+            Op::LoopEnd(_) => {
+                writeln!(out, "{}}}", indent).unwrap();
+            }
+            Op::Debug => {}
+            Op::ProcStart(_)
+            | Op::ProcEnd
+            | Op::Call
+            | Op::Fork
+            | Op::Extended(_)
+            | Op::Plugin(_)
+            | Op::OutputNumber
+            | Op::InputNumber => {
+                unreachable!("dialect extensions are only interpreted")
+            }
+            Op::AddMove(..) | Op::MoveAdd(..) | Op::AddAt(..) => {
+                unreachable!("fused instructions are only interpreted")
+            }
+        }
+    }
+
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::ir;
+
+    #[test]
+    fn emit_program() {
+        let ops = ir::compile(b",[->+<]>.").unwrap();
+        let source = emit(&ops, &Config::default());
+
+        assert!(source
+            .starts_with("#![allow(unused)]\n\nconst TAPE_SIZE: usize = 30000;\ntype Cell = u8;\n\nuse std::io::"));
+        assert!(source.contains(
+            "    tape[ptr] = read_byte(&mut out, tape[ptr]);
+    while tape[ptr] != 0 {
+        tape[ptr] = tape[ptr].wrapping_add(255);
+        if ptr + 1 >= TAPE_SIZE {
+            fail(&mut out, \"Memory overflow\");
+        }
+        ptr += 1;
+        tape[ptr] = tape[ptr].wrapping_add(1);
+        if ptr < 1 {
+            fail(&mut out, \"Memory underflow\");
+        }
+        ptr -= 1;
+    }
+"
+        ));
+        assert!(source.ends_with("    out.write_all(&[tape[ptr] as u8]).unwrap();\n}\n"));
+    }
+
+    #[test]
+    fn emit_eof_behavior() {
+        let ops = ir::compile(b",").unwrap();
+        let config = Config {
+            eof: Eof::MinusOne,
+            ..Config::default()
+        };
+        let source = emit(&ops, &config);
+
+        assert!(source.contains("        Err(_) => Cell::MAX,\n"));
+    }
+}
@@ -0,0 +1,493 @@
+use crate::config::{CellSize, Config, Eof, TapeMode};
+use crate::ir::Op;
+use crate::leb128;
+use std::fmt::Write;
+
+const PAGE_SIZE: usize = 65_536;
+
+// Values returned by the exported `run` function.
+const STATUS_OK: i32 = 0;
+const STATUS_OVERFLOW: i32 = 1;
+const STATUS_UNDERFLOW: i32 = 2;
+
+// Function indices; imports come first.
+const READ_BYTE: u32 = 0;
+const WRITE_BYTE: u32 = 1;
+
+// Local indices inside `run`. `$ptr` holds the byte address of the current
+// cell rather than its index.
+const PTR: u32 = 0;
+const INPUT: u32 = 1;
+
+/// The subset of WebAssembly instructions the code generator needs. Both the
+/// text and binary encodings are produced from the same instruction list so
+/// the two can never disagree.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Instr {
+    Block,
+    Loop,
+    If,
+    End,
+    Br(u32),
+    BrIf(u32),
+    Return,
+    Call(u32),
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    I32Load8U,
+    I32Load16U,
+    I32Load,
+    I32Store8,
+    I32Store16,
+    I32Store,
+    I32Const(i32),
+    I32Eqz,
+    I32LtS,
+    I32LtU,
+    I32GeU,
+    I32Add,
+    I32Sub,
+    I32And,
+}
+
+/// Emits the module in the WebAssembly text format (`.wat`).
+pub(crate) fn emit_text(ops: &[Op], config: &Config) -> String {
+    let mut out = String::new();
+    out.push_str("(module\n");
+    out.push_str("  (import \"env\" \"read_byte\" (func $read_byte (result i32)))\n");
+    out.push_str("  (import \"env\" \"write_byte\" (func $write_byte (param i32)))\n");
+    writeln!(out, "  (memory (export \"memory\") {})", pages(config)).unwrap();
+    out.push_str("  (func (export \"run\") (result i32)\n");
+    out.push_str("    (local $ptr i32)\n");
+    out.push_str("    (local $input i32)\n");
+
+    let mut depth = 2;
+    for instr in lower(ops, config) {
+        if instr == Instr::End {
+            depth -= 1;
+        }
+        let indent = "  ".repeat(depth);
+
+        let text = match instr {
+            Instr::Block => "block".to_owned(),
+            Instr::Loop => "loop".to_owned(),
+            Instr::If => "if".to_owned(),
+            Instr::End => "end".to_owned(),
+            Instr::Br(n) => format!("br {}", n),
+            Instr::BrIf(n) => format!("br_if {}", n),
+            Instr::Return => "return".to_owned(),
+            Instr::Call(READ_BYTE) => "call $read_byte".to_owned(),
+            Instr::Call(_) => "call $write_byte".to_owned(),
+            Instr::LocalGet(n) => format!("local.get {}", local_name(n)),
+            Instr::LocalSet(n) => format!("local.set {}", local_name(n)),
+            Instr::LocalTee(n) => format!("local.tee {}", local_name(n)),
+            Instr::I32Load8U => "i32.load8_u".to_owned(),
+            Instr::I32Load16U => "i32.load16_u".to_owned(),
+            Instr::I32Load => "i32.load".to_owned(),
+            Instr::I32Store8 => "i32.store8".to_owned(),
+            Instr::I32Store16 => "i32.store16".to_owned(),
+            Instr::I32Store => "i32.store".to_owned(),
+            Instr::I32Const(n) => format!("i32.const {}", n),
+            Instr::I32Eqz => "i32.eqz".to_owned(),
+            Instr::I32LtS => "i32.lt_s".to_owned(),
+            Instr::I32LtU => "i32.lt_u".to_owned(),
+            Instr::I32GeU => "i32.ge_u".to_owned(),
+            Instr::I32Add => "i32.add".to_owned(),
+            Instr::I32Sub => "i32.sub".to_owned(),
+            Instr::I32And => "i32.and".to_owned(),
+        };
+        writeln!(out, "{}{}", indent, text).unwrap();
+
+        if matches!(instr, Instr::Block | Instr::Loop | Instr::If) {
+            depth += 1;
+        }
+    }
+
+    out.push_str("  )\n)\n");
+    out
+}
+
+/// Emits the module in the WebAssembly binary format (`.wasm`).
+pub(crate) fn emit_binary(ops: &[Op], config: &Config) -> Vec<u8> {
+    let mut out = b"\0asm".to_vec();
+    out.extend_from_slice(&1u32.to_le_bytes());
+
+    // Type section: 0 is `() -> i32`, 1 is `(i32) -> ()`.
+    section(
+        &mut out,
+        1,
+        &[0x02, 0x60, 0x00, 0x01, 0x7f, 0x60, 0x01, 0x7f, 0x00],
+    );
+
+    let mut imports = vec![0x02];
+    import(&mut imports, "read_byte", 0);
+    import(&mut imports, "write_byte", 1);
+    section(&mut out, 2, &imports);
+
+    // Function section: `run` has type 0.
+    section(&mut out, 3, &[0x01, 0x00]);
+
+    let mut memory = vec![0x01, 0x00];
+    uleb(&mut memory, pages(config) as u32);
+    section(&mut out, 5, &memory);
+
+    let mut exports = vec![0x02];
+    name(&mut exports, "memory");
+    exports.extend_from_slice(&[0x02, 0x00]);
+    name(&mut exports, "run");
+    exports.push(0x00);
+    uleb(&mut exports, 2);
+    section(&mut out, 7, &exports);
+
+    // A single run of two i32 locals: the data pointer and the last input.
+    let mut body = vec![0x01, 0x02, 0x7f];
+    for instr in lower(ops, config) {
+        encode(&mut body, instr);
+    }
+    body.push(0x0b);
+
+    let mut code = vec![0x01];
+    uleb(&mut code, body.len() as u32);
+    code.extend_from_slice(&body);
+    section(&mut out, 10, &code);
+
+    out
+}
+
+/// JavaScript that loads `module_file` and runs it, either in a browser via
+/// the exported `run` function or from the command line with Node.js.
+pub(crate) fn glue(module_file: &str) -> String {
+    format!(
+        r#"// Glue code for {module_file}.
+//
+// Browser: `await run(await (await fetch("{module_file}")).arrayBuffer(), readByte, writeByte)`
+// Node.js: `node this_file.js < input`
+
+const ERRORS = {{ {overflow}: "Memory overflow", {underflow}: "Memory underflow" }};
+
+// `readByte` returns the next input byte, or -1 at EOF.
+async function run(bytes, readByte, writeByte) {{
+  const {{ instance }} = await WebAssembly.instantiate(bytes, {{
+    env: {{ read_byte: readByte, write_byte: writeByte }},
+  }});
+  const status = instance.exports.run();
+  if (status !== {ok}) {{
+    throw new Error(ERRORS[status]);
+  }}
+}}
+
+if (typeof module !== "undefined" && require.main === module) {{
+  const fs = require("fs");
+  const path = require("path");
+  const input = fs.readFileSync(0);
+  const output = [];
+  let pos = 0;
+  run(
+    fs.readFileSync(path.join(__dirname, "{module_file}")),
+    () => (pos < input.length ? input[pos++] : -1),
+    (byte) => output.push(byte)
+  )
+    .catch((e) => {{
+      process.exitCode = 1;
+      return e;
+    }})
+    .then((e) => {{
+      process.stdout.write(Buffer.from(output));
+      if (e) {{
+        console.error(`ERROR: ${{e.message}}`);
+      }}
+    }});
+}} else if (typeof module !== "undefined") {{
+  module.exports = {{ run }};
+}}
+"#,
+        module_file = module_file,
+        ok = STATUS_OK,
+        overflow = STATUS_OVERFLOW,
+        underflow = STATUS_UNDERFLOW,
+    )
+}
+
+fn lower(ops: &[Op], config: &Config) -> Vec<Instr> {
+    use Instr::*;
+
+    let (load, store) = match config.cell_size {
+        CellSize::Bits8 => (I32Load8U, I32Store8),
+        CellSize::Bits16 => (I32Load16U, I32Store16),
+        CellSize::Bits32 => (I32Load, I32Store),
+    };
+    let bytes = config.cell_size.bytes() as isize;
+    let tape_bytes = config.tape_size * config.cell_size.bytes();
+
+    let mut instrs = vec![];
+    for op in ops.iter().copied() {
+        match op {
+            Op::Add(n) => instrs.extend_from_slice(&[
+                LocalGet(PTR),
+                LocalGet(PTR),
+                load,
+                I32Const(n as i32),
+                I32Add,
+                store,
+            ]),
+            Op::Move(n) if config.tape_mode == TapeMode::Wrap => {
+                let n = config.wrapped_move(n) * bytes as usize;
+                if n != 0 {
+                    instrs.extend_from_slice(&[
+                        LocalGet(PTR),
+                        I32Const(n as i32),
+                        I32Add,
+                        LocalTee(PTR),
+                        I32Const(tape_bytes as i32),
+                        I32GeU,
+                        If,
+                        LocalGet(PTR),
+                        I32Const(tape_bytes as i32),
+                        I32Sub,
+                        LocalSet(PTR),
+                        End,
+                    ]);
+                }
+            }
+            Op::Move(n) if n > 0 => instrs.extend_from_slice(&[
+                LocalGet(PTR),
+                I32Const((n * bytes) as i32),
+                I32Add,
+                LocalTee(PTR),
+                I32Const(tape_bytes as i32),
+                I32GeU,
+                If,
+                I32Const(STATUS_OVERFLOW),
+                Return,
+                End,
+            ]),
+            Op::Move(n) => instrs.extend_from_slice(&[
+                LocalGet(PTR),
+                I32Const((-n * bytes) as i32),
+                I32LtU,
+                If,
+                I32Const(STATUS_UNDERFLOW),
+                Return,
+                End,
+                LocalGet(PTR),
+                I32Const((-n * bytes) as i32),
+                I32Sub,
+                LocalSet(PTR),
+            ]),
+            Op::Set(n) => instrs.extend_from_slice(&[LocalGet(PTR), I32Const(n as i32), store]),
+            Op::Output => {
+                instrs.extend_from_slice(&[LocalGet(PTR), load]);
+                // `write_byte` only ever sees the low byte of wider cells.
+                if config.cell_size != CellSize::Bits8 {
+                    instrs.extend_from_slice(&[I32Const(0xff), I32And]);
+                }
+                instrs.push(Call(WRITE_BYTE));
+            }
+            Op::Input => instrs.extend_from_slice(&match config.eof {
+                Eof::Zero => vec![
+                    Call(READ_BYTE),
+                    LocalTee(INPUT),
+                    I32Const(0),
+                    I32LtS,
+                    If,
+                    I32Const(0),
+                    LocalSet(INPUT),
+                    End,
+                    LocalGet(PTR),
+                    LocalGet(INPUT),
+                    store,
+                ],
+                // `read_byte` already returns -1 at EOF, and the store
+                // truncates it to the cell width.
+                Eof::MinusOne => vec![
+                    Call(READ_BYTE),
+                    LocalSet(INPUT),
+                    LocalGet(PTR),
+                    LocalGet(INPUT),
+                    store,
+                ],
+                Eof::Unchanged => vec![
+                    Call(READ_BYTE),
+                    LocalTee(INPUT),
+                    I32Const(0),
+                    I32LtS,
+                    I32Eqz,
+                    If,
+                    LocalGet(PTR),
+                    LocalGet(INPUT),
+                    store,
+                    End,
+                ],
+                Eof::Halt => vec![
+                    Call(READ_BYTE),
+                    LocalTee(INPUT),
+                    I32Const(0),
+                    I32LtS,
+                    If,
+                    I32Const(STATUS_OK),
+                    Return,
+                    End,
+                    LocalGet(PTR),
+                    LocalGet(INPUT),
+                    store,
+                ],
+            }),
+            Op::LoopStart(_) => {
+                instrs.extend_from_slice(&[Block, Loop, LocalGet(PTR), load, I32Eqz, BrIf(1)])
+            }
+            Op::LoopEnd(_) => instrs.extend_from_slice(&[Br(0), End, End]),
+            Op::Debug => {}
+            Op::ProcStart(_)
+            | Op::ProcEnd
+            | Op::Call
+            | Op::Fork
+            | Op::Extended(_)
+            | Op::Plugin(_)
+            | Op::OutputNumber
+            | Op::InputNumber => {
+                unreachable!("dialect extensions are only interpreted")
+            }
+            Op::AddMove(..) | Op::MoveAdd(..) | Op::AddAt(..) => {
+                unreachable!("fused instructions are only interpreted")
+            }
+        }
+    }
+    instrs.push(I32Const(STATUS_OK));
+    instrs
+}
+
+fn encode(out: &mut Vec<u8>, instr: Instr) {
+    match instr {
+        Instr::Block => out.extend_from_slice(&[0x02, 0x40]),
+        Instr::Loop => out.extend_from_slice(&[0x03, 0x40]),
+        Instr::If => out.extend_from_slice(&[0x04, 0x40]),
+        Instr::End => out.push(0x0b),
+        Instr::Br(n) => {
+            out.push(0x0c);
+            uleb(out, n);
+        }
+        Instr::BrIf(n) => {
+            out.push(0x0d);
+            uleb(out, n);
+        }
+        Instr::Return => out.push(0x0f),
+        Instr::Call(n) => {
+            out.push(0x10);
+            uleb(out, n);
+        }
+        Instr::LocalGet(n) => {
+            out.push(0x20);
+            uleb(out, n);
+        }
+        Instr::LocalSet(n) => {
+            out.push(0x21);
+            uleb(out, n);
+        }
+        Instr::LocalTee(n) => {
+            out.push(0x22);
+            uleb(out, n);
+        }
+        // Memory accesses are followed by the log2 of their natural
+        // alignment and a zero offset.
+        Instr::I32Load8U => out.extend_from_slice(&[0x2d, 0x00, 0x00]),
+        Instr::I32Load16U => out.extend_from_slice(&[0x2f, 0x01, 0x00]),
+        Instr::I32Load => out.extend_from_slice(&[0x28, 0x02, 0x00]),
+        Instr::I32Store8 => out.extend_from_slice(&[0x3a, 0x00, 0x00]),
+        Instr::I32Store16 => out.extend_from_slice(&[0x3b, 0x01, 0x00]),
+        Instr::I32Store => out.extend_from_slice(&[0x36, 0x02, 0x00]),
+        Instr::I32Const(n) => {
+            out.push(0x41);
+            sleb(out, n);
+        }
+        Instr::I32Eqz => out.push(0x45),
+        Instr::I32LtS => out.push(0x48),
+        Instr::I32LtU => out.push(0x49),
+        Instr::I32GeU => out.push(0x4f),
+        Instr::I32Add => out.push(0x6a),
+        Instr::I32Sub => out.push(0x6b),
+        Instr::I32And => out.push(0x71),
+    }
+}
+
+fn pages(config: &Config) -> usize {
+    (config.tape_size * config.cell_size.bytes()).div_ceil(PAGE_SIZE)
+}
+
+fn local_name(n: u32) -> &'static str {
+    match n {
+        PTR => "$ptr",
+        _ => "$input",
+    }
+}
+
+fn uleb(out: &mut Vec<u8>, n: u32) {
+    leb128::write_unsigned(out, n as u64);
+}
+
+fn sleb(out: &mut Vec<u8>, n: i32) {
+    leb128::write_signed(out, n as i64);
+}
+
+fn section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    out.push(id);
+    uleb(out, contents.len() as u32);
+    out.extend_from_slice(contents);
+}
+
+fn import(out: &mut Vec<u8>, field: &str, type_index: u32) {
+    name(out, "env");
+    name(out, field);
+    out.push(0x00);
+    uleb(out, type_index);
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    uleb(out, name.len() as u32);
+    out.extend_from_slice(name.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::ir;
+
+    #[test]
+    fn emit_text_program() {
+        let ops = ir::compile(b"+[<]").unwrap();
+        let source = emit_text(&ops, &Config::default());
+
+        assert!(source.contains(
+            "    block
+      loop
+        local.get $ptr
+        i32.load8_u
+        i32.eqz
+        br_if 1
+        local.get $ptr
+        i32.const 1
+        i32.lt_u
+        if
+          i32.const 2
+          return
+        end
+"
+        ));
+        assert!(source.ends_with("        br 0\n      end\n    end\n    i32.const 0\n  )\n)\n"));
+    }
+
+    #[test]
+    fn emit_binary_program() {
+        let ops = ir::compile(b",.").unwrap();
+        let module = emit_binary(&ops, &Config::default());
+
+        assert!(module.starts_with(b"\0asm\x01\0\0\0"));
+        // The code section is last: one body with two locals, `,` then `.`.
+        assert!(module.ends_with(&[
+            0x0a, 0x23, 0x01, 0x21, 0x01, 0x02, 0x7f, 0x10, 0x00, 0x22, 0x01, 0x41, 0x00, 0x48,
+            0x04, 0x40, 0x41, 0x00, 0x0f, 0x0b, 0x20, 0x00, 0x20, 0x01, 0x3a, 0x00, 0x00, 0x20,
+            0x00, 0x2d, 0x00, 0x00, 0x10, 0x01, 0x41, 0x00, 0x0b,
+        ]));
+    }
+}
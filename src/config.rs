@@ -0,0 +1,213 @@
+//! Execution semantics shared by the interpreter and the code generators.
+
+use std::str::FromStr;
+
+pub(crate) const DEFAULT_TAPE_SIZE: usize = 30_000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// Number of cells, or the initial number of cells for a growing tape.
+    pub(crate) tape_size: usize,
+    pub tape_mode: TapeMode,
+    pub cell_size: CellSize,
+    pub(crate) eof: Eof,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            tape_size: DEFAULT_TAPE_SIZE,
+            tape_mode: TapeMode::Fixed,
+            cell_size: CellSize::Bits8,
+            eof: Eof::Halt,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TapeMode {
+    /// Moving past either end of the tape is an error.
+    Fixed,
+    /// Moving past the right end extends the tape.
+    Grow,
+    /// The data pointer wraps around at both ends, as if the tape were a
+    /// ring.
+    Wrap,
+}
+
+impl FromStr for TapeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(TapeMode::Fixed),
+            "grow" => Ok(TapeMode::Grow),
+            "wrap" => Ok(TapeMode::Wrap),
+            _ => Err(format!(
+                "Invalid tape mode '{}', expected fixed, grow or wrap",
+                s
+            )),
+        }
+    }
+}
+
+/// Width of a cell. Arithmetic wraps around at the cell width; input and
+/// output always operate on the low byte.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CellSize {
+    Bits8,
+    Bits16,
+    Bits32,
+}
+
+impl CellSize {
+    pub(crate) fn bits(self) -> u32 {
+        match self {
+            CellSize::Bits8 => 8,
+            CellSize::Bits16 => 16,
+            CellSize::Bits32 => 32,
+        }
+    }
+
+    pub(crate) fn bytes(self) -> usize {
+        self.bits() as usize / 8
+    }
+
+    /// The largest value a cell can hold.
+    pub(crate) fn max(self) -> u32 {
+        u32::MAX >> (32 - self.bits())
+    }
+}
+
+impl FromStr for CellSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(CellSize::Bits8),
+            "16" => Ok(CellSize::Bits16),
+            "32" => Ok(CellSize::Bits32),
+            _ => Err(format!("Invalid cell size '{}', expected 8, 16 or 32", s)),
+        }
+    }
+}
+
+/// What `,` does once the input is exhausted.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum Eof {
+    /// Store 0 in the current cell.
+    Zero,
+    /// Store -1, i.e. the largest value a cell can hold.
+    MinusOne,
+    /// Leave the current cell as it is.
+    Unchanged,
+    /// Stop the program successfully.
+    Halt,
+}
+
+impl FromStr for Eof {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zero" => Ok(Eof::Zero),
+            "minus-one" => Ok(Eof::MinusOne),
+            "unchanged" => Ok(Eof::Unchanged),
+            "halt" => Ok(Eof::Halt),
+            _ => Err(format!(
+                "Invalid EOF behavior '{}', expected zero, minus-one, unchanged or halt",
+                s
+            )),
+        }
+    }
+}
+
+/// Value of `--tape-size`: a cell count or `unlimited`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum TapeSize {
+    Cells(usize),
+    Unlimited,
+}
+
+impl FromStr for TapeSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "unlimited" {
+            return Ok(TapeSize::Unlimited);
+        }
+        match s.parse::<usize>() {
+            Ok(0) => Err("Tape size must be at least 1".to_owned()),
+            Ok(n) => Ok(TapeSize::Cells(n)),
+            Err(_) => Err(format!(
+                "Invalid tape size '{}', expected a number of cells or 'unlimited'",
+                s
+            )),
+        }
+    }
+}
+
+impl Config {
+    /// For a wrapping tape, the move to the right by less than the tape size
+    /// that lands on the same cell as a move by `n`.
+    pub(crate) fn wrapped_move(&self, n: isize) -> usize {
+        n.rem_euclid(self.tape_size as isize) as usize
+    }
+
+    /// Applies `--tape-size`. A cell count keeps the tape mode, so it can be
+    /// combined with `--tape`; `unlimited` is short for `--tape grow`.
+    pub(crate) fn set_tape_size(&mut self, size: TapeSize) {
+        match size {
+            TapeSize::Cells(n) => self.tape_size = n,
+            TapeSize::Unlimited => self.tape_mode = TapeMode::Grow,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parse_cell_size() {
+        assert_eq!("16".parse(), Ok(CellSize::Bits16));
+        assert_eq!(
+            "64".parse::<CellSize>().unwrap_err(),
+            "Invalid cell size '64', expected 8, 16 or 32"
+        );
+        assert_eq!(CellSize::Bits8.max(), 255);
+        assert_eq!(CellSize::Bits16.max(), 65_535);
+        assert_eq!(CellSize::Bits32.max(), u32::MAX);
+    }
+
+    #[test]
+    fn parse_eof() {
+        assert_eq!("minus-one".parse(), Ok(Eof::MinusOne));
+        assert_eq!(
+            "-1".parse::<Eof>().unwrap_err(),
+            "Invalid EOF behavior '-1', expected zero, minus-one, unchanged or halt"
+        );
+    }
+
+    #[test]
+    fn parse_tape_mode() {
+        assert_eq!("grow".parse(), Ok(TapeMode::Grow));
+        assert_eq!(
+            "endless".parse::<TapeMode>().unwrap_err(),
+            "Invalid tape mode 'endless', expected fixed, grow or wrap"
+        );
+    }
+
+    #[test]
+    fn parse_tape_size() {
+        assert_eq!("1000000".parse(), Ok(TapeSize::Cells(1_000_000)));
+        assert_eq!("unlimited".parse(), Ok(TapeSize::Unlimited));
+        assert_eq!(
+            "0".parse::<TapeSize>().unwrap_err(),
+            "Tape size must be at least 1"
+        );
+        assert!("-5".parse::<TapeSize>().is_err());
+        assert!("lots".parse::<TapeSize>().is_err());
+    }
+}
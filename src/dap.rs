@@ -0,0 +1,588 @@
+//! `bf dap`: a Debug Adapter Protocol server on stdin and stdout, so editors
+//! such as VS Code can debug programs with their own breakpoints, stepping
+//! and variable views.
+
+use crate::bf_interpreter::BfInterpreter;
+use crate::bytecode;
+use crate::config::Config;
+use crate::ir;
+use crate::json::Json;
+use crate::profile::{self, SourceMap};
+use std::io::{BufRead, Cursor, Write};
+use std::path::Path;
+
+/// A program has just the one thread.
+const THREAD_ID: usize = 1;
+
+/// `variablesReference` of the scope with the program counter and the data
+/// pointer.
+const REGISTERS: usize = 1;
+/// `variablesReference` of the scope with the cells.
+const TAPE: usize = 2;
+
+pub fn run() -> Result<(), String> {
+    let mut input = std::io::stdin().lock();
+    let mut server = Server {
+        client: Client {
+            out: Box::new(std::io::stdout()),
+            seq: 1,
+        },
+        program: None,
+    };
+    while let Some(request) = read_message(&mut input)? {
+        if !server.handle(&request)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Reads one message: a `Content-Length` header, a blank line and a JSON
+/// body of that length. Returns `None` at the end of the input. The
+/// Language Server Protocol frames messages the same way, see `lsp`.
+pub(crate) fn read_message(input: &mut dyn BufRead) -> Result<Option<Json>, String> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        let read = input
+            .read_line(&mut line)
+            .map_err(|e| format!("Failed to read a message: {}", e))?;
+        if read == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() && length.is_some() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            let value = value.trim();
+            length = Some(
+                value
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid Content-Length '{}'", value))?,
+            );
+        }
+    }
+
+    let mut body = vec![0; length.unwrap()];
+    input
+        .read_exact(&mut body)
+        .map_err(|e| format!("Failed to read a message: {}", e))?;
+    let body = String::from_utf8(body).map_err(|_| "Message is not UTF-8".to_owned())?;
+    Json::parse(&body).map(Some)
+}
+
+/// Sends `message` in the framing `read_message` reads.
+pub(crate) fn write_message(out: &mut dyn Write, message: &Json) -> Result<(), String> {
+    let body = message.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+        .and_then(|_| out.flush())
+        .map_err(|e| format!("Failed to send a message: {}", e))
+}
+
+/// The editor on the other end.
+struct Client {
+    out: Box<dyn Write>,
+    /// Sequence number of the next message sent.
+    seq: usize,
+}
+
+impl Client {
+    fn send<'a>(
+        &mut self,
+        fields: impl IntoIterator<Item = (&'a str, Json)>,
+    ) -> Result<(), String> {
+        let mut message = vec![("seq", self.seq.into())];
+        message.extend(fields);
+        self.seq += 1;
+        write_message(&mut self.out, &Json::object(message))
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) -> Result<(), String> {
+        let mut fields = vec![
+            ("type", "response".into()),
+            (
+                "request_seq",
+                request.get("seq").cloned().unwrap_or(Json::Null),
+            ),
+            (
+                "command",
+                request.get("command").cloned().unwrap_or(Json::Null),
+            ),
+        ];
+        match result {
+            Ok(body) => {
+                fields.push(("success", true.into()));
+                if body != Json::Null {
+                    fields.push(("body", body));
+                }
+            }
+            Err(e) => {
+                fields.push(("success", false.into()));
+                fields.push(("message", e.into()));
+            }
+        }
+        self.send(fields)
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> Result<(), String> {
+        let mut fields = vec![("type", "event".into()), ("event", event.into())];
+        if body != Json::Null {
+            fields.push(("body", body));
+        }
+        self.send(fields)
+    }
+
+    /// Sends what the program wrote since the last call, if anything.
+    fn output(&mut self, output: &mut Vec<u8>) -> Result<(), String> {
+        if output.is_empty() {
+            return Ok(());
+        }
+        let text = String::from_utf8_lossy(output).into_owned();
+        output.clear();
+        self.event(
+            "output",
+            Json::object([("category", "stdout".into()), ("output", text.into())]),
+        )
+    }
+
+    fn stopped(&mut self, reason: &str, error: Option<String>) -> Result<(), String> {
+        let mut body = vec![
+            ("reason", reason.into()),
+            ("threadId", THREAD_ID.into()),
+            ("allThreadsStopped", true.into()),
+        ];
+        if let Some(error) = error {
+            body.push(("text", error.into()));
+        }
+        self.event("stopped", Json::object(body))
+    }
+}
+
+struct Server {
+    client: Client,
+    program: Option<Program>,
+}
+
+/// The program being debugged.
+struct Program {
+    path: String,
+    source: Vec<u8>,
+    offsets: ir::Offsets,
+    interpreter: BfInterpreter,
+    /// The `input` launch argument, read by `,`.
+    input: Cursor<Vec<u8>>,
+    stop_on_entry: bool,
+    /// Instructions with a breakpoint.
+    breakpoints: Vec<usize>,
+}
+
+impl Program {
+    fn finished(&self) -> bool {
+        self.interpreter.pc() >= self.interpreter.program().len()
+    }
+
+    fn source_map(&self) -> SourceMap<'_> {
+        SourceMap {
+            source: &self.source,
+            offsets: &self.offsets,
+        }
+    }
+
+    /// Line and column of the next instruction.
+    fn position(&self) -> (usize, usize) {
+        let offset = self
+            .offsets
+            .get(self.interpreter.pc())
+            .copied()
+            .unwrap_or(self.source.len());
+        profile::position(&self.source, offset)
+    }
+}
+
+impl Server {
+    /// Handles one request. Returns false once the client disconnects.
+    fn handle(&mut self, request: &Json) -> Result<bool, String> {
+        let command = request
+            .get("command")
+            .and_then(Json::as_str)
+            .unwrap_or_default();
+        let arguments = request.get("arguments").unwrap_or(&Json::Null);
+        let result = match command {
+            "initialize" => Ok(Json::object([(
+                "supportsConfigurationDoneRequest",
+                true.into(),
+            )])),
+            "launch" => self.launch(arguments).map(|()| Json::Null),
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "configurationDone" | "next" | "stepIn" | "stepOut" => {
+                self.program().map(|_| Json::Null)
+            }
+            "continue" => self
+                .program()
+                .map(|_| Json::object([("allThreadsContinued", true.into())])),
+            // A program runs to its next stop before the next request is
+            // read, so there's never anything to pause.
+            "pause" | "disconnect" | "terminate" => Ok(Json::Null),
+            "threads" => Ok(Json::object([(
+                "threads",
+                vec![Json::object([
+                    ("id", THREAD_ID.into()),
+                    ("name", "main".into()),
+                ])]
+                .into(),
+            )])),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(Json::object([(
+                "scopes",
+                vec![scope("Registers", REGISTERS), scope("Tape", TAPE)].into(),
+            )])),
+            "variables" => self.variables(arguments),
+            _ => Err(format!("Unsupported request '{}'", command)),
+        };
+        let success = result.is_ok();
+        self.client.respond(request, result)?;
+        if !success {
+            return Ok(true);
+        }
+
+        match command {
+            // Breakpoints can only be placed once the source is known.
+            "launch" => self.client.event("initialized", Json::Null)?,
+            "configurationDone" if self.program()?.stop_on_entry => {
+                self.client.stopped("entry", None)?
+            }
+            "configurationDone" | "continue" => self.run(false)?,
+            "next" | "stepIn" | "stepOut" => self.run(true)?,
+            "disconnect" | "terminate" => return Ok(false),
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn program(&mut self) -> Result<&mut Program, String> {
+        self.program
+            .as_mut()
+            .ok_or("No program has been launched".to_owned())
+    }
+
+    /// Loads the program given in the `launch` arguments, along with its
+    /// `input` and the same configuration options as on the command line.
+    fn launch(&mut self, arguments: &Json) -> Result<(), String> {
+        let option = |key| arguments.get(key).and_then(Json::as_str);
+        let path = option("program").ok_or("Missing program in the launch arguments")?;
+        let config = config_options(arguments)?;
+
+        let source = crate::runner::read_file(path)?;
+        if bytecode::is_bytecode(&source) {
+            return Err("Bytecode can't be debugged, use the Brainfuck source".to_owned());
+        }
+        let (ops, offsets) = ir::compile_with_offsets(&source, false)?;
+        self.program = Some(Program {
+            path: path.to_owned(),
+            source,
+            offsets,
+            interpreter: BfInterpreter::builder().config(config).build_ops(ops)?,
+            input: Cursor::new(option("input").unwrap_or_default().as_bytes().to_vec()),
+            stop_on_entry: arguments
+                .get("stopOnEntry")
+                .and_then(Json::as_bool)
+                .unwrap_or(false),
+            breakpoints: vec![],
+        });
+        Ok(())
+    }
+
+    /// Replaces the breakpoints with ones on the first instruction at or
+    /// after each requested line and column. Lines without instructions get
+    /// an unverified breakpoint.
+    fn set_breakpoints(&mut self, arguments: &Json) -> Result<Json, String> {
+        let program = self.program()?;
+        let map = program.source_map();
+        let mut breakpoints = vec![];
+        let mut results = vec![];
+        let requested = arguments
+            .get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or_default();
+        for breakpoint in requested {
+            let line = breakpoint.get("line").and_then(Json::as_usize).unwrap_or(0);
+            let column = breakpoint
+                .get("column")
+                .and_then(Json::as_usize)
+                .unwrap_or(1);
+            let found = map
+                .instruction_at(line, column)
+                .map(|pc| (pc, profile::position(map.source, map.offsets[pc])))
+                .filter(|&(_, (found_line, _))| found_line == line);
+            match found {
+                Some((pc, (line, column))) => {
+                    breakpoints.push(pc);
+                    results.push(Json::object([
+                        ("verified", true.into()),
+                        ("line", line.into()),
+                        ("column", column.into()),
+                    ]));
+                }
+                None => results.push(Json::object([
+                    ("verified", false.into()),
+                    ("line", line.into()),
+                    ("message", "No instruction on this line".into()),
+                ])),
+            }
+        }
+        program.breakpoints = breakpoints;
+        Ok(Json::object([("breakpoints", results.into())]))
+    }
+
+    fn stack_trace(&mut self) -> Result<Json, String> {
+        let program = self.program()?;
+        let (line, column) = program.position();
+        let name = Path::new(&program.path)
+            .file_name()
+            .map_or(program.path.clone(), |name| {
+                name.to_string_lossy().into_owned()
+            });
+        let frame = Json::object([
+            ("id", 0.into()),
+            ("name", "main".into()),
+            ("line", line.into()),
+            ("column", column.into()),
+            (
+                "source",
+                Json::object([
+                    ("name", name.into()),
+                    ("path", program.path.as_str().into()),
+                ]),
+            ),
+        ]);
+        Ok(Json::object([
+            ("stackFrames", vec![frame].into()),
+            ("totalFrames", 1.into()),
+        ]))
+    }
+
+    /// The registers, or every non-zero cell plus the current one.
+    fn variables(&mut self, arguments: &Json) -> Result<Json, String> {
+        let interpreter = &self.program()?.interpreter;
+        let variable = |name: String, value: usize| {
+            Json::object([
+                ("name", name.into()),
+                ("value", value.to_string().into()),
+                ("variablesReference", 0.into()),
+            ])
+        };
+        let tape = interpreter.tape();
+        let variables = match arguments.get("variablesReference").and_then(Json::as_usize) {
+            Some(REGISTERS) => vec![
+                variable("pc".to_owned(), interpreter.pc()),
+                variable("data pointer".to_owned(), interpreter.data_ptr()),
+            ],
+            Some(TAPE) => {
+                let ptr = interpreter.data_ptr();
+                let current = (tape.get(ptr) == 0).then_some(ptr);
+                let mut cells: Vec<usize> = tape.nonzero().map(|(i, _)| i).chain(current).collect();
+                cells.sort_unstable();
+                cells
+                    .into_iter()
+                    .map(|i| variable(format!("[{}]", i), tape.get(i) as usize))
+                    .collect()
+            }
+            _ => vec![],
+        };
+        Ok(Json::object([("variables", variables.into())]))
+    }
+
+    /// Runs a single instruction, or until a breakpoint, and tells the
+    /// client where the program stopped or that it ended.
+    fn run(&mut self, single_step: bool) -> Result<(), String> {
+        let Some(program) = self.program.as_mut() else {
+            return Ok(());
+        };
+        let mut output = vec![];
+        let result = loop {
+            if program.finished() {
+                break Ok(None);
+            }
+            let step = program.interpreter.step().and_then(|ret| {
+                crate::runner::handle_ret(
+                    &mut program.interpreter,
+                    ret,
+                    &mut program.input,
+                    &mut output,
+                    false,
+                )
+            });
+            if let Err(e) = step {
+                break Err(e);
+            }
+            if output.ends_with(b"\n") {
+                self.client.output(&mut output)?;
+            }
+            if program.finished() {
+                break Ok(None);
+            }
+            if single_step {
+                break Ok(Some("step"));
+            }
+            if program.breakpoints.contains(&program.interpreter.pc()) {
+                break Ok(Some("breakpoint"));
+            }
+        };
+        self.client.output(&mut output)?;
+
+        match result {
+            Ok(Some(reason)) => self.client.stopped(reason, None),
+            Ok(None) => {
+                self.client
+                    .event("exited", Json::object([("exitCode", 0.into())]))?;
+                self.client.event("terminated", Json::Null)
+            }
+            Err(e) => self.client.stopped("exception", Some(e)),
+        }
+    }
+}
+
+/// The configuration given as `tape`, `tapeSize`, `cellSize` and `eof` in
+/// `arguments`, with the same values as the options of `bf run`. `bf serve`
+/// takes them in its requests too.
+pub(crate) fn config_options(arguments: &Json) -> Result<Config, String> {
+    let option = |key| arguments.get(key).and_then(Json::as_str);
+    let mut config = Config::default();
+    if let Some(mode) = option("tape") {
+        config.tape_mode = mode.parse()?;
+    }
+    if let Some(size) = option("tapeSize") {
+        config.set_tape_size(size.parse()?);
+    }
+    if let Some(size) = option("cellSize") {
+        config.cell_size = size.parse()?;
+    }
+    if let Some(eof) = option("eof") {
+        config.eof = eof.parse()?;
+    }
+    Ok(config)
+}
+
+fn scope(name: &str, reference: usize) -> Json {
+    Json::object([
+        ("name", name.into()),
+        ("variablesReference", reference.into()),
+        ("expensive", false.into()),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Collects everything the server sends.
+    #[derive(Clone, Default)]
+    struct Log(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Log {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Sends `request` and returns the messages sent back, without their
+    /// sequence numbers.
+    fn request(server: &mut Server, log: &Log, request: &str) -> Vec<String> {
+        server.handle(&Json::parse(request).unwrap()).unwrap();
+        let sent = log.0.take();
+        let mut sent = Cursor::new(sent);
+        let mut messages = vec![];
+        while let Some(message) = read_message(&mut sent).unwrap() {
+            let message = message.to_string();
+            messages.push(message[message.find(',').unwrap() + 1..].to_owned());
+        }
+        messages
+    }
+
+    #[test]
+    fn debug_session() {
+        let path = std::env::temp_dir().join(format!("bf-dap-{}.b", std::process::id()));
+        std::fs::write(&path, "++\n[->+<]>\n.").unwrap();
+        let log = Log::default();
+        let mut server = Server {
+            client: Client {
+                out: Box::new(log.clone()),
+                seq: 1,
+            },
+            program: None,
+        };
+
+        let launch = format!(
+            r#"{{"seq":1,"command":"launch","arguments":{{"program":{}}}}}"#,
+            Json::from(path.to_string_lossy().into_owned())
+        );
+        assert_eq!(
+            request(&mut server, &log, &launch),
+            [
+                r#""type":"response","request_seq":1,"command":"launch","success":true}"#,
+                r#""type":"event","event":"initialized"}"#
+            ]
+        );
+        assert_eq!(
+            request(
+                &mut server,
+                &log,
+                r#"{"seq":2,"command":"setBreakpoints","arguments":{"breakpoints":[{"line":2,"column":2},{"line":4}]}}"#
+            ),
+            [
+                r#""type":"response","request_seq":2,"command":"setBreakpoints","success":true,"body":{"breakpoints":[{"verified":true,"line":2,"column":2},{"verified":false,"line":4,"message":"No instruction on this line"}]}}"#
+            ]
+        );
+        assert_eq!(
+            request(
+                &mut server,
+                &log,
+                r#"{"seq":3,"command":"configurationDone"}"#
+            ),
+            [
+                r#""type":"response","request_seq":3,"command":"configurationDone","success":true}"#,
+                r#""type":"event","event":"stopped","body":{"reason":"breakpoint","threadId":1,"allThreadsStopped":true}}"#
+            ]
+        );
+        assert_eq!(
+            request(
+                &mut server,
+                &log,
+                r#"{"seq":4,"command":"variables","arguments":{"variablesReference":2}}"#
+            ),
+            [
+                r#""type":"response","request_seq":4,"command":"variables","success":true,"body":{"variables":[{"name":"[0]","value":"2","variablesReference":0}]}}"#
+            ]
+        );
+        request(&mut server, &log, r#"{"seq":5,"command":"continue"}"#);
+        assert_eq!(
+            request(&mut server, &log, r#"{"seq":6,"command":"stackTrace"}"#),
+            [format!(
+                r#""type":"response","request_seq":6,"command":"stackTrace","success":true,"body":{{"stackFrames":[{{"id":0,"name":"main","line":2,"column":2,"source":{{"name":{},"path":{}}}}}],"totalFrames":1}}}}"#,
+                Json::from(path.file_name().unwrap().to_string_lossy().into_owned()),
+                Json::from(path.to_string_lossy().into_owned())
+            )]
+        );
+
+        server.program().unwrap().breakpoints.clear();
+        assert_eq!(
+            request(&mut server, &log, r#"{"seq":7,"command":"continue"}"#),
+            [
+                r#""type":"response","request_seq":7,"command":"continue","success":true,"body":{"allThreadsContinued":true}}"#,
+                r#""type":"event","event":"output","body":{"category":"stdout","output":"\u0002"}}"#,
+                r#""type":"event","event":"exited","body":{"exitCode":0}}"#,
+                r#""type":"event","event":"terminated"}"#
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
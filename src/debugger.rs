@@ -0,0 +1,743 @@
+//! `bf debug`: runs a program an instruction at a time from a prompt,
+//! stopping at breakpoints, and can run it backwards too.
+
+use crate::bf_interpreter::BfInterpreter;
+use crate::cli::{DebugOptions, Input};
+use crate::profile::{self, SourceMap};
+use crate::repl::parse_range;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, BufRead, Cursor, Read, Write};
+use std::str::FromStr;
+
+pub fn run(options: DebugOptions) -> Result<(), String> {
+    let content = crate::runner::read_file(&options.program)?;
+    let builder = BfInterpreter::builder().config(options.config);
+    let (interpreter, offsets) = crate::runner::load_program(&content, builder, false)?;
+    let source = offsets.as_deref().map(|offsets| SourceMap {
+        source: &content,
+        offsets,
+    });
+    let input: Box<dyn Read> = match options.input {
+        Input::Stdin => Box::new(std::io::stdin()),
+        Input::File(path) => Box::new(Cursor::new(crate::runner::read_file(&path)?)),
+        Input::String(input) => Box::new(Cursor::new(input.into_bytes())),
+    };
+    let mut debugger = Debugger::new(interpreter, source, input);
+    println!("{}", debugger.position());
+
+    let mut buf = String::new();
+    loop {
+        print!("(bf) ");
+        std::io::stdout().flush().unwrap();
+
+        buf.clear();
+        match std::io::stdin().lock().read_line(&mut buf) {
+            Ok(0) | Err(_) => {
+                println!();
+                break;
+            }
+            Ok(_) => {}
+        }
+        let line = buf.trim();
+        if matches!(line, "quit" | "q") {
+            break;
+        }
+        if line.is_empty() {
+            continue;
+        }
+        if let Err(e) = debugger.command(line) {
+            println!("ERROR: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// How many steps `step-back` and `reverse-continue` can undo.
+const HISTORY_LIMIT: usize = 1_000_000;
+
+struct Debugger<'a> {
+    interpreter: BfInterpreter,
+    source: Option<SourceMap<'a>>,
+    /// Where `,` reads from.
+    input: Replay,
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    /// Index of the cell each watchpoint watches. Watchpoints and
+    /// breakpoints are numbered together.
+    watchpoints: BTreeMap<usize, usize>,
+    next_breakpoint: usize,
+    /// How to undo each of the last steps, newest last.
+    history: VecDeque<Change>,
+}
+
+/// What a step overwrote. A step changes at most the cell at the data
+/// pointer, so this is enough to undo it without snapshots of the tape.
+struct Change {
+    pc: usize,
+    data_ptr: usize,
+    cell: u32,
+    /// The byte a `,` read, to be read again after going back over it.
+    read: Option<u8>,
+}
+
+/// Reads the program input, first giving back the bytes of `,`s that were
+/// undone.
+struct Replay {
+    inner: Box<dyn Read>,
+    unread: Vec<u8>,
+    /// The last byte read.
+    last: Option<u8>,
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = match (self.unread.pop(), buf.first_mut()) {
+            (Some(byte), Some(first)) => {
+                *first = byte;
+                1
+            }
+            _ => self.inner.read(buf)?,
+        };
+        if n > 0 {
+            self.last = Some(buf[n - 1]);
+        }
+        Ok(n)
+    }
+}
+
+impl<'a> Debugger<'a> {
+    fn new(
+        interpreter: BfInterpreter,
+        source: Option<SourceMap<'a>>,
+        input: Box<dyn Read>,
+    ) -> Self {
+        Debugger {
+            interpreter,
+            source,
+            input: Replay {
+                inner: input,
+                unread: vec![],
+                last: None,
+            },
+            breakpoints: BTreeMap::new(),
+            watchpoints: BTreeMap::new(),
+            next_breakpoint: 1,
+            history: VecDeque::new(),
+        }
+    }
+
+    fn command(&mut self, line: &str) -> Result<(), String> {
+        let (name, arg) = match line.split_once(' ') {
+            Some((name, arg)) => (name, Some(arg.trim())),
+            None => (line, None),
+        };
+
+        match name {
+            "break" | "b" => {
+                let arg = arg.ok_or("Missing location for break")?;
+                let (location, condition) = match arg.split_once(" if ") {
+                    Some((location, condition)) => (location, Some(condition.parse()?)),
+                    None => (arg, None),
+                };
+                let pc = parse_location(
+                    location.trim(),
+                    self.source.as_ref(),
+                    self.interpreter.program().len(),
+                )?;
+                let number = self.next_breakpoint;
+                self.next_breakpoint += 1;
+                let breakpoint = Breakpoint { pc, condition };
+                println!("Breakpoint {} at {}", number, self.describe_breakpoint(&breakpoint));
+                self.breakpoints.insert(number, breakpoint);
+            }
+            "watch" | "w" => {
+                let arg = arg.ok_or("Missing cell index for watch")?;
+                let cell = arg
+                    .parse()
+                    .map_err(|_| format!("Invalid cell index '{}'", arg))?;
+                let number = self.next_breakpoint;
+                self.next_breakpoint += 1;
+                self.watchpoints.insert(number, cell);
+                println!(
+                    "Watchpoint {} on cell[{}] = {}",
+                    number,
+                    cell,
+                    cell_value(&self.interpreter, cell)
+                );
+            }
+            "delete" | "d" => {
+                let arg = arg.ok_or("Missing breakpoint number for delete")?;
+                let number = arg
+                    .parse()
+                    .map_err(|_| format!("Invalid breakpoint number '{}'", arg))?;
+                if self.breakpoints.remove(&number).is_none()
+                    && self.watchpoints.remove(&number).is_none()
+                {
+                    return Err(format!("No breakpoint or watchpoint {}", number));
+                }
+            }
+            "info" => {
+                if self.breakpoints.is_empty() && self.watchpoints.is_empty() {
+                    println!("No breakpoints or watchpoints");
+                }
+                for (number, breakpoint) in &self.breakpoints {
+                    println!("Breakpoint {} at {}", number, self.describe_breakpoint(breakpoint));
+                }
+                for (number, cell) in &self.watchpoints {
+                    println!("Watchpoint {} on cell[{}]", number, cell);
+                }
+            }
+            "step" | "s" => {
+                let count = parse_count(arg)?;
+                self.check_running()?;
+                for _ in 0..count {
+                    if self.finished() || self.step()? {
+                        break;
+                    }
+                }
+                println!("{}", self.position());
+            }
+            "continue" | "c" => self.resume()?,
+            "step-back" | "sb" => {
+                let count = parse_count(arg)?;
+                if self.history.is_empty() {
+                    return Err("No earlier state recorded".to_owned());
+                }
+                for _ in 0..count {
+                    if self.history.is_empty() || self.step_back() {
+                        break;
+                    }
+                }
+                println!("{}", self.position());
+            }
+            "reverse-continue" | "rc" => self.reverse()?,
+            "print" | "p" => {
+                println!("{}", self.position());
+                println!(
+                    "data pointer: {}, cell: {}",
+                    self.interpreter.data_ptr(),
+                    self.interpreter.current_cell()
+                );
+            }
+            "tape" => {
+                let cells = arg.map(parse_range).transpose()?;
+                print!("{}", self.interpreter.dump_cells(cells));
+            }
+            _ => {
+                return Err(format!(
+                    "Unknown command '{}', expected break, watch, delete, info, step, continue, step-back, reverse-continue, print, tape or quit",
+                    name
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs until the program finishes, a watched cell changes or an
+    /// instruction with a breakpoint whose condition holds is next. The
+    /// current instruction always runs, so continuing from a breakpoint moves
+    /// past it.
+    fn resume(&mut self) -> Result<(), String> {
+        self.check_running()?;
+        loop {
+            if self.step()? {
+                println!("{}", self.position());
+                return Ok(());
+            }
+            if self.finished() {
+                println!("{}", self.position());
+                return Ok(());
+            }
+            if self.at_breakpoint() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Like `resume`, but undoes steps until a breakpoint or watchpoint is
+    /// hit or no earlier state is recorded.
+    fn reverse(&mut self) -> Result<(), String> {
+        if self.history.is_empty() {
+            return Err("No earlier state recorded".to_owned());
+        }
+        loop {
+            if self.step_back() || self.at_breakpoint() {
+                return Ok(());
+            }
+            if self.history.is_empty() {
+                println!("Reached the earliest recorded state");
+                println!("{}", self.position());
+                return Ok(());
+            }
+        }
+    }
+
+    /// Whether the next instruction has a breakpoint whose condition holds,
+    /// reporting it if so.
+    fn at_breakpoint(&self) -> bool {
+        let pc = self.interpreter.pc();
+        let hit = self.breakpoints.iter().find(|(_, breakpoint)| {
+            breakpoint.pc == pc
+                && breakpoint
+                    .condition
+                    .as_ref()
+                    .is_none_or(|condition| condition.holds(&self.interpreter))
+        });
+        if let Some((number, breakpoint)) = hit {
+            println!(
+                "Breakpoint {} at {}",
+                number,
+                self.describe_breakpoint(breakpoint)
+            );
+        }
+        hit.is_some()
+    }
+
+    /// Executes the next instruction, doing its I/O. Returns whether it
+    /// changed a watched cell, after reporting the change.
+    fn step(&mut self) -> Result<bool, String> {
+        let change = Change {
+            pc: self.interpreter.pc(),
+            data_ptr: self.interpreter.data_ptr(),
+            cell: self.interpreter.current_cell(),
+            read: None,
+        };
+        let before = self.watched_cells();
+
+        let mut stdout = std::io::stdout();
+        let ret = self.interpreter.step()?;
+        if self.history.len() == HISTORY_LIMIT {
+            self.history.pop_front();
+        }
+        let pc = change.pc;
+        self.history.push_back(change);
+        self.input.last = None;
+        crate::runner::handle_ret(
+            &mut self.interpreter,
+            ret,
+            &mut self.input,
+            &mut stdout,
+            false,
+        )?;
+        stdout
+            .flush()
+            .map_err(|e| format!("Failed to write output: {}", e))?;
+        if let Some(change) = self.history.back_mut() {
+            change.read = self.input.last;
+        }
+
+        Ok(self.report_watchpoints(pc, &before, &self.watched_cells()))
+    }
+
+    /// Undoes the last recorded step. Returns whether that step had changed
+    /// a watched cell, after reporting the change. Output can't be taken
+    /// back.
+    fn step_back(&mut self) -> bool {
+        let Some(change) = self.history.pop_back() else {
+            return false;
+        };
+        let after = self.watched_cells();
+        self.interpreter
+            .rewind(change.pc, change.data_ptr, change.cell);
+        self.input.unread.extend(change.read);
+        self.report_watchpoints(change.pc, &self.watched_cells(), &after)
+    }
+
+    fn watched_cells(&self) -> Vec<u32> {
+        self.watchpoints
+            .values()
+            .map(|&cell| cell_value(&self.interpreter, cell))
+            .collect()
+    }
+
+    /// Reports the watched cells that instruction `pc` changed from `old` to
+    /// `new`, both as returned by `watched_cells`. Returns whether there were
+    /// any.
+    fn report_watchpoints(&self, pc: usize, old: &[u32], new: &[u32]) -> bool {
+        let mut changed = false;
+        for (((number, cell), old), new) in self.watchpoints.iter().zip(old).zip(new) {
+            if new != old {
+                println!(
+                    "Watchpoint {}: cell[{}] changed from {} to {} by {}",
+                    number,
+                    cell,
+                    old,
+                    new,
+                    self.describe(pc)
+                );
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn finished(&self) -> bool {
+        self.interpreter.pc() >= self.interpreter.program().len()
+    }
+
+    fn check_running(&self) -> Result<(), String> {
+        if self.finished() {
+            return Err("The program has finished".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Where execution stopped, e.g. `Stopped at 2:3 (@3): add 1`.
+    fn position(&self) -> String {
+        if self.finished() {
+            return "Program finished".to_owned();
+        }
+        format!("Stopped at {}", self.describe(self.interpreter.pc()))
+    }
+
+    fn describe_breakpoint(&self, breakpoint: &Breakpoint) -> String {
+        match &breakpoint.condition {
+            Some(condition) => format!("{} if {}", self.describe(breakpoint.pc), condition.text),
+            None => self.describe(breakpoint.pc),
+        }
+    }
+
+    /// Instruction `pc` with its source location.
+    fn describe(&self, pc: usize) -> String {
+        let op = self.interpreter.program()[pc];
+        match &self.source {
+            Some(map) => format!("{} (@{}): {}", profile::location(Some(map), pc), pc, op),
+            None => format!("@{}: {}", pc, op),
+        }
+    }
+}
+
+/// A breakpoint stops before instruction `pc` runs, but only when its
+/// condition holds if it has one.
+struct Breakpoint {
+    pc: usize,
+    condition: Option<Condition>,
+}
+
+/// A comparison such as `cell[3] == 65`, checked against the interpreter
+/// state each time its breakpoint is reached.
+#[derive(Debug, PartialEq)]
+struct Condition {
+    left: Operand,
+    comparison: Comparison,
+    right: Operand,
+    /// The condition as written, for `info`.
+    text: String,
+}
+
+#[derive(Debug, PartialEq)]
+enum Operand {
+    /// `cell[n]`.
+    Cell(usize),
+    /// `cell`, the cell at the data pointer.
+    CurrentCell,
+    /// `ptr`.
+    DataPointer,
+    Number(u64),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Le,
+    Ge,
+    Lt,
+    Gt,
+}
+
+impl Comparison {
+    /// Two-character operators come first so that `<=` isn't read as `<`.
+    const ALL: [(&'static str, Comparison); 6] = [
+        ("==", Comparison::Eq),
+        ("!=", Comparison::Ne),
+        ("<=", Comparison::Le),
+        (">=", Comparison::Ge),
+        ("<", Comparison::Lt),
+        (">", Comparison::Gt),
+    ];
+}
+
+impl Condition {
+    fn holds(&self, interpreter: &BfInterpreter) -> bool {
+        let left = self.left.value(interpreter);
+        let right = self.right.value(interpreter);
+        match self.comparison {
+            Comparison::Eq => left == right,
+            Comparison::Ne => left != right,
+            Comparison::Le => left <= right,
+            Comparison::Ge => left >= right,
+            Comparison::Lt => left < right,
+            Comparison::Gt => left > right,
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (left, comparison, right) = Comparison::ALL
+            .iter()
+            .find_map(|&(operator, comparison)| {
+                s.split_once(operator)
+                    .map(|(left, right)| (left, comparison, right))
+            })
+            .ok_or(format!(
+                "Invalid condition '{}', expected e.g. cell[3] == 65",
+                s
+            ))?;
+        Ok(Condition {
+            left: left.trim().parse()?,
+            comparison,
+            right: right.trim().parse()?,
+            text: s.trim().to_owned(),
+        })
+    }
+}
+
+impl Operand {
+    fn value(&self, interpreter: &BfInterpreter) -> u64 {
+        match *self {
+            Operand::Cell(i) => cell_value(interpreter, i) as u64,
+            Operand::CurrentCell => interpreter.current_cell() as u64,
+            Operand::DataPointer => interpreter.data_ptr() as u64,
+            Operand::Number(n) => n,
+        }
+    }
+}
+
+impl FromStr for Operand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid operand '{}', expected cell, cell[<n>], ptr or a number",
+                s
+            )
+        };
+        match s {
+            "cell" => Ok(Operand::CurrentCell),
+            "ptr" => Ok(Operand::DataPointer),
+            _ => match s.strip_prefix("cell[").and_then(|s| s.strip_suffix(']')) {
+                Some(i) => Ok(Operand::Cell(i.trim().parse().map_err(|_| invalid())?)),
+                None => Ok(Operand::Number(s.parse().map_err(|_| invalid())?)),
+            },
+        }
+    }
+}
+
+/// The value of cell `i`. Cells past the end of a growing tape are still 0.
+fn cell_value(interpreter: &BfInterpreter, i: usize) -> u32 {
+    let tape = interpreter.tape();
+    if i < tape.len() {
+        tape.get(i)
+    } else {
+        0
+    }
+}
+
+/// Parses the optional count of `step` and `step-back`.
+fn parse_count(arg: Option<&str>) -> Result<u64, String> {
+    match arg {
+        Some(arg) => arg.parse().map_err(|_| format!("Invalid count '{}'", arg)),
+        None => Ok(1),
+    }
+}
+
+/// Parses the location for `break`, `line:column` in the source or `@index`
+/// for an instruction, into an instruction index. A position between
+/// instructions means the next one.
+fn parse_location(s: &str, source: Option<&SourceMap>, len: usize) -> Result<usize, String> {
+    let invalid = || {
+        format!(
+            "Invalid location '{}', expected <line>:<column> or @<index>",
+            s
+        )
+    };
+    if let Some(index) = s.strip_prefix('@') {
+        let i = index.parse::<usize>().map_err(|_| invalid())?;
+        if i >= len {
+            return Err(format!(
+                "No instruction @{}, the program has {} instructions",
+                i, len
+            ));
+        }
+        return Ok(i);
+    }
+
+    let (line, column) = s.split_once(':').ok_or_else(invalid)?;
+    let line = line.parse().map_err(|_| invalid())?;
+    let column = column.parse().map_err(|_| invalid())?;
+    let map = source.ok_or("Bytecode has no source positions, use @<index>")?;
+    map.instruction_at(line, column)
+        .ok_or(format!("No instruction at or after {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::ir;
+
+    #[test]
+    fn parse_locations() {
+        let source = b"+++\n[>+<-]";
+        let (_, offsets) = ir::compile_with_offsets(source, false).unwrap();
+        let map = SourceMap {
+            source,
+            offsets: &offsets,
+        };
+
+        assert_eq!(parse_location("2:3", Some(&map), 7), Ok(3));
+        assert_eq!(parse_location("@5", Some(&map), 7), Ok(5));
+        assert_eq!(parse_location("@5", None, 7), Ok(5));
+        assert_eq!(
+            parse_location("@7", None, 7).unwrap_err(),
+            "No instruction @7, the program has 7 instructions"
+        );
+        assert_eq!(
+            parse_location("2:3", None, 7).unwrap_err(),
+            "Bytecode has no source positions, use @<index>"
+        );
+        assert_eq!(
+            parse_location("3:1", Some(&map), 7).unwrap_err(),
+            "No instruction at or after 3:1"
+        );
+        assert_eq!(
+            parse_location("x", Some(&map), 7).unwrap_err(),
+            "Invalid location 'x', expected <line>:<column> or @<index>"
+        );
+    }
+
+    #[test]
+    fn stop_at_breakpoints() {
+        let source = b"+++[>+<-]";
+        let (ops, offsets) = ir::compile_with_offsets(source, false).unwrap();
+        let mut debugger = Debugger::new(
+            BfInterpreter::builder().build_ops(ops).unwrap(),
+            Some(SourceMap {
+                source,
+                offsets: &offsets,
+            }),
+            Box::new(std::io::empty()),
+        );
+
+        debugger.command("break 1:6").unwrap();
+        debugger.command("continue").unwrap();
+        assert_eq!(debugger.interpreter.pc(), 3);
+        assert_eq!(debugger.interpreter.tape().get(1), 0);
+        debugger.command("continue").unwrap();
+        assert_eq!(debugger.interpreter.pc(), 3);
+        assert_eq!(debugger.interpreter.tape().get(1), 1);
+
+        debugger.command("delete 1").unwrap();
+        debugger.command("continue").unwrap();
+        assert!(debugger.finished());
+        assert_eq!(debugger.interpreter.tape().get(1), 3);
+        assert_eq!(
+            debugger.command("step").unwrap_err(),
+            "The program has finished"
+        );
+    }
+
+    #[test]
+    fn parse_conditions() {
+        assert_eq!(
+            "cell[3] == 65".parse(),
+            Ok(Condition {
+                left: Operand::Cell(3),
+                comparison: Comparison::Eq,
+                right: Operand::Number(65),
+                text: "cell[3] == 65".to_owned(),
+            })
+        );
+        let condition = "ptr>=cell".parse::<Condition>().unwrap();
+        assert_eq!(condition.left, Operand::DataPointer);
+        assert_eq!(condition.comparison, Comparison::Ge);
+        assert_eq!(condition.right, Operand::CurrentCell);
+        assert_eq!(
+            "cell[3]".parse::<Condition>().unwrap_err(),
+            "Invalid condition 'cell[3]', expected e.g. cell[3] == 65"
+        );
+        assert_eq!(
+            "cell[x] < 2".parse::<Condition>().unwrap_err(),
+            "Invalid operand 'cell[x]', expected cell, cell[<n>], ptr or a number"
+        );
+    }
+
+    #[test]
+    fn conditional_breakpoint() {
+        let (ops, _) = ir::compile_with_offsets(b"++++++[>+<-]", false).unwrap();
+        let mut debugger = Debugger::new(
+            BfInterpreter::builder().build_ops(ops).unwrap(),
+            None,
+            Box::new(std::io::empty()),
+        );
+
+        debugger.command("break @3 if cell[1] == 4").unwrap();
+        debugger.command("continue").unwrap();
+        assert_eq!(debugger.interpreter.pc(), 3);
+        assert_eq!(debugger.interpreter.tape().get(0), 2);
+        assert_eq!(debugger.interpreter.tape().get(1), 4);
+    }
+
+    #[test]
+    fn watch_cell() {
+        let (ops, _) = ir::compile_with_offsets(b"+>++[-<+>]", false).unwrap();
+        let mut debugger = Debugger::new(
+            BfInterpreter::builder().build_ops(ops).unwrap(),
+            None,
+            Box::new(std::io::empty()),
+        );
+
+        debugger.command("watch 0").unwrap();
+        // `+` changes cell 0 right away.
+        debugger.command("continue").unwrap();
+        assert_eq!(debugger.interpreter.pc(), 1);
+        // Then the `+` in the loop.
+        debugger.command("step 10").unwrap();
+        assert_eq!(debugger.interpreter.pc(), 7);
+        assert_eq!(debugger.interpreter.tape().get(0), 2);
+
+        debugger.command("delete 1").unwrap();
+        debugger.command("continue").unwrap();
+        assert!(debugger.finished());
+        assert_eq!(debugger.interpreter.tape().get(0), 3);
+    }
+
+    #[test]
+    fn step_back() {
+        let (ops, _) = ir::compile_with_offsets(b",[>+<-],", false).unwrap();
+        let mut debugger = Debugger::new(
+            BfInterpreter::builder().build_ops(ops).unwrap(),
+            None,
+            Box::new(Cursor::new(b"\x02a".to_vec())),
+        );
+        debugger.command("step 12").unwrap();
+        assert_eq!(debugger.interpreter.pc(), 7);
+        assert_eq!(debugger.interpreter.tape().get(0), 0);
+        assert_eq!(debugger.interpreter.tape().get(1), 2);
+
+        // Back over the second `+`.
+        debugger.command("watch 1").unwrap();
+        debugger.command("reverse-continue").unwrap();
+        assert_eq!(debugger.interpreter.pc(), 3);
+        assert_eq!(debugger.interpreter.tape().get(1), 1);
+
+        debugger.command("delete 1").unwrap();
+        debugger.command("rc").unwrap();
+        assert_eq!(debugger.interpreter.pc(), 0);
+        assert_eq!(debugger.interpreter.tape().get(0), 0);
+        assert_eq!(
+            debugger.command("step-back").unwrap_err(),
+            "No earlier state recorded"
+        );
+
+        // The undone `,` reads the same byte again.
+        debugger.command("step").unwrap();
+        assert_eq!(debugger.interpreter.tape().get(0), 2);
+        debugger.command("continue").unwrap();
+        assert_eq!(debugger.interpreter.tape().get(0), b'a' as u32);
+    }
+}
@@ -0,0 +1,326 @@
+//! Languages other than Brainfuck that map one to one onto its commands.
+//! Their programs are translated to Brainfuck before they are compiled,
+//! keeping where each command came from so errors, traces and profiles point
+//! into the original source.
+
+use crate::parser::{Token, BRAINFORK, DECIMAL, EXTENDED1, PBRAIN};
+use crate::profile;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Dialect {
+    Brainfuck,
+    /// [Ook!](https://esolangs.org/wiki/Ook!), where each command is a pair
+    /// of `Ook.`, `Ook?` or `Ook!`.
+    Ook,
+    /// [pbrain](https://esolangs.org/wiki/Pbrain), Brainfuck with procedures:
+    /// `(` `)` define one numbered by the current cell and `:` calls the one
+    /// numbered by the current cell. It isn't translated but compiled as is.
+    Pbrain,
+    /// [Brainfork](https://esolangs.org/wiki/Brainfork), Brainfuck with
+    /// threads started by `Y`. Compiled as is, like pbrain.
+    Brainfork,
+    /// [Extended Brainfuck Type I](https://esolangs.org/wiki/Extended_Brainfuck),
+    /// which adds a storage cell, bitwise operations and `@` to end the
+    /// program.
+    Extended1,
+    /// Brainfuck with decimal I/O: `:` writes the current cell as a number
+    /// and `;` reads one into it, next to the byte I/O of `.` and `,`.
+    Decimal,
+    /// A language that spells the commands differently, loaded with
+    /// `--dialect-file`.
+    Custom(Mapping),
+}
+
+/// The word that stands for each command in a custom dialect.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mapping {
+    /// Longest words first, so the first match is the longest one.
+    words: Vec<(Vec<u8>, u8)>,
+}
+
+impl FromStr for Dialect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bf" | "brainfuck" => Ok(Dialect::Brainfuck),
+            "ook" => Ok(Dialect::Ook),
+            "pbrain" => Ok(Dialect::Pbrain),
+            "brainfork" => Ok(Dialect::Brainfork),
+            "extended1" => Ok(Dialect::Extended1),
+            "decimal" => Ok(Dialect::Decimal),
+            _ => Err(format!(
+                "Invalid dialect '{}', expected bf, ook, pbrain, brainfork, extended1 or decimal",
+                s
+            )),
+        }
+    }
+}
+
+impl Dialect {
+    /// The dialect a file is written in, going by its extension.
+    pub fn from_path(path: &str) -> Dialect {
+        match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("ook") => Dialect::Ook,
+            _ => Dialect::Brainfuck,
+        }
+    }
+
+    /// The commands the dialect adds to Brainfuck, if it isn't translated.
+    pub fn extensions(&self) -> &'static [(u8, Token)] {
+        match self {
+            Dialect::Pbrain => PBRAIN,
+            Dialect::Brainfork => BRAINFORK,
+            Dialect::Extended1 => EXTENDED1,
+            Dialect::Decimal => DECIMAL,
+            _ => &[],
+        }
+    }
+
+    /// Translates `source` to Brainfuck, or leaves dialects that only add
+    /// commands to it, such as pbrain, as they are. Also
+    /// returns the offset in `source` of each byte of the translation, for
+    /// dialects that are translated.
+    pub fn translate(&self, source: &[u8]) -> Result<(Vec<u8>, Option<Vec<usize>>), String> {
+        match self {
+            Dialect::Ook => {
+                let (program, offsets) = translate_ook(source)?;
+                Ok((program, Some(offsets)))
+            }
+            Dialect::Custom(mapping) => {
+                let (program, offsets) = mapping.translate(source);
+                Ok((program, Some(offsets)))
+            }
+            // Brainfuck and the dialects that only add commands to it.
+            _ => Ok((source.to_vec(), None)),
+        }
+    }
+}
+
+impl Mapping {
+    /// Reads a mapping from a TOML file with one key per command, each
+    /// quoted and set to the word for it:
+    ///
+    /// ```toml
+    /// "+" = "more"
+    /// "[" = "while"
+    /// ```
+    ///
+    /// Only this subset of TOML is understood. Commands left out can't be
+    /// written in the dialect.
+    pub fn parse(toml: &str) -> Result<Mapping, String> {
+        let mut words: Vec<(Vec<u8>, u8)> = vec![];
+        for (i, line) in toml.lines().enumerate() {
+            let error = |message: String| format!("line {}: {}", i + 1, message);
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, rest) = parse_string(line).map_err(error)?;
+            let rest = rest.trim_start();
+            let Some(rest) = rest.strip_prefix('=') else {
+                return Err(error("expected '=' after the command".to_owned()));
+            };
+            let (word, rest) = parse_string(rest.trim_start()).map_err(error)?;
+            let rest = rest.trim_start();
+            if !(rest.is_empty() || rest.starts_with('#')) {
+                return Err(error(format!("unexpected '{}'", rest)));
+            }
+
+            let command = match key.as_bytes() {
+                &[command] if b"<>+-.,[]".contains(&command) => command,
+                _ => {
+                    return Err(error(format!(
+                        "'{}' is not a command, expected one of < > + - . , [ ]",
+                        key
+                    )))
+                }
+            };
+            if word.is_empty() {
+                return Err(error(format!("the word for '{}' is empty", key)));
+            }
+            if let Some((_, other)) = words.iter().find(|(_, c)| *c == command) {
+                return Err(error(format!("'{}' is already mapped", *other as char)));
+            }
+            if let Some((_, other)) = words.iter().find(|(w, _)| *w == word.as_bytes()) {
+                return Err(error(format!(
+                    "'{}' already stands for '{}'",
+                    word, *other as char
+                )));
+            }
+            words.push((word.into_bytes(), command));
+        }
+        if words.is_empty() {
+            return Err("The dialect maps no commands".to_owned());
+        }
+        words.sort_by_key(|(word, _)| std::cmp::Reverse(word.len()));
+        Ok(Mapping { words })
+    }
+
+    /// Replaces the longest word at each position with its command. Anything
+    /// that isn't a word is a comment.
+    fn translate(&self, source: &[u8]) -> (Vec<u8>, Vec<usize>) {
+        let mut program = vec![];
+        let mut offsets = vec![];
+        let mut i = 0;
+        while i < source.len() {
+            match self
+                .words
+                .iter()
+                .find(|(word, _)| source[i..].starts_with(word))
+            {
+                Some((word, command)) => {
+                    program.push(*command);
+                    offsets.push(i);
+                    i += word.len();
+                }
+                None => i += 1,
+            }
+        }
+        (program, offsets)
+    }
+}
+
+/// Parses the TOML string at the start of `s`, in double quotes with
+/// backslash escapes or in single quotes without. Returns it and the rest of
+/// `s`.
+pub(crate) fn parse_string(s: &str) -> Result<(String, &str), String> {
+    if let Some(rest) = s.strip_prefix('\'') {
+        let end = rest.find('\'').ok_or("missing closing '")?;
+        return Ok((rest[..end].to_owned(), &rest[end + 1..]));
+    }
+    let Some(rest) = s.strip_prefix('"') else {
+        return Err("expected a quoted string".to_owned());
+    };
+    let mut string = String::new();
+    let mut chars = rest.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((string, &rest[i + 1..])),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('"') => string.push('"'),
+                Some('\\') => string.push('\\'),
+                Some('n') => string.push('\n'),
+                Some('t') => string.push('\t'),
+                Some(c) => return Err(format!("unknown escape '\\{}'", c)),
+                None => break,
+            },
+            c => string.push(c),
+        }
+    }
+    Err("missing closing \"".to_owned())
+}
+
+/// Everything but the `Ook.`, `Ook?` and `Ook!` words is a comment.
+fn translate_ook(source: &[u8]) -> Result<(Vec<u8>, Vec<usize>), String> {
+    let words = source
+        .windows(4)
+        .enumerate()
+        .filter(|(_, word)| matches!(word, [b'O', b'o', b'k', b'.' | b'?' | b'!']))
+        .map(|(offset, word)| (offset, word[3]))
+        .collect::<Vec<_>>();
+
+    let mut program = vec![];
+    let mut offsets = vec![];
+    for pair in words.chunks(2) {
+        let location = |offset| {
+            let (line, column) = profile::position(source, offset);
+            format!("{}:{}", line, column)
+        };
+        let (offset, first) = pair[0];
+        let Some(&(_, second)) = pair.get(1) else {
+            return Err(format!(
+                "{}: 'Ook{}' is missing its second half",
+                location(offset),
+                first as char
+            ));
+        };
+        let command = match (first, second) {
+            (b'.', b'?') => b'>',
+            (b'?', b'.') => b'<',
+            (b'.', b'.') => b'+',
+            (b'!', b'!') => b'-',
+            (b'!', b'.') => b'.',
+            (b'.', b'!') => b',',
+            (b'!', b'?') => b'[',
+            (b'?', b'!') => b']',
+            _ => {
+                return Err(format!(
+                    "{}: 'Ook{} Ook{}' is not a command",
+                    location(offset),
+                    first as char,
+                    second as char
+                ))
+            }
+        };
+        program.push(command);
+        offsets.push(offset);
+    }
+    Ok((program, offsets))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn translate_ook() {
+        let source = b"Ook. Ook. Ook! Ook?\nloop: Ook! Ook!\nOok? Ook! Ook! Ook.";
+        let (program, offsets) = Dialect::Ook.translate(source).unwrap();
+        assert_eq!(program, b"+[-].");
+        assert_eq!(offsets.unwrap(), [0, 10, 26, 36, 46]);
+
+        assert_eq!(
+            Dialect::Ook.translate(b"Ook. Ook.\nOok? Ook?").unwrap_err(),
+            "2:1: 'Ook? Ook?' is not a command"
+        );
+        assert_eq!(
+            Dialect::Ook.translate(b"Ook. Ook. Ook!").unwrap_err(),
+            "1:11: 'Ook!' is missing its second half"
+        );
+        assert_eq!(Dialect::from_path("dir/hello.ook"), Dialect::Ook);
+        assert_eq!(Dialect::from_path("hello.b"), Dialect::Brainfuck);
+    }
+
+    #[test]
+    fn custom_dialect() {
+        let mapping = Mapping::parse(
+            r#"# Brainfuck with words
+"+" = "up"
+'-' = "down" # comment
+"[" = "up up"
+"]" = "end"
+"." = "say\n"
+"#,
+        )
+        .unwrap();
+        let (program, offsets) = mapping.translate(b"up up up down x end say\n");
+        // The longest word wins.
+        assert_eq!(program, b"[+-].");
+        assert_eq!(offsets, [0, 6, 9, 16, 20]);
+
+        let error = |toml| Mapping::parse(toml).unwrap_err();
+        assert_eq!(
+            error("\"+\" = \"a\"\n\"-\" = \"a\""),
+            "line 2: 'a' already stands for '+'"
+        );
+        assert_eq!(
+            error("\"+\" = \"a\"\n\"+\" = \"b\""),
+            "line 2: '+' is already mapped"
+        );
+        assert_eq!(
+            error("\"x\" = \"a\""),
+            "line 1: 'x' is not a command, expected one of < > + - . , [ ]"
+        );
+        assert_eq!(error("\"+\" = \"\""), "line 1: the word for '+' is empty");
+        assert_eq!(
+            error("\"+\" \"a\""),
+            "line 1: expected '=' after the command"
+        );
+        assert_eq!(error("\"+\" = \"a"), "line 1: missing closing \"");
+        assert_eq!(error("# nothing"), "The dialect maps no commands");
+    }
+}
@@ -0,0 +1,423 @@
+//! `bf difftest`: runs a program on the same input through several
+//! backends, the interpreters, the native code of `bf run --backend` and
+//! the compiled targets, and reports where they disagree. Compiled targets
+//! run in a process of their own, so only their output and whether they
+//! failed are compared; the backends in this process also have to leave the
+//! same data pointer and tape.
+
+use crate::bf_interpreter::BfInterpreter;
+use crate::cli::{DifftestOptions, Input};
+use crate::codegen::{self, Target};
+use crate::config::Config;
+use crate::ir::{self, Op};
+use crate::temp_dir::TempDir;
+use crate::verify::{self, Outcome};
+use crate::{bytecode, fuse, native, preprocess};
+use std::fs::File;
+use std::io::Cursor;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// The node harness for the `js` target: feeds it stdin and writes what it
+/// prints to stdout, and a thrown error to stderr like the other targets.
+const JS_HARNESS: &str = r#"
+const input = require("fs").readFileSync(0);
+const output = [];
+let next = 0;
+run(async () => (next < input.length ? input[next++] : -1), (byte) => output.push(byte))
+  .catch((e) => {
+    console.error("ERROR: " + e.message);
+    process.exitCode = 1;
+  })
+  .finally(() => process.stdout.write(Buffer.from(output)));
+"#;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum Backend {
+    /// The optimized interpreter `bf run` uses.
+    Interp,
+    /// The interpreter of `bf verify` that runs one command at a time.
+    Naive,
+    /// The optimized interpreter on the program compiled to bytecode and
+    /// decoded again.
+    Bfc,
+    /// The optimized interpreter on the program compiled to Brainfuck.
+    Bf,
+    /// Native code from Cranelift, as with `bf run --backend jit`.
+    Jit,
+    /// x86-64 code, as with `bf run --backend asm`.
+    Asm,
+    C,
+    Rust,
+    Js,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interp" => Ok(Backend::Interp),
+            "naive" => Ok(Backend::Naive),
+            "bfc" => Ok(Backend::Bfc),
+            "bf" => Ok(Backend::Bf),
+            "jit" => Ok(Backend::Jit),
+            "asm" => Ok(Backend::Asm),
+            "c" => Ok(Backend::C),
+            "rust" => Ok(Backend::Rust),
+            "js" => Ok(Backend::Js),
+            _ => Err(format!(
+                "Unknown backend '{}', expected interp, naive, bfc, bf, jit, asm, c, rust or js",
+                s
+            )),
+        }
+    }
+}
+
+impl Backend {
+    fn name(self) -> &'static str {
+        match self {
+            Backend::Interp => "interp",
+            Backend::Naive => "naive",
+            Backend::Bfc => "bfc",
+            Backend::Bf => "bf",
+            Backend::Jit => "jit",
+            Backend::Asm => "asm",
+            Backend::C => "c",
+            Backend::Rust => "rust",
+            Backend::Js => "js",
+        }
+    }
+
+    /// The backend of `bf run` that compiles to native code in this process.
+    fn native(self) -> Option<native::Backend> {
+        match self {
+            Backend::Jit => Some(native::Backend::Jit),
+            Backend::Asm => Some(native::Backend::Asm),
+            _ => None,
+        }
+    }
+}
+
+pub fn run(options: DifftestOptions) -> Result<(), String> {
+    let content = crate::runner::read_file(&options.program)?;
+    if bytecode::is_bytecode(&content) {
+        return Err("Bytecode has no source to compile for the other backends".to_owned());
+    }
+    let (program, _) =
+        preprocess::preprocess(&content, crate::runner::source_path(&options.program))?;
+    let input = match &options.input {
+        Input::Stdin => crate::runner::read_file("-")?,
+        Input::File(path) => crate::runner::read_file(path)?,
+        Input::String(input) => input.clone().into_bytes(),
+    };
+
+    let mut backends = vec![];
+    for &backend in &options.backends {
+        match backend.native() {
+            Some(native) if !native.available() => println!(
+                "{}: unavailable, this build has no {} backend",
+                backend.name(),
+                native.name()
+            ),
+            _ => backends.push(backend),
+        }
+    }
+    if backends.len() < 2 {
+        return Err("Fewer than two of the backends are available to compare".to_owned());
+    }
+
+    // Native code runs without a step limit or a timeout, so it runs last,
+    // once the backends with one have seen the program end.
+    let mut order: Vec<usize> = (0..backends.len()).collect();
+    order.sort_by_key(|&i| backends[i].native().is_some());
+    let mut outcomes: Vec<Option<Outcome>> = backends.iter().map(|_| None).collect();
+    for i in order {
+        let backend = backends[i];
+        let outcome = run_backend(backend, &program, &input, &options)
+            .map_err(|e| format!("{}: {}", backend.name(), e))?;
+        // A run cut short says nothing about where the program would end.
+        if let Err(e) = &outcome.result {
+            if e.starts_with("Step limit") || e.starts_with("Timed out") {
+                return Err(format!("The {} run stopped: {}", backend.name(), e));
+            }
+        }
+        outcomes[i] = Some(outcome);
+    }
+    let outcomes: Vec<(Backend, Outcome)> = backends
+        .into_iter()
+        .zip(outcomes.into_iter().map(Option::unwrap))
+        .collect();
+
+    let (first, reference) = &outcomes[0];
+    match &reference.result {
+        Ok(()) => println!(
+            "{}: finished, {} bytes of output",
+            first.name(),
+            reference.output.len()
+        ),
+        Err(e) => println!(
+            "{}: failed with '{}' after {} bytes of output",
+            first.name(),
+            e,
+            reference.output.len()
+        ),
+    }
+    let mut differ = 0;
+    for (backend, outcome) in &outcomes[1..] {
+        match verify::compare(reference, outcome, [first.name(), backend.name()]) {
+            Ok(()) if outcome.memory.is_none() || reference.memory.is_none() => println!(
+                "{}: same output as {} (no tape to compare)",
+                backend.name(),
+                first.name()
+            ),
+            Ok(()) => println!("{}: same as {}", backend.name(), first.name()),
+            Err(e) => {
+                println!("{}: DIFFERS: {}", backend.name(), e);
+                differ += 1;
+            }
+        }
+    }
+    if differ > 0 {
+        return Err(format!(
+            "{} of {} backends differ from {}",
+            differ,
+            outcomes.len() - 1,
+            first.name()
+        ));
+    }
+    Ok(())
+}
+
+fn run_backend(
+    backend: Backend,
+    program: &[u8],
+    input: &[u8],
+    options: &DifftestOptions,
+) -> Result<Outcome, String> {
+    let config = &options.config;
+    let ops = match backend {
+        Backend::Naive => return verify::run_naive(program, config, input, options.max_steps),
+        Backend::Interp => fuse::fuse(&ir::compile(program)?, None).ops,
+        Backend::Bfc => bytecode::decode(&codegen::compile(program, Target::Bfc, config, None)?)?,
+        Backend::Bf => {
+            let program = codegen::compile(program, Target::Bf, config, None)?;
+            fuse::fuse(&ir::compile(&program)?, None).ops
+        }
+        Backend::Jit | Backend::Asm => return run_native(backend, program, input, config),
+        Backend::C | Backend::Rust | Backend::Js => {
+            return run_compiled(backend, program, input, options)
+        }
+    };
+    run_ops(ops, config, input, options.max_steps)
+}
+
+fn run_ops(
+    ops: Box<[Op]>,
+    config: &Config,
+    input: &[u8],
+    max_steps: Option<u64>,
+) -> Result<Outcome, String> {
+    let mut interpreter = BfInterpreter::builder()
+        .config(config.clone())
+        .max_steps(max_steps)
+        .build_ops(ops)?;
+    let mut output = vec![];
+    let result = crate::runner::run_interpreter(
+        &mut interpreter,
+        &mut Cursor::new(input),
+        &mut output,
+        None,
+        None,
+        None,
+        false,
+    );
+    Ok(Outcome {
+        result,
+        output,
+        memory: Some((interpreter.data_ptr(), interpreter.tape().clone())),
+    })
+}
+
+/// Compiles `program` to native code in this process and runs it, as
+/// `bf run --backend` does.
+fn run_native(
+    backend: Backend,
+    program: &[u8],
+    input: &[u8],
+    config: &Config,
+) -> Result<Outcome, String> {
+    let native = backend.native().unwrap();
+    let mut compiled = native::Program::compile(native, &ir::compile(program)?, config)?;
+    let mut output = vec![];
+    let result = compiled.run(&mut Cursor::new(input), &mut output);
+    Ok(Outcome {
+        result,
+        output,
+        memory: Some((compiled.data_ptr(), compiled.tape())),
+    })
+}
+
+/// Compiles `program` for the target of `backend`, builds it in a temporary
+/// directory if it needs building, and runs it.
+fn run_compiled(
+    backend: Backend,
+    program: &[u8],
+    input: &[u8],
+    options: &DifftestOptions,
+) -> Result<Outcome, String> {
+    if cfg!(target_os = "wasi") {
+        return Err("bf difftest can't run compiled programs under WASI".to_owned());
+    }
+    let dir = TempDir::new(&format!("bf-difftest-{}", backend.name()))?;
+    build_and_run(backend, program, input, options, dir.path())
+}
+
+fn build_and_run(
+    backend: Backend,
+    program: &[u8],
+    input: &[u8],
+    options: &DifftestOptions,
+    dir: &Path,
+) -> Result<Outcome, String> {
+    let write = |name: &str, contents: &[u8]| {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok::<_, String>(path)
+    };
+    let (target, source, compiler) = match backend {
+        Backend::C => (Target::C, "program.c", Some("cc")),
+        Backend::Rust => (Target::Rust, "program.rs", Some("rustc")),
+        _ => (Target::Js, "program.js", None),
+    };
+    let mut code = codegen::compile(program, target, &options.config, None)?;
+    if target == Target::Js {
+        code.extend_from_slice(JS_HARNESS.as_bytes());
+    }
+    let source = write(source, &code)?;
+    let mut command = match compiler {
+        Some(compiler) => {
+            let exe = dir.join("program");
+            let built = Command::new(compiler)
+                .arg("-O")
+                .arg(&source)
+                .arg("-o")
+                .arg(&exe)
+                .output()
+                .map_err(|e| format!("Failed to run {}: {}", compiler, e))?;
+            if !built.status.success() {
+                return Err(format!(
+                    "{} failed with {}:\n{}",
+                    compiler,
+                    built.status,
+                    String::from_utf8_lossy(&built.stderr).trim_end()
+                ));
+            }
+            Command::new(exe)
+        }
+        None => {
+            let mut node = Command::new("node");
+            node.arg(&source);
+            node
+        }
+    };
+
+    let open = |path: &Path| File::open(path).map_err(|e| format!("{}: {}", path.display(), e));
+    let create = |path: &Path| File::create(path).map_err(|e| format!("{}: {}", path.display(), e));
+    let (stdout, stderr) = (dir.join("stdout"), dir.join("stderr"));
+    let mut child = command
+        .stdin(open(&write("stdin", input)?)?)
+        .stdout(create(&stdout)?)
+        .stderr(create(&stderr)?)
+        .spawn()
+        .map_err(|e| format!("Failed to run {}: {}", backend.name(), e))?;
+    let deadline = Instant::now() + options.timeout;
+    let status = loop {
+        match child.try_wait().map_err(|e| e.to_string())? {
+            Some(status) => break Some(status),
+            None if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                break None;
+            }
+            None => std::thread::sleep(Duration::from_millis(10)),
+        }
+    };
+
+    let read = |path: &Path| std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e));
+    let errors = String::from_utf8_lossy(&read(&stderr)?).into_owned();
+    let result = match status {
+        None => Err(format!("Timed out after {:?}", options.timeout)),
+        Some(status) if status.success() => Ok(()),
+        // The targets report errors the way `bf run` does.
+        Some(status) => Err(errors
+            .lines()
+            .find_map(|line| line.strip_prefix("ERROR: "))
+            .map_or_else(|| format!("Exited with {}", status), str::to_owned)),
+    };
+    Ok(Outcome {
+        result,
+        output: read(&stdout)?,
+        memory: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config::Eof;
+
+    fn options(backends: &[Backend], config: Config) -> DifftestOptions {
+        DifftestOptions {
+            program: String::new(),
+            backends: backends.to_vec(),
+            input: Input::Stdin,
+            max_steps: Some(100_000),
+            timeout: Duration::from_secs(10),
+            config,
+        }
+    }
+
+    #[test]
+    fn in_process_backends_agree() {
+        let config = Config {
+            eof: Eof::Zero,
+            ..Config::default()
+        };
+        let backends: Vec<Backend> = [
+            Backend::Interp,
+            Backend::Naive,
+            Backend::Bfc,
+            Backend::Bf,
+            Backend::Jit,
+            Backend::Asm,
+        ]
+        .into_iter()
+        .filter(|backend| backend.native().is_none_or(native::Backend::available))
+        .collect();
+        let options = options(&backends, config);
+        for program in [",[>++<-]>[.-]", "+[>+]", "++[>+++[>+<-]<-]<"] {
+            let outcomes: Vec<Outcome> = backends
+                .iter()
+                .map(|&backend| run_backend(backend, program.as_bytes(), b"\x03", &options))
+                .collect::<Result<_, _>>()
+                .unwrap();
+            for outcome in &outcomes[1..] {
+                verify::compare(&outcomes[0], outcome, ["a", "b"]).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn parse_backends() {
+        assert_eq!("bfc".parse(), Ok(Backend::Bfc));
+        assert_eq!("jit".parse(), Ok(Backend::Jit));
+        assert_eq!(
+            "gpu".parse::<Backend>().unwrap_err(),
+            "Unknown backend 'gpu', expected interp, naive, bfc, bf, jit, asm, c, rust or js"
+        );
+    }
+}
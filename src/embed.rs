@@ -0,0 +1,388 @@
+//! The interpreter for embedding in other programs: it runs a given number
+//! of steps at a time, with its input and output kept in buffers, so the
+//! host decides when to feed it and when to show what it printed. `wasm`
+//! wraps it for JavaScript and `ffi` for C.
+//!
+//! With the `tracing` feature, `load` and `step` are spans, with the parsing
+//! and optimizing in `load`, and every byte read or printed is a trace event,
+//! for whatever subscriber the host has set.
+
+use crate::bf_interpreter::{BfInterpreter, Ret};
+use crate::config::Config;
+use crate::fuse;
+use crate::ir;
+use std::collections::VecDeque;
+use std::ops::Range;
+
+/// Where a run stopped after `Session::step`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Status {
+    /// All the steps ran and the program isn't done.
+    Running,
+    /// A `,` is waiting for `Session::feed_input` or `Session::close_input`.
+    Input,
+    /// The fuel given with `Session::set_fuel` ran out; `Session::add_fuel`
+    /// lets the program go on.
+    OutOfFuel,
+    Finished,
+}
+
+pub struct Session {
+    config: Config,
+    interpreter: BfInterpreter,
+    input: VecDeque<u8>,
+    /// Whether `close_input` was called, so `,` sees the end of input once
+    /// `input` is empty instead of waiting.
+    input_closed: bool,
+    output: Vec<u8>,
+}
+
+impl Session {
+    /// A session with an empty program, to `load` one into.
+    pub fn new(config: Config) -> Result<Session, String> {
+        let interpreter = BfInterpreter::builder().config(config.clone()).build(b"")?;
+        Ok(Session {
+            config,
+            interpreter,
+            input: VecDeque::new(),
+            input_closed: false,
+            output: vec![],
+        })
+    }
+
+    /// Starts `program` from the beginning on a fresh tape. Input and output
+    /// left over from the previous program are dropped, while the fuel left
+    /// carries over.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "load", skip_all, fields(bytes = program.len()), err)
+    )]
+    pub fn load(&mut self, program: &[u8]) -> Result<(), String> {
+        let ops = fuse::fuse(&ir::compile(program)?, None).ops;
+        let fuel = self.interpreter.fuel();
+        self.interpreter = BfInterpreter::builder()
+            .config(self.config.clone())
+            .build_ops(ops)?;
+        if let Some(fuel) = fuel {
+            self.interpreter.set_fuel(fuel);
+        }
+        self.input.clear();
+        self.input_closed = false;
+        self.output.clear();
+        Ok(())
+    }
+
+    /// Runs up to `steps` instructions, stopping early at the end of the
+    /// program, at a `,` with no input to read or when the fuel runs out.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "run",
+            skip(self),
+            fields(executed = tracing::field::Empty),
+            ret,
+            err
+        )
+    )]
+    pub fn step(&mut self, steps: u32) -> Result<Status, String> {
+        #[cfg(feature = "tracing")]
+        let before = self.steps();
+        let status = self.run(steps);
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("executed", self.steps() - before);
+        status
+    }
+
+    fn run(&mut self, steps: u32) -> Result<Status, String> {
+        let mut left = steps;
+        loop {
+            if self.interpreter.pending_input() {
+                match self.input.pop_front() {
+                    Some(byte) => {
+                        #[cfg(feature = "tracing")]
+                        tracing::trace!(byte, "input");
+                        self.interpreter.set_input(byte)
+                    }
+                    None if self.input_closed => {
+                        #[cfg(feature = "tracing")]
+                        tracing::trace!("end of input");
+                        self.interpreter.set_eof()
+                    }
+                    None => return Ok(Status::Input),
+                }
+            }
+            if left == 0 {
+                return Ok(Status::Running);
+            }
+            let before = self.interpreter.stats().steps;
+            let ret = self.interpreter.run_batch(left)?;
+            left -= (self.interpreter.stats().steps - before) as u32;
+            match ret {
+                Ret::Continue | Ret::Input => {}
+                Ret::Output(byte) => {
+                    #[cfg(feature = "tracing")]
+                    tracing::trace!(byte, "output");
+                    self.output.push(byte)
+                }
+                Ret::OutOfFuel => return Ok(Status::OutOfFuel),
+                Ret::Finished => return Ok(Status::Finished),
+                Ret::Debug | Ret::Fork | Ret::OutputNumber(_) | Ret::InputNumber => {
+                    unreachable!("not a Brainfuck command")
+                }
+            }
+        }
+    }
+
+    /// Gives the program `fuel` to burn, one unit or more per instruction as
+    /// `BfInterpreter::set_fuel` says; `step` returns `Status::OutOfFuel`
+    /// once it's used up.
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.interpreter.set_fuel(fuel);
+    }
+
+    /// Adds to the fuel left, for `step` to go on after `Status::OutOfFuel`.
+    pub fn add_fuel(&mut self, fuel: u64) {
+        self.interpreter.add_fuel(fuel);
+    }
+
+    /// The fuel left, or `None` if the program isn't metered.
+    pub fn fuel(&self) -> Option<u64> {
+        self.interpreter.fuel()
+    }
+
+    /// Adds `input` for `,` to read after whatever is still buffered.
+    pub fn feed_input(&mut self, input: &[u8]) {
+        self.input.extend(input);
+    }
+
+    /// Makes `,` see the end of input once the buffered input is read, and
+    /// act as `Config::eof` says.
+    pub fn close_input(&mut self) {
+        self.input_closed = true;
+    }
+
+    /// Everything printed since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Moves as much of what was printed as fits into `buf`, oldest first,
+    /// returning how many bytes that was.
+    pub fn read_output(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.output.len());
+        buf[..n].copy_from_slice(&self.output[..n]);
+        self.output.drain(..n);
+        n
+    }
+
+    /// The values of the cells in `cells`, cut off at the end of the tape.
+    pub fn tape_slice(&self, cells: Range<usize>) -> Vec<u32> {
+        let tape = self.interpreter.tape();
+        let end = cells.end.min(tape.len());
+        (cells.start.min(end)..end).map(|i| tape.get(i)).collect()
+    }
+
+    pub fn data_ptr(&self) -> usize {
+        self.interpreter.data_ptr()
+    }
+
+    /// The instructions executed since the program was loaded. They're those
+    /// of the optimized program, so the count for a program only changes
+    /// when the optimizer does.
+    pub fn steps(&self) -> u64 {
+        self.interpreter.stats().steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn step_through_input() {
+        let mut session = Session::new(Config::default()).unwrap();
+        session.load(b",[.,]").unwrap();
+        assert_eq!(session.step(100), Ok(Status::Input));
+
+        session.feed_input(b"hi");
+        assert_eq!(session.step(100), Ok(Status::Input));
+        assert_eq!(session.take_output(), b"hi");
+        assert_eq!(session.take_output(), b"");
+
+        session.feed_input(b"!");
+        session.close_input();
+        assert_eq!(session.step(100), Ok(Status::Finished));
+        assert_eq!(session.take_output(), b"!");
+    }
+
+    #[test]
+    fn step_a_few_at_a_time() {
+        let mut session = Session::new(Config::default()).unwrap();
+        session.load(b"+++[>++<-]>.").unwrap();
+        let mut steps = 0;
+        while session.step(1).unwrap() == Status::Running {
+            steps += 1;
+        }
+        assert!(steps > 3);
+        assert_eq!(session.steps(), steps);
+        assert_eq!(session.take_output(), [6]);
+        assert_eq!(session.tape_slice(0..3), [0, 6, 0]);
+        assert_eq!(session.data_ptr(), 1);
+        assert_eq!(session.tape_slice(29_999..usize::MAX), [0]);
+
+        session.load(b"+").unwrap();
+        assert_eq!(session.steps(), 0);
+        assert_eq!(
+            session.load(b"[").unwrap_err(),
+            ir::compile(b"[").unwrap_err()
+        );
+    }
+
+    #[test]
+    fn run_on_fuel() {
+        let mut session = Session::new(Config::default()).unwrap();
+        session.load(b"++[>+++<-]>.").unwrap();
+        let mut runs = 0;
+        session.set_fuel(5);
+        while session.step(u32::MAX).unwrap() == Status::OutOfFuel {
+            session.add_fuel(5);
+            runs += 1;
+        }
+        assert_eq!(session.take_output(), [6]);
+
+        // The same program burns the same fuel every time.
+        let used = 5 * (runs + 1) - session.fuel().unwrap();
+        session.load(b"++[>+++<-]>.").unwrap();
+        let left = session.fuel().unwrap();
+        session.add_fuel(used);
+        assert_eq!(session.step(u32::MAX), Ok(Status::Finished));
+        assert_eq!(session.fuel(), Some(left));
+
+        // An output costs more than what's left.
+        session.load(b"+.").unwrap();
+        session.set_fuel(3);
+        assert_eq!(session.step(u32::MAX), Ok(Status::OutOfFuel));
+        assert_eq!(session.fuel(), Some(2));
+        assert_eq!(session.step(u32::MAX), Ok(Status::OutOfFuel));
+        session.add_fuel(2);
+        assert_eq!(session.step(u32::MAX), Ok(Status::Finished));
+        assert_eq!(session.take_output(), [1]);
+        assert_eq!(session.fuel(), Some(0));
+    }
+
+    /// Writes down every span, field recorded later and event, one line
+    /// each.
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct Capture(std::sync::Arc<std::sync::Mutex<Captured>>);
+
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct Captured {
+        lines: Vec<String>,
+        /// The metadata of each span, by ID less 1.
+        spans: Vec<&'static tracing::Metadata<'static>>,
+        /// The spans entered, innermost last, for `Span::current`.
+        entered: Vec<u64>,
+    }
+
+    #[cfg(feature = "tracing")]
+    struct Fields(String);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for Fields {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0 += &format!(" {}={:?}", field.name(), value);
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for Capture {
+        fn enabled(&self, _: &tracing::Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes) -> tracing::span::Id {
+            let mut fields = Fields(format!("span {}", span.metadata().name()));
+            span.record(&mut fields);
+            let mut captured = self.0.lock().unwrap();
+            captured.lines.push(fields.0);
+            captured.spans.push(span.metadata());
+            tracing::span::Id::from_u64(captured.spans.len() as u64)
+        }
+
+        fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record) {
+            let mut captured = self.0.lock().unwrap();
+            let name = captured.spans[span.into_u64() as usize - 1].name();
+            let mut fields = Fields(format!("record {}", name));
+            values.record(&mut fields);
+            captured.lines.push(fields.0);
+        }
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event) {
+            let mut fields = Fields(event.metadata().level().to_string());
+            event.record(&mut fields);
+            self.0.lock().unwrap().lines.push(fields.0);
+        }
+
+        fn enter(&self, span: &tracing::span::Id) {
+            self.0.lock().unwrap().entered.push(span.into_u64());
+        }
+
+        fn exit(&self, _: &tracing::span::Id) {
+            self.0.lock().unwrap().entered.pop();
+        }
+
+        fn current_span(&self) -> tracing_core::span::Current {
+            let captured = self.0.lock().unwrap();
+            match captured.entered.last() {
+                Some(&id) => tracing_core::span::Current::new(
+                    tracing::span::Id::from_u64(id),
+                    captured.spans[id as usize - 1],
+                ),
+                None => tracing_core::span::Current::none(),
+            }
+        }
+    }
+
+    /// The spans, the fields they record and the events are what a host's
+    /// logs are made of, so they're kept as they are.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn trace_loading_and_running() {
+        let capture = Capture::default();
+        tracing::subscriber::with_default(capture.clone(), || {
+            let mut session = Session::new(Config::default()).unwrap();
+            session.load(b",[.,]").unwrap();
+            session.feed_input(b"h");
+            session.close_input();
+            assert_eq!(session.step(100), Ok(Status::Finished));
+            assert!(session.load(b"+[").is_err());
+        });
+        let lines = capture.0.lock().unwrap().lines.clone();
+        // `Session::new` parses an empty program first.
+        let load = lines.iter().position(|line| line.starts_with("span load"));
+        assert_eq!(
+            lines[load.unwrap()..],
+            [
+                "span load bytes=5",
+                "span parse bytes=5",
+                "record parse ops=5",
+                "span optimize ops=5",
+                "record optimize fused=5",
+                "span run steps=100",
+                "TRACE message=input byte=104",
+                "TRACE message=output byte=104",
+                "TRACE message=end of input",
+                "record run executed=4",
+                "INFO return=Finished",
+                "span load bytes=2",
+                "span parse bytes=2",
+                "ERROR error=Missing ']'",
+            ]
+        );
+    }
+}
@@ -0,0 +1,60 @@
+//! `--escape-output`: shows non-printable bytes as `\xNN` instead of sending
+//! raw control characters to the terminal.
+
+use std::io::{self, Write};
+
+const COLOR: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+pub struct Escaper<W> {
+    inner: W,
+    /// Dim the escapes so they stand out from real output.
+    color: bool,
+}
+
+impl<W: Write> Escaper<W> {
+    pub fn new(inner: W, color: bool) -> Self {
+        Self { inner, color }
+    }
+}
+
+/// Whether `byte` is written as it is: printable ASCII, newlines and tabs.
+fn is_printable(byte: u8) -> bool {
+    byte.is_ascii_graphic() || matches!(byte, b' ' | b'\n' | b'\t')
+}
+
+impl<W: Write> Write for Escaper<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if is_printable(byte) {
+                self.inner.write_all(&[byte])?;
+            } else if self.color {
+                write!(self.inner, "{}\\x{:02x}{}", COLOR, byte, RESET)?;
+            } else {
+                write!(self.inner, "\\x{:02x}", byte)?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn escape_bytes() {
+        let mut out = Escaper::new(vec![], false);
+        out.write_all(b"a\x1b[2J\tb\x00\xff\n").unwrap();
+        assert_eq!(out.inner, b"a\\x1b[2J\tb\\x00\\xff\n");
+
+        let mut out = Escaper::new(vec![], true);
+        out.write_all(b"\x07").unwrap();
+        assert_eq!(out.inner, b"\x1b[2m\\x07\x1b[0m");
+    }
+}
@@ -0,0 +1,350 @@
+//! The C API, declared in `include/bf.h`: a session (see `embed::Session`)
+//! behind an opaque pointer, for C, C++ and anything else that can call C
+//! to embed the interpreter through the shared library.
+
+use crate::config::Config;
+use crate::embed::{Session, Status};
+use crate::gen;
+use std::ffi::{c_char, c_int, CStr, CString};
+
+pub const BF_RUNNING: c_int = 0;
+pub const BF_INPUT: c_int = 1;
+pub const BF_FINISHED: c_int = 2;
+pub const BF_OUT_OF_FUEL: c_int = 3;
+pub const BF_ERROR: c_int = -1;
+
+pub struct BfSession {
+    session: Session,
+    /// Why the program failed to compile or the run stopped, for `bf_error`.
+    /// Once set, `bf_step` does nothing.
+    error: Option<CString>,
+}
+
+impl BfSession {
+    fn fail(&mut self, message: String) {
+        // Messages don't contain NULs, but one would end the C string early.
+        self.error = Some(CString::new(message.replace('\0', "")).unwrap());
+    }
+}
+
+/// Creates a session running `program`, a NUL-terminated string, on a tape
+/// of 30,000 8-bit cells. A program that doesn't compile still gets a
+/// session, whose `bf_step` fails with the reason.
+///
+/// # Safety
+///
+/// `program` must point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn bf_new(program: *const c_char) -> *mut BfSession {
+    let mut bf = BfSession {
+        session: Session::new(Config::default()).unwrap(),
+        error: None,
+    };
+    let program = unsafe { CStr::from_ptr(program) };
+    if let Err(e) = bf.session.load(program.to_bytes()) {
+        bf.fail(e);
+    }
+    Box::into_raw(Box::new(bf))
+}
+
+/// Runs up to `steps` instructions. Returns `BF_RUNNING` if they all ran,
+/// `BF_INPUT` if a `,` is waiting for `bf_set_input` or `bf_close_input`,
+/// `BF_OUT_OF_FUEL` if the fuel given with `bf_set_fuel` ran out,
+/// `BF_FINISHED` at the end of the program and `BF_ERROR` if it failed.
+///
+/// # Safety
+///
+/// `bf` must come from `bf_new` and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn bf_step(bf: *mut BfSession, steps: u32) -> c_int {
+    let bf = unsafe { &mut *bf };
+    if bf.error.is_some() {
+        return BF_ERROR;
+    }
+    match bf.session.step(steps) {
+        Ok(Status::Running) => BF_RUNNING,
+        Ok(Status::Input) => BF_INPUT,
+        Ok(Status::OutOfFuel) => BF_OUT_OF_FUEL,
+        Ok(Status::Finished) => BF_FINISHED,
+        Err(e) => {
+            bf.fail(e);
+            BF_ERROR
+        }
+    }
+}
+
+/// Gives the program `fuel` to burn; `bf_step` returns `BF_OUT_OF_FUEL`
+/// once it's used up.
+///
+/// # Safety
+///
+/// As for `bf_step`.
+#[no_mangle]
+pub unsafe extern "C" fn bf_set_fuel(bf: *mut BfSession, fuel: u64) {
+    unsafe { &mut *bf }.session.set_fuel(fuel);
+}
+
+/// Adds fuel, for `bf_step` to go on after `BF_OUT_OF_FUEL`.
+///
+/// # Safety
+///
+/// As for `bf_step`.
+#[no_mangle]
+pub unsafe extern "C" fn bf_add_fuel(bf: *mut BfSession, fuel: u64) {
+    unsafe { &mut *bf }.session.add_fuel(fuel);
+}
+
+/// The fuel left, or `u64::MAX` if the program isn't metered.
+///
+/// # Safety
+///
+/// As for `bf_step`.
+#[no_mangle]
+pub unsafe extern "C" fn bf_fuel(bf: *const BfSession) -> u64 {
+    unsafe { &*bf }.session.fuel().unwrap_or(u64::MAX)
+}
+
+/// The instructions executed since the program was loaded, counting those
+/// of the optimized program.
+///
+/// # Safety
+///
+/// As for `bf_step`.
+#[no_mangle]
+pub unsafe extern "C" fn bf_steps(bf: *const BfSession) -> u64 {
+    unsafe { &*bf }.session.steps()
+}
+
+/// Adds `len` bytes from `input` for `,` to read.
+///
+/// # Safety
+///
+/// `bf` as for `bf_step`, and `input` must point to `len` bytes, or may be
+/// NULL if `len` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn bf_set_input(bf: *mut BfSession, input: *const u8, len: usize) {
+    if len > 0 {
+        let bf = unsafe { &mut *bf };
+        bf.session
+            .feed_input(unsafe { std::slice::from_raw_parts(input, len) });
+    }
+}
+
+/// Makes `,` see the end of input once what was given to `bf_set_input` is
+/// read; by default that stops the program.
+///
+/// # Safety
+///
+/// As for `bf_step`.
+#[no_mangle]
+pub unsafe extern "C" fn bf_close_input(bf: *mut BfSession) {
+    unsafe { &mut *bf }.session.close_input();
+}
+
+/// Copies up to `len` bytes of the output not yet taken into `buf`, oldest
+/// first, and returns how many it copied. Call it until it returns less
+/// than `len` to take all of it.
+///
+/// # Safety
+///
+/// `bf` as for `bf_step`, and `buf` must point to `len` writable bytes, or
+/// may be NULL if `len` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn bf_get_output(bf: *mut BfSession, buf: *mut u8, len: usize) -> usize {
+    if len == 0 {
+        return 0;
+    }
+    let bf = unsafe { &mut *bf };
+    bf.session
+        .read_output(unsafe { std::slice::from_raw_parts_mut(buf, len) })
+}
+
+/// Why the session failed, as a NUL-terminated string that lives as long as
+/// the session, or NULL if it hasn't.
+///
+/// # Safety
+///
+/// As for `bf_step`.
+#[no_mangle]
+pub unsafe extern "C" fn bf_error(bf: *const BfSession) -> *const c_char {
+    match &unsafe { &*bf }.error {
+        Some(message) => message.as_ptr(),
+        None => std::ptr::null(),
+    }
+}
+
+/// Frees a session. NULL is ignored.
+///
+/// # Safety
+///
+/// `bf` must come from `bf_new` and not have been freed already.
+#[no_mangle]
+pub unsafe extern "C" fn bf_free(bf: *mut BfSession) {
+    if !bf.is_null() {
+        drop(unsafe { Box::from_raw(bf) });
+    }
+}
+
+/// Writes a random program of `size` commands with balanced brackets to
+/// `buf`, followed by a NUL, for fuzzing. The same `seed` gives the same
+/// program, and `loops`, from 0 to 1, is the chance of each command being a
+/// bracket.
+///
+/// # Safety
+///
+/// `buf` must have room for `size + 1` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn bf_gen_random(buf: *mut c_char, size: usize, seed: u64, loops: f64) {
+    let program = gen::random(size, seed, loops);
+    unsafe {
+        std::ptr::copy_nonoverlapping(program.as_ptr(), buf.cast(), size);
+        *buf.add(size) = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn run_through_the_c_api() {
+        unsafe {
+            let bf = bf_new(c",[.,]".as_ptr());
+            assert_eq!(bf_step(bf, 100), BF_INPUT);
+            bf_set_input(bf, b"hey".as_ptr(), 3);
+            bf_close_input(bf);
+            assert_eq!(bf_step(bf, 100), BF_FINISHED);
+            assert_eq!(bf_steps(bf), 10);
+
+            let mut buf = [0; 2];
+            assert_eq!(bf_get_output(bf, buf.as_mut_ptr(), 2), 2);
+            assert_eq!(&buf, b"he");
+            assert_eq!(bf_get_output(bf, buf.as_mut_ptr(), 2), 1);
+            assert_eq!(buf[0], b'y');
+            assert!(bf_error(bf).is_null());
+            bf_free(bf);
+        }
+    }
+
+    #[test]
+    fn meter_with_fuel() {
+        unsafe {
+            let bf = bf_new(c"+[]".as_ptr());
+            assert_eq!(bf_fuel(bf), u64::MAX);
+            bf_set_fuel(bf, 100);
+            assert_eq!(bf_step(bf, u32::MAX), BF_OUT_OF_FUEL);
+            assert_eq!(bf_fuel(bf), 0);
+            bf_add_fuel(bf, 10);
+            assert_eq!(bf_step(bf, 5), BF_RUNNING);
+            assert_eq!(bf_fuel(bf), 5);
+            bf_free(bf);
+        }
+    }
+
+    #[test]
+    fn report_errors() {
+        unsafe {
+            let bf = bf_new(c"+[".as_ptr());
+            assert_eq!(bf_step(bf, 100), BF_ERROR);
+            assert_eq!(CStr::from_ptr(bf_error(bf)), c"Missing ']'");
+            bf_free(bf);
+
+            let bf = bf_new(c"<".as_ptr());
+            assert_eq!(bf_step(bf, 100), BF_ERROR);
+            assert_eq!(bf_step(bf, 100), BF_ERROR);
+            assert_eq!(CStr::from_ptr(bf_error(bf)), c"Memory underflow");
+            bf_free(bf);
+        }
+    }
+
+    #[test]
+    fn generate_random_programs() {
+        let mut buf = [1; 21];
+        unsafe {
+            bf_gen_random(buf.as_mut_ptr(), 20, 7, 0.2);
+            let program = CStr::from_ptr(buf.as_ptr()).to_str().unwrap();
+            assert_eq!(program, gen::random(20, 7, 0.2));
+        }
+    }
+
+    /// The C spelling of a Rust type in a signature here.
+    fn c_type(rust: &str) -> String {
+        if let Some(pointee) = rust.strip_prefix("*const ") {
+            return format!("const {} *", c_type(pointee));
+        }
+        if let Some(pointee) = rust.strip_prefix("*mut ") {
+            return format!("{} *", c_type(pointee));
+        }
+        match rust {
+            "c_char" => "char",
+            "c_int" => "int",
+            "u8" => "uint8_t",
+            "u32" => "uint32_t",
+            "u64" => "uint64_t",
+            "usize" => "size_t",
+            "f64" => "double",
+            "BfSession" => "BfSession",
+            _ => panic!("no C type for {}", rust),
+        }
+        .to_owned()
+    }
+
+    /// `ty` followed by `name`, as C writes a declaration.
+    fn declare(ty: &str, name: &str) -> String {
+        if ty.ends_with('*') {
+            format!("{}{}", ty, name)
+        } else {
+            format!("{} {}", ty, name)
+        }
+    }
+
+    /// The header declares everything the library exports, with the same
+    /// types, and nothing else.
+    #[test]
+    fn header_matches() {
+        let header = include_str!("../include/bf.h");
+        let mut expected = vec![];
+        for line in include_str!("ffi.rs").lines() {
+            if let Some(rest) = line.strip_prefix("pub unsafe extern \"C\" fn ") {
+                let (name, rest) = rest.split_once('(').unwrap();
+                let (params, rest) = rest.split_once(')').unwrap();
+                let returns = match rest.trim_end_matches(" {").strip_prefix(" -> ") {
+                    Some(ty) => c_type(ty),
+                    None => "void".to_owned(),
+                };
+                let params: Vec<String> = params
+                    .split(", ")
+                    .map(|param| {
+                        let (name, ty) = param.split_once(": ").unwrap();
+                        declare(&c_type(ty), name)
+                    })
+                    .collect();
+                expected.push(format!(
+                    "{}({});",
+                    declare(&returns, name),
+                    params.join(", ")
+                ));
+            }
+            if let Some(rest) = line.strip_prefix("pub const ") {
+                let (name, rest) = rest.split_once(": c_int = ").unwrap();
+                let value = rest.trim_end_matches(';');
+                let value = if value.starts_with('-') {
+                    format!("({})", value)
+                } else {
+                    value.to_owned()
+                };
+                expected.push(format!("#define {} {}", name, value));
+            }
+        }
+        let declared: Vec<&str> = header
+            .lines()
+            // Everything but the include guard.
+            .filter(|line| {
+                (line.starts_with("#define BF_") && *line != "#define BF_H")
+                    || (line.contains("bf_") && line.ends_with(");"))
+            })
+            .collect();
+        assert_eq!(declared, expected);
+    }
+}
@@ -0,0 +1,32 @@
+//! `--flush-every-byte`: passes output on as soon as it's written, for
+//! whatever reads it to see each byte right away.
+
+use std::io::{self, Write};
+
+pub struct FlushEveryByte<W>(pub W);
+
+impl<W: Write> Write for FlushEveryByte<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.0.write(buf)?;
+        self.0.flush()?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::BufWriter;
+
+    #[test]
+    fn flushes_every_write() {
+        let mut out = FlushEveryByte(BufWriter::new(vec![]));
+        out.write_all(b"a").unwrap();
+        assert_eq!(out.0.get_ref(), b"a");
+    }
+}
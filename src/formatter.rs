@@ -0,0 +1,244 @@
+use crate::parser::{find_matching_parens, parse_program};
+
+const INDENT: &str = "    ";
+
+/// Line width `bf fmt` wraps at unless told otherwise.
+pub(crate) const DEFAULT_WIDTH: usize = 80;
+
+/// A piece of a program as the formatter lays it out.
+#[derive(Debug, PartialEq)]
+enum Item {
+    /// A run of one repeated command other than `[` and `]`.
+    Run(u8, usize),
+    Open,
+    Close,
+    /// The non-command text of one source line between two commands, with
+    /// whitespace collapsed.
+    Comment(String),
+}
+
+/// Formats a program with every `[` and `]` on a line of its own and loop
+/// bodies indented by nesting depth. Runs of the same command are separated
+/// by spaces and lines are wrapped at `width` columns where possible.
+/// Comments are kept, each on a line of its own.
+pub fn format(program: &[u8], width: usize) -> Result<String, String> {
+    find_matching_parens(&parse_program(program, false))?;
+
+    let mut out = Output {
+        text: String::new(),
+        line: String::new(),
+        depth: 0,
+        width,
+    };
+    for item in items(program) {
+        match item {
+            Item::Run(command, count) => out.run(command, count),
+            Item::Open => {
+                out.write_line("[");
+                out.depth += 1;
+            }
+            Item::Close => {
+                out.flush();
+                out.depth -= 1;
+                out.write_line("]");
+            }
+            Item::Comment(comment) => out.comment(&comment),
+        }
+    }
+    out.flush();
+    Ok(out.text)
+}
+
+/// Strips everything but commands from a program. With `remove_no_ops`,
+/// adjacent commands that cancel out (`+-`, `-+`, `<>` and `><`) are removed
+/// too, assuming cells wrap and the program doesn't move off the tape.
+pub fn minify(program: &[u8], remove_no_ops: bool) -> Result<String, String> {
+    find_matching_parens(&parse_program(program, false))?;
+
+    let mut out = String::new();
+    for &byte in program {
+        let inverse = match byte {
+            b'+' => '-',
+            b'-' => '+',
+            b'<' => '>',
+            b'>' => '<',
+            b'.' | b',' | b'[' | b']' => {
+                out.push(byte as char);
+                continue;
+            }
+            _ => continue,
+        };
+        if remove_no_ops && out.ends_with(inverse) {
+            out.pop();
+        } else {
+            out.push(byte as char);
+        }
+    }
+    Ok(out)
+}
+
+fn items(program: &[u8]) -> Vec<Item> {
+    let mut items = vec![];
+    let mut comment = vec![];
+    let end_comment = |comment: &mut Vec<u8>, items: &mut Vec<Item>| {
+        let text = String::from_utf8_lossy(comment)
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        if !text.is_empty() {
+            items.push(Item::Comment(text));
+        }
+        comment.clear();
+    };
+
+    for &byte in program {
+        match byte {
+            b'+' | b'-' | b'<' | b'>' | b'.' | b',' => {
+                end_comment(&mut comment, &mut items);
+                match items.last_mut() {
+                    Some(Item::Run(command, count)) if *command == byte => *count += 1,
+                    _ => items.push(Item::Run(byte, 1)),
+                }
+            }
+            b'[' | b']' => {
+                end_comment(&mut comment, &mut items);
+                items.push(if byte == b'[' {
+                    Item::Open
+                } else {
+                    Item::Close
+                });
+            }
+            b'\n' => end_comment(&mut comment, &mut items),
+            _ => comment.push(byte),
+        }
+    }
+    end_comment(&mut comment, &mut items);
+    items
+}
+
+struct Output {
+    text: String,
+    /// The code line being filled, without indentation.
+    line: String,
+    depth: usize,
+    width: usize,
+}
+
+impl Output {
+    /// Columns left for text after the indentation, at least 1.
+    fn available(&self) -> usize {
+        self.width.saturating_sub(INDENT.len() * self.depth).max(1)
+    }
+
+    /// Ends the current code line, if any.
+    fn flush(&mut self) {
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            self.write_line(&line);
+        }
+    }
+
+    /// Writes `text` as a line of its own.
+    fn write_line(&mut self, text: &str) {
+        self.flush();
+        self.text.push_str(&INDENT.repeat(self.depth));
+        self.text.push_str(text);
+        self.text.push('\n');
+    }
+
+    fn run(&mut self, command: u8, count: usize) {
+        let available = self.available();
+        if !self.line.is_empty() && self.line.len() + 1 + count <= available {
+            self.line.push(' ');
+        } else {
+            self.flush();
+        }
+        let mut count = count;
+        // Runs longer than a line are split over several.
+        while self.line.len() + count > available {
+            let part = available - self.line.len();
+            self.line.extend(std::iter::repeat_n(command as char, part));
+            self.flush();
+            count -= part;
+        }
+        self.line
+            .extend(std::iter::repeat_n(command as char, count));
+    }
+
+    fn comment(&mut self, comment: &str) {
+        self.flush();
+        let available = self.available();
+        let mut line = String::new();
+        for word in comment.split(' ') {
+            let columns = line.chars().count() + 1 + word.chars().count();
+            if !line.is_empty() && columns > available {
+                self.write_line(&line);
+                line.clear();
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(word);
+        }
+        self.write_line(&line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn format_loops() {
+        let formatted = format(b"++ comment [>+[-]<-] >.", DEFAULT_WIDTH).unwrap();
+        assert_eq!(
+            formatted,
+            "++
+comment
+[
+    > +
+    [
+        -
+    ]
+    < -
+]
+> .
+"
+        );
+
+        assert_eq!(format(b"", DEFAULT_WIDTH).unwrap(), "");
+        assert_eq!(format(b"[", DEFAULT_WIDTH).unwrap_err(), "Missing ']'");
+    }
+
+    #[test]
+    fn minify_programs() {
+        let program = b"a +-+ comment [>+<-]\n<<>+>-.";
+        assert_eq!(minify(program, false).unwrap(), "+-+[>+<-]<<>+>-.");
+        assert_eq!(minify(program, true).unwrap(), "+[>+<-]<+>-.");
+        assert_eq!(minify(b"+[-+]>+-<", true).unwrap(), "+[]");
+        assert_eq!(minify(b"]", true).unwrap_err(), "Missing '['");
+    }
+
+    #[test]
+    fn wrap_lines() {
+        let program = b"set the cell to 20 and print it\n+++++ +++++ +++++ +++++ >>+<<[.-]";
+        let formatted = format(program, 10).unwrap();
+        assert_eq!(
+            formatted,
+            "set the
+cell to 20
+and print
+it
+++++++++++
+++++++++++
+>> + <<
+[
+    . -
+]
+"
+        );
+        // Formatting is stable, which `bf fmt --check` relies on.
+        assert_eq!(format(formatted.as_bytes(), 10).unwrap(), formatted);
+    }
+}
@@ -0,0 +1,152 @@
+//! Superinstructions: instructions that often come one after another fused
+//! into one, so the interpreter dispatches fewer of them. Only the
+//! interpreter runs fused programs; the code generators get the plain IR.
+
+use crate::ir::{Offsets, Op};
+use std::fmt::Write;
+
+/// A program after `fuse`.
+pub struct Fused {
+    pub ops: Box<[Op]>,
+    /// Where each fused instruction starts in the source: the start of the
+    /// first instruction it replaces.
+    pub offsets: Option<Offsets>,
+    /// How many times each pattern was fused, most frequent first.
+    pub(crate) patterns: Vec<(&'static str, usize)>,
+}
+
+impl Fused {
+    /// Lists the patterns fused, for `--show-fusions`.
+    pub fn report(&self) -> String {
+        if self.patterns.is_empty() {
+            return "fused instructions: none\n".to_owned();
+        }
+        let mut out = "fused instructions:\n".to_owned();
+        for (pattern, count) in &self.patterns {
+            writeln!(out, "  {}: {}", pattern, count).unwrap();
+        }
+        out
+    }
+}
+
+/// Fuses the instructions of `ops` that have a superinstruction, longest
+/// pattern first, and moves the loop targets to match. `offsets` is the
+/// source offset of each instruction, if known.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "optimize",
+        level = "debug",
+        skip_all,
+        fields(ops = ops.len(), fused = tracing::field::Empty)
+    )
+)]
+pub fn fuse(ops: &[Op], offsets: Option<&[usize]>) -> Fused {
+    let mut fused: Vec<Op> = Vec::with_capacity(ops.len());
+    let mut fused_offsets = vec![];
+    let mut counts: Vec<(&'static str, usize)> = vec![];
+    // The index in `fused` of each instruction of `ops`.
+    let mut index = Vec::with_capacity(ops.len());
+
+    let mut i = 0;
+    while i < ops.len() {
+        let (op, len, pattern) = match ops[i..] {
+            [Op::Move(a), Op::Add(n), Op::Move(b), ..] if a == -b => {
+                (Op::AddAt(a, n), 3, Some("move+add+move"))
+            }
+            [Op::Set(a), Op::Add(b), ..] => (Op::Set(a.wrapping_add(b)), 2, Some("set+add")),
+            [Op::Add(n), Op::Move(a), ..] => (Op::AddMove(n, a), 2, Some("add+move")),
+            [Op::Move(a), Op::Add(n), ..] => (Op::MoveAdd(a, n), 2, Some("move+add")),
+            _ => (ops[i], 1, None),
+        };
+        if let Some(pattern) = pattern {
+            match counts.iter_mut().find(|(p, _)| *p == pattern) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((pattern, 1)),
+            }
+        }
+        index.extend(std::iter::repeat_n(fused.len(), len));
+        if let Some(offsets) = offsets {
+            fused_offsets.push(offsets[i]);
+        }
+        fused.push(op);
+        i += len;
+    }
+
+    // Jumps only ever go to instructions that weren't fused.
+    for op in &mut fused {
+        match op {
+            Op::LoopStart(target) | Op::LoopEnd(target) | Op::ProcStart(target) => {
+                *target = index[*target]
+            }
+            _ => {}
+        }
+    }
+    counts.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("fused", fused.len());
+    Fused {
+        ops: fused.into_boxed_slice(),
+        offsets: offsets.map(|_| fused_offsets.into_boxed_slice()),
+        patterns: counts,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::bf_interpreter::BfInterpreter;
+    use crate::ir;
+
+    #[test]
+    fn fuse_patterns() {
+        let (ops, offsets) = ir::compile_with_offsets(b"+[->>+<<]>[-]+>+.", false).unwrap();
+        let fused = fuse(&ops, Some(&offsets));
+        assert_eq!(
+            *fused.ops,
+            [
+                Op::Add(1),
+                Op::LoopStart(4),
+                Op::AddMove(u32::MAX, 2),
+                Op::AddMove(1, -2),
+                Op::LoopEnd(1),
+                Op::Move(1),
+                Op::Set(1),
+                Op::MoveAdd(1, 1),
+                Op::Output,
+            ]
+        );
+        assert_eq!(*fused.offsets.unwrap(), [0, 1, 2, 5, 8, 9, 10, 14, 16]);
+        assert_eq!(
+            fused.patterns,
+            [("add+move", 2), ("set+add", 1), ("move+add", 1)]
+        );
+
+        let fused = fuse(&ir::compile(b"[>+<-]").unwrap(), None);
+        assert_eq!(
+            *fused.ops,
+            [
+                Op::LoopStart(3),
+                Op::AddAt(1, 1),
+                Op::Add(u32::MAX),
+                Op::LoopEnd(0)
+            ]
+        );
+    }
+
+    #[test]
+    fn fused_programs_run_the_same() {
+        let run = |ops: Box<[Op]>| {
+            let mut bf = BfInterpreter::builder().build_ops(ops).unwrap();
+            let output = bf.run_to_end(b"").unwrap();
+            (output, bf.stats().steps)
+        };
+        let program = b"++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        let ops = ir::compile(program).unwrap();
+        let (plain, plain_steps) = run(ops.clone());
+        let (fused, fused_steps) = run(fuse(&ops, None).ops);
+        assert_eq!(fused, plain);
+        assert!(fused_steps < plain_steps);
+    }
+}
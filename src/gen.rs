@@ -0,0 +1,550 @@
+//! `bf gen`: generates Brainfuck programs from integer expressions, or at
+//! random for fuzzing, and ones that recreate a tape for `bf run
+//! --tape-program`. The code for an expression works like a stack machine:
+//! every number is pushed onto the cell after the ones in use, and every
+//! operator combines the two cells on top into the first one, so the result
+//! ends up in cell 0. Cells past the top are kept at 0 for the next push and
+//! for scratch space.
+
+use crate::config::CellSize;
+use crate::plugin::splitmix64;
+use crate::tape::Tape;
+use std::fmt::Write;
+
+/// Turns `n d 0 0 0` into `0 d-n%d n%d n/d 0`, starting and ending on `n`.
+/// `d` must not be 0.
+const DIVMOD: &str = "[->-[>+>>]>[+[-<+>]>+>>]<<<<<]";
+
+#[derive(Debug, PartialEq)]
+enum Expr {
+    Number(u64),
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, BinOp, Box<Expr>),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl BinOp {
+    fn name(self) -> &'static str {
+        match self {
+            BinOp::Add => "add",
+            BinOp::Sub => "subtract",
+            BinOp::Mul => "multiply",
+            BinOp::Div => "divide",
+            BinOp::Rem => "remainder",
+        }
+    }
+}
+
+/// Generates a program that computes `expression` into cell 0, with one line
+/// per step, commented with what it does. With `print`, the program then
+/// prints the result in decimal, followed by a newline. Arithmetic wraps
+/// around at `cell_size`, as it does when the program runs.
+pub fn expr(expression: &str, print: bool, cell_size: CellSize) -> Result<String, String> {
+    let expr = Parser::new(expression).parse()?;
+    // Dividing by 0 can't be done in Brainfuck, so it's caught here.
+    evaluate(&expr, cell_size)?;
+
+    let mut emitter = Emitter::new(cell_size);
+    emitter.expr(&expr, 0);
+    if print {
+        emitter.print_decimal();
+    }
+    Ok(emitter.out)
+}
+
+/// The value of `expr` with arithmetic wrapping around at `cell_size`, as
+/// the generated program computes it.
+fn evaluate(expr: &Expr, cell_size: CellSize) -> Result<u64, String> {
+    let max = cell_size.max() as u64;
+    Ok(match expr {
+        Expr::Number(n) => n & max,
+        Expr::Neg(e) => evaluate(e, cell_size)?.wrapping_neg() & max,
+        Expr::Binary(a, op, b) => {
+            let (a, b) = (evaluate(a, cell_size)?, evaluate(b, cell_size)?);
+            if b == 0 && matches!(op, BinOp::Div | BinOp::Rem) {
+                return Err("Division by zero".to_owned());
+            }
+            let value = match op {
+                BinOp::Add => a.wrapping_add(b),
+                BinOp::Sub => a.wrapping_sub(b),
+                BinOp::Mul => a.wrapping_mul(b),
+                BinOp::Div => a / b,
+                BinOp::Rem => a % b,
+            };
+            value & max
+        }
+    })
+}
+
+/// A recursive descent parser for the usual precedence: `*`, `/` and `%`
+/// bind tighter than `+` and `-`, and all of them are left-associative.
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        Parser { text, pos: 0 }
+    }
+
+    fn parse(mut self) -> Result<Expr, String> {
+        let expr = self.sum()?;
+        match self.peek() {
+            Some(c) => Err(self.unexpected(c)),
+            None => Ok(expr),
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut expr = self.product()?;
+        loop {
+            let op = match self.peek() {
+                Some('+') => BinOp::Add,
+                Some('-') => BinOp::Sub,
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some('*') => BinOp::Mul,
+                Some('/') => BinOp::Div,
+                Some('%') => BinOp::Rem,
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                Ok(Expr::Neg(Box::new(self.unary()?)))
+            }
+            Some('+') => {
+                self.pos += 1;
+                self.unary()
+            }
+            Some('(') => {
+                self.pos += 1;
+                let expr = self.sum()?;
+                match self.peek() {
+                    Some(')') => {
+                        self.pos += 1;
+                        Ok(expr)
+                    }
+                    Some(c) => Err(self.unexpected(c)),
+                    None => Err("Missing ')'".to_owned()),
+                }
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let digits = &self.text[self.pos..];
+                let len = digits
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(digits.len());
+                let number = digits[..len]
+                    .parse()
+                    .map_err(|_| format!("Number too large at column {}", self.pos + 1))?;
+                self.pos += len;
+                Ok(Expr::Number(number))
+            }
+            Some(c) => Err(self.unexpected(c)),
+            None => Err("Unexpected end of expression".to_owned()),
+        }
+    }
+
+    /// The next character that isn't whitespace, which `pos` is moved to.
+    fn peek(&mut self) -> Option<char> {
+        let rest = &self.text[self.pos..];
+        let trimmed = rest.trim_start();
+        self.pos += rest.len() - trimmed.len();
+        trimmed.chars().next()
+    }
+
+    fn unexpected(&self, c: char) -> String {
+        format!("Unexpected '{}' at column {}", c, self.pos + 1)
+    }
+}
+
+/// Writes Brainfuck, keeping track of where the data pointer is so code can
+/// be written in terms of cells. `mini` compiles with it too.
+pub(crate) struct Emitter {
+    out: String,
+    /// The code of the step being written, see `end_step`.
+    step: String,
+    pos: usize,
+    cell_size: CellSize,
+}
+
+impl Emitter {
+    pub(crate) fn new(cell_size: CellSize) -> Self {
+        Emitter {
+            out: String::new(),
+            step: String::new(),
+            pos: 0,
+            cell_size,
+        }
+    }
+
+    /// Writes the code that computes `expr` into `cell`, using the cells
+    /// after it, which must be 0 and are again afterwards. Every step leaves
+    /// the data pointer on its result, so the program ends on cell 0.
+    fn expr(&mut self, expr: &Expr, cell: usize) {
+        match expr {
+            Expr::Number(n) => {
+                self.number(cell, *n & self.cell_size.max() as u64);
+                self.end_step(&n.to_string());
+            }
+            Expr::Neg(e) => {
+                self.expr(e, cell);
+                self.move_to(cell, &[(cell + 1, false)]);
+                self.move_to(cell + 1, &[(cell, true)]);
+                self.goto(cell);
+                self.end_step("negate");
+            }
+            Expr::Binary(a, op, b) => {
+                self.expr(a, cell);
+                self.expr(b, cell + 1);
+                self.binary(*op, cell);
+                self.goto(cell);
+                self.end_step(op.name());
+            }
+        }
+    }
+
+    /// Combines `cell` and the one after it into `cell`.
+    fn binary(&mut self, op: BinOp, cell: usize) {
+        let b = cell + 1;
+        match op {
+            BinOp::Add => self.move_to(b, &[(cell, true)]),
+            BinOp::Sub => self.move_to(b, &[(cell, false)]),
+            BinOp::Mul => {
+                // Adds `b` to the product once for every unit of `a`,
+                // restoring it from a copy each time.
+                let (count, copy) = (cell + 2, cell + 3);
+                self.move_to(cell, &[(count, true)]);
+                self.goto(count);
+                self.step.push('[');
+                self.add(count, u32::MAX);
+                self.move_to(b, &[(cell, true), (copy, true)]);
+                self.move_to(copy, &[(b, true)]);
+                self.goto(count);
+                self.step.push(']');
+                self.clear(b);
+            }
+            BinOp::Div | BinOp::Rem => {
+                let (remainder, quotient) = (cell + 2, cell + 3);
+                self.goto(cell);
+                self.step.push_str(DIVMOD);
+                self.clear(b);
+                let (result, other) = if op == BinOp::Div {
+                    (quotient, remainder)
+                } else {
+                    (remainder, quotient)
+                };
+                self.clear(other);
+                self.move_to(result, &[(cell, true)]);
+            }
+        }
+    }
+
+    /// Prints cell 0 in decimal and a newline, using the cells after it.
+    /// Digits are split off with `DIVMOD` into a row of cells, each holding
+    /// its digit plus 1, with a 0 in cell 1 to stop at when printing them
+    /// back to front.
+    fn print_decimal(&mut self) {
+        self.move_to(0, &[(2, true), (3, true)]);
+        self.move_to(3, &[(0, true)]);
+        // Turns `n` into the digit `n % 10` plus 1 and moves on to the next
+        // cell, which gets `n / 10`, once 10 is in the cell after `n`.
+        let digit = format!("{}>[-]>[-<<+>>]<<+>>>[-<<+>>]<<", DIVMOD);
+        self.add(3, 10);
+        self.goto(2);
+        write!(self.step, "{}[>{}<{}]", digit, "+".repeat(10), digit).unwrap();
+        write!(self.step, "<[{}.[-]<]", "+".repeat(b'0' as usize - 1)).unwrap();
+        self.pos = 1;
+        self.add(1, 10);
+        self.step.push('.');
+        self.clear(1);
+        self.goto(0);
+        self.end_step("print");
+    }
+
+    /// Puts `n` in `cell`, with a multiplication loop in the cell after it
+    /// for all but the smallest numbers.
+    fn number(&mut self, cell: usize, n: u64) {
+        let factor = (n as f64).sqrt() as u64;
+        if n < 16 {
+            self.add(cell, n as u32);
+            return;
+        }
+        self.add(cell + 1, factor as u32);
+        self.goto(cell + 1);
+        self.step.push('[');
+        self.add(cell + 1, u32::MAX);
+        self.add(cell, (n / factor) as u32);
+        self.goto(cell + 1);
+        self.step.push(']');
+        self.add(cell, (n % factor) as u32);
+    }
+
+    /// Empties `from` into each of `to`, adding to the ones that are `true`
+    /// and subtracting from the others.
+    pub(crate) fn move_to(&mut self, from: usize, to: &[(usize, bool)]) {
+        self.goto(from);
+        self.step.push_str("[-");
+        for &(cell, add) in to {
+            self.add(cell, if add { 1 } else { u32::MAX });
+        }
+        self.goto(from);
+        self.step.push(']');
+    }
+
+    pub(crate) fn clear(&mut self, cell: usize) {
+        self.goto(cell);
+        self.step.push_str("[-]");
+    }
+
+    /// Adds `n`, wrapping around, with `-` if that's shorter.
+    pub(crate) fn add(&mut self, cell: usize, n: u32) {
+        self.goto(cell);
+        let n = n & self.cell_size.max();
+        if n <= self.cell_size.max() / 2 + 1 {
+            self.step.push_str(&"+".repeat(n as usize));
+        } else {
+            let n = self.cell_size.max() - n + 1;
+            self.step.push_str(&"-".repeat(n as usize));
+        }
+    }
+
+    pub(crate) fn goto(&mut self, cell: usize) {
+        if cell > self.pos {
+            self.step.push_str(&">".repeat(cell - self.pos));
+        } else {
+            self.step.push_str(&"<".repeat(self.pos - cell));
+        }
+        self.pos = cell;
+    }
+
+    /// Writes `code` as is, at the cell the data pointer is on.
+    pub(crate) fn push(&mut self, code: &str) {
+        self.step.push_str(code);
+    }
+
+    /// Writes the code of a step on a line of its own with `comment`, which
+    /// must not contain any commands.
+    fn end_step(&mut self, comment: &str) {
+        writeln!(self.out, "{} {}", self.step, comment).unwrap();
+        self.step.clear();
+    }
+
+    /// Everything written, without comments for the last step.
+    pub(crate) fn finish(mut self) -> String {
+        self.out.push_str(&self.step);
+        self.out
+    }
+}
+
+/// Generates a program that, run on a fresh tape, leaves it like `tape`: one
+/// line per cell that isn't 0, and the data pointer on `data_ptr`. The loop
+/// that puts a larger number in a cell counts down in the cell after it, which
+/// the program hasn't set yet, so only the last cell of the tape is set one
+/// `+` or `-` at a time.
+pub fn tape(tape: &Tape, data_ptr: usize) -> String {
+    let cell_size = tape.cell_size();
+    let max = cell_size.max() as u64;
+    let mut emitter = Emitter::new(cell_size);
+    for (i, value) in tape.nonzero() {
+        let value = value as u64;
+        if value.min(max - value + 1) < 16 || i + 1 == tape.len() {
+            emitter.add(i, value as u32);
+        } else {
+            emitter.number(i, value);
+        }
+        emitter.end_step(&format!("cell {}", i));
+    }
+    if emitter.pos != data_ptr {
+        emitter.goto(data_ptr);
+        emitter.end_step("data pointer");
+    }
+    emitter.out
+}
+
+/// Generates a program of `size` commands with balanced brackets, the same
+/// one for the same `seed`. `loops`, from 0 to 1, is the chance of each
+/// command being a bracket rather than one of `+-<>,.`. The programs are
+/// valid but can do anything else: move left of the tape, or never finish.
+pub fn random(size: usize, seed: u64, loops: f64) -> String {
+    let mut state = seed;
+    let mut chance = || (splitmix64(&mut state) >> 11) as f64 / (1u64 << 53) as f64;
+    let mut program = String::with_capacity(size);
+    let mut open = 0;
+    for i in 0..size {
+        let left = size - i;
+        // Loops still open take the rest of the program to close.
+        // A new loop needs room for its `]` too.
+        let can_open = open + 2 <= left;
+        if open == left {
+            program.push(']');
+            open -= 1;
+        } else if (open > 0 || can_open) && chance() < loops {
+            if open > 0 && (!can_open || chance() < 0.5) {
+                program.push(']');
+                open -= 1;
+            } else {
+                program.push('[');
+                open += 1;
+            }
+        } else {
+            let commands = b"+-<>,.";
+            let command = commands[(chance() * commands.len() as f64) as usize];
+            program.push(command as char);
+        }
+    }
+    program
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::bf_interpreter::{BfInterpreter, Ret};
+    use crate::config::Config;
+
+    /// Runs a generated program, returning what it printed and cell 0.
+    fn run(program: &str, cell_size: CellSize) -> (String, u32) {
+        let config = Config {
+            cell_size,
+            ..Config::default()
+        };
+        let mut bf = BfInterpreter::builder()
+            .config(config)
+            .build(program.as_bytes())
+            .unwrap();
+        let output = bf.run_to_end(b"").unwrap();
+        assert_eq!(bf.data_ptr(), 0);
+        (String::from_utf8(output).unwrap(), bf.tape().get(0))
+    }
+
+    #[test]
+    fn compute_expressions() {
+        for (expression, value) in [
+            ("3*7+2", 23),
+            ("2 + 3 * (4 - 1)", 11),
+            ("100 / 7", 14),
+            ("100 % 7", 2),
+            ("7 / 9 + 9 / 9 + 0 % 5", 1),
+            ("-(2 - 5)", 3),
+            ("2 - 5", 253),
+            ("17 * 16", 16),
+            ("((42))", 42),
+            ("0", 0),
+        ] {
+            let program = expr(expression, false, CellSize::Bits8).unwrap();
+            assert_eq!(run(&program, CellSize::Bits8), (String::new(), value));
+        }
+    }
+
+    #[test]
+    fn print_results() {
+        for (expression, cell_size, printed) in [
+            ("3*7+2", CellSize::Bits8, "23\n"),
+            ("0", CellSize::Bits8, "0\n"),
+            ("2-5", CellSize::Bits8, "253\n"),
+            ("1000 * 1000 + 7", CellSize::Bits32, "1000007\n"),
+            ("65535 + 1", CellSize::Bits16, "0\n"),
+        ] {
+            let program = expr(expression, true, cell_size).unwrap();
+            let (output, value) = run(&program, cell_size);
+            assert_eq!(output, printed);
+            assert_eq!(value.to_string() + "\n", printed);
+        }
+    }
+
+    #[test]
+    fn comment_every_step() {
+        assert_eq!(
+            expr("3*(7+2)", false, CellSize::Bits8).unwrap(),
+            "+++ 3\n\
+             >+++++++ 7\n\
+             >++ 2\n\
+             [-<+>]< add\n\
+             <[->>+<<]>>[-<[-<+>>>+<<]>>[-<<+>>]<]<[-]< multiply\n"
+        );
+    }
+
+    #[test]
+    fn reject_bad_expressions() {
+        for (expression, error) in [
+            ("", "Unexpected end of expression"),
+            ("3 +", "Unexpected end of expression"),
+            ("(1 + 2", "Missing ')'"),
+            ("1 2", "Unexpected '2' at column 3"),
+            ("2 ^ 3", "Unexpected '^' at column 3"),
+            ("4 / (2 - 2)", "Division by zero"),
+            ("5 % 256", "Division by zero"),
+            ("99999999999999999999", "Number too large at column 1"),
+        ] {
+            assert_eq!(expr(expression, false, CellSize::Bits8).unwrap_err(), error);
+        }
+    }
+
+    #[test]
+    fn generate_random_programs() {
+        for seed in 0..50 {
+            let program = random(seed as usize, seed, 0.3);
+            assert_eq!(program.len(), seed as usize);
+            crate::ir::compile(program.as_bytes()).unwrap();
+            assert_eq!(random(seed as usize, seed, 0.3), program);
+        }
+        assert_ne!(random(100, 1, 0.3), random(100, 2, 0.3));
+        assert!(!random(100, 1, 0.0).contains(['[', ']']));
+        let program = random(100, 1, 1.0);
+        assert!(program.chars().all(|c| c == '[' || c == ']'));
+        assert!(program.starts_with('['));
+    }
+
+    #[test]
+    fn recreate_tapes() {
+        for cell_size in [CellSize::Bits8, CellSize::Bits16, CellSize::Bits32] {
+            let max = cell_size.max();
+            let mut tape = Tape::new(cell_size, 10);
+            for (i, value) in [(0, 3), (1, 200), (2, max), (4, 1000), (7, max / 3), (9, 77)] {
+                tape.set(i, value);
+            }
+            let program = super::tape(&tape, 5);
+            let config = Config {
+                cell_size,
+                tape_size: 10,
+                ..Config::default()
+            };
+            let mut bf = BfInterpreter::builder()
+                .config(config)
+                .build(program.as_bytes())
+                .unwrap();
+            while bf.step().unwrap() != Ret::Finished {}
+            assert_eq!(bf.data_ptr(), 5);
+            assert_eq!(
+                bf.tape().nonzero().collect::<Vec<_>>(),
+                tape.nonzero().collect::<Vec<_>>()
+            );
+        }
+        assert_eq!(super::tape(&Tape::new(CellSize::Bits8, 10), 0), "");
+    }
+}
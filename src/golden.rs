@@ -0,0 +1,277 @@
+//! `bf test`: golden tests for Brainfuck programs. Every `name.b` with a
+//! `name.out` next to it is a test, run on `name.in` if there is one and on
+//! empty input otherwise, and it passes if it finishes printing exactly what
+//! `name.out` holds.
+
+use crate::bf_interpreter::BfInterpreter;
+use crate::cli::TestOptions;
+use crate::escape::Escaper;
+use crate::preprocess;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+
+/// Lines shown around each change in a diff.
+const CONTEXT: usize = 2;
+
+pub fn run(options: TestOptions) -> Result<(), String> {
+    let mut tests = vec![];
+    for path in &options.paths {
+        find_tests(Path::new(path), &mut tests)?;
+    }
+    if tests.is_empty() {
+        return Err("No tests found: a test is a .b file with a .out file next to it".to_owned());
+    }
+
+    let mut failed = 0;
+    for test in &tests {
+        match run_test(test, &options) {
+            Ok(()) => println!("PASS {}", test.display()),
+            Err(report) => {
+                println!("FAIL {}", test.display());
+                print!("{}", report);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{} of {} tests failed", failed, tests.len()));
+    }
+    println!("OK: {} passed", tests.len());
+    Ok(())
+}
+
+/// Adds the tests in `path`, a directory searched recursively or a `.b`
+/// file, to `tests` in order of their paths.
+fn find_tests(path: &Path, tests: &mut Vec<PathBuf>) -> Result<(), String> {
+    if !path.is_dir() {
+        if !path.exists() {
+            return Err(format!("{}: No such file or directory", path.display()));
+        }
+        if path.with_extension("out").is_file() {
+            tests.push(path.to_owned());
+        }
+        return Ok(());
+    }
+    let mut entries = std::fs::read_dir(path)
+        .and_then(|entries| {
+            entries
+                .map(|entry| Ok(entry?.path()))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() || entry.extension().is_some_and(|ext| ext == "b") {
+            find_tests(&entry, tests)?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs the test `path`, returning what went wrong, one indented line at a
+/// time, if it fails.
+fn run_test(path: &Path, options: &TestOptions) -> Result<(), String> {
+    let read =
+        |path: &Path| std::fs::read(path).map_err(|e| format!("  {}: {}\n", path.display(), e));
+    let expected = read(&path.with_extension("out"))?;
+    let input_path = path.with_extension("in");
+    let input = if input_path.is_file() {
+        read(&input_path)?
+    } else {
+        vec![]
+    };
+
+    let source = read(path)?;
+    let mut interpreter = preprocess::preprocess(&source, Some(path))
+        .and_then(|(program, _)| {
+            BfInterpreter::builder()
+                .config(options.config.clone())
+                .max_steps(options.max_steps)
+                .build(&program)
+        })
+        .map_err(|e| format!("  {}\n", e))?;
+    let mut output = vec![];
+    let result = crate::runner::run_interpreter(
+        &mut interpreter,
+        &mut Cursor::new(input),
+        &mut output,
+        options.timeout,
+        None,
+        None,
+        false,
+    );
+
+    let mut report = String::new();
+    if let Err(e) = result {
+        report += &format!("  The program stopped: {}\n", e);
+    }
+    if output != expected {
+        report += &diff(&expected, &output);
+    }
+    if report.is_empty() {
+        Ok(())
+    } else {
+        Err(report)
+    }
+}
+
+/// A line diff from the `expected` output to the `actual` one, with `-` for
+/// lines only expected, `+` for lines only printed and `CONTEXT` unchanged
+/// lines around them. Non-printable bytes are escaped.
+fn diff(expected: &[u8], actual: &[u8]) -> String {
+    let expected: Vec<&[u8]> = expected.split_inclusive(|&b| b == b'\n').collect();
+    let actual: Vec<&[u8]> = actual.split_inclusive(|&b| b == b'\n').collect();
+    let edits = edits(&expected, &actual);
+
+    let mut out = String::from("  --- expected\n  +++ actual\n");
+    let mut last = None;
+    for (i, &(sign, line)) in edits.iter().enumerate() {
+        let near_change = edits[i.saturating_sub(CONTEXT)..edits.len().min(i + CONTEXT + 1)]
+            .iter()
+            .any(|&(sign, _)| sign != ' ');
+        if !near_change {
+            continue;
+        }
+        if last.is_some_and(|last| last + 1 < i) {
+            out += "  ...\n";
+        }
+        last = Some(i);
+        let mut escaped = vec![];
+        Escaper::new(&mut escaped, false)
+            .write_all(line.strip_suffix(b"\n").unwrap_or(line))
+            .unwrap();
+        out += &format!("  {}{}\n", sign, String::from_utf8_lossy(&escaped));
+        if !line.ends_with(b"\n") {
+            out += "  \\ No newline at end\n";
+        }
+    }
+    out
+}
+
+/// The lines of `expected` and `actual` in order, marked with ` ` if they're
+/// in both, `-` if only in `expected` and `+` if only in `actual`, keeping
+/// as many in both as possible.
+fn edits<'a>(expected: &[&'a [u8]], actual: &[&'a [u8]]) -> Vec<(char, &'a [u8])> {
+    let prefix = expected
+        .iter()
+        .zip(actual)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = expected[prefix..]
+        .iter()
+        .rev()
+        .zip(actual[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (a, b) = (
+        &expected[prefix..expected.len() - suffix],
+        &actual[prefix..actual.len() - suffix],
+    );
+
+    let mut edits: Vec<_> = expected[..prefix].iter().map(|&line| (' ', line)).collect();
+    // The longest common subsequence of what's left, unless that would take
+    // too long, in which case all of it is shown as changed.
+    if a.len() * b.len() <= 1 << 22 {
+        // `common[i][j]` is the length of the longest one of `a[i..]` and
+        // `b[j..]`.
+        let mut common = vec![vec![0; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                common[i][j] = if a[i] == b[j] {
+                    common[i + 1][j + 1] + 1
+                } else {
+                    common[i + 1][j].max(common[i][j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            if i < a.len() && j < b.len() && a[i] == b[j] {
+                edits.push((' ', a[i]));
+                i += 1;
+                j += 1;
+            } else if j == b.len() || (i < a.len() && common[i + 1][j] >= common[i][j + 1]) {
+                edits.push(('-', a[i]));
+                i += 1;
+            } else {
+                edits.push(('+', b[j]));
+                j += 1;
+            }
+        }
+    } else {
+        edits.extend(a.iter().map(|&line| ('-', line)));
+        edits.extend(b.iter().map(|&line| ('+', line)));
+    }
+    edits.extend(
+        expected[expected.len() - suffix..]
+            .iter()
+            .map(|&line| (' ', line)),
+    );
+    edits
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn diff_lines() {
+        assert_eq!(
+            diff(b"a\nb\nc\nd\ne\nf\ng\n", b"a\nb\nc\nD\ne\nf\ng\n"),
+            "  --- expected\n  +++ actual\n   b\n   c\n  -d\n  +D\n   e\n   f\n"
+        );
+        assert_eq!(
+            diff(b"1\n2\n3\n4\n5\n6\n7\n", b"0\n1\n2\n3\n4\n5\n6\n"),
+            "  --- expected\n  +++ actual\n  +0\n   1\n   2\n  ...\n   5\n   6\n  -7\n"
+        );
+        assert_eq!(
+            diff(b"hi\n", b"hi\x07"),
+            "  --- expected\n  +++ actual\n  -hi\n  +hi\\x07\n  \\ No newline at end\n"
+        );
+    }
+
+    #[test]
+    fn run_tests() {
+        let dir = std::env::temp_dir().join(format!("bf-golden-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("more")).unwrap();
+        std::fs::write(dir.join("cat.b"), ",[.,]").unwrap();
+        std::fs::write(dir.join("cat.in"), "meow\n").unwrap();
+        std::fs::write(dir.join("cat.out"), "meow\n").unwrap();
+        std::fs::write(dir.join("lib.b"), "[-]").unwrap();
+        std::fs::write(dir.join("more/a.b"), "++++++++[>++++++++<-]>+.").unwrap();
+        std::fs::write(dir.join("more/a.out"), "B").unwrap();
+        std::fs::write(dir.join("more/loop.b"), "+[]").unwrap();
+        std::fs::write(dir.join("more/loop.out"), "").unwrap();
+
+        let mut tests = vec![];
+        find_tests(&dir, &mut tests).unwrap();
+        assert_eq!(
+            tests,
+            [
+                dir.join("cat.b"),
+                dir.join("more/a.b"),
+                dir.join("more/loop.b")
+            ]
+        );
+
+        let options = TestOptions {
+            paths: vec![],
+            max_steps: Some(1000),
+            timeout: None,
+            config: Config::default(),
+        };
+        assert_eq!(run_test(&tests[0], &options), Ok(()));
+        assert_eq!(
+            run_test(&tests[1], &options).unwrap_err(),
+            "  --- expected\n  +++ actual\n  -B\n  \\ No newline at end\n  +A\n  \\ No newline at end\n"
+        );
+        assert_eq!(
+            run_test(&tests[2], &options).unwrap_err(),
+            "  The program stopped: Step limit of 1000 exceeded\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
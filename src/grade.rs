@@ -0,0 +1,545 @@
+//! `bf grade`: runs every submission in a directory against the cases of a
+//! spec and reports, per student, which cases passed and what each run took.
+//!
+//! The spec is a TOML file with the limits and a `[[case]]` table for each
+//! case:
+//!
+//! ```toml
+//! max_steps = 1000000
+//! timeout = "2s"
+//!
+//! [[case]]
+//! name = "echo"
+//! input = "hello"
+//! output = "hello"
+//!
+//! [[case]]
+//! input_file = "cases/long.in"
+//! output_file = "cases/long.out"
+//! ```
+//!
+//! Only this subset of TOML is understood: strings and whole numbers, one
+//! key per line.
+
+use crate::bf_interpreter::BfInterpreter;
+use crate::cli::{self, GradeOptions};
+use crate::config::Config;
+use crate::dialect::parse_string;
+use crate::ir::{self, Op};
+use crate::json::Json;
+use crate::{fuse, preprocess};
+use std::fmt::Write;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// The step limit of a case if the spec doesn't set one.
+const DEFAULT_MAX_STEPS: u64 = 100_000_000;
+
+#[derive(Debug, PartialEq)]
+struct Spec {
+    max_steps: Option<u64>,
+    timeout: Option<Duration>,
+    cases: Vec<Case>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Case {
+    name: String,
+    input: Vec<u8>,
+    output: Vec<u8>,
+}
+
+/// How a submission did on one case.
+struct CaseResult {
+    name: String,
+    /// Why it failed: the run stopped or the output was wrong.
+    error: Option<String>,
+    steps: u64,
+    time: Duration,
+    /// The number of cells up to the rightmost one the program reached.
+    cells: usize,
+}
+
+struct Submission {
+    student: String,
+    /// The program, or why there isn't one to run, e.g. a directory with
+    /// several.
+    file: Result<PathBuf, String>,
+}
+
+struct Grade {
+    submission: Submission,
+    /// Why the program couldn't run, in which case no case passed.
+    error: Option<String>,
+    cases: Vec<CaseResult>,
+}
+
+pub fn run(options: GradeOptions) -> Result<(), String> {
+    let toml = String::from_utf8_lossy(&crate::runner::read_file(&options.spec)?).into_owned();
+    let dir = Path::new(&options.spec).parent().unwrap_or(Path::new(""));
+    let spec = parse_spec(&toml, dir).map_err(|e| format!("{}: {}", options.spec, e))?;
+
+    let grades: Vec<Grade> = find_submissions(Path::new(&options.submissions))?
+        .into_iter()
+        .map(|submission| grade(submission, &spec, &options.config))
+        .collect();
+    let report = if options.csv {
+        csv_report(&grades, spec.cases.len())
+    } else {
+        json_report(&grades, spec.cases.len()).to_string() + "\n"
+    };
+    match &options.output {
+        Some(path) => std::fs::write(path, report).map_err(|e| format!("{}: {}", path, e)),
+        None => {
+            print!("{}", report);
+            Ok(())
+        }
+    }
+}
+
+fn parse_spec(toml: &str, dir: &Path) -> Result<Spec, String> {
+    let mut spec = Spec {
+        max_steps: Some(DEFAULT_MAX_STEPS),
+        timeout: None,
+        cases: vec![],
+    };
+    // The fields of each case so far, with the line it starts on.
+    let mut cases: Vec<(usize, Vec<(String, String)>)> = vec![];
+    for (i, line) in toml.lines().enumerate() {
+        let error = |message: String| format!("line {}: {}", i + 1, message);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == "[[case]]" {
+            cases.push((i + 1, vec![]));
+            continue;
+        }
+        if line.starts_with('[') {
+            return Err(error(format!(
+                "unknown table '{}', expected [[case]]",
+                line
+            )));
+        }
+
+        let (key, rest) = line
+            .split_once('=')
+            .ok_or_else(|| error("expected '='".to_owned()))?;
+        let key = key.trim();
+        let rest = rest.trim_start();
+        let (value, rest) = if rest.starts_with(['"', '\'']) {
+            parse_string(rest).map_err(error)?
+        } else {
+            let end = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            if end == 0 {
+                return Err(error(format!("expected a value for '{}'", key)));
+            }
+            (rest[..end].to_owned(), &rest[end..])
+        };
+        let rest = rest.trim_start();
+        if !(rest.is_empty() || rest.starts_with('#')) {
+            return Err(error(format!("unexpected '{}'", rest)));
+        }
+
+        match (cases.last_mut(), key) {
+            (Some((_, fields)), "name" | "input" | "output" | "input_file" | "output_file") => {
+                fields.push((key.to_owned(), value))
+            }
+            (None, "max_steps") => {
+                spec.max_steps = Some(cli::parse_step_count(&value).map_err(error)?)
+            }
+            (None, "timeout") => spec.timeout = Some(cli::parse_duration(&value).map_err(error)?),
+            _ => return Err(error(format!("unknown key '{}'", key))),
+        }
+    }
+
+    for (n, (line, fields)) in cases.into_iter().enumerate() {
+        let error = |message: &str| format!("the case on line {} {}", line, message);
+        let field = |key: &str| {
+            fields
+                .iter()
+                .rev()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+        };
+        // The text itself, or the file it's in.
+        let text = |key: &str| match (field(key), field(&format!("{}_file", key))) {
+            (Some(_), Some(_)) => Err(error(&format!("has both {} and {}_file", key, key))),
+            (Some(text), None) => Ok(Some(text.as_bytes().to_vec())),
+            (None, Some(file)) => {
+                let path = dir.join(file);
+                std::fs::read(&path)
+                    .map(Some)
+                    .map_err(|e| format!("{}: {}", path.display(), e))
+            }
+            (None, None) => Ok(None),
+        };
+        spec.cases.push(Case {
+            name: field("name").map_or_else(|| format!("case {}", n + 1), str::to_owned),
+            input: text("input")?.unwrap_or_default(),
+            output: text("output")?.ok_or_else(|| error("has no output"))?,
+        });
+    }
+    if spec.cases.is_empty() {
+        return Err("The spec has no [[case]]".to_owned());
+    }
+    Ok(spec)
+}
+
+/// The submissions in `dir`, in order of the students' names: every `.b`
+/// file, named after the file, and every directory, named after the
+/// directory, that should hold a single `.b` file.
+fn find_submissions(dir: &Path) -> Result<Vec<Submission>, String> {
+    let entries = |dir: &Path| -> Result<Vec<PathBuf>, String> {
+        let mut entries = std::fs::read_dir(dir)
+            .and_then(|entries| {
+                entries
+                    .map(|entry| Ok(entry?.path()))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(|e| format!("{}: {}", dir.display(), e))?;
+        entries.sort();
+        Ok(entries)
+    };
+    let is_program = |path: &Path| path.is_file() && path.extension().is_some_and(|e| e == "b");
+    let name = |path: &Path| {
+        path.file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    };
+
+    let mut submissions = vec![];
+    for entry in entries(dir)? {
+        if is_program(&entry) {
+            submissions.push(Submission {
+                student: name(&entry),
+                file: Ok(entry),
+            });
+        } else if entry.is_dir() {
+            let mut programs: Vec<PathBuf> = entries(&entry)?
+                .into_iter()
+                .filter(|path| is_program(path))
+                .collect();
+            let file = match programs.len() {
+                0 => Err("No .b file".to_owned()),
+                1 => Ok(programs.remove(0)),
+                _ => Err(format!(
+                    "Several .b files: {}",
+                    programs
+                        .iter()
+                        .map(|path| path.file_name().unwrap_or_default().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )),
+            };
+            submissions.push(Submission {
+                student: entry
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned(),
+                file,
+            });
+        }
+    }
+    if submissions.is_empty() {
+        return Err(format!("{}: No submissions", dir.display()));
+    }
+    Ok(submissions)
+}
+
+fn grade(submission: Submission, spec: &Spec, config: &Config) -> Grade {
+    let ops = submission.file.clone().and_then(|path| {
+        let source = std::fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let (program, _) = preprocess::preprocess(&source, Some(&path))?;
+        Ok(fuse::fuse(&ir::compile(&program)?, None).ops)
+    });
+    match ops {
+        Ok(ops) => Grade {
+            submission,
+            error: None,
+            cases: spec
+                .cases
+                .iter()
+                .map(|case| run_case(&ops, case, spec, config))
+                .collect(),
+        },
+        Err(e) => Grade {
+            submission,
+            error: Some(e),
+            cases: vec![],
+        },
+    }
+}
+
+fn run_case(ops: &[Op], case: &Case, spec: &Spec, config: &Config) -> CaseResult {
+    let start = Instant::now();
+    let mut output = vec![];
+    let mut interpreter = match BfInterpreter::builder()
+        .config(config.clone())
+        .max_steps(spec.max_steps)
+        .build_ops(ops.into())
+    {
+        Ok(interpreter) => interpreter,
+        Err(e) => {
+            return CaseResult {
+                name: case.name.clone(),
+                error: Some(e),
+                steps: 0,
+                time: Duration::ZERO,
+                cells: 0,
+            }
+        }
+    };
+    let result = crate::runner::run_interpreter(
+        &mut interpreter,
+        &mut Cursor::new(&case.input),
+        &mut output,
+        spec.timeout,
+        None,
+        None,
+        false,
+    );
+    let error = match result {
+        Err(e) => Some(e),
+        Ok(()) if output != case.output => {
+            let byte = output
+                .iter()
+                .zip(&case.output)
+                .position(|(a, b)| a != b)
+                .unwrap_or(output.len().min(case.output.len()));
+            Some(format!("Wrong output at byte {}", byte))
+        }
+        Ok(()) => None,
+    };
+    CaseResult {
+        name: case.name.clone(),
+        error,
+        steps: interpreter.stats().steps,
+        time: start.elapsed(),
+        cells: interpreter.stats().peak_index + 1,
+    }
+}
+
+impl Grade {
+    fn passed(&self) -> usize {
+        self.cases
+            .iter()
+            .filter(|case| case.error.is_none())
+            .count()
+    }
+
+    /// Why the submission couldn't run at all, if it couldn't.
+    fn error(&self) -> Option<&str> {
+        match &self.submission.file {
+            Err(e) => Some(e),
+            Ok(_) => self.error.as_deref(),
+        }
+    }
+
+    fn file(&self) -> Option<String> {
+        let file = self.submission.file.as_ref().ok()?;
+        Some(file.display().to_string())
+    }
+}
+
+fn json_report(grades: &[Grade], total: usize) -> Json {
+    let grades = grades.iter().map(|grade| {
+        let cases = grade.cases.iter().map(|case| {
+            Json::object([
+                ("name", case.name.as_str().into()),
+                ("passed", case.error.is_none().into()),
+                ("error", case.error.clone().map_or(Json::Null, Json::from)),
+                ("steps", (case.steps as usize).into()),
+                ("time", Json::Number(case.time.as_secs_f64())),
+                ("cells", case.cells.into()),
+            ])
+        });
+        Json::object([
+            ("student", grade.submission.student.as_str().into()),
+            ("file", grade.file().map_or(Json::Null, Json::from)),
+            ("passed", grade.passed().into()),
+            ("total", total.into()),
+            ("error", grade.error().map_or(Json::Null, Json::from)),
+            ("cases", Json::Array(cases.collect())),
+        ])
+    });
+    Json::Array(grades.collect())
+}
+
+/// One line per student, with the cases failed by name and the steps and
+/// seconds taken by all of them.
+fn csv_report(grades: &[Grade], total: usize) -> String {
+    let mut out = String::from("student,file,passed,total,failed,steps,time,error\n");
+    for grade in grades {
+        let failed: Vec<&str> = grade
+            .cases
+            .iter()
+            .filter(|case| case.error.is_some())
+            .map(|case| case.name.as_str())
+            .collect();
+        let steps: u64 = grade.cases.iter().map(|case| case.steps).sum();
+        let time: Duration = grade.cases.iter().map(|case| case.time).sum();
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{:.3},{}",
+            csv_field(&grade.submission.student),
+            csv_field(&grade.file().unwrap_or_default()),
+            grade.passed(),
+            total,
+            csv_field(&failed.join(";")),
+            steps,
+            time.as_secs_f64(),
+            csv_field(grade.error().unwrap_or_default()),
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// `field` quoted if it needs to be.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parse_specs() {
+        let dir = std::env::temp_dir().join(format!("bf-grade-spec-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("long.out"), "b\n").unwrap();
+        let toml = "\
+# Limits
+max_steps = 500
+timeout = '2s'
+
+[[case]]
+name = \"echo\"
+input = \"a\"
+output = \"a\"  # the same
+
+[[case]]
+output_file = \"long.out\"
+";
+        assert_eq!(
+            parse_spec(toml, &dir).unwrap(),
+            Spec {
+                max_steps: Some(500),
+                timeout: Some(Duration::from_secs(2)),
+                cases: vec![
+                    Case {
+                        name: "echo".to_owned(),
+                        input: b"a".to_vec(),
+                        output: b"a".to_vec(),
+                    },
+                    Case {
+                        name: "case 2".to_owned(),
+                        input: vec![],
+                        output: b"b\n".to_vec(),
+                    },
+                ],
+            }
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        for (toml, error) in [
+            ("", "The spec has no [[case]]"),
+            (
+                "[case]",
+                "line 1: unknown table '[case]', expected [[case]]",
+            ),
+            ("[[case]]\ninput = \"\"", "the case on line 1 has no output"),
+            ("[[case]]\noutput = 3 4", "line 2: unexpected '4'"),
+            ("max_steps = \"many\"", "line 1: Invalid step count 'many'"),
+            ("[[case]]\nmax_steps = 3", "line 2: unknown key 'max_steps'"),
+            (
+                "[[case]]\noutput = \"\"\noutput_file = \"a\"",
+                "the case on line 1 has both output and output_file",
+            ),
+        ] {
+            assert_eq!(parse_spec(toml, Path::new("")).unwrap_err(), error);
+        }
+    }
+
+    #[test]
+    fn grade_submissions() {
+        let dir = std::env::temp_dir().join(format!("bf-grade-{}", std::process::id()));
+        for student in ["bob", "carol", "dave"] {
+            std::fs::create_dir_all(dir.join(student)).unwrap();
+        }
+        std::fs::write(dir.join("alice.b"), ",[.,]").unwrap();
+        std::fs::write(dir.join("bob/cat.b"), ",.").unwrap();
+        std::fs::write(dir.join("carol/a.b"), "").unwrap();
+        std::fs::write(dir.join("carol/b.b"), "").unwrap();
+        std::fs::write(dir.join("dave/x.b"), "+[").unwrap();
+        std::fs::write(dir.join("notes.txt"), "").unwrap();
+
+        let spec = Spec {
+            max_steps: Some(1000),
+            timeout: None,
+            cases: vec![
+                Case {
+                    name: "one".to_owned(),
+                    input: b"a".to_vec(),
+                    output: b"a".to_vec(),
+                },
+                Case {
+                    name: "two, more".to_owned(),
+                    input: b"ab".to_vec(),
+                    output: b"ab".to_vec(),
+                },
+            ],
+        };
+        let grades: Vec<Grade> = find_submissions(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|submission| grade(submission, &spec, &Config::default()))
+            .collect();
+        let students: Vec<&str> = grades
+            .iter()
+            .map(|grade| grade.submission.student.as_str())
+            .collect();
+        assert_eq!(students, ["alice", "bob", "carol", "dave"]);
+        assert_eq!(grades[0].passed(), 2);
+        assert_eq!(grades[0].cases[1].steps, 7);
+        assert_eq!(grades[1].passed(), 1);
+        assert_eq!(
+            grades[1].cases[1].error.as_deref(),
+            Some("Wrong output at byte 1")
+        );
+        assert_eq!(grades[2].error(), Some("Several .b files: a.b, b.b"));
+        assert_eq!(grades[3].error(), Some("Missing ']'"));
+
+        let csv = csv_report(&grades, 2);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[0],
+            "student,file,passed,total,failed,steps,time,error"
+        );
+        assert!(lines[2].starts_with(&format!(
+            "bob,{},1,2,\"two, more\",",
+            dir.join("bob/cat.b").display()
+        )));
+        assert!(lines[3].starts_with("carol,,0,2,,0,0.000,"));
+
+        let json = json_report(&grades, 2);
+        let bob = &json.as_array().unwrap()[1];
+        assert_eq!(bob.get("passed").and_then(Json::as_usize), Some(1));
+        let case = &bob.get("cases").and_then(Json::as_array).unwrap()[0];
+        assert_eq!(case.get("passed").and_then(Json::as_bool), Some(true));
+        assert_eq!(case.get("cells").and_then(Json::as_usize), Some(1));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+//! `bf graph`: the control flow of a program as a GraphViz graph. Straight
+//! runs of instructions are boxes, `[` and `]` are the branches between
+//! them and every loop is a cluster around its body.
+
+use crate::ir::Op;
+use crate::profile::{self, SourceMap};
+use std::fmt::Write;
+
+/// Longest code shown in a box before it's cut off.
+const MAX_LABEL: usize = 24;
+
+/// Formats the graph in the DOT language. With `counts` from a trace, each
+/// node says how often it ran and hotter nodes are shaded darker.
+pub fn graph(ops: &[Op], map: &SourceMap, counts: Option<&[u64]>) -> String {
+    let max = counts.map_or(0, |counts| counts.iter().copied().max().unwrap_or(0));
+    let node = |i: usize| {
+        if i == ops.len() {
+            "end".to_owned()
+        } else {
+            format!("n{}", i)
+        }
+    };
+
+    let mut out = String::new();
+    let mut edges = String::new();
+    let mut depth = 1;
+    let indent = |depth: usize| "    ".repeat(depth);
+    writeln!(out, "digraph program {{").unwrap();
+    writeln!(out, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+    writeln!(out, "    start [shape=point];").unwrap();
+    writeln!(out, "    end [shape=point];").unwrap();
+    writeln!(edges, "    start -> {};", node(0)).unwrap();
+
+    let mut i = 0;
+    while i < ops.len() {
+        let location = profile::location(Some(map), i);
+        let (label, shape, next) = match ops[i] {
+            Op::LoopStart(end) => {
+                writeln!(out, "{}subgraph cluster_{} {{", indent(depth), i).unwrap();
+                depth += 1;
+                writeln!(out, "{}label=\"loop at {}\";", indent(depth), location).unwrap();
+                writeln!(
+                    edges,
+                    "    {} -> {} [label=\"non-zero\"];",
+                    node(i),
+                    node(i + 1)
+                )
+                .unwrap();
+                writeln!(
+                    edges,
+                    "    {} -> {} [label=\"zero\"];",
+                    node(i),
+                    node(end + 1)
+                )
+                .unwrap();
+                ("[".to_owned(), "diamond", i + 1)
+            }
+            Op::LoopEnd(start) => {
+                writeln!(
+                    edges,
+                    "    {} -> {} [label=\"non-zero\"];",
+                    node(i),
+                    node(start + 1)
+                )
+                .unwrap();
+                writeln!(
+                    edges,
+                    "    {} -> {} [label=\"zero\"];",
+                    node(i),
+                    node(i + 1)
+                )
+                .unwrap();
+                ("]".to_owned(), "diamond", i + 1)
+            }
+            _ => {
+                let end = ops[i..]
+                    .iter()
+                    .position(|op| matches!(op, Op::LoopStart(_) | Op::LoopEnd(_)))
+                    .map_or(ops.len(), |n| i + n);
+                writeln!(edges, "    {} -> {};", node(i), node(end)).unwrap();
+                (code(ops, map, i, end), "box", end)
+            }
+        };
+
+        let mut attributes = format!("shape={}, label=\"{}\\n{}", shape, label, location);
+        if let Some(counts) = counts {
+            write!(attributes, "\\n{}x\"", counts[i]).unwrap();
+            // From white for instructions that never ran to red for the
+            // hottest ones.
+            let heat = if max == 0 {
+                0.0
+            } else {
+                counts[i] as f64 / max as f64
+            };
+            write!(
+                attributes,
+                ", style=filled, fillcolor=\"0.000 {:.3} 1.000\"",
+                heat
+            )
+            .unwrap();
+        } else {
+            attributes.push('"');
+        }
+        writeln!(out, "{}{} [{}];", indent(depth), node(i), attributes).unwrap();
+
+        if let Op::LoopEnd(_) = ops[i] {
+            depth -= 1;
+            writeln!(out, "{}}}", indent(depth)).unwrap();
+        }
+        i = next;
+    }
+
+    out.push_str(&edges);
+    out.push_str("}\n");
+    out
+}
+
+/// The commands of `ops[start..end]`, as they appear in the source.
+fn code(ops: &[Op], map: &SourceMap, start: usize, end: usize) -> String {
+    let to = map.offsets.get(end).copied().unwrap_or(map.source.len());
+    let mut code = map.source[map.offsets[start]..to]
+        .iter()
+        .filter(|b| b"+-<>.,[]#".contains(b))
+        .map(|&b| b as char)
+        .collect::<String>();
+    if ops[start..end].contains(&Op::Set(0)) {
+        // Clear loops would look like loops with their brackets.
+        code = code.replace("[-]", "0").replace("[+]", "0");
+    }
+    if code.chars().count() > MAX_LABEL {
+        code = code.chars().take(MAX_LABEL - 3).collect::<String>() + "...";
+    }
+    code
+}
+
+/// Counts how often each instruction ran from a `--trace` log.
+pub fn read_trace(trace: &str, len: usize) -> Result<Vec<u64>, String> {
+    let mut counts = vec![0; len];
+    for line in trace.lines() {
+        let Some(pc) = line
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix("pc="))
+        else {
+            continue;
+        };
+        match pc.parse::<usize>() {
+            Ok(pc) if pc < len => counts[pc] += 1,
+            _ => return Err("The trace was made of another program".to_owned()),
+        }
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::ir;
+
+    #[test]
+    fn graph_loops() {
+        let source = b"++[>[-]+<-]>.";
+        let (ops, offsets) = ir::compile_with_offsets(source, false).unwrap();
+        let map = SourceMap {
+            source,
+            offsets: &offsets,
+        };
+        assert_eq!(
+            graph(&ops, &map, None),
+            r#"digraph program {
+    node [shape=box, fontname="monospace"];
+    start [shape=point];
+    end [shape=point];
+    n0 [shape=box, label="++\n1:1"];
+    subgraph cluster_1 {
+        label="loop at 1:3";
+        n1 [shape=diamond, label="[\n1:3"];
+        n2 [shape=box, label=">0+<-\n1:4"];
+        n7 [shape=diamond, label="]\n1:11"];
+    }
+    n8 [shape=box, label=">.\n1:12"];
+    start -> n0;
+    n0 -> n1;
+    n1 -> n2 [label="non-zero"];
+    n1 -> n8 [label="zero"];
+    n2 -> n7;
+    n7 -> n2 [label="non-zero"];
+    n7 -> n8 [label="zero"];
+    n8 -> end;
+}
+"#
+        );
+    }
+
+    #[test]
+    fn hot_annotations() {
+        let trace = "         0  1:1         pc=0 ptr=0 cell=0  add 2
+         1  1:3         pc=1 ptr=0 cell=2  loop start
+         2  1:4         pc=2 ptr=0 cell=2  add -1
+         3  1:5         pc=3 ptr=0 cell=1  output
+         4  1:6         pc=4 ptr=0 cell=1  loop end
+         5  1:4         pc=2 ptr=0 cell=1  add -1
+trace limit reached
+";
+        let counts = read_trace(trace, 5).unwrap();
+        assert_eq!(counts, [1, 1, 2, 1, 1]);
+        assert_eq!(
+            read_trace(trace, 2).unwrap_err(),
+            "The trace was made of another program"
+        );
+
+        let source = b"++[-.]";
+        let (ops, offsets) = ir::compile_with_offsets(source, false).unwrap();
+        let map = SourceMap {
+            source,
+            offsets: &offsets,
+        };
+        let graph = graph(&ops, &map, Some(&counts));
+        assert!(graph.contains(
+            r#"n2 [shape=box, label="-.\n1:4\n2x", style=filled, fillcolor="0.000 1.000 1.000"];"#
+        ));
+        assert!(graph.contains(
+            r#"n4 [shape=diamond, label="]\n1:6\n1x", style=filled, fillcolor="0.000 0.500 1.000"];"#
+        ));
+    }
+}
@@ -0,0 +1,210 @@
+//! `--detect-hangs`: stops a program that is stuck in a loop.
+//!
+//! Every time a `]` is about to run, the state of the program is hashed:
+//! where it is, the data pointer, the whole tape and what the dialect adds
+//! to it, such as the pbrain procedures being called. If the same state
+//! comes back without any I/O in between, the program will do the same thing
+//! again forever. The hash of the tape is kept up to date one cell at a time
+//! as the program changes it, so this costs the same for any tape size. Two
+//! states having the same hash is possible, though very unlikely, so a hang
+//! is only reported as probable.
+
+use crate::bf_interpreter::BfInterpreter;
+use crate::ir::Op;
+use crate::profile::{self, SourceMap};
+use crate::tape::Tape;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+
+/// States remembered at most, to bound memory use on long runs without I/O.
+/// Loops that take longer than this to come back to a state are missed.
+const MAX_STATES: usize = 1 << 20;
+
+pub struct HangDetector<'a> {
+    seen: HashSet<u64>,
+    /// Hash of the tape, computed on the first check.
+    tape_hash: Option<u64>,
+    /// The cell the previous instruction could change and its value before.
+    last: Option<(usize, u32)>,
+    source: Option<SourceMap<'a>>,
+}
+
+impl<'a> HangDetector<'a> {
+    pub fn new(source: Option<SourceMap<'a>>) -> Self {
+        Self {
+            seen: HashSet::new(),
+            tape_hash: None,
+            last: None,
+            source,
+        }
+    }
+
+    /// Looks at the instruction `interpreter` is about to execute, which
+    /// must be the one after the instruction of the previous check. Fails if
+    /// it's a `]` that has seen the same state before.
+    pub(crate) fn check(&mut self, interpreter: &BfInterpreter) -> Result<(), String> {
+        let tape = interpreter.tape();
+        let ptr = interpreter.data_ptr();
+        let tape_hash = match (self.tape_hash, self.last) {
+            // Only that one cell can have changed.
+            (Some(hash), Some((i, old))) => hash
+                .wrapping_sub(cell_hash(i, old))
+                .wrapping_add(cell_hash(i, cell(tape, i))),
+            (Some(hash), None) => hash,
+            (None, _) => tape.nonzero().fold(0, |hash: u64, (i, value)| {
+                hash.wrapping_add(cell_hash(i, value))
+            }),
+        };
+        self.tape_hash = Some(tape_hash);
+        let op = interpreter.program().get(interpreter.pc());
+        // Fused instructions change a cell away from the data pointer.
+        let changed = match op {
+            Some(Op::MoveAdd(offset, _) | Op::AddAt(offset, _)) => interpreter.cell_index(*offset),
+            _ => Some(ptr),
+        };
+        self.last = changed.map(|i| (i, cell(tape, i)));
+
+        let start = match op {
+            Some(Op::LoopEnd(start)) => *start,
+            // I/O can change what happens next, and so can plugins.
+            Some(
+                Op::Input
+                | Op::Output
+                | Op::InputNumber
+                | Op::OutputNumber
+                | Op::Debug
+                | Op::Plugin(_),
+            ) => {
+                self.seen.clear();
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+
+        let mut hasher = DefaultHasher::new();
+        (
+            interpreter.pc(),
+            ptr,
+            tape_hash,
+            interpreter.storage(),
+            interpreter.call_stack(),
+        )
+            .hash(&mut hasher);
+        if self.seen.len() == MAX_STATES {
+            self.seen.clear();
+        }
+        if !self.seen.insert(hasher.finish()) {
+            return Err(format!(
+                "Probable infinite loop at {}",
+                profile::location(self.source.as_ref(), start)
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The value of cell `i`, which is 0 past the end of a tape yet to grow.
+fn cell(tape: &Tape, i: usize) -> u32 {
+    if i < tape.len() {
+        tape.get(i)
+    } else {
+        0
+    }
+}
+
+/// What cell `i` holding `value` adds to the hash of the tape. Cells that
+/// are 0 add nothing, so growing the tape doesn't change the hash.
+fn cell_hash(i: usize, value: u32) -> u64 {
+    if value == 0 {
+        return 0;
+    }
+    // splitmix64, to spread the bits of both over the whole hash.
+    let mut x = ((i as u64) << 32 | value as u64).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::bf_interpreter::Ret;
+    use crate::config::{Config, TapeMode};
+    use crate::fuse::fuse;
+    use crate::ir;
+
+    /// Runs `program` with `,` seeing the end of input, until it finishes
+    /// or fails.
+    fn run(program: &[u8]) -> Result<(), String> {
+        run_with(program, Config::default(), false)
+    }
+
+    /// Like `run`, with `config` and the instructions fused if `fused`.
+    fn run_with(program: &[u8], config: Config, fused: bool) -> Result<(), String> {
+        let (mut ops, mut offsets) = ir::compile_with_offsets(program, false).unwrap();
+        if fused {
+            let fused = fuse(&ops, Some(&offsets));
+            ops = fused.ops;
+            offsets = fused.offsets.unwrap();
+        }
+        let mut detector = HangDetector::new(Some(SourceMap {
+            source: program,
+            offsets: &offsets,
+        }));
+        let mut bf = BfInterpreter::builder()
+            .config(config)
+            .max_steps(Some(1_000_000))
+            .build_ops(ops)
+            .unwrap();
+        loop {
+            detector.check(&bf)?;
+            match bf.step()? {
+                Ret::Finished => return Ok(()),
+                Ret::Input => bf.set_eof(),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn detect_hangs() {
+        assert_eq!(
+            run(b"+\n+[>+<]").unwrap_err(),
+            "Probable infinite loop at 2:2"
+        );
+        assert_eq!(
+            run(b"++[>+[-]<]").unwrap_err(),
+            "Probable infinite loop at 1:3"
+        );
+        assert!(run(b"++[>+<-]").is_ok());
+        // The inner loops end in the same place with the same cells around
+        // them every time, but the outer loop makes progress further away.
+        let far = ">".repeat(30);
+        let near = "<".repeat(30);
+        let program = format!("{far}+++[{near}+[>+<-]>[-<+>]<[-]{far}-]");
+        assert!(run(program.as_bytes()).is_ok());
+        // A loop that keeps moving right never repeats a state.
+        assert_eq!(run(b"+[>+]").unwrap_err(), "Memory overflow");
+        // Neither does one that keeps printing.
+        assert_eq!(run(b"+[.]").unwrap_err(), "Step limit of 1000000 exceeded");
+    }
+
+    #[test]
+    fn detect_hangs_in_fused_programs() {
+        let wrap = Config {
+            tape_size: 4,
+            tape_mode: TapeMode::Wrap,
+            ..Config::default()
+        };
+        // `>>+` fuses into one instruction that changes a cell other than
+        // the one under the data pointer when the check runs.
+        assert!(run_with(b"+[>>+]>+.", wrap, true).is_ok());
+        // Once the cell two to the right wraps around, the state repeats.
+        assert_eq!(
+            run_with(b"+[>>+<<]", Config::default(), true).unwrap_err(),
+            "Probable infinite loop at 1:2"
+        );
+    }
+}
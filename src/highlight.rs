@@ -0,0 +1,60 @@
+//! ANSI colors for the REPL: Brainfuck commands by category, and program
+//! output set apart from what the user typed.
+
+use std::io::IsTerminal;
+
+pub(crate) const RESET: &str = "\x1b[0m";
+pub(crate) const OUTPUT: &str = "\x1b[1m";
+const MOVE: &str = "\x1b[36m";
+const ARITHMETIC: &str = "\x1b[33m";
+const IO: &str = "\x1b[32m";
+const LOOP: &str = "\x1b[35m";
+const COMMENT: &str = "\x1b[2m";
+
+/// Whether to color the REPL: only on a terminal, and never if `NO_COLOR` is
+/// set.
+pub(crate) fn enabled() -> bool {
+    std::io::stdin().is_terminal()
+        && std::io::stdout().is_terminal()
+        && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+}
+
+/// Colors each command of `line` by what it does; anything else is dimmed as
+/// a comment.
+pub(crate) fn highlight(line: &str) -> String {
+    let mut out = String::new();
+    let mut current = "";
+    for c in line.chars() {
+        let color = match c {
+            '<' | '>' => MOVE,
+            '+' | '-' => ARITHMETIC,
+            '.' | ',' => IO,
+            '[' | ']' => LOOP,
+            _ => COMMENT,
+        };
+        if color != current {
+            out.push_str(color);
+            current = color;
+        }
+        out.push(c);
+    }
+    if !current.is_empty() {
+        out.push_str(RESET);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn highlight_commands() {
+        assert_eq!(
+            highlight("++>[-] x"),
+            "\x1b[33m++\x1b[36m>\x1b[35m[\x1b[33m-\x1b[35m]\x1b[2m x\x1b[0m"
+        );
+        assert_eq!(highlight(""), "");
+    }
+}
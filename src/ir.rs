@@ -0,0 +1,374 @@
+use crate::parser::{
+    find_matching_parens, parse_extended, parse_program, token_offsets, Extended, Token,
+};
+use std::fmt;
+
+/// Optimized instruction stream shared by the interpreter and the code
+/// generators.
+///
+/// Runs of `+`/`-` and of `>` or `<` are folded into a single instruction,
+/// clear loops become `Set(0)`, and loop instructions store the index of
+/// their matching partner so backends never have to search for it. Values
+/// wrap modulo 2^32, which stays correct for any narrower cell width once
+/// truncated.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Op {
+    Add(u32),
+    Move(isize),
+    Set(u32),
+    Output,
+    Input,
+    LoopStart(usize),
+    LoopEnd(usize),
+    /// Show the interpreter state, see `--debug-char`. Code generators
+    /// ignore it.
+    Debug,
+    /// pbrain's `(`: defines a procedure numbered by the current cell, whose
+    /// body runs up to the `ProcEnd` at the index given. Only the interpreter
+    /// supports procedures.
+    ProcStart(usize),
+    /// pbrain's `)`: returns from the procedure.
+    ProcEnd,
+    /// pbrain's `:`: calls the procedure numbered by the current cell.
+    Call,
+    /// Brainfork's `Y`: starts a thread with a copy of the tape. Only the
+    /// interpreter supports threads.
+    Fork,
+    /// A command of Extended Brainfuck Type I. Only the interpreter supports
+    /// them.
+    Extended(Extended),
+    /// A command registered with `plugin::Registry`, which only the
+    /// interpreter can run.
+    Plugin(u8),
+    /// The decimal dialect's `:` and `;`: write or read the current cell as
+    /// a decimal number. Only the interpreter supports them.
+    OutputNumber,
+    InputNumber,
+    /// Superinstructions made by `fuse::fuse`: an add then a move, a move
+    /// then an add, and an add to the cell at an offset from the data
+    /// pointer, which stays where it is. Only the interpreter runs them.
+    AddMove(u32, isize),
+    MoveAdd(isize, u32),
+    AddAt(isize, u32),
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            // Shown signed, since `-` folds into a large addition.
+            Op::Add(n) => write!(f, "add {}", n as i32),
+            Op::Move(n) => write!(f, "move {}", n),
+            Op::Set(n) => write!(f, "set {}", n),
+            Op::Output => write!(f, "output"),
+            Op::Input => write!(f, "input"),
+            Op::LoopStart(_) => write!(f, "loop start"),
+            Op::LoopEnd(_) => write!(f, "loop end"),
+            Op::Debug => write!(f, "debug"),
+            Op::ProcStart(_) => write!(f, "procedure"),
+            Op::ProcEnd => write!(f, "return"),
+            Op::Call => write!(f, "call"),
+            Op::Fork => write!(f, "fork"),
+            Op::Extended(command) => write!(f, "{}", command.name()),
+            Op::Plugin(command) => write!(f, "plugin {}", command as char),
+            Op::OutputNumber => write!(f, "output number"),
+            Op::InputNumber => write!(f, "input number"),
+            Op::AddMove(n, m) => write!(f, "add {} move {}", n as i32, m),
+            Op::MoveAdd(m, n) => write!(f, "move {} add {}", m, n as i32),
+            Op::AddAt(offset, n) => write!(f, "add {} at {}", n as i32, offset),
+        }
+    }
+}
+
+/// Source byte offset of each instruction, see `compile_with_offsets`.
+pub(crate) type Offsets = Box<[usize]>;
+
+pub fn compile(program: &[u8]) -> Result<Box<[Op]>, String> {
+    compile_with_offsets(program, false).map(|(ops, _)| ops)
+}
+
+/// Like `compile`, but also returns the source byte offset each instruction
+/// starts at: the first command of a folded run, or the `[` of a clear loop.
+/// With `debug_char`, `#` compiles to `Op::Debug`.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "parse",
+        level = "debug",
+        skip_all,
+        fields(bytes = program.len(), ops = tracing::field::Empty)
+    )
+)]
+pub fn compile_with_offsets(
+    program: &[u8],
+    debug_char: bool,
+) -> Result<(Box<[Op]>, Offsets), String> {
+    let tokens = parse_program(program, debug_char);
+    // Validate brackets up front so the optimizer can assume balanced loops.
+    find_matching_parens(&tokens)?;
+    let (ops, offsets) = optimize(&tokens, &token_offsets(program, debug_char), false);
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("ops", ops.len());
+    Ok((ops, offsets))
+}
+
+/// Like `compile_with_offsets`, for a dialect with the `extensions` commands,
+/// see `parser::parse_extended`.
+pub fn compile_extended(
+    program: &[u8],
+    debug_char: bool,
+    extensions: &[(u8, Token)],
+) -> Result<(Box<[Op]>, Offsets), String> {
+    let (tokens, offsets) = parse_extended(program, debug_char, extensions);
+    find_matching_parens(&tokens)?;
+    Ok(optimize(&tokens, &offsets, false))
+}
+
+/// Like `compile_extended`, for `CellOverflow::Error`: only folds what can't
+/// hide an overflow. Runs of `+` and `-` are split where they change
+/// direction, so `-+` still underflows at 0, and `[-]` is the only loop that
+/// becomes a clear, as `[+]` overflows before it reaches zero.
+pub fn compile_strict(
+    program: &[u8],
+    debug_char: bool,
+    extensions: &[(u8, Token)],
+) -> Result<(Box<[Op]>, Offsets), String> {
+    let (tokens, offsets) = parse_extended(program, debug_char, extensions);
+    find_matching_parens(&tokens)?;
+    Ok(optimize(&tokens, &offsets, true))
+}
+
+fn optimize(tokens: &[Token], token_offsets: &[usize], strict: bool) -> (Box<[Op]>, Offsets) {
+    let mut ops = vec![];
+    let mut offsets = vec![];
+    let mut loop_starts = vec![];
+
+    for (token, offset) in tokens.iter().copied().zip(token_offsets.iter().copied()) {
+        use Token::*;
+        match token {
+            IncByte => push_add(&mut ops, 1, strict),
+            DecByte => push_add(&mut ops, u32::MAX, strict),
+            IncDataPtr => push_move(&mut ops, 1),
+            DecDataPtr => push_move(&mut ops, -1),
+            WriteByte => ops.push(Op::Output),
+            ReadByte => ops.push(Op::Input),
+            Debug => ops.push(Op::Debug),
+            BeginLoop | BeginProcedure => {
+                loop_starts.push(ops.len());
+                // Patched once the matching `]` or `)` is seen.
+                ops.push(if token == BeginLoop {
+                    Op::LoopStart(0)
+                } else {
+                    Op::ProcStart(0)
+                });
+            }
+            EndProcedure => {
+                let start = loop_starts.pop().unwrap();
+                ops[start] = Op::ProcStart(ops.len());
+                ops.push(Op::ProcEnd);
+            }
+            Call => ops.push(Op::Call),
+            Fork => ops.push(Op::Fork),
+            Extended(command) => ops.push(Op::Extended(command)),
+            Plugin(command) => ops.push(Op::Plugin(command)),
+            WriteNumber => ops.push(Op::OutputNumber),
+            ReadNumber => ops.push(Op::InputNumber),
+            EndLoop => {
+                let start = loop_starts.pop().unwrap();
+                match ops[start + 1..] {
+                    // Adding an odd value always reaches zero eventually, at
+                    // any cell width, so `[-]`, `[+]` and friends are just a
+                    // clear.
+                    [Op::Add(n)] if n % 2 == 1 && (!strict || n == u32::MAX) => {
+                        ops.truncate(start);
+                        ops.push(Op::Set(0));
+                    }
+                    _ => {
+                        ops[start] = Op::LoopStart(ops.len());
+                        ops.push(Op::LoopEnd(start));
+                    }
+                }
+            }
+        }
+
+        // Instructions only ever change at the end of the list: a new one
+        // starts at this token, and dropped ones take their offsets along.
+        offsets.truncate(ops.len());
+        offsets.resize(ops.len(), offset);
+    }
+
+    (ops.into_boxed_slice(), offsets.into_boxed_slice())
+}
+
+/// With `strict`, `n` (1 or -1) only folds into a run going the same way.
+fn push_add(ops: &mut Vec<Op>, n: u32, strict: bool) {
+    match ops.last_mut() {
+        Some(Op::Add(m)) if !strict || ((*m as i32) < 0) == ((n as i32) < 0) => {
+            *m = m.wrapping_add(n);
+            if *m == 0 {
+                ops.pop();
+            }
+        }
+        _ => ops.push(Op::Add(n)),
+    }
+}
+
+/// `n` (1 or -1) only folds into a run going the same way, so a move never
+/// passes an end of the tape it comes back from: `<>` at cell 0 still
+/// underflows.
+fn push_move(ops: &mut Vec<Op>, n: isize) {
+    match ops.last_mut() {
+        Some(Op::Move(m)) if (*m < 0) == (n < 0) => *m += n,
+        _ => ops.push(Op::Move(n)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parser::PBRAIN;
+
+    #[test]
+    fn fold_runs() {
+        let ops = compile(b"+++--->>><<+++++.>>>,<").unwrap();
+        assert_eq!(
+            &*ops,
+            [
+                Op::Move(3),
+                Op::Move(-2),
+                Op::Add(5),
+                Op::Output,
+                Op::Move(3),
+                Op::Input,
+                Op::Move(-1)
+            ]
+        );
+
+        // Moves that turn back don't cancel out, they may leave the tape.
+        let ops = compile(b"+-<>").unwrap();
+        assert_eq!(&*ops, [Op::Move(-1), Op::Move(1)]);
+    }
+
+    #[test]
+    fn clear_loops() {
+        for c in ["[-]", "[+]", "[---]"] {
+            assert_eq!(&*compile(c.as_bytes()).unwrap(), [Op::Set(0)]);
+        }
+
+        let ops = compile(b"[--]").unwrap();
+        assert_eq!(
+            &*ops,
+            [Op::LoopStart(2), Op::Add(u32::MAX - 1), Op::LoopEnd(0)]
+        );
+    }
+
+    #[test]
+    fn strict_folding() {
+        let (ops, _) = compile_strict(b"-+++-->>[-][+]", false, &[]).unwrap();
+        assert_eq!(
+            &*ops,
+            [
+                Op::Add(u32::MAX),
+                Op::Add(3),
+                Op::Add(u32::MAX - 1),
+                Op::Move(2),
+                Op::Set(0),
+                Op::LoopStart(7),
+                Op::Add(1),
+                Op::LoopEnd(5),
+            ]
+        );
+    }
+
+    #[test]
+    fn loop_targets() {
+        let ops = compile(b"+[>[-]<[>+<-]]").unwrap();
+        assert_eq!(
+            &*ops,
+            [
+                Op::Add(1),
+                Op::LoopStart(11),
+                Op::Move(1),
+                Op::Set(0),
+                Op::Move(-1),
+                Op::LoopStart(10),
+                Op::Move(1),
+                Op::Add(1),
+                Op::Move(-1),
+                Op::Add(u32::MAX),
+                Op::LoopEnd(5),
+                Op::LoopEnd(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn pbrain_procedures() {
+        let (ops, offsets) = compile_extended(b"+(-[>])>:", false, PBRAIN).unwrap();
+        assert_eq!(
+            &*ops,
+            [
+                Op::Add(1),
+                Op::ProcStart(6),
+                Op::Add(u32::MAX),
+                Op::LoopStart(5),
+                Op::Move(1),
+                Op::LoopEnd(3),
+                Op::ProcEnd,
+                Op::Move(1),
+                Op::Call,
+            ]
+        );
+        assert_eq!(&*offsets, [0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        // Plain Brainfuck ignores the pbrain commands.
+        assert_eq!(&*compile(b"(:)").unwrap(), []);
+
+        assert_eq!(
+            compile_extended(b"([)]", false, PBRAIN).unwrap_err(),
+            "Missing ']'"
+        );
+        assert_eq!(
+            compile_extended(b"[(])", false, PBRAIN).unwrap_err(),
+            "Missing ')'"
+        );
+        assert_eq!(
+            compile_extended(b")", false, PBRAIN).unwrap_err(),
+            "Missing '('"
+        );
+        assert_eq!(
+            compile_extended(b"((", false, PBRAIN).unwrap_err(),
+            "Missing ')'"
+        );
+    }
+
+    #[test]
+    fn source_offsets() {
+        let (ops, offsets) = compile_with_offsets(b"a ++-\n[-]>[<+-]", false).unwrap();
+        assert_eq!(
+            &*ops,
+            [
+                Op::Add(1),
+                Op::Set(0),
+                Op::Move(1),
+                Op::LoopStart(5),
+                Op::Move(-1),
+                Op::LoopEnd(3)
+            ]
+        );
+        assert_eq!(&*offsets, [2, 6, 9, 10, 11, 14]);
+    }
+
+    #[test]
+    fn debug_char() {
+        assert_eq!(&*compile(b"+#+").unwrap(), [Op::Add(2)]);
+        let (ops, offsets) = compile_with_offsets(b"+#+", true).unwrap();
+        assert_eq!(&*ops, [Op::Add(1), Op::Debug, Op::Add(1)]);
+        assert_eq!(&*offsets, [0, 1, 2]);
+    }
+
+    #[test]
+    fn handle_missing_brackets_error() {
+        assert_eq!(compile(b"[[]").unwrap_err(), "Missing ']'");
+        assert_eq!(compile(b"[]]").unwrap_err(), "Missing '['");
+    }
+}
@@ -0,0 +1,684 @@
+//! `bf kernel`: a Jupyter kernel. Every cell runs on the tape left by the
+//! previous ones, the same way lines do in `bf repl`, and what it prints is
+//! shown as text. A cell starting with `%%input` supplies the input for `,`.
+
+use crate::bf_interpreter::BfInterpreter;
+use crate::cli::KernelOptions;
+use crate::json::Json;
+use crate::repl::{self, Session};
+use crate::sha256;
+use crate::snapshot;
+use crate::zmtp;
+use std::cell::RefCell;
+use std::io::{Cursor, Write};
+use std::net::{TcpListener, TcpStream};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the Jupyter messaging protocol spoken.
+const PROTOCOL_VERSION: &str = "5.3";
+
+/// Separates the routing frames of a message from the rest.
+const DELIMITER: &[u8] = b"<IDS|MSG>";
+
+/// The sockets a kernel listens on, see the Jupyter messaging docs.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Channel {
+    Shell,
+    Control,
+    Stdin,
+}
+
+/// A message received on a channel, along with the connection to reply on.
+struct Incoming {
+    channel: Channel,
+    frames: Vec<Vec<u8>>,
+    connection: TcpStream,
+}
+
+pub fn run(options: KernelOptions) -> Result<(), String> {
+    let connection = crate::runner::read_file(&options.connection_file)?;
+    let connection = Json::parse(&String::from_utf8_lossy(&connection))
+        .map_err(|e| format!("{}: {}", options.connection_file, e))?;
+    let field = |name: &str| {
+        connection
+            .get(name)
+            .ok_or(format!("Missing {} in {}", name, options.connection_file))
+    };
+    let transport = field("transport")?.as_str().unwrap_or_default();
+    if transport != "tcp" {
+        return Err(format!("Unsupported transport '{}'", transport));
+    }
+    let key = field("key")?.as_str().unwrap_or_default().to_owned();
+    let scheme = field("signature_scheme")?.as_str().unwrap_or_default();
+    if !key.is_empty() && scheme != "hmac-sha256" {
+        return Err(format!("Unsupported signature scheme '{}'", scheme));
+    }
+    let ip = field("ip")?.as_str().unwrap_or_default();
+    let listen = |name: &str| {
+        let port = field(name)?
+            .as_usize()
+            .and_then(|port| u16::try_from(port).ok())
+            .ok_or(format!("Invalid {} in {}", name, options.connection_file))?;
+        TcpListener::bind((ip, port)).map_err(|e| format!("{}:{}: {}", ip, port, e))
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    serve(listen("shell_port")?, Channel::Shell, sender.clone());
+    serve(listen("control_port")?, Channel::Control, sender.clone());
+    serve(listen("stdin_port")?, Channel::Stdin, sender);
+    let subscribers = publish(listen("iopub_port")?);
+    heartbeat(listen("hb_port")?);
+
+    // Interrupting the kernel sends SIGINT, which stops the cell running.
+    snapshot::catch_interrupt();
+    let mut kernel = Kernel::new(&options, key.into_bytes())?;
+    for mut incoming in receiver {
+        let Some(request) = kernel.parse(&incoming.frames) else {
+            continue;
+        };
+        let mut sent = Ok(());
+        for reply in kernel.handle(incoming.channel, &request) {
+            sent = sent.and(match reply {
+                Reply::Response(message) => {
+                    zmtp::write_message(&mut incoming.connection, &kernel.frames(&message))
+                }
+                Reply::Publish(message) => {
+                    let frames = kernel.frames(&message);
+                    // Subscribers that went away are dropped.
+                    subscribers
+                        .lock()
+                        .unwrap()
+                        .retain_mut(|stream| zmtp::write_message(stream, &frames).is_ok());
+                    Ok(())
+                }
+            });
+        }
+        if let Err(e) = sent {
+            eprintln!("{}", e);
+        }
+        if kernel.shutdown {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Accepts connections to a `ROUTER` socket, passing their messages on.
+fn serve(listener: TcpListener, channel: Channel, sender: Sender<Incoming>) {
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let sender = sender.clone();
+            std::thread::spawn(move || -> Result<(), String> {
+                zmtp::handshake(&mut stream, "ROUTER")?;
+                while let Some(frames) = zmtp::read_message(&mut stream)? {
+                    let connection = stream.try_clone().map_err(|e| e.to_string())?;
+                    let incoming = Incoming {
+                        channel,
+                        frames,
+                        connection,
+                    };
+                    if sender.send(incoming).is_err() {
+                        break;
+                    }
+                }
+                Ok(())
+            });
+        }
+    });
+}
+
+/// Accepts subscribers to a `PUB` socket. Every message published goes to
+/// all of them, whatever they subscribed to.
+fn publish(listener: TcpListener) -> Arc<Mutex<Vec<TcpStream>>> {
+    let subscribers = Arc::new(Mutex::new(vec![]));
+    let accepted = subscribers.clone();
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let accepted = accepted.clone();
+            std::thread::spawn(move || -> Result<(), String> {
+                zmtp::handshake(&mut stream, "PUB")?;
+                let writer = stream.try_clone().map_err(|e| e.to_string())?;
+                accepted.lock().unwrap().push(writer);
+                // Subscriptions are read and ignored.
+                while zmtp::read_message(&mut stream)?.is_some() {}
+                Ok(())
+            });
+        }
+    });
+    subscribers
+}
+
+/// Answers heartbeats on a `REP` socket by sending them back, on threads of
+/// their own so that they keep coming while a cell runs.
+fn heartbeat(listener: TcpListener) {
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            std::thread::spawn(move || -> Result<(), String> {
+                zmtp::handshake(&mut stream, "REP")?;
+                while let Some(frames) = zmtp::read_message(&mut stream)? {
+                    zmtp::write_message(&mut stream, &frames)?;
+                }
+                Ok(())
+            });
+        }
+    });
+}
+
+/// A message of the Jupyter protocol.
+#[derive(Clone, Debug, PartialEq)]
+struct Message {
+    /// Routing frames before the delimiter, sent back with a reply. For a
+    /// published message, the topic.
+    ids: Vec<Vec<u8>>,
+    header: Json,
+    parent_header: Json,
+    metadata: Json,
+    content: Json,
+}
+
+impl Message {
+    fn msg_type(&self) -> &str {
+        self.header
+            .get("msg_type")
+            .and_then(Json::as_str)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    /// Sent back on the connection the request came from.
+    Response(Message),
+    /// Sent to every IOPub subscriber.
+    Publish(Message),
+}
+
+/// Collects what cells print.
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Kernel {
+    session: Session,
+    output: Output,
+    /// Signs messages. Empty if they aren't signed.
+    key: Vec<u8>,
+    /// Identifies this run of the kernel in headers.
+    session_id: String,
+    /// Messages sent so far, for unique message IDs.
+    sent: usize,
+    execution_count: usize,
+    shutdown: bool,
+}
+
+impl Kernel {
+    fn new(options: &KernelOptions, key: Vec<u8>) -> Result<Self, String> {
+        let interpreter = BfInterpreter::builder()
+            .config(options.config.clone())
+            .max_steps(options.max_steps)
+            .build(b"")?;
+        let output = Output::default();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Kernel {
+            session: Session::new(
+                interpreter,
+                false,
+                Box::new(Cursor::new(vec![])),
+                Box::new(output.clone()),
+            ),
+            output,
+            key,
+            session_id: format!("{:x}-{:x}", std::process::id(), now.as_nanos()),
+            sent: 0,
+            execution_count: 0,
+            shutdown: false,
+        })
+    }
+
+    fn sign(&self, parts: &[&[u8]]) -> String {
+        if self.key.is_empty() {
+            return String::new();
+        }
+        sha256::hex(&sha256::hmac(&self.key, parts))
+    }
+
+    /// Reads a message, checking its signature. Messages that aren't valid
+    /// are reported and dropped.
+    fn parse(&self, frames: &[Vec<u8>]) -> Option<Message> {
+        let result = (|| {
+            let delimiter = frames
+                .iter()
+                .position(|frame| frame == DELIMITER)
+                .ok_or("Missing delimiter")?;
+            let [signature, parts @ ..] = &frames[delimiter + 1..] else {
+                return Err("Missing signature".to_owned());
+            };
+            if parts.len() < 4 {
+                return Err("Missing message parts".to_owned());
+            }
+            let parts: Vec<&[u8]> = parts[..4].iter().map(Vec::as_slice).collect();
+            if !sha256::ct_eq(self.sign(&parts).as_bytes(), signature) {
+                return Err("Invalid signature".to_owned());
+            }
+            let json = |part: &[u8]| Json::parse(&String::from_utf8_lossy(part));
+            Ok(Message {
+                ids: frames[..delimiter].to_vec(),
+                header: json(parts[0])?,
+                parent_header: json(parts[1])?,
+                metadata: json(parts[2])?,
+                content: json(parts[3])?,
+            })
+        })();
+        result
+            .map_err(|e: String| eprintln!("Dropped a message: {}", e))
+            .ok()
+    }
+
+    /// The frames of a signed message.
+    fn frames(&self, message: &Message) -> Vec<Vec<u8>> {
+        let parts = [
+            &message.header,
+            &message.parent_header,
+            &message.metadata,
+            &message.content,
+        ]
+        .map(|json| json.to_string().into_bytes());
+        let signature = self.sign(&parts.each_ref().map(Vec::as_slice));
+        let mut frames = message.ids.clone();
+        frames.push(DELIMITER.to_vec());
+        frames.push(signature.into_bytes());
+        frames.extend(parts);
+        frames
+    }
+
+    /// A message of type `msg_type` in reply to, or caused by, `parent`.
+    fn message(&mut self, parent: &Message, msg_type: &str, content: Json) -> Message {
+        self.sent += 1;
+        Message {
+            ids: parent.ids.clone(),
+            header: Json::object([
+                (
+                    "msg_id",
+                    format!("{}-{}", self.session_id, self.sent).into(),
+                ),
+                ("session", self.session_id.clone().into()),
+                ("username", "bf".into()),
+                ("date", timestamp(SystemTime::now()).into()),
+                ("msg_type", msg_type.into()),
+                ("version", PROTOCOL_VERSION.into()),
+            ]),
+            parent_header: parent.header.clone(),
+            metadata: Json::object([]),
+            content,
+        }
+    }
+
+    fn publish(&mut self, parent: &Message, msg_type: &str, content: Json) -> Reply {
+        let mut message = self.message(parent, msg_type, content);
+        message.ids = vec![msg_type.as_bytes().to_vec()];
+        Reply::Publish(message)
+    }
+
+    fn status(&mut self, parent: &Message, state: &str) -> Reply {
+        self.publish(
+            parent,
+            "status",
+            Json::object([("execution_state", state.into())]),
+        )
+    }
+
+    /// Handles a request, returning the replies to it and what to publish
+    /// meanwhile.
+    fn handle(&mut self, channel: Channel, request: &Message) -> Vec<Reply> {
+        if channel == Channel::Stdin {
+            // Input comes from `%%input` cells, so nothing is ever asked for.
+            return vec![];
+        }
+        let mut replies = vec![self.status(request, "busy")];
+        let msg_type = request.msg_type().to_owned();
+        let content = match msg_type.as_str() {
+            "kernel_info_request" => Some(Json::object([
+                ("status", "ok".into()),
+                ("protocol_version", PROTOCOL_VERSION.into()),
+                ("implementation", "bf".into()),
+                ("implementation_version", env!("CARGO_PKG_VERSION").into()),
+                (
+                    "language_info",
+                    Json::object([
+                        ("name", "brainfuck".into()),
+                        ("version", "".into()),
+                        ("mimetype", "text/x-brainfuck".into()),
+                        ("file_extension", ".b".into()),
+                    ]),
+                ),
+                (
+                    "banner",
+                    "Brainfuck, with the commands of bf repl. A cell starting with %%input \
+                     gives the input for `,`."
+                        .into(),
+                ),
+                ("help_links", vec![].into()),
+            ])),
+            "execute_request" => Some(self.execute(request, &mut replies)),
+            "is_complete_request" => {
+                let code = request.content.get("code").and_then(Json::as_str);
+                let status = match code {
+                    Some(code) if repl::has_open_loop(code.as_bytes()) => "incomplete",
+                    _ => "complete",
+                };
+                let mut content = vec![("status", status.into())];
+                if status == "incomplete" {
+                    content.push(("indent", "".into()));
+                }
+                Some(Json::object(content))
+            }
+            "complete_request" => {
+                let cursor = request.content.get("cursor_pos").cloned();
+                let cursor = cursor.unwrap_or(Json::Null);
+                Some(Json::object([
+                    ("status", "ok".into()),
+                    ("matches", vec![].into()),
+                    ("cursor_start", cursor.clone()),
+                    ("cursor_end", cursor),
+                    ("metadata", Json::object([])),
+                ]))
+            }
+            "inspect_request" => Some(Json::object([
+                ("status", "ok".into()),
+                ("found", false.into()),
+                ("data", Json::object([])),
+                ("metadata", Json::object([])),
+            ])),
+            "history_request" => Some(Json::object([
+                ("status", "ok".into()),
+                ("history", vec![].into()),
+            ])),
+            "comm_info_request" => Some(Json::object([
+                ("status", "ok".into()),
+                ("comms", Json::object([])),
+            ])),
+            // A cell runs before the next request is read, so there's nothing
+            // left to interrupt by the time this arrives.
+            "interrupt_request" => Some(Json::object([("status", "ok".into())])),
+            "shutdown_request" => {
+                self.shutdown = true;
+                let restart = request.content.get("restart").cloned();
+                Some(Json::object([
+                    ("status", "ok".into()),
+                    ("restart", restart.unwrap_or(false.into())),
+                ]))
+            }
+            _ => {
+                eprintln!("Unsupported message type '{}'", msg_type);
+                None
+            }
+        };
+        if let Some(content) = content {
+            let reply_type = msg_type.replace("_request", "_reply");
+            let reply = self.message(request, &reply_type, content);
+            replies.push(Reply::Response(reply));
+        }
+        replies.push(self.status(request, "idle"));
+        replies
+    }
+
+    /// Runs a cell, publishing its code and output, and returns the content
+    /// of the reply.
+    fn execute(&mut self, request: &Message, replies: &mut Vec<Reply>) -> Json {
+        let code = request
+            .content
+            .get("code")
+            .and_then(Json::as_str)
+            .unwrap_or_default()
+            .to_owned();
+        let silent = request
+            .content
+            .get("silent")
+            .and_then(Json::as_bool)
+            .unwrap_or(false);
+        if !silent {
+            self.execution_count += 1;
+            let content = Json::object([
+                ("code", code.as_str().into()),
+                ("execution_count", self.execution_count.into()),
+            ]);
+            replies.push(self.publish(request, "execute_input", content));
+        }
+
+        snapshot::clear_interrupt();
+        let result = self.run_cell(&code);
+        let output = self.output.0.take();
+        if !output.is_empty() && !silent {
+            let content = Json::object([
+                ("name", "stdout".into()),
+                ("text", String::from_utf8_lossy(&output).into_owned().into()),
+            ]);
+            replies.push(self.publish(request, "stream", content));
+        }
+
+        let mut content = vec![("execution_count", self.execution_count.into())];
+        match result {
+            Ok(()) => {
+                content.push(("status", "ok".into()));
+                content.push(("payload", vec![].into()));
+                content.push(("user_expressions", Json::object([])));
+            }
+            Err(e) => {
+                let error = [
+                    ("ename", "Error".into()),
+                    ("evalue", e.as_str().into()),
+                    ("traceback", vec![e.as_str().into()].into()),
+                ];
+                if !silent {
+                    replies.push(self.publish(request, "error", Json::object(error.clone())));
+                }
+                content.push(("status", "error".into()));
+                content.extend(error);
+            }
+        }
+        Json::object(content)
+    }
+
+    /// Runs the lines of a cell the way `bf repl` runs lines, or with
+    /// `%%input` on the first line, makes the rest of the cell the input for
+    /// `,` from then on.
+    fn run_cell(&mut self, code: &str) -> Result<(), String> {
+        if let Some(input) = code.strip_prefix("%%input") {
+            let input = match input.split_once('\n') {
+                Some((rest, input)) if rest.trim().is_empty() => input,
+                None if input.trim().is_empty() => "",
+                _ => return Err("%%input takes no arguments".to_owned()),
+            };
+            self.session
+                .set_input(Box::new(Cursor::new(input.as_bytes().to_vec())));
+            return Ok(());
+        }
+
+        let mut pending = String::new();
+        for line in code.lines() {
+            self.session.feed_line(&mut pending, line)?;
+        }
+        if pending.is_empty() {
+            Ok(())
+        } else {
+            // Reports the `[` without a match.
+            self.session.run_line(pending.as_bytes())
+        }
+    }
+}
+
+/// `time` in ISO 8601, in UTC.
+fn timestamp(time: SystemTime) -> String {
+    let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = time.as_secs();
+    let (days, seconds) = (seconds / 86_400, seconds % 86_400);
+
+    // Converts days since 1970-01-01 to a date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        time.subsec_micros()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config::Config;
+    use std::time::Duration;
+
+    fn kernel() -> Kernel {
+        let options = KernelOptions {
+            connection_file: String::new(),
+            max_steps: Some(1000),
+            config: Config::default(),
+        };
+        Kernel::new(&options, b"secret".to_vec()).unwrap()
+    }
+
+    fn request(kernel: &mut Kernel, msg_type: &str, content: Json) -> Message {
+        let mut request = kernel.message(
+            &Message {
+                ids: vec![b"client".to_vec()],
+                header: Json::Null,
+                parent_header: Json::Null,
+                metadata: Json::Null,
+                content: Json::Null,
+            },
+            msg_type,
+            content,
+        );
+        request.parent_header = Json::object([]);
+        // Goes through the wire format, signature and all.
+        kernel.parse(&kernel.frames(&request)).unwrap()
+    }
+
+    /// Runs a cell and returns the types and contents of what was sent,
+    /// without the status messages.
+    fn execute(kernel: &mut Kernel, code: &str) -> Vec<(String, String)> {
+        let request = request(
+            kernel,
+            "execute_request",
+            Json::object([("code", code.into()), ("silent", false.into())]),
+        );
+        let replies = kernel.handle(Channel::Shell, &request);
+        let mut sent = vec![];
+        for reply in replies {
+            let (Reply::Response(message) | Reply::Publish(message)) = reply;
+            assert_eq!(message.parent_header, request.header);
+            if message.msg_type() != "status" {
+                sent.push((message.msg_type().to_owned(), message.content.to_string()));
+            }
+        }
+        sent
+    }
+
+    #[test]
+    fn run_cells() {
+        let mut kernel = kernel();
+        assert_eq!(
+            execute(&mut kernel, "%%input\nhi"),
+            [
+                (
+                    "execute_input".to_owned(),
+                    r#"{"code":"%%input\nhi","execution_count":1}"#.to_owned()
+                ),
+                (
+                    "execute_reply".to_owned(),
+                    r#"{"execution_count":1,"status":"ok","payload":[],"user_expressions":{}}"#
+                        .to_owned()
+                )
+            ]
+        );
+        let sent = execute(&mut kernel, ",+.>\n:ptr\n,[\n.,\n]");
+        assert_eq!(
+            sent[1],
+            (
+                "stream".to_owned(),
+                r#"{"name":"stdout","text":"idata pointer: 1\ni"}"#.to_owned()
+            )
+        );
+        let sent = execute(&mut kernel, "+[");
+        assert_eq!(
+            sent[1],
+            (
+                "error".to_owned(),
+                r#"{"ename":"Error","evalue":"Missing ']'","traceback":["Missing ']'"]}"#
+                    .to_owned()
+            )
+        );
+        assert!(sent[2]
+            .1
+            .contains(r#""execution_count":3,"status":"error""#));
+        assert!(execute(&mut kernel, "+[]")[2].1.contains("error"));
+    }
+
+    #[test]
+    fn reject_bad_signatures() {
+        let mut kernel = kernel();
+        let request = request(&mut kernel, "kernel_info_request", Json::object([]));
+        let mut frames = kernel.frames(&request);
+        assert_eq!(frames[0], b"client");
+        assert_eq!(frames[1], DELIMITER);
+        assert_eq!(kernel.parse(&frames), Some(request));
+        frames[3] = b"{}".to_vec();
+        assert_eq!(kernel.parse(&frames), None);
+    }
+
+    #[test]
+    fn shut_down() {
+        let mut kernel = kernel();
+        let request = request(
+            &mut kernel,
+            "shutdown_request",
+            Json::object([("restart", false.into())]),
+        );
+        let replies = kernel.handle(Channel::Control, &request);
+        assert!(kernel.shutdown);
+        let Reply::Response(reply) = &replies[1] else {
+            panic!("expected a response");
+        };
+        assert_eq!(reply.msg_type(), "shutdown_reply");
+        assert_eq!(reply.ids, [b"client"]);
+    }
+
+    #[test]
+    fn format_timestamps() {
+        let time = UNIX_EPOCH + Duration::from_micros(1_709_210_096_123_456);
+        assert_eq!(timestamp(time), "2024-02-29T12:34:56.123456Z");
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000000Z");
+    }
+}
@@ -0,0 +1,104 @@
+//! LEB128 variable-length integers, as used by WebAssembly and the `.bfc`
+//! bytecode format.
+
+pub(crate) fn write_unsigned(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+pub(crate) fn write_signed(out: &mut Vec<u8>, mut n: i64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        let done = (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Reads an unsigned integer starting at `*pos`, advancing `*pos` past it.
+pub(crate) fn read_unsigned(bytes: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut n = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *bytes.get(*pos)?;
+        *pos += 1;
+        if shift >= 64 {
+            return None;
+        }
+        n |= ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Some(n);
+        }
+    }
+}
+
+/// Reads a signed integer starting at `*pos`, advancing `*pos` past it.
+pub(crate) fn read_signed(bytes: &[u8], pos: &mut usize) -> Option<i64> {
+    let mut n = 0i64;
+    let mut shift = 0;
+    loop {
+        let byte = *bytes.get(*pos)?;
+        *pos += 1;
+        if shift >= 64 {
+            return None;
+        }
+        n |= ((byte & 0x7f) as i64) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            if shift < 64 && byte & 0x40 != 0 {
+                n |= -1 << shift;
+            }
+            return Some(n);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut out = vec![];
+        write_unsigned(&mut out, 624_485);
+        assert_eq!(out, [0xe5, 0x8e, 0x26]);
+        assert_eq!(read_unsigned(&out, &mut 0), Some(624_485));
+
+        let mut out = vec![];
+        write_signed(&mut out, -123_456);
+        assert_eq!(out, [0xc0, 0xbb, 0x78]);
+        assert_eq!(read_signed(&out, &mut 0), Some(-123_456));
+
+        let mut out = vec![];
+        write_signed(&mut out, 64);
+        assert_eq!(out, [0xc0, 0x00]);
+        assert_eq!(read_signed(&out, &mut 0), Some(64));
+
+        for n in [0, 1, -1, i64::MIN, i64::MAX] {
+            let mut out = vec![];
+            write_signed(&mut out, n);
+            let mut pos = 0;
+            assert_eq!(read_signed(&out, &mut pos), Some(n));
+            assert_eq!(pos, out.len());
+        }
+    }
+
+    #[test]
+    fn truncated_input() {
+        assert_eq!(read_unsigned(&[0x80, 0x80], &mut 0), None);
+        assert_eq!(read_signed(&[], &mut 0), None);
+    }
+}
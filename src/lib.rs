@@ -0,0 +1,61 @@
+//! The interpreter as a library, for embedding it in other programs, and the
+//! commands of the `bf` binary, which is a thin `main` over these modules.
+
+pub mod bench;
+pub mod bf_interpreter;
+pub mod bounds;
+pub mod bytecode;
+pub mod cli;
+pub mod codegen;
+pub mod config;
+pub mod dap;
+pub mod debugger;
+pub mod dialect;
+pub mod difftest;
+pub mod embed;
+pub mod escape;
+mod ffi;
+pub mod flush;
+pub mod formatter;
+pub mod fuse;
+pub mod gen;
+pub mod golden;
+pub mod grade;
+pub mod graph;
+pub mod hang;
+mod highlight;
+pub mod ir;
+mod json;
+pub mod kernel;
+mod leb128;
+pub mod lint;
+pub mod listing;
+pub mod lsp;
+pub mod mini;
+mod mmap;
+pub mod native;
+pub mod numeric;
+mod parser;
+pub mod pgo;
+pub mod plugin;
+pub mod preprocess;
+pub mod profile;
+#[cfg(feature = "python")]
+mod python;
+pub mod raw_input;
+pub mod record;
+pub mod repl;
+pub mod runner;
+pub mod serve;
+mod sha256;
+pub mod snapshot;
+pub mod stats;
+mod tape;
+pub mod temp_dir;
+pub mod threads;
+pub mod trace;
+pub mod verify;
+pub mod visualize;
+#[cfg(feature = "wasm")]
+mod wasm;
+mod zmtp;
@@ -0,0 +1,298 @@
+//! `bf lint`: finds loops that are probably mistakes, without running the
+//! program. Cell values are only followed as far as they are certain, so
+//! every warning holds for any input.
+
+use crate::bounds;
+use crate::ir;
+use crate::parser::{find_matching_parens, parse_program, token_offsets, Token};
+use std::collections::HashMap;
+
+pub const UNDERFLOW: &str =
+    "this moves the data pointer off the left end of the tape whenever it runs";
+
+#[derive(Debug, PartialEq)]
+pub struct Warning {
+    /// Byte offset in the program.
+    pub offset: usize,
+    pub message: String,
+}
+
+/// What is known about a cell.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Value {
+    /// Only kept while small enough that it's the same for every cell size.
+    Exact(i64),
+    NonZero,
+    Unknown,
+}
+
+/// The cells around the data pointer, relative to where the analysis of a
+/// block started.
+struct State {
+    ptr: isize,
+    cells: HashMap<isize, Value>,
+    /// Whether the cells not in `cells` are still 0.
+    rest_zero: bool,
+}
+
+impl State {
+    fn with(value: Value, rest_zero: bool) -> Self {
+        Self {
+            ptr: 0,
+            cells: HashMap::from([(0, value)]),
+            rest_zero,
+        }
+    }
+
+    fn get(&self) -> Value {
+        match self.cells.get(&self.ptr) {
+            Some(&value) => value,
+            None if self.rest_zero => Value::Exact(0),
+            None => Value::Unknown,
+        }
+    }
+
+    fn set(&mut self, value: Value) {
+        self.cells.insert(self.ptr, value);
+    }
+
+    fn add(&mut self, n: i64) {
+        let value = match self.get() {
+            Value::Exact(v) if (v + n).abs() < 256 => Value::Exact(v + n),
+            _ => Value::Unknown,
+        };
+        self.set(value);
+    }
+}
+
+pub fn lint(program: &[u8]) -> Result<Vec<Warning>, String> {
+    let tokens = parse_program(program, false);
+    let mut linter = Linter {
+        matching: find_matching_parens(&tokens)?,
+        tokens,
+        warnings: vec![],
+    };
+
+    linter.block(
+        0,
+        linter.tokens.len(),
+        &mut State::with(Value::Exact(0), true),
+    );
+    linter.drift();
+
+    let offsets = token_offsets(program, false);
+    let mut warnings = linter
+        .warnings
+        .into_iter()
+        .map(|(i, message)| Warning {
+            offset: offsets[i],
+            message: message.to_owned(),
+        })
+        .collect::<Vec<_>>();
+    let (ops, offsets) = ir::compile_with_offsets(program, false)?;
+    if let Some(i) = bounds::underflow(&ops) {
+        warnings.push(Warning {
+            offset: offsets[i],
+            message: UNDERFLOW.to_owned(),
+        });
+    }
+    warnings.sort_by_key(|warning| warning.offset);
+    Ok(warnings)
+}
+
+struct Linter {
+    tokens: Box<[Token]>,
+    matching: HashMap<usize, usize>,
+    /// Token indices and messages.
+    warnings: Vec<(usize, &'static str)>,
+}
+
+impl Linter {
+    /// Follows the tokens `start..end` from `state`. Returns false if they
+    /// never finish.
+    fn block(&mut self, start: usize, end: usize, state: &mut State) -> bool {
+        let mut i = start;
+        while i < end {
+            match self.tokens[i] {
+                Token::IncByte => state.add(1),
+                Token::DecByte => state.add(-1),
+                Token::IncDataPtr => state.ptr += 1,
+                Token::DecDataPtr => state.ptr -= 1,
+                Token::ReadByte => state.set(Value::Unknown),
+                Token::WriteByte | Token::Debug | Token::EndLoop => {}
+                // Only programs with extensions have these, and they aren't linted.
+                Token::BeginProcedure
+                | Token::EndProcedure
+                | Token::Call
+                | Token::Fork
+                | Token::Extended(_)
+                | Token::Plugin(_)
+                | Token::WriteNumber
+                | Token::ReadNumber => {
+                    unreachable!("extension in a Brainfuck program")
+                }
+                Token::BeginLoop => {
+                    let close = self.matching[&i];
+                    let value = state.get();
+                    let ends = match value {
+                        Value::Exact(0) => {
+                            self.warnings
+                                .push((i, "this loop never runs, the cell is always 0 here"));
+                            i = close + 1;
+                            continue;
+                        }
+                        _ if close == i + 1 && value == Value::Unknown => {
+                            self.warnings
+                                .push((i, "this loop never ends if the cell isn't 0 here"));
+                            true
+                        }
+                        _ if close == i + 1 => {
+                            self.warnings
+                                .push((i, "this loop never ends, the cell is never 0 here"));
+                            false
+                        }
+                        _ => self.block(i + 1, close, &mut State::with(Value::NonZero, false)),
+                    };
+                    if !ends {
+                        // Reported once, for the innermost loop.
+                        if self
+                            .tokens
+                            .get(close + 1)
+                            .is_some_and(|&next| next != Token::EndLoop)
+                        {
+                            self.warnings.push((
+                                close + 1,
+                                "this code never runs, the loop before it never ends",
+                            ));
+                        }
+                        return false;
+                    }
+                    // Only the cell the loop ended on is known.
+                    *state = State::with(Value::Exact(0), false);
+                    i = close;
+                }
+            }
+            i += 1;
+        }
+        true
+    }
+
+    /// Reports innermost loops that don't return the data pointer to where
+    /// they started. Loops like `[>]` that only move are searches for a zero
+    /// cell, and loops with nested loops usually walk over an array on
+    /// purpose, so both are left alone.
+    fn drift(&mut self) {
+        for (open, &close) in &self.matching {
+            // `matching` maps both ways.
+            if *open > close {
+                continue;
+            }
+            let body = &self.tokens[open + 1..close];
+            if body.contains(&Token::BeginLoop) {
+                continue;
+            }
+            let moved = body
+                .iter()
+                .map(|&token| match token {
+                    Token::IncDataPtr => 1,
+                    Token::DecDataPtr => -1,
+                    _ => 0,
+                })
+                .sum::<isize>();
+            let only_moves = body
+                .iter()
+                .all(|&token| matches!(token, Token::IncDataPtr | Token::DecDataPtr));
+            if moved != 0 && !only_moves {
+                self.warnings.push((
+                    *open,
+                    "this loop moves the data pointer on every iteration, a `<` or `>` may be missing",
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn messages(program: &[u8]) -> Vec<(usize, String)> {
+        lint(program)
+            .unwrap()
+            .into_iter()
+            .map(|warning| (warning.offset, warning.message))
+            .collect()
+    }
+
+    #[test]
+    fn dead_and_infinite_loops() {
+        assert_eq!(
+            messages(b"[comment.] +[-][-] +[] >"),
+            [
+                (
+                    0,
+                    "this loop never runs, the cell is always 0 here".to_owned()
+                ),
+                (
+                    15,
+                    "this loop never runs, the cell is always 0 here".to_owned()
+                ),
+                (
+                    20,
+                    "this loop never ends, the cell is never 0 here".to_owned()
+                ),
+                (
+                    23,
+                    "this code never runs, the loop before it never ends".to_owned()
+                ),
+            ]
+        );
+        assert_eq!(
+            messages(b",[] +[[-]]"),
+            [(
+                1,
+                "this loop never ends if the cell isn't 0 here".to_owned()
+            )]
+        );
+        assert_eq!(
+            messages(b",[-[]]."),
+            [(
+                3,
+                "this loop never ends if the cell isn't 0 here".to_owned()
+            )]
+        );
+        assert_eq!(
+            messages(b",[[]]."),
+            [
+                (
+                    2,
+                    "this loop never ends, the cell is never 0 here".to_owned()
+                ),
+                (
+                    5,
+                    "this code never runs, the loop before it never ends".to_owned()
+                ),
+            ]
+        );
+        assert!(messages(b"++[>+<-]>.").is_empty());
+    }
+
+    #[test]
+    fn pointer_drift() {
+        assert_eq!(
+            messages(b",[>+<->] ,[>] ,[[>]<-] ,[[->+<]>]"),
+            [(
+                1,
+                "this loop moves the data pointer on every iteration, a `<` or `>` may be missing"
+                    .to_owned()
+            )]
+        );
+        assert_eq!(lint(b"[").unwrap_err(), "Missing ']'");
+    }
+
+    #[test]
+    fn underflow() {
+        assert_eq!(messages(b"+>-<<"), [(3, UNDERFLOW.to_owned())]);
+    }
+}
@@ -0,0 +1,190 @@
+//! `bf parse`: the commands or the optimized instructions of a program with
+//! their source positions, as text or as JSON for other tools.
+
+use crate::ir::{self, Op};
+use crate::json::Json;
+use crate::parser::{find_matching_parens, parse_program, token_offsets, Token};
+use crate::profile;
+use std::fmt::Write;
+
+/// One command or instruction.
+struct Entry {
+    /// The command character, or the instruction name.
+    name: String,
+    /// The amount of an `add`, `move` or `set`.
+    value: Option<i64>,
+    /// Index of the matching `[` or `]`.
+    matching: Option<usize>,
+    offset: usize,
+}
+
+/// Lists the commands of `program`, or with `ir` its optimized
+/// instructions.
+pub fn list(program: &[u8], ir: bool, debug_char: bool, json: bool) -> Result<String, String> {
+    let entries = if ir {
+        instructions(program, debug_char)?
+    } else {
+        commands(program, debug_char)?
+    };
+
+    if json {
+        let items = entries
+            .iter()
+            .map(|entry| {
+                let (line, column) = profile::position(program, entry.offset);
+                let mut fields = vec![(
+                    if ir { "op" } else { "command" },
+                    Json::from(entry.name.as_str()),
+                )];
+                if let Some(value) = entry.value {
+                    fields.push(("value", Json::Number(value as f64)));
+                }
+                if let Some(matching) = entry.matching {
+                    fields.push(("match", Json::from(matching)));
+                }
+                fields.push(("offset", Json::from(entry.offset)));
+                fields.push(("line", Json::from(line)));
+                fields.push(("column", Json::from(column)));
+                Json::object(fields)
+            })
+            .collect::<Vec<_>>();
+        let kind = if ir { "ir" } else { "tokens" };
+        return Ok(format!(
+            "{}\n",
+            Json::object([("kind", Json::from(kind)), ("items", Json::from(items))])
+        ));
+    }
+
+    let mut out = String::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let (line, column) = profile::position(program, entry.offset);
+        write!(
+            out,
+            "{:>6}  {:<10}  {}",
+            i,
+            format!("{}:{}", line, column),
+            entry.name
+        )
+        .unwrap();
+        if let Some(value) = entry.value {
+            write!(out, " {}", value).unwrap();
+        }
+        if let Some(matching) = entry.matching {
+            write!(out, " (matches {})", matching).unwrap();
+        }
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+fn commands(program: &[u8], debug_char: bool) -> Result<Vec<Entry>, String> {
+    let tokens = parse_program(program, debug_char);
+    let matching = find_matching_parens(&tokens)?;
+    let offsets = token_offsets(program, debug_char);
+    Ok(tokens
+        .iter()
+        .enumerate()
+        .map(|(i, token)| Entry {
+            name: match token {
+                Token::IncDataPtr => ">",
+                Token::DecDataPtr => "<",
+                Token::IncByte => "+",
+                Token::DecByte => "-",
+                Token::WriteByte => ".",
+                Token::ReadByte => ",",
+                Token::BeginLoop => "[",
+                Token::EndLoop => "]",
+                Token::Debug => "#",
+                Token::BeginProcedure => "(",
+                Token::EndProcedure => ")",
+                Token::Call => ":",
+                Token::Fork => "Y",
+                Token::Extended(command) => command.command(),
+                Token::Plugin(_) => unreachable!("plugins in a Brainfuck program"),
+                Token::WriteNumber => ":",
+                Token::ReadNumber => ";",
+            }
+            .to_owned(),
+            value: None,
+            matching: matching.get(&i).copied(),
+            offset: offsets[i],
+        })
+        .collect())
+}
+
+fn instructions(program: &[u8], debug_char: bool) -> Result<Vec<Entry>, String> {
+    let (ops, offsets) = ir::compile_with_offsets(program, debug_char)?;
+    Ok(ops
+        .iter()
+        .zip(offsets.iter())
+        .map(|(&op, &offset)| {
+            let (name, value, matching) = match op {
+                // Signed, like in the `--trace` log.
+                Op::Add(n) => ("add", Some(n as i32 as i64), None),
+                Op::Move(n) => ("move", Some(n as i64), None),
+                Op::Set(n) => ("set", Some(n as i64), None),
+                Op::Output => ("output", None, None),
+                Op::Input => ("input", None, None),
+                Op::LoopStart(end) => ("loop_start", None, Some(end)),
+                Op::LoopEnd(start) => ("loop_end", None, Some(start)),
+                Op::Debug => ("debug", None, None),
+                Op::ProcStart(end) => ("procedure", None, Some(end)),
+                Op::ProcEnd => ("return", None, None),
+                Op::Call => ("call", None, None),
+                Op::Fork => ("fork", None, None),
+                Op::Extended(command) => (command.name(), None, None),
+                Op::Plugin(_) => ("plugin", None, None),
+                Op::OutputNumber => ("output_number", None, None),
+                Op::InputNumber => ("input_number", None, None),
+                Op::AddMove(..) | Op::MoveAdd(..) | Op::AddAt(..) => {
+                    unreachable!("fused instructions are only interpreted")
+                }
+            };
+            Entry {
+                name: name.to_owned(),
+                value,
+                matching,
+                offset,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn list_commands() {
+        assert_eq!(
+            list(b"a+\n[-]", false, false, false).unwrap(),
+            "     0  1:2         +
+     1  2:1         [ (matches 3)
+     2  2:2         -
+     3  2:3         ] (matches 1)
+"
+        );
+        assert_eq!(
+            list(b"+#", false, true, true).unwrap(),
+            r##"{"kind":"tokens","items":[{"command":"+","offset":0,"line":1,"column":1},{"command":"#","offset":1,"line":1,"column":2}]}
+"##
+        );
+        assert_eq!(list(b"[", false, false, true).unwrap_err(), "Missing ']'");
+    }
+
+    #[test]
+    fn list_instructions() {
+        assert_eq!(
+            list(b"--[>.<-]", true, false, true).unwrap(),
+            r#"{"kind":"ir","items":[{"op":"add","value":-2,"offset":0,"line":1,"column":1},{"op":"loop_start","match":6,"offset":2,"line":1,"column":3},{"op":"move","value":1,"offset":3,"line":1,"column":4},{"op":"output","offset":4,"line":1,"column":5},{"op":"move","value":-1,"offset":5,"line":1,"column":6},{"op":"add","value":-1,"offset":6,"line":1,"column":7},{"op":"loop_end","match":1,"offset":7,"line":1,"column":8}]}
+"#
+        );
+        assert_eq!(
+            list(b">>[-]", true, false, false).unwrap(),
+            "     0  1:1         move 2
+     1  1:3         set 0
+"
+        );
+    }
+}
@@ -0,0 +1,450 @@
+//! `bf lsp`: a Language Server Protocol server on stdin and stdout, so
+//! editors can show unmatched brackets and lint warnings as the program is
+//! typed, tell the loop depth on hover, jump between matching brackets and
+//! format programs with `bf fmt`.
+
+use crate::dap::{read_message, write_message};
+use crate::formatter;
+use crate::json::Json;
+use crate::lint;
+use std::collections::HashMap;
+
+/// Error codes of JSON-RPC and the protocol.
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const REQUEST_FAILED: i32 = -32803;
+
+/// `DiagnosticSeverity`s.
+const ERROR: usize = 1;
+const WARNING: usize = 2;
+
+pub fn run() -> Result<(), String> {
+    let mut input = std::io::stdin().lock();
+    let mut out = std::io::stdout();
+    let mut server = Server::default();
+    while let Some(message) = read_message(&mut input)? {
+        for reply in server.handle(&message) {
+            write_message(&mut out, &reply)?;
+        }
+        if server.exited {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Server {
+    /// The text of every open document by URI. Editors send the whole text
+    /// on every change.
+    documents: HashMap<String, String>,
+    exited: bool,
+}
+
+impl Server {
+    /// Handles one request or notification and returns the messages to send
+    /// back: the response to a request, and diagnostics for a document that
+    /// changed.
+    fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let method = message
+            .get("method")
+            .and_then(Json::as_str)
+            .unwrap_or_default();
+        let params = message.get("params").unwrap_or(&Json::Null);
+        let uri = params
+            .get("textDocument")
+            .and_then(|document| document.get("uri"))
+            .and_then(Json::as_str)
+            .unwrap_or_default()
+            .to_owned();
+
+        let Some(id) = message.get("id") else {
+            return match method {
+                "textDocument/didOpen" => {
+                    let text = params
+                        .get("textDocument")
+                        .and_then(|document| document.get("text"))
+                        .and_then(Json::as_str)
+                        .unwrap_or_default();
+                    self.documents.insert(uri.clone(), text.to_owned());
+                    vec![self.diagnostics(&uri)]
+                }
+                "textDocument/didChange" => {
+                    let text = params
+                        .get("contentChanges")
+                        .and_then(Json::as_array)
+                        .and_then(|changes| changes.last())
+                        .and_then(|change| change.get("text"))
+                        .and_then(Json::as_str);
+                    match text {
+                        Some(text) => {
+                            self.documents.insert(uri.clone(), text.to_owned());
+                            vec![self.diagnostics(&uri)]
+                        }
+                        None => vec![],
+                    }
+                }
+                "textDocument/didClose" => {
+                    self.documents.remove(&uri);
+                    // Clears the diagnostics of the closed document.
+                    vec![publish_diagnostics(&uri, vec![])]
+                }
+                "exit" => {
+                    self.exited = true;
+                    vec![]
+                }
+                // Other notifications, such as `initialized`, need nothing.
+                _ => vec![],
+            };
+        };
+
+        let result = match method {
+            "initialize" => Ok(Json::object([
+                (
+                    "capabilities",
+                    Json::object([
+                        // The whole text on every change.
+                        ("textDocumentSync", 1.into()),
+                        ("hoverProvider", true.into()),
+                        ("definitionProvider", true.into()),
+                        ("documentFormattingProvider", true.into()),
+                    ]),
+                ),
+                (
+                    "serverInfo",
+                    Json::object([
+                        ("name", "bf".into()),
+                        ("version", env!("CARGO_PKG_VERSION").into()),
+                    ]),
+                ),
+            ])),
+            "shutdown" => Ok(Json::Null),
+            "textDocument/hover" => self.hover(&uri, params),
+            "textDocument/definition" => self.definition(&uri, params),
+            "textDocument/formatting" => self.formatting(&uri),
+            _ => Err((
+                METHOD_NOT_FOUND,
+                format!("Unsupported request '{}'", method),
+            )),
+        };
+
+        let mut response = vec![("jsonrpc", "2.0".into()), ("id", id.clone())];
+        match result {
+            Ok(result) => response.push(("result", result)),
+            Err((code, message)) => response.push((
+                "error",
+                Json::object([
+                    ("code", Json::Number(code.into())),
+                    ("message", message.into()),
+                ]),
+            )),
+        }
+        vec![Json::object(response)]
+    }
+
+    fn document(&self, uri: &str) -> Result<&str, (i32, String)> {
+        self.documents
+            .get(uri)
+            .map(String::as_str)
+            .ok_or((INVALID_PARAMS, format!("Document '{}' is not open", uri)))
+    }
+
+    /// The document and the byte offset of the `position` parameter.
+    fn position(&self, uri: &str, params: &Json) -> Result<(&str, usize), (i32, String)> {
+        let text = self.document(uri)?;
+        let position = params.get("position").unwrap_or(&Json::Null);
+        match (
+            position.get("line").and_then(Json::as_usize),
+            position.get("character").and_then(Json::as_usize),
+        ) {
+            (Some(line), Some(character)) => Ok((text, offset(text, line, character))),
+            _ => Err((INVALID_PARAMS, "Missing position".to_owned())),
+        }
+    }
+
+    fn diagnostics(&self, uri: &str) -> Json {
+        let text = &self.documents[uri];
+        let brackets = Brackets::new(text);
+        let mut diagnostics: Vec<_> = brackets
+            .unmatched
+            .iter()
+            .map(|&offset| {
+                let message = if text.as_bytes()[offset] == b'[' {
+                    "This '[' has no matching ']'"
+                } else {
+                    "This ']' has no matching '['"
+                };
+                diagnostic(text, offset, ERROR, message)
+            })
+            .collect();
+        // The lints need a program that compiles.
+        if diagnostics.is_empty() {
+            if let Ok(warnings) = lint::lint(text.as_bytes()) {
+                diagnostics.extend(
+                    warnings
+                        .iter()
+                        .map(|w| diagnostic(text, w.offset, WARNING, &w.message)),
+                );
+            }
+        }
+        publish_diagnostics(uri, diagnostics)
+    }
+
+    /// The loop depth at the position, and for a bracket where its match is.
+    fn hover(&self, uri: &str, params: &Json) -> Result<Json, (i32, String)> {
+        let (text, offset) = self.position(uri, params)?;
+        let brackets = Brackets::new(text);
+        let mut value = format!("Loop depth {}", brackets.depth(offset));
+        if let Some(&other) = brackets.matching.get(&offset) {
+            let (line, character) = line_character(text, other);
+            value += &format!(
+                "\n\nMatches '{}' at line {}, column {}",
+                text.as_bytes()[other] as char,
+                line + 1,
+                character + 1
+            );
+        } else if brackets.unmatched.contains(&offset) {
+            value += "\n\nUnmatched";
+        }
+        Ok(Json::object([
+            (
+                "contents",
+                Json::object([("kind", "plaintext".into()), ("value", value.into())]),
+            ),
+            ("range", range(text, offset)),
+        ]))
+    }
+
+    /// Goes from a bracket to the one matching it.
+    fn definition(&self, uri: &str, params: &Json) -> Result<Json, (i32, String)> {
+        let (text, offset) = self.position(uri, params)?;
+        Ok(match Brackets::new(text).matching.get(&offset) {
+            Some(&other) => Json::object([("uri", uri.into()), ("range", range(text, other))]),
+            None => Json::Null,
+        })
+    }
+
+    /// Replaces the whole document with what `bf fmt` makes of it.
+    fn formatting(&self, uri: &str) -> Result<Json, (i32, String)> {
+        let text = self.document(uri)?;
+        let formatted = formatter::format(text.as_bytes(), formatter::DEFAULT_WIDTH)
+            .map_err(|e| (REQUEST_FAILED, e))?;
+        Ok(vec![Json::object([
+            (
+                "range",
+                Json::object([
+                    ("start", position(text, 0)),
+                    ("end", position(text, text.len())),
+                ]),
+            ),
+            ("newText", formatted.into()),
+        ])]
+        .into())
+    }
+}
+
+/// The brackets of a document by byte offset.
+struct Brackets {
+    matching: HashMap<usize, usize>,
+    /// Brackets without a match, in order.
+    unmatched: Vec<usize>,
+}
+
+impl Brackets {
+    /// Pairs brackets the way the parser does, but keeps going past one
+    /// without a match so every such bracket can be reported.
+    fn new(text: &str) -> Self {
+        let mut brackets = Brackets {
+            matching: HashMap::new(),
+            unmatched: vec![],
+        };
+        let mut open = vec![];
+        for (i, b) in text.bytes().enumerate() {
+            match b {
+                b'[' => open.push(i),
+                b']' => match open.pop() {
+                    Some(start) => {
+                        brackets.matching.insert(start, i);
+                        brackets.matching.insert(i, start);
+                    }
+                    None => brackets.unmatched.push(i),
+                },
+                _ => {}
+            }
+        }
+        brackets.unmatched.extend(open);
+        brackets.unmatched.sort_unstable();
+        brackets
+    }
+
+    /// How many loops the byte at `offset` is in. A bracket counts as part
+    /// of the loop it opens or closes.
+    fn depth(&self, offset: usize) -> usize {
+        self.matching
+            .iter()
+            .filter(|(&start, &end)| start <= offset && offset <= end && start < end)
+            .count()
+    }
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Json>) -> Json {
+    Json::object([
+        ("jsonrpc", "2.0".into()),
+        ("method", "textDocument/publishDiagnostics".into()),
+        (
+            "params",
+            Json::object([("uri", uri.into()), ("diagnostics", diagnostics.into())]),
+        ),
+    ])
+}
+
+fn diagnostic(text: &str, offset: usize, severity: usize, message: &str) -> Json {
+    Json::object([
+        ("range", range(text, offset)),
+        ("severity", severity.into()),
+        ("source", "bf".into()),
+        ("message", message.into()),
+    ])
+}
+
+/// The range of the character at `offset`.
+fn range(text: &str, offset: usize) -> Json {
+    let end = text[offset..]
+        .chars()
+        .next()
+        .map_or(offset, |c| offset + c.len_utf8());
+    Json::object([
+        ("start", position(text, offset)),
+        ("end", position(text, end)),
+    ])
+}
+
+/// The protocol's position of a byte offset: a line from 0 and a column in
+/// UTF-16 code units from 0.
+fn position(text: &str, offset: usize) -> Json {
+    let (line, character) = line_character(text, offset);
+    Json::object([("line", line.into()), ("character", character.into())])
+}
+
+fn line_character(text: &str, offset: usize) -> (usize, usize) {
+    let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    (
+        text[..offset].matches('\n').count(),
+        text[line_start..offset].encode_utf16().count(),
+    )
+}
+
+/// The byte offset of a protocol position, clamped to the end of its line
+/// and to the end of the text.
+fn offset(text: &str, line: usize, character: usize) -> usize {
+    let Some(line_start) = (0..line).try_fold(0, |start, _| {
+        text[start..].find('\n').map(|i| start + i + 1)
+    }) else {
+        return text.len();
+    };
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn send(server: &mut Server, message: &str) -> Vec<String> {
+        server
+            .handle(&Json::parse(message).unwrap())
+            .iter()
+            .map(Json::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn language_server_session() {
+        let mut server = Server::default();
+        let initialize = send(
+            &mut server,
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+        );
+        assert!(initialize[0].contains(r#""hoverProvider":true"#));
+
+        assert_eq!(
+            send(
+                &mut server,
+                r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///a.b","text":"+[\n é[-]]]"}}}"#
+            ),
+            [
+                r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///a.b","diagnostics":[{"range":{"start":{"line":1,"character":6},"end":{"line":1,"character":7}},"severity":1,"source":"bf","message":"This ']' has no matching '['"}]}}"#
+            ]
+        );
+        assert_eq!(
+            send(
+                &mut server,
+                r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///a.b"},"position":{"line":1,"character":2}}}"#
+            ),
+            [
+                r#"{"jsonrpc":"2.0","id":2,"result":{"contents":{"kind":"plaintext","value":"Loop depth 2\n\nMatches ']' at line 2, column 5"},"range":{"start":{"line":1,"character":2},"end":{"line":1,"character":3}}}}"#
+            ]
+        );
+        assert_eq!(
+            send(
+                &mut server,
+                r#"{"jsonrpc":"2.0","id":3,"method":"textDocument/definition","params":{"textDocument":{"uri":"file:///a.b"},"position":{"line":0,"character":1}}}"#
+            ),
+            [
+                r#"{"jsonrpc":"2.0","id":3,"result":{"uri":"file:///a.b","range":{"start":{"line":1,"character":5},"end":{"line":1,"character":6}}}}"#
+            ]
+        );
+        assert!(send(
+            &mut server,
+            r#"{"jsonrpc":"2.0","id":4,"method":"textDocument/formatting","params":{"textDocument":{"uri":"file:///a.b"}}}"#
+        )[0]
+            .contains(r#""error":{"code":-32803,"message":"Missing '['"}"#));
+
+        assert_eq!(
+            send(
+                &mut server,
+                r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///a.b"},"contentChanges":[{"text":"+[-]"}]}}"#
+            ),
+            [
+                r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///a.b","diagnostics":[]}}"#
+            ]
+        );
+        assert_eq!(
+            send(
+                &mut server,
+                r#"{"jsonrpc":"2.0","id":5,"method":"textDocument/formatting","params":{"textDocument":{"uri":"file:///a.b"}}}"#
+            ),
+            [
+                r#"{"jsonrpc":"2.0","id":5,"result":[{"range":{"start":{"line":0,"character":0},"end":{"line":0,"character":4}},"newText":"+\n[\n    -\n]\n"}]}"#
+            ]
+        );
+
+        assert!(
+            send(&mut server, r#"{"jsonrpc":"2.0","id":6,"method":"foo"}"#)[0].contains("-32601")
+        );
+        send(
+            &mut server,
+            r#"{"jsonrpc":"2.0","id":7,"method":"shutdown"}"#,
+        );
+        send(&mut server, r#"{"jsonrpc":"2.0","method":"exit"}"#);
+        assert!(server.exited);
+    }
+
+    #[test]
+    fn convert_positions() {
+        let text = "a\nxé𝄞b\n";
+        assert_eq!(offset(text, 1, 0), 2);
+        assert_eq!(offset(text, 1, 2), 5);
+        assert_eq!(offset(text, 1, 4), 9);
+        assert_eq!(offset(text, 1, 99), 10);
+        assert_eq!(offset(text, 9, 0), text.len());
+        assert_eq!(position(text, 9).to_string(), r#"{"line":1,"character":4}"#);
+    }
+}
@@ -1,104 +1,690 @@
-mod bf_interpreter;
-
-use bf_interpreter::{BfInterpreter, Ret};
+use bf::bf_interpreter::{BfInterpreter, CellOverflow, Stats};
+use bf::cli::{
+    BuildBackend, BuildOptions, CheckOptions, Command, CompileOptions, Destination, FmtOptions,
+    GenOptions, GraphOptions, Input, IoPath, LintOptions, ParseOptions, RunOptions, Source,
+    StatsOptions,
+};
+use bf::codegen::{Language, Target};
+use bf::config::TapeMode;
+use bf::dialect::{Dialect, Mapping};
+use bf::escape::Escaper;
+use bf::flush::FlushEveryByte;
+use bf::hang::HangDetector;
+use bf::pgo::Profile;
+use bf::plugin::Registry;
+use bf::profile::SourceMap;
+use bf::raw_input::{RawMode, RawStdin};
+use bf::record::{Recorder, Replay};
+use bf::runner::{load_ops, read_file, run_interpreter, source_path};
+use bf::temp_dir::TempDir;
+use bf::trace::Tracer;
+use bf::{
+    bench, bounds, bytecode, cli, codegen, dap, debugger, difftest, formatter, fuse, gen, golden,
+    grade, graph, ir, kernel, lint, listing, lsp, mini, native, plugin, preprocess, profile, repl,
+    serve, snapshot, stats, threads, verify, visualize,
+};
 use std::{
-    io::{BufRead, Read, Write},
+    fs::File,
+    io::{BufReader, BufWriter, Cursor, IsTerminal, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
     process::ExitCode,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 fn main() -> ExitCode {
     let args = std::env::args().skip(1).collect::<Vec<_>>();
-    if args.len() != 1 {
-        eprintln!("ERROR: invalid args");
+    let command = match cli::parse(&args) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let result = match command {
+        Command::Run(options) => run(*options),
+        Command::Repl(options) => repl::run(options),
+        Command::Debug(options) => debugger::run(options),
+        Command::Dap => dap::run(),
+        Command::Lsp => lsp::run(),
+        Command::Kernel(options) => kernel::run(options),
+        Command::Serve(options) => serve::run(options),
+        Command::Visualize(options) => visualize::run(options),
+        Command::Compile(options) => compile(options),
+        Command::Build(options) => build(options),
+        Command::Check(options) => check(options),
+        Command::Lint(options) => lint(options),
+        Command::Stats(options) => stats(options),
+        Command::Parse(options) => parse(options),
+        Command::Graph(options) => graph(options),
+        Command::Verify(options) => verify::run(options),
+        Command::Difftest(options) => difftest::run(options),
+        Command::Test(options) => golden::run(options),
+        Command::Grade(options) => grade::run(options),
+        Command::Bench(options) => bench::run(options),
+        Command::Fmt(options) => fmt(options),
+        Command::Gen(options) => gen(options),
+        Command::Help(usage) => {
+            print!("{}", usage);
+            Ok(())
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("ERROR: {}", e);
         return ExitCode::FAILURE;
     }
 
-    let arg = args.first().unwrap();
+    ExitCode::SUCCESS
+}
 
-    if arg == "--repl" {
-        repl();
+fn run(options: RunOptions) -> Result<(), String> {
+    let inline = matches!(&options.program, Source::Eval(_))
+        || options.program == Source::File("-".to_owned());
+    let path = match &options.program {
+        Source::File(path) => source_path(path).map(Path::to_owned),
+        Source::Eval(_) => None,
+    };
+    let (mut content, dialect) = match options.program {
+        Source::File(path) => (read_file(&path)?, Dialect::from_path(&path)),
+        Source::Eval(program) => (program.into_bytes(), Dialect::Brainfuck),
+    };
+    let dialect = match (&options.dialect_file, options.dialect) {
+        (Some(path), _) => {
+            let toml = String::from_utf8_lossy(&read_file(path)?).into_owned();
+            Dialect::Custom(Mapping::parse(&toml).map_err(|e| format!("{}: {}", path, e))?)
+        }
+        (None, given) => given.unwrap_or(dialect),
+    };
+    // Like dbfi, a program from stdin or -e can be followed by `!` and its
+    // input, unless it's given some other input or `!` is a command.
+    let mut inline_input = None;
+    if inline
+        && dialect == Dialect::Brainfuck
+        && !bytecode::is_bytecode(&content)
+        && options.input == Input::Stdin
+        && options.listen.is_none()
+        && options.io_in.is_none()
+        && !options.raw_input
+        && options.replay.is_none()
+    {
+        if let Some(i) = content.iter().position(|&b| b == b'!') {
+            inline_input = Some(content.split_off(i)[1..].to_vec());
+        }
+    }
+    let (program, positions) = match dialect {
+        _ if bytecode::is_bytecode(&content) => (content.clone(), None),
+        // `@` is a command in Extended Brainfuck.
+        Dialect::Ook | Dialect::Custom(_) | Dialect::Extended1 => dialect.translate(&content)?,
+        _ => preprocess::preprocess(&content, path.as_deref())?,
+    };
+    let tape_mode = options.config.tape_mode;
+    // Builds without the backend's feature run the interpreter instead.
+    let compile = match options.backend {
+        native::Backend::Interp => false,
+        backend if backend.available() => true,
+        backend => {
+            eprintln!(
+                "WARNING: Built without the {} feature, running the interpreter",
+                backend.name()
+            );
+            false
+        }
+    };
+    let builder = BfInterpreter::builder()
+        .config(options.config.clone())
+        .max_steps(options.max_steps)
+        .cell_overflow(options.cell_overflow)
+        .dispatch(options.dispatch)
+        .unsafe_fast(options.unsafe_fast)
+        .tape_file(options.tape_file.clone())
+        .profile(options.profile.is_some() || options.profile_out.is_some() || options.coverage);
+    let replay = match &options.replay {
+        Some(path) => Some(Replay::new(read_file(path)?).map_err(|e| format!("{}: {}", path, e))?),
+        None => None,
+    };
+    // A replay gets the seed of the recording, so `?` reads the same bytes.
+    let seed = match (options.random, replay.as_ref().map(Replay::seed)) {
+        (false, Some(Some(_))) => return Err("The recording was made with --random".to_owned()),
+        (false, _) => None,
+        (true, Some(None)) => return Err("The recording was made without --random".to_owned()),
+        (true, Some(Some(recorded))) if options.seed.is_some_and(|seed| seed != recorded) => {
+            return Err(format!(
+                "--seed differs from the seed of the recording, {}",
+                recorded
+            ))
+        }
+        (true, Some(Some(recorded))) => Some(recorded),
+        (true, None) => Some(options.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos() as u64)
+        })),
+    };
+    let mut plugins = Registry::default();
+    if let Some(seed) = seed {
+        plugins.register(b'?', plugin::random(seed))?;
+    }
+    let mut extensions = dialect.extensions().to_vec();
+    extensions.extend(plugins.extensions());
+    let builder = builder.plugins(plugins);
+    let extended = !extensions.is_empty() && !bytecode::is_bytecode(&program);
+    // The bounds analysis trusts loops to nest the way the parser makes them,
+    // which bytecode only promises.
+    if options.unsafe_fast && bytecode::is_bytecode(&program) {
+        return Err("--unsafe-fast only works with source, not bytecode".to_owned());
+    }
+    // Bytecode was folded without caring where cells overflow.
+    let strict = options.cell_overflow == CellOverflow::Error;
+    if strict && bytecode::is_bytecode(&program) {
+        return Err("--cell-overflow error only works with source, not bytecode".to_owned());
+    }
+    if compile && extended {
+        return Err(format!(
+            "--backend {} only runs Brainfuck",
+            options.backend.name()
+        ));
+    }
+    if options.self_modifying && (bytecode::is_bytecode(&program) || dialect != Dialect::Brainfuck)
+    {
+        return Err("--self-modifying only works with Brainfuck source".to_owned());
+    }
+    let (mut interpreter, offsets) = if options.self_modifying {
+        (builder.build_self_modifying(&program)?, None)
     } else {
-        let content = std::fs::read_to_string(arg).unwrap();
-        if let Err(e) = run_interpreter(content.as_bytes()) {
-            eprintln!("ERROR: {}", e);
-            return ExitCode::FAILURE;
+        let (ops, mut offsets) = if extended || strict {
+            if extended && (options.resume.is_some() || options.snapshot_out.is_some()) {
+                return Err("Snapshots only work with Brainfuck".to_owned());
+            }
+            let compile = if strict {
+                ir::compile_strict
+            } else {
+                ir::compile_extended
+            };
+            let (ops, offsets) = compile(&program, options.debug_char, &extensions)?;
+            (ops, Some(offsets))
+        } else {
+            load_ops(&program, options.debug_char)?
+        };
+        // Point into the source as it was written rather than its translation.
+        if let (Some(offsets), Some(positions)) = (&mut offsets, &positions) {
+            for offset in offsets.iter_mut() {
+                *offset = positions[*offset];
+            }
+        }
+        // Checked before fusing, so the warning points at the move itself.
+        if tape_mode != TapeMode::Wrap {
+            if let Some(i) = bounds::underflow(&ops) {
+                let source = offsets.as_deref().map(|offsets| SourceMap {
+                    source: &content,
+                    offsets,
+                });
+                eprintln!(
+                    "WARNING: {}: {}",
+                    profile::location(source.as_ref(), i),
+                    lint::UNDERFLOW
+                );
+            }
+        }
+        // Profiles and traces show every instruction of the program, cell
+        // overflows point at the `+` or `-` itself, and snapshots are only
+        // taken of the program as compiled. The native backends compile
+        // the plain instructions.
+        let fuse = !strict
+            && !compile
+            && options.profile.is_none()
+            && options.profile_out.is_none()
+            && !options.coverage
+            && options.trace.is_none()
+            && options.snapshot_out.is_none()
+            && options.resume.is_none();
+        if fuse {
+            let fused = fuse::fuse(&ops, offsets.as_deref());
+            if options.show_fusions {
+                eprint!("{}", fused.report());
+            }
+            (builder.build_ops(fused.ops)?, fused.offsets)
+        } else {
+            (builder.build_ops(ops)?, offsets)
         }
+    };
+    let source = offsets.as_deref().map(|offsets| SourceMap {
+        source: &content,
+        offsets,
+    });
+    if options.coverage && source.is_none() {
+        return Err("--coverage needs the Brainfuck source, not bytecode".to_owned());
+    }
+    if options.coverage && (positions.is_some() || extended) {
+        return Err("--coverage only works with Brainfuck source".to_owned());
+    }
+    if let Some(path) = &options.resume {
+        snapshot::restore(&mut interpreter, &read_file(path)?)
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    if options.snapshot_out.is_some() {
+        snapshot::catch_interrupt();
     }
 
-    ExitCode::SUCCESS
+    // Restores the terminal when dropped at the end of the run.
+    let raw_mode = options.raw_input.then(RawMode::enable).transpose()?;
+    let mut input: Box<dyn Read> = match (options.input, inline_input) {
+        (_, Some(input)) => Box::new(Cursor::new(input)),
+        (Input::Stdin, None) if raw_mode.is_some() => Box::new(RawStdin(std::io::stdin())),
+        (Input::Stdin, None) => Box::new(std::io::stdin().lock()),
+        (Input::File(path), None) => Box::new(Cursor::new(read_file(&path)?)),
+        (Input::String(input), None) => Box::new(Cursor::new(input.into_bytes())),
+    };
+    let connection = options.listen.as_deref().map(accept).transpose()?;
+    if let Some(stream) = &connection {
+        let stream = stream.try_clone().map_err(|e| e.to_string())?;
+        input = Box::new(BufReader::new(stream));
+    }
+    if let Some(path) = &options.io_in {
+        input = Box::new(BufReader::new(open_io(path, false)?));
+    }
+    if let Some(replay) = replay {
+        input = Box::new(replay);
+    }
+    if let Some(path) = &options.record {
+        let out = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        input = Box::new(Recorder::new(input, BufWriter::new(out), seed)?);
+    }
+    let stream: Option<Box<dyn Write>> = match (connection, &options.io_out) {
+        (Some(connection), _) => Some(Box::new(connection)),
+        (None, Some(path)) => Some(Box::new(open_io(path, true)?)),
+        (None, None) => None,
+    };
+    let mut output: Box<dyn Write> = match (&options.output, stream) {
+        (Some(path), _) => Box::new(BufWriter::new(
+            File::create(path).map_err(|e| format!("{}: {}", path, e))?,
+        )),
+        // Output is flushed before every read, so whatever is on the other
+        // end sees it in time.
+        (None, Some(stream)) => Box::new(BufWriter::new(stream)),
+        // Stdout flushes every line by itself, which is right for a terminal
+        // but slow for long output going to a pipe or file.
+        (None, None) if std::io::stdout().is_terminal() => Box::new(std::io::stdout().lock()),
+        (None, None) => Box::new(BufWriter::new(std::io::stdout().lock())),
+    };
+    if options.escape_output {
+        let color = options.output.is_none()
+            && options.listen.is_none()
+            && options.io_out.is_none()
+            && std::io::stdout().is_terminal();
+        output = Box::new(Escaper::new(output, color));
+    }
+    if options.flush_every_byte {
+        output = Box::new(FlushEveryByte(output));
+    }
+    let mut tracer = match &options.trace {
+        Some(Destination::Stderr) => Some(Box::new(std::io::stderr().lock()) as Box<dyn Write>),
+        Some(Destination::File(path)) => Some(Box::new(BufWriter::new(
+            File::create(path).map_err(|e| format!("{}: {}", path, e))?,
+        )) as Box<dyn Write>),
+        None => None,
+    }
+    .map(|out| Tracer::new(out, options.trace_format, options.trace_limit, source));
+    let mut hangs = options.detect_hangs.then(|| HangDetector::new(source));
+    let threaded = extended && dialect == Dialect::Brainfork;
+    if threaded && (tracer.is_some() || hangs.is_some()) {
+        return Err("--trace and --detect-hangs don't work with brainfork".to_owned());
+    }
+
+    let mut compiled = compile
+        .then(|| native::Program::compile(options.backend, interpreter.program(), &options.config))
+        .transpose()?;
+
+    let start = Instant::now();
+    let result = if let Some(program) = &mut compiled {
+        program.run(&mut input, &mut output)
+    } else if threaded {
+        threads::run(
+            &mut interpreter,
+            &mut input,
+            &mut output,
+            options.timeout,
+            options.numeric_io,
+        )
+    } else {
+        run_interpreter(
+            &mut interpreter,
+            &mut input,
+            &mut output,
+            options.timeout,
+            tracer.as_mut(),
+            hangs.as_mut(),
+            options.numeric_io,
+        )
+    };
+    // The interpreter only knows the instruction a cell overflowed at, the
+    // source map where it is.
+    let result = match (result, &source) {
+        (Err(e), Some(map)) if strict => Err(e.replacen(
+            &format!("at pc {},", interpreter.pc()),
+            &format!("at {},", profile::location(Some(map), interpreter.pc())),
+            1,
+        )),
+        (result, _) => result,
+    };
+    if options.stats {
+        print_stats(interpreter.stats(), start.elapsed());
+    }
+    if let (Some(top), Some(counts)) = (options.profile, interpreter.profile()) {
+        eprint!(
+            "{}",
+            profile::report(interpreter.program(), counts, source.as_ref(), top)
+        );
+    }
+    if let (Some(path), Some(counts)) = (&options.profile_out, interpreter.profile()) {
+        std::fs::write(path, Profile::save(interpreter.program(), counts))
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    if let (true, Some(map), Some(counts)) = (options.coverage, &source, interpreter.profile()) {
+        let color = std::io::stderr().is_terminal();
+        eprint!("{}", profile::coverage(map, counts, color));
+    }
+
+    if let Some(path) = &options.snapshot_out {
+        std::fs::write(path, snapshot::encode(&interpreter))
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+
+    match options.dump_tape {
+        Some(Destination::Stderr) => eprint!("{}", interpreter.dump_tape()),
+        Some(Destination::File(path)) => std::fs::write(&path, interpreter.dump_tape())
+            .map_err(|e| format!("{}: {}", path, e))?,
+        None => {}
+    }
+    if let Some(path) = &options.tape_program {
+        std::fs::write(path, gen::tape(interpreter.tape(), interpreter.data_ptr()))
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    result
 }
 
-fn run_interpreter(program: &[u8]) -> Result<(), String> {
-    let mut interpreter = BfInterpreter::new(program)?;
-
-    let mut stdin = std::io::stdin().lock();
-    let mut stdout = std::io::stdout().lock();
-    loop {
-        match interpreter.step() {
-            Ok(ret) => {
-                match ret {
-                    Ret::Input => {
-                        let mut buf = [0u8; 1];
-                        match stdin.read_exact(&mut buf) {
-                            Ok(_) => {
-                                interpreter.set_input(buf[0]);
-                            }
-                            Err(_) => {
-                                break;
-                            }
-                        }
-                    }
-                    Ret::Output(byte) => {
-                        write!(stdout, "{}", unsafe {
-                            std::str::from_utf8_unchecked(&[byte])
-                        })
-                        .unwrap();
-                        stdout.flush().unwrap();
-                    }
-                    Ret::Continue => {
-                        // Continue.
-                    }
-                    Ret::Finished => break,
-                }
+/// Opens the file or file descriptor of `--io-in` or `--io-out`.
+fn open_io(path: &IoPath, write: bool) -> Result<File, String> {
+    match path {
+        IoPath::Path(path) => if write {
+            File::create(path)
+        } else {
+            File::open(path)
+        }
+        .map_err(|e| format!("{}: {}", path, e)),
+        #[cfg(any(unix, target_os = "wasi"))]
+        IoPath::Fd(fd) => {
+            use std::os::fd::FromRawFd;
+            // SAFETY: the descriptor was handed to this process for the
+            // program's I/O, and nothing else here owns it. One that isn't
+            // open fails on the first read or write.
+            Ok(unsafe { File::from_raw_fd(*fd) })
+        }
+        #[cfg(not(any(unix, target_os = "wasi")))]
+        IoPath::Fd(_) => Err("File descriptors only work on Unix".to_owned()),
+    }
+}
+
+/// Waits for one connection on `address`, for `--listen`.
+fn accept(address: &str) -> Result<TcpStream, String> {
+    let listener = TcpListener::bind(address).map_err(|e| format!("{}: {}", address, e))?;
+    let address = listener.local_addr().map_err(|e| e.to_string())?;
+    eprintln!("Listening on {}", address);
+    let (stream, _) = listener
+        .accept()
+        .map_err(|e| format!("{}: {}", address, e))?;
+    Ok(stream)
+}
+
+fn compile(options: CompileOptions) -> Result<(), String> {
+    let content = read_file(&options.program)?;
+    let from = options
+        .from
+        .unwrap_or_else(|| Language::from_path(&options.program));
+    let content = match from {
+        Language::Bf => preprocess::preprocess(&content, source_path(&options.program))?.0,
+        Language::Mini => mini::compile(&content, options.config.cell_size)?,
+    };
+    let profile = options.profile.as_deref().map(read_file).transpose()?;
+    let profile = profile
+        .map(String::from_utf8)
+        .transpose()
+        .map_err(|_| "Invalid profile".to_owned())?;
+    let compiled = codegen::compile(
+        &content,
+        options.target,
+        &options.config,
+        profile.as_deref(),
+    )?;
+
+    match options.output {
+        Some(path) => {
+            std::fs::write(&path, compiled).map_err(|e| format!("{}: {}", path, e))?;
+
+            let path = Path::new(&path);
+            let module_file = path.with_extension("wasm");
+            let module_file = module_file.file_name().unwrap().to_string_lossy();
+            if let Some(glue) = codegen::glue(options.target, &module_file) {
+                let glue_path = path.with_extension("js");
+                std::fs::write(&glue_path, glue)
+                    .map_err(|e| format!("{}: {}", glue_path.display(), e))?;
             }
-            Err(e) => {
-                return Err(e);
+            Ok(())
+        }
+        None => std::io::stdout()
+            .write_all(&compiled)
+            .map_err(|e| e.to_string()),
+    }
+}
+
+fn build(options: BuildOptions) -> Result<(), String> {
+    let input = Path::new(&options.program);
+    let output = match &options.output {
+        Some(output) => Path::new(output).to_owned(),
+        None if options.program == "-" => {
+            return Err("Reading the program from stdin requires -o".to_owned())
+        }
+        None => input.with_extension(""),
+    };
+    if output == input {
+        return Err("Output would overwrite the program file, use -o".to_owned());
+    }
+
+    if cfg!(target_os = "wasi") {
+        return Err("bf build can't run a C compiler under WASI, use bf compile".to_owned());
+    }
+    let content = read_file(&options.program)?;
+    let (content, _) = preprocess::preprocess(&content, source_path(&options.program))?;
+    // The compiler reads what's written here, so it goes in a directory
+    // no one else can get into, which is removed however the build ends.
+    let dir = TempDir::new("bf-build")?;
+    let write = |name: &str, contents: &[u8]| {
+        let path = dir.path().join(name);
+        std::fs::write(&path, contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok::<_, String>(path)
+    };
+    let mut command = std::process::Command::new(&options.cc);
+    command.arg(&options.opt_level);
+    match options.backend {
+        BuildBackend::C => {
+            let source = codegen::compile(&content, Target::C, &options.config, None)?;
+            command.arg(write("program.c", &source)?);
+        }
+        BuildBackend::Cranelift => {
+            let opt_level = match options.opt_level.as_str() {
+                "-O0" => "none",
+                "-Os" => "speed_and_size",
+                _ => "speed",
+            };
+            let ops = ir::compile(&content)?;
+            let object = native::object(&ops, &options.config, opt_level)?;
+            let runtime = native::runtime_c(&options.config);
+            command
+                .arg(write("runtime.c", runtime.as_bytes())?)
+                .arg(write("program.o", &object)?);
+        }
+    }
+    let status = command.arg("-o").arg(&output).status();
+
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("{} failed with {}", options.cc, status)),
+        Err(e) => Err(format!("Failed to run {}: {}", options.cc, e)),
+    }
+}
+
+fn check(options: CheckOptions) -> Result<(), String> {
+    let mut failed = 0;
+    for program in &options.programs {
+        let result = read_file(program).and_then(|content| {
+            if bytecode::is_bytecode(&content) {
+                bytecode::decode(&content).map(drop)
+            } else {
+                let (content, _) = preprocess::preprocess(&content, source_path(program))?;
+                ir::compile(&content).map(drop)
             }
+        });
+        if let Err(e) = result {
+            eprintln!("{}: {}", program, e);
+            failed += 1;
         }
     }
 
+    if failed > 0 {
+        return Err(format!(
+            "{} of {} files failed",
+            failed,
+            options.programs.len()
+        ));
+    }
     Ok(())
 }
 
-fn repl() {
-    let mut buf = String::new();
-    loop {
-        print!("# ");
-        std::io::stdout().flush().unwrap();
-
-        buf.clear();
-        let input_ret = std::io::stdin().lock().read_line(&mut buf);
-        let buf = buf.trim_end();
-
-        match input_ret {
-            Ok(_) => {
-                if buf == "exit" {
-                    return;
-                }
-
-                match run_interpreter(buf.as_bytes()) {
-                    Ok(_) => {
-                        // Do nothing.
-                    }
-                    Err(e) => {
-                        println!("ERROR: {}", e);
-                        std::io::stdout().flush().unwrap();
-                    }
-                }
-            }
-            Err(_) => {
-                break;
-            }
+fn lint(options: LintOptions) -> Result<(), String> {
+    let mut count = 0;
+    for program in &options.programs {
+        let content = read_file(program)?;
+        if bytecode::is_bytecode(&content) {
+            return Err(format!("{}: Bytecode can't be linted", program));
+        }
+        for warning in lint::lint(&content).map_err(|e| format!("{}: {}", program, e))? {
+            let (line, column) = profile::position(&content, warning.offset);
+            println!("{}:{}:{}: {}", program, line, column, warning.message);
+            count += 1;
         }
     }
+
+    match count {
+        0 => Ok(()),
+        1 => Err("1 warning".to_owned()),
+        _ => Err(format!("{} warnings", count)),
+    }
+}
+
+fn stats(options: StatsOptions) -> Result<(), String> {
+    let content = read_file(&options.program)?;
+    if bytecode::is_bytecode(&content) {
+        return Err("Bytecode has no source to show statistics for".to_owned());
+    }
+    print!("{}", stats::report(&content)?);
+    Ok(())
+}
+
+fn parse(options: ParseOptions) -> Result<(), String> {
+    let content = read_file(&options.program)?;
+    if bytecode::is_bytecode(&content) {
+        return Err("Bytecode has no source to parse".to_owned());
+    }
+    print!(
+        "{}",
+        listing::list(&content, options.ir, options.debug_char, options.json)?
+    );
+    Ok(())
+}
+
+fn graph(options: GraphOptions) -> Result<(), String> {
+    let content = read_file(&options.program)?;
+    if bytecode::is_bytecode(&content) {
+        return Err("Bytecode has no source to draw a graph of".to_owned());
+    }
+    let (ops, offsets) = ir::compile_with_offsets(&content, false)?;
+    let counts = match &options.trace {
+        Some(path) => {
+            let trace = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            Some(graph::read_trace(&trace, ops.len())?)
+        }
+        None => None,
+    };
+    let map = SourceMap {
+        source: &content,
+        offsets: &offsets,
+    };
+    let dot = graph::graph(&ops, &map, counts.as_deref());
+
+    match options.output {
+        Some(path) => std::fs::write(&path, dot).map_err(|e| format!("{}: {}", path, e)),
+        None => {
+            print!("{}", dot);
+            Ok(())
+        }
+    }
+}
+
+fn fmt(options: FmtOptions) -> Result<(), String> {
+    let content = read_file(&options.program)?;
+    if options.minify {
+        let minified = formatter::minify(&content, options.remove_no_ops)?;
+        println!("{}", minified);
+        eprintln!("{} -> {} bytes", content.len(), minified.len());
+        return Ok(());
+    }
+    let formatted = formatter::format(&content, options.width)?;
+    if options.check {
+        if formatted.as_bytes() != content {
+            return Err(format!("{} is not formatted", options.program));
+        }
+        return Ok(());
+    }
+    print!("{}", formatted);
+    Ok(())
+}
+
+fn gen(options: GenOptions) -> Result<(), String> {
+    let program = match options {
+        GenOptions::Expr {
+            expression,
+            print,
+            cell_size,
+        } => gen::expr(&expression, print, cell_size)?,
+        GenOptions::Random { size, seed, loops } => {
+            let seed = seed.unwrap_or_else(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |time| time.as_nanos() as u64)
+            });
+            gen::random(size, seed, loops) + "\n"
+        }
+    };
+    print!("{}", program);
+    Ok(())
+}
+
+fn print_stats(stats: &Stats, elapsed: Duration) {
+    eprintln!("instructions executed: {}", stats.steps);
+    for (name, count) in [
+        ("add", stats.adds),
+        ("move", stats.moves),
+        ("set", stats.sets),
+        ("output", stats.outputs),
+        ("input", stats.inputs),
+        ("loop start", stats.loop_starts),
+        ("loop end", stats.loop_ends),
+    ] {
+        eprintln!("  {:<12}{}", name, count);
+    }
+    eprintln!("bytes read: {}", stats.bytes_read);
+    eprintln!("bytes written: {}", stats.bytes_written);
+    eprintln!("peak tape index: {}", stats.peak_index);
+    eprintln!("wall time: {:?}", elapsed);
 }
@@ -0,0 +1,480 @@
+//! Mini, a small structured language that compiles to Brainfuck, for
+//! `bf compile --from mini`:
+//!
+//! ```text
+//! # Echoes its input up to a 0, with spaces as underscores.
+//! var c, t
+//! read c
+//! while c {
+//!     t = c
+//!     t -= ' '
+//!     if t { write c } else { write '_' }
+//!     read c
+//! }
+//! ```
+//!
+//! Each variable is a cell, numbered in the order they're declared. A
+//! variable can be set to, or have added or subtracted, a number, a
+//! character or another variable; `read` and `write` do byte I/O, and
+//! `write` also takes a string. `while x { ... }` loops while `x` isn't 0,
+//! and `if x { ... } else { ... }` tests it once. The cells after the
+//! variables are scratch space for copies and conditions.
+
+use crate::config::CellSize;
+use crate::gen::Emitter;
+use crate::profile;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Number(u64),
+    String(Vec<u8>),
+    Symbol(&'static str),
+}
+
+/// Something a variable can be set to.
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Number(u64),
+    /// A variable's cell.
+    Cell(usize),
+}
+
+#[derive(Debug, PartialEq)]
+enum Statement {
+    Set(usize, Value),
+    Add(usize, Value),
+    Sub(usize, Value),
+    Read(usize),
+    Write(usize),
+    WriteBytes(Vec<u8>),
+    While(usize, Vec<Statement>),
+    If(usize, Vec<Statement>, Vec<Statement>),
+}
+
+/// Words that can't be variable names.
+const KEYWORDS: &[&str] = &["var", "read", "write", "while", "if", "else"];
+
+/// Compiles a Mini program to Brainfuck for cells of `cell_size`. Errors
+/// point at the source as `line:column`.
+pub fn compile(source: &[u8], cell_size: CellSize) -> Result<Vec<u8>, String> {
+    let error = |(offset, message): (usize, String)| {
+        let (line, column) = profile::position(source, offset);
+        format!("{}:{}: {}", line, column, message)
+    };
+    let tokens = tokenize(source).map_err(error)?;
+    let mut parser = Parser {
+        tokens: &tokens,
+        pos: 0,
+        end: source.len(),
+        variables: HashMap::new(),
+        cell_size,
+    };
+    let program = parser.block(false).map_err(error)?;
+
+    let mut compiler = Compiler {
+        emitter: Emitter::new(cell_size),
+        scratch: parser.variables.len(),
+    };
+    compiler.block(&program);
+    Ok(compiler.emitter.finish().into_bytes())
+}
+
+/// Splits `source` into tokens, each with its offset. Numbers are decimal,
+/// characters are quoted with `'` and strings with `"`, both with the escapes
+/// `\n`, `\t`, `\0`, `\\`, `\'` and `\"`. Comments run from `#` to the end of
+/// the line.
+fn tokenize(source: &[u8]) -> Result<Vec<(usize, Token)>, (usize, String)> {
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < source.len() {
+        let start = i;
+        let token = match source[i] {
+            b if b.is_ascii_whitespace() || b == b';' => {
+                i += 1;
+                continue;
+            }
+            b'#' => {
+                while i < source.len() && source[i] != b'\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            b if b.is_ascii_alphabetic() || b == b'_' => {
+                while i < source.len() && (source[i].is_ascii_alphanumeric() || source[i] == b'_') {
+                    i += 1;
+                }
+                Token::Word(String::from_utf8_lossy(&source[start..i]).into_owned())
+            }
+            b if b.is_ascii_digit() => {
+                while i < source.len() && source[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let digits = std::str::from_utf8(&source[start..i]).unwrap();
+                Token::Number(
+                    digits
+                        .parse()
+                        .map_err(|_| (start, "Number too large".to_owned()))?,
+                )
+            }
+            quote @ (b'\'' | b'"') => {
+                let (bytes, len) = quoted(&source[i..], quote).map_err(|e| (start, e))?;
+                i += len;
+                if quote == b'"' {
+                    Token::String(bytes)
+                } else if let [byte] = bytes[..] {
+                    Token::Number(byte as u64)
+                } else {
+                    return Err((start, "A character must be a single byte".to_owned()));
+                }
+            }
+            _ => {
+                let symbol = ["+=", "-=", "=", "{", "}", ","]
+                    .into_iter()
+                    .find(|symbol| source[i..].starts_with(symbol.as_bytes()))
+                    .ok_or_else(|| {
+                        let c = String::from_utf8_lossy(&source[i..])
+                            .chars()
+                            .next()
+                            .unwrap();
+                        (start, format!("Unexpected '{}'", c))
+                    })?;
+                i += symbol.len();
+                Token::Symbol(symbol)
+            }
+        };
+        tokens.push((start, token));
+    }
+    Ok(tokens)
+}
+
+/// The bytes of the character or string literal `text` starts with, and the
+/// length of the literal with its quotes.
+fn quoted(text: &[u8], quote: u8) -> Result<(Vec<u8>, usize), String> {
+    let mut bytes = vec![];
+    let mut i = 1;
+    loop {
+        let byte = match text.get(i) {
+            None | Some(b'\n') => return Err("Unterminated literal".to_owned()),
+            Some(&b) if b == quote => return Ok((bytes, i + 1)),
+            Some(b'\\') => {
+                i += 1;
+                match text.get(i) {
+                    Some(b'n') => b'\n',
+                    Some(b't') => b'\t',
+                    Some(b'0') => 0,
+                    Some(&b @ (b'\\' | b'\'' | b'"')) => b,
+                    _ => return Err("Invalid escape".to_owned()),
+                }
+            }
+            Some(&b) => b,
+        };
+        bytes.push(byte);
+        i += 1;
+    }
+}
+
+struct Parser<'a> {
+    tokens: &'a [(usize, Token)],
+    pos: usize,
+    /// The length of the source, where errors at the end point.
+    end: usize,
+    /// The cell of each variable declared so far.
+    variables: HashMap<String, usize>,
+    cell_size: CellSize,
+}
+
+impl<'a> Parser<'a> {
+    /// Parses statements up to the end of the program, or to the `}` that
+    /// closes a block.
+    fn block(&mut self, nested: bool) -> Result<Vec<Statement>, (usize, String)> {
+        let mut statements = vec![];
+        loop {
+            match self.peek() {
+                None if nested => return Err((self.end, "Missing '}'".to_owned())),
+                None => return Ok(statements),
+                Some(Token::Symbol("}")) if nested => {
+                    self.pos += 1;
+                    return Ok(statements);
+                }
+                _ => {}
+            }
+            let (offset, token) = self.next()?;
+            let statement = match token {
+                Token::Word(word) if word == "var" => {
+                    loop {
+                        let (offset, name) = self.name()?;
+                        if self.variables.contains_key(&name) {
+                            return Err((offset, format!("'{}' is already declared", name)));
+                        }
+                        self.variables.insert(name, self.variables.len());
+                        if self.peek() != Some(&Token::Symbol(",")) {
+                            break;
+                        }
+                        self.pos += 1;
+                    }
+                    continue;
+                }
+                Token::Word(word) if word == "read" => Statement::Read(self.variable()?),
+                Token::Word(word) if word == "write" => match self.peek() {
+                    Some(Token::String(bytes)) => {
+                        let bytes = bytes.clone();
+                        self.pos += 1;
+                        Statement::WriteBytes(bytes)
+                    }
+                    Some(Token::Number(_)) => match self.value()? {
+                        Value::Number(n) => Statement::WriteBytes(vec![n as u8]),
+                        Value::Cell(_) => unreachable!(),
+                    },
+                    _ => Statement::Write(self.variable()?),
+                },
+                Token::Word(word) if word == "while" => {
+                    let cell = self.variable()?;
+                    self.expect("{")?;
+                    Statement::While(cell, self.block(true)?)
+                }
+                Token::Word(word) if word == "if" => {
+                    let cell = self.variable()?;
+                    self.expect("{")?;
+                    let then = self.block(true)?;
+                    let otherwise = match self.peek() {
+                        Some(Token::Word(word)) if word == "else" => {
+                            self.pos += 1;
+                            self.expect("{")?;
+                            self.block(true)?
+                        }
+                        _ => vec![],
+                    };
+                    Statement::If(cell, then, otherwise)
+                }
+                Token::Word(_) => {
+                    self.pos -= 1;
+                    let cell = self.variable()?;
+                    let (offset, token) = self.next()?;
+                    match token {
+                        Token::Symbol("=") => Statement::Set(cell, self.value()?),
+                        Token::Symbol("+=") => Statement::Add(cell, self.value()?),
+                        Token::Symbol("-=") => Statement::Sub(cell, self.value()?),
+                        _ => return Err((offset, "Expected '=', '+=' or '-='".to_owned())),
+                    }
+                }
+                _ => return Err((offset, "Expected a statement".to_owned())),
+            };
+            statements.push(statement);
+        }
+    }
+
+    /// A number, a character or a variable.
+    fn value(&mut self) -> Result<Value, (usize, String)> {
+        match self.peek() {
+            Some(&Token::Number(n)) => {
+                let offset = self.tokens[self.pos].0;
+                self.pos += 1;
+                if n > self.cell_size.max() as u64 {
+                    return Err((offset, format!("{} doesn't fit in a cell", n)));
+                }
+                Ok(Value::Number(n))
+            }
+            _ => Ok(Value::Cell(self.variable()?)),
+        }
+    }
+
+    /// The cell of a declared variable.
+    fn variable(&mut self) -> Result<usize, (usize, String)> {
+        let (offset, name) = self.name()?;
+        self.variables
+            .get(&name)
+            .copied()
+            .ok_or((offset, format!("Undeclared variable '{}'", name)))
+    }
+
+    fn name(&mut self) -> Result<(usize, String), (usize, String)> {
+        match self.next()? {
+            (offset, Token::Word(word)) if !KEYWORDS.contains(&word.as_str()) => {
+                Ok((offset, word.clone()))
+            }
+            (offset, _) => Err((offset, "Expected a variable".to_owned())),
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), (usize, String)> {
+        match self.next()? {
+            (_, Token::Symbol(s)) if *s == symbol => Ok(()),
+            (offset, _) => Err((offset, format!("Expected '{}'", symbol))),
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn next(&mut self) -> Result<(usize, &'a Token), (usize, String)> {
+        let (offset, token) = self
+            .tokens
+            .get(self.pos)
+            .ok_or((self.end, "Unexpected end of program".to_owned()))?;
+        self.pos += 1;
+        Ok((*offset, token))
+    }
+}
+
+struct Compiler {
+    emitter: Emitter,
+    /// The first scratch cell not in use. Cells from here on are 0.
+    scratch: usize,
+}
+
+impl Compiler {
+    fn block(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        let e = &mut self.emitter;
+        let t = self.scratch;
+        match *statement {
+            Statement::Set(cell, Value::Cell(from)) if cell == from => {}
+            Statement::Set(cell, ref value) => {
+                e.clear(cell);
+                self.add(cell, value, true);
+            }
+            Statement::Add(cell, ref value) => self.add(cell, value, true),
+            Statement::Sub(cell, ref value) => self.add(cell, value, false),
+            Statement::Read(cell) => {
+                e.goto(cell);
+                e.push(",");
+            }
+            Statement::Write(cell) => {
+                e.goto(cell);
+                e.push(".");
+            }
+            Statement::WriteBytes(ref bytes) => {
+                let mut value = 0;
+                for &byte in bytes {
+                    e.add(t, (byte as u32).wrapping_sub(value));
+                    e.push(".");
+                    value = byte as u32;
+                }
+                e.clear(t);
+            }
+            Statement::While(cell, ref body) => {
+                e.goto(cell);
+                e.push("[");
+                self.block(body);
+                self.emitter.goto(cell);
+                self.emitter.push("]");
+            }
+            Statement::If(cell, ref then, ref otherwise) => {
+                // Runs `then` once if a copy of the cell isn't 0, clearing a
+                // flag that otherwise runs `otherwise`.
+                let (condition, flag, copy) = (t, t + 1, t + 2);
+                e.move_to(cell, &[(condition, true), (copy, true)]);
+                e.move_to(copy, &[(cell, true)]);
+                if !otherwise.is_empty() {
+                    e.add(flag, 1);
+                }
+                e.goto(condition);
+                e.push("[");
+                e.clear(condition);
+                if !otherwise.is_empty() {
+                    e.clear(flag);
+                }
+                self.scratch += 2;
+                self.block(then);
+                self.emitter.goto(condition);
+                self.emitter.push("]");
+                if !otherwise.is_empty() {
+                    self.emitter.goto(flag);
+                    self.emitter.push("[");
+                    self.emitter.clear(flag);
+                    self.block(otherwise);
+                    self.emitter.goto(flag);
+                    self.emitter.push("]");
+                }
+                self.scratch -= 2;
+            }
+        }
+    }
+
+    /// Adds `value` to `cell`, or subtracts it. A variable is copied through
+    /// a scratch cell, so it keeps its value, even if it's `cell` itself.
+    fn add(&mut self, cell: usize, value: &Value, add: bool) {
+        let e = &mut self.emitter;
+        match *value {
+            Value::Number(n) if add => e.add(cell, n as u32),
+            Value::Number(n) => e.add(cell, (n as u32).wrapping_neg()),
+            Value::Cell(from) => {
+                let t = self.scratch;
+                e.move_to(from, &[(t, true)]);
+                e.move_to(t, &[(cell, add), (from, true)]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::bf_interpreter::BfInterpreter;
+
+    fn run(source: &str, input: &[u8]) -> Vec<u8> {
+        let program = compile(source.as_bytes(), CellSize::Bits8).unwrap();
+        let mut bf = BfInterpreter::builder().build(&program).unwrap();
+        bf.run_to_end(input).unwrap()
+    }
+
+    #[test]
+    fn run_programs() {
+        assert_eq!(run("write \"hi\\n\" write 33", b""), b"hi\n!");
+        let underscores = "
+            # Echoes its input up to a 0, with spaces as underscores.
+            var c, t
+            read c
+            while c {
+                t = c
+                t -= ' '
+                if t { write c } else { write '_' }
+                read c
+            }
+        ";
+        assert_eq!(run(underscores, b"a b  c\0"), b"a_b__c");
+
+        let branches = "
+            var x, y
+            read x
+            y = x
+            y += y
+            if x { write 'T' } else { write 'F' }
+            if y { write y }
+            x -= x
+            if x { write 'T' } else { write 'F' }
+        ";
+        assert_eq!(run(branches, b"\x21"), b"TBF");
+        assert_eq!(run(branches, b"\0"), b"FF");
+    }
+
+    #[test]
+    fn report_errors() {
+        for (source, error) in [
+            ("var a\nb = 1", "2:1: Undeclared variable 'b'"),
+            ("var a, a", "1:8: 'a' is already declared"),
+            ("var a\nwhile a {", "2:10: Missing '}'"),
+            ("var a\na = 256", "2:5: 256 doesn't fit in a cell"),
+            ("var a a 2", "1:9: Expected '=', '+=' or '-='"),
+            ("var while", "1:5: Expected a variable"),
+            ("write 'ab'", "1:7: A character must be a single byte"),
+            ("write \"hi", "1:7: Unterminated literal"),
+            ("}", "1:1: Expected a statement"),
+            ("var a\na =", "2:4: Unexpected end of program"),
+        ] {
+            assert_eq!(
+                compile(source.as_bytes(), CellSize::Bits8).unwrap_err(),
+                error
+            );
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! `--tape-file`: a file mapped into memory, so the kernel pages it in and
+//! out as needed and whatever was written to it is kept if the process dies.
+
+use std::fs::OpenOptions;
+use std::io;
+
+#[derive(Debug)]
+pub(crate) struct MappedFile {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl MappedFile {
+    /// Creates the file at `path`, or empties it if it exists, makes it
+    /// `len` bytes of zeros and maps it.
+    pub(crate) fn create(path: &str, len: usize) -> Result<MappedFile, String> {
+        let error = |e: io::Error| format!("{}: {}", path, e);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(error)?;
+        // Sparse on most file systems, so unused cells take no disk space.
+        file.set_len(len as u64).map_err(error)?;
+        map(&file, len).map_err(error)
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        // SAFETY: the mapping is `len` bytes long and lives as long as self.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    pub(crate) fn bytes_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as in `bytes`, and borrowing self mutably makes this the
+        // only reference to the mapping.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+#[cfg(unix)]
+extern "C" {
+    // `offset` is an off_t, which is a long.
+    fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: isize) -> *mut u8;
+    fn munmap(addr: *mut u8, len: usize) -> i32;
+}
+
+#[cfg(unix)]
+fn map(file: &std::fs::File, len: usize) -> io::Result<MappedFile> {
+    use std::os::unix::io::AsRawFd;
+    const PROT_READ: i32 = 1;
+    const PROT_WRITE: i32 = 2;
+    const MAP_SHARED: i32 = 1;
+
+    // The mapping stays valid after the file is closed.
+    // SAFETY: maps the whole file, which is `len` bytes long, at an address
+    // of the system's choosing.
+    let ptr = unsafe {
+        mmap(
+            std::ptr::null_mut(),
+            len,
+            PROT_READ | PROT_WRITE,
+            MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    if ptr as isize == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(MappedFile { ptr, len })
+}
+
+#[cfg(not(unix))]
+fn map(_: &std::fs::File, _: usize) -> io::Result<MappedFile> {
+    Err(io::Error::other("mapping files needs a Unix-like system"))
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        // SAFETY: unmaps what `map` mapped, which nothing refers to anymore.
+        #[cfg(unix)]
+        unsafe {
+            munmap(self.ptr, self.len);
+        }
+    }
+}
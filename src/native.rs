@@ -0,0 +1,535 @@
+//! `bf run --backend jit` and `--backend asm`: runs a program as native
+//! code, compiled from the IR as the program starts. The code keeps the semantics of the interpreter,
+//! including where the data pointer stops when it runs off the tape, and
+//! calls back into a small runtime here for `.`, `,` and to grow the tape.
+//!
+//! `bf build --backend cranelift` compiles the same code to an object file
+//! instead, linked with a runtime written in C that does the same.
+//!
+//! The `jit` backend needs the `jit` feature, which brings in Cranelift. The
+//! `asm` backend assembles x86-64 code for the System V calling convention
+//! with dynasm, and needs the `asm` feature and such a machine. Without
+//! them `bf run` falls back to the interpreter.
+
+// Without a backend to compile with, the runtime is never called.
+#![cfg_attr(
+    not(any(feature = "jit", all(feature = "asm", target_arch = "x86_64", unix))),
+    allow(dead_code)
+)]
+
+#[cfg(feature = "jit")]
+mod cranelift;
+#[cfg(all(feature = "asm", target_arch = "x86_64", unix))]
+mod dynasm;
+
+use crate::config::{CellSize, Config, Eof, TapeMode};
+use crate::ir::Op;
+use crate::tape::Tape;
+use std::io::{ErrorKind, Read, Write};
+use std::str::FromStr;
+
+/// What runs a program in `bf run`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Backend {
+    /// The interpreter, which supports every option.
+    #[default]
+    Interp,
+    /// Native code compiled with Cranelift.
+    Jit,
+    /// x86-64 code assembled directly.
+    Asm,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interp" => Ok(Backend::Interp),
+            "jit" => Ok(Backend::Jit),
+            "asm" => Ok(Backend::Asm),
+            _ => Err(format!(
+                "Invalid backend '{}', expected interp, jit or asm",
+                s
+            )),
+        }
+    }
+}
+
+impl Backend {
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Interp => "interp",
+            Backend::Jit => "jit",
+            Backend::Asm => "asm",
+        }
+    }
+
+    /// Whether this build can run programs with the backend.
+    pub fn available(self) -> bool {
+        match self {
+            Backend::Interp => true,
+            Backend::Jit => cfg!(feature = "jit"),
+            Backend::Asm => cfg!(all(feature = "asm", target_arch = "x86_64", unix)),
+        }
+    }
+}
+
+/// The tape as compiled code sees it: `len` cells of the configured width
+/// starting at `cells`, and the data pointer as an index into them.
+#[repr(C)]
+struct RawTape {
+    cells: *mut u8,
+    len: usize,
+    ptr: usize,
+}
+
+/// What compiled code returns: the program finished, the runtime stopped
+/// it, or the data pointer ran off the tape.
+const FINISHED: u32 = 0;
+const STOPPED: u32 = 1;
+const OVERFLOW: u32 = 2;
+const UNDERFLOW: u32 = 3;
+
+/// A compiled program, called with the runtime and the tape.
+type Entry = unsafe extern "C" fn(*mut Runtime, *mut RawTape) -> u32;
+
+/// Machine code for a program, freed when dropped.
+trait Code {
+    fn entry(&self) -> Entry;
+}
+
+/// What the callbacks of a running program work with.
+struct Runtime<'a> {
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    cells: Vec<u8>,
+    cell_size: CellSize,
+    eof: Eof,
+    /// Why the runtime stopped the program. It stops without one when `,`
+    /// sees the end of input with `--eof halt`.
+    error: Option<String>,
+}
+
+/// `.`: writes the low byte of `value`. Returns 0 to carry on.
+extern "C" fn output(runtime: &mut Runtime, value: u32) -> u32 {
+    match runtime.output.write_all(&[value as u8]) {
+        Ok(()) => 0,
+        Err(e) => {
+            runtime.error = Some(format!("Failed to write output: {}", e));
+            1
+        }
+    }
+}
+
+/// `,`: returns the new value of the current cell, which holds `current`,
+/// or -1 to stop the program.
+extern "C" fn input(runtime: &mut Runtime, current: u32) -> i64 {
+    if let Err(e) = runtime.output.flush() {
+        runtime.error = Some(format!("Failed to write output: {}", e));
+        return -1;
+    }
+    let mut buf = [0u8; 1];
+    match runtime.input.read_exact(&mut buf) {
+        Ok(_) => buf[0] as i64,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => match runtime.eof {
+            Eof::Zero => 0,
+            Eof::MinusOne => runtime.cell_size.max() as i64,
+            Eof::Unchanged => current as i64,
+            Eof::Halt => -1,
+        },
+        Err(e) => {
+            runtime.error = Some(format!("Failed to read input: {}", e));
+            -1
+        }
+    }
+}
+
+/// Doubles a growing tape until cell `index` is on it, like the interpreter.
+extern "C" fn grow(runtime: &mut Runtime, tape: &mut RawTape, index: usize) {
+    let mut len = tape.len;
+    while len <= index {
+        len = len.saturating_mul(2);
+    }
+    let bytes = runtime.cell_size.bytes();
+    runtime.cells.resize(len.saturating_mul(bytes), 0);
+    tape.cells = runtime.cells.as_mut_ptr();
+    tape.len = len;
+}
+
+/// A program compiled to native code, with its tape.
+pub struct Program {
+    code: Box<dyn Code>,
+    cells: Vec<u8>,
+    cell_size: CellSize,
+    eof: Eof,
+    data_ptr: usize,
+}
+
+impl Program {
+    /// Compiles `ops` for `backend`, which must be available. Only plain
+    /// Brainfuck compiles: no dialect extensions or fused instructions.
+    pub fn compile(backend: Backend, ops: &[Op], config: &Config) -> Result<Program, String> {
+        check_plain(backend.name(), ops)?;
+        let cells = config
+            .tape_size
+            .checked_mul(config.cell_size.bytes())
+            .ok_or("Memory overflow")?;
+
+        Ok(Program {
+            code: code(backend, ops, config)?,
+            cells: vec![0; cells],
+            cell_size: config.cell_size,
+            eof: config.eof,
+            data_ptr: 0,
+        })
+    }
+
+    /// Runs the program to the end, reading `,` from `input` and writing `.`
+    /// to `output`. Output is flushed before every read and at the end, as
+    /// with the interpreter.
+    pub fn run(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), String> {
+        let mut runtime = Runtime {
+            input,
+            output,
+            cells: std::mem::take(&mut self.cells),
+            cell_size: self.cell_size,
+            eof: self.eof,
+            error: None,
+        };
+        let mut tape = RawTape {
+            cells: runtime.cells.as_mut_ptr(),
+            len: runtime.cells.len() / self.cell_size.bytes(),
+            ptr: self.data_ptr,
+        };
+        // SAFETY: the code was compiled for this cell size and tape mode, and
+        // only touches the cells of `tape`, whose memory `runtime` owns.
+        let status = unsafe { (self.code.entry())(&mut runtime, &mut tape) };
+        self.cells = runtime.cells;
+        self.data_ptr = tape.ptr;
+
+        let result = match status {
+            FINISHED => Ok(()),
+            OVERFLOW => Err("Memory overflow".to_owned()),
+            UNDERFLOW => Err("Memory underflow".to_owned()),
+            _ => runtime.error.map_or(Ok(()), Err),
+        };
+        runtime
+            .output
+            .flush()
+            .map_err(|e| format!("Failed to write output: {}", e))?;
+        result
+    }
+
+    pub fn data_ptr(&self) -> usize {
+        self.data_ptr
+    }
+
+    /// A copy of the tape, to compare with the interpreter's.
+    pub fn tape(&self) -> Tape {
+        let bytes = self.cell_size.bytes();
+        let mut tape = Tape::new(self.cell_size, self.cells.len() / bytes);
+        for (i, cell) in self.cells.chunks_exact(bytes).enumerate() {
+            let value = match *cell {
+                [a] => a as u32,
+                [a, b] => u16::from_ne_bytes([a, b]) as u32,
+                [a, b, c, d] => u32::from_ne_bytes([a, b, c, d]),
+                _ => unreachable!(),
+            };
+            if value != 0 {
+                tape.set(i, value);
+            }
+        }
+        tape
+    }
+}
+
+/// Compiles `ops` to machine code with `backend`.
+#[cfg_attr(
+    not(any(feature = "jit", all(feature = "asm", target_arch = "x86_64", unix))),
+    allow(unused_variables)
+)]
+fn code(backend: Backend, ops: &[Op], config: &Config) -> Result<Box<dyn Code>, String> {
+    match backend {
+        #[cfg(feature = "jit")]
+        Backend::Jit => Ok(Box::new(cranelift::compile(ops, config)?)),
+        #[cfg(all(feature = "asm", target_arch = "x86_64", unix))]
+        Backend::Asm => Ok(Box::new(dynasm::compile(ops, config)?)),
+        _ => Err(format!("This build has no {} backend", backend.name())),
+    }
+}
+
+/// Errors unless `ops` are plain Brainfuck: no dialect extensions or fused
+/// instructions.
+fn check_plain(backend: &str, ops: &[Op]) -> Result<(), String> {
+    let plain = |op: &Op| {
+        matches!(
+            op,
+            Op::Add(_)
+                | Op::Move(_)
+                | Op::Set(_)
+                | Op::Output
+                | Op::Input
+                | Op::LoopStart(_)
+                | Op::LoopEnd(_)
+                | Op::Debug
+        )
+    };
+    if !ops.iter().all(plain) {
+        return Err(format!("The {} backend only runs Brainfuck", backend));
+    }
+    Ok(())
+}
+
+/// Compiles `ops` to an object file for this machine with Cranelift, for
+/// `bf build --backend cranelift`. `opt_level` is Cranelift's setting:
+/// none, speed or speed_and_size. The object defines `bf_program`, which
+/// needs the runtime from `runtime_c` to link.
+#[cfg_attr(not(feature = "jit"), allow(unused_variables))]
+pub fn object(ops: &[Op], config: &Config, opt_level: &str) -> Result<Vec<u8>, String> {
+    check_plain("cranelift", ops)?;
+    #[cfg(feature = "jit")]
+    {
+        cranelift::object(ops, config, opt_level)
+    }
+    #[cfg(not(feature = "jit"))]
+    {
+        Err("This build has no cranelift backend, it needs the jit feature".to_owned())
+    }
+}
+
+/// The runtime of `object` in C: `main`, which sets up the tape and calls
+/// `bf_program`, and the callbacks, which behave like the ones here.
+pub fn runtime_c(config: &Config) -> String {
+    let on_eof = match config.eof {
+        Eof::Zero => "return 0",
+        Eof::MinusOne => "return CELL_MAX",
+        Eof::Unchanged => "return current",
+        Eof::Halt => "return -1",
+    };
+    format!(
+        r#"#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#define TAPE_SIZE {tape_size}
+#define CELL_BYTES {bytes}
+#define CELL_MAX {max}u
+
+struct tape {{
+    unsigned char *cells;
+    size_t len;
+    size_t ptr;
+}};
+
+uint32_t bf_program(void *runtime, struct tape *tape);
+
+static void fail(const char *msg) {{
+    fflush(stdout);
+    fprintf(stderr, "ERROR: %s\n", msg);
+    exit(EXIT_FAILURE);
+}}
+
+uint32_t bf_output(void *runtime, uint32_t value) {{
+    (void)runtime;
+    return putchar((unsigned char)value) == EOF;
+}}
+
+int64_t bf_input(void *runtime, uint32_t current) {{
+    int c;
+    (void)runtime;
+    (void)current;
+    fflush(stdout);
+    c = getchar();
+    if (c == EOF) {on_eof};
+    return (unsigned char)c;
+}}
+
+void bf_grow(void *runtime, struct tape *tape, size_t index) {{
+    size_t len = tape->len;
+    (void)runtime;
+    while (len <= index) len *= 2;
+    tape->cells = realloc(tape->cells, len * CELL_BYTES);
+    if (!tape->cells) fail("Memory overflow");
+    memset(tape->cells + tape->len * CELL_BYTES, 0, (len - tape->len) * CELL_BYTES);
+    tape->len = len;
+}}
+
+int main(void) {{
+    struct tape tape = {{ calloc(TAPE_SIZE, CELL_BYTES), TAPE_SIZE, 0 }};
+    if (!tape.cells) fail("Memory overflow");
+    switch (bf_program(NULL, &tape)) {{
+    case {overflow}: fail("Memory overflow");
+    case {underflow}: fail("Memory underflow");
+    }}
+    if (fflush(stdout) == EOF || ferror(stdout)) fail("Failed to write output");
+    return 0;
+}}
+"#,
+        tape_size = config.tape_size,
+        bytes = config.cell_size.bytes(),
+        max = config.cell_size.max(),
+        on_eof = on_eof,
+        overflow = OVERFLOW,
+        underflow = UNDERFLOW,
+    )
+}
+
+/// The move to the right by less than the tape size that a move by `n`
+/// makes on a wrapping tape, or `n` itself on other tapes.
+fn wrapped_move(config: &Config, n: isize) -> isize {
+    match config.tape_mode {
+        TapeMode::Wrap => config.wrapped_move(n) as isize,
+        TapeMode::Fixed | TapeMode::Grow => n,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::ir;
+    use std::io::Cursor;
+
+    /// Runs `program` on `input` with each backend this build has and with
+    /// the interpreter, and checks that they end the same way.
+    fn run_both(program: &[u8], input: &[u8], config: Config) -> (Result<(), String>, Vec<u8>) {
+        let ops = ir::compile(program).unwrap();
+        let mut interpreter = crate::bf_interpreter::BfInterpreter::builder()
+            .config(config.clone())
+            .build_ops(ops.clone())
+            .unwrap();
+        let mut expected = vec![];
+        let result = crate::runner::run_interpreter(
+            &mut interpreter,
+            &mut Cursor::new(input),
+            &mut expected,
+            None,
+            None,
+            None,
+            false,
+        );
+
+        for backend in [Backend::Jit, Backend::Asm] {
+            if !backend.available() {
+                continue;
+            }
+            let mut compiled = Program::compile(backend, &ops, &config).unwrap();
+            let mut output = vec![];
+            let compiled_result = compiled.run(&mut Cursor::new(input), &mut output);
+            assert_eq!(compiled_result, result, "{}", backend.name());
+            assert_eq!(output, expected, "{}", backend.name());
+            assert_eq!(compiled.data_ptr(), interpreter.data_ptr());
+            let cells: Vec<_> = compiled.tape().nonzero().collect();
+            assert_eq!(cells, interpreter.tape().nonzero().collect::<Vec<_>>());
+        }
+        (result, expected)
+    }
+
+    #[test]
+    fn run_programs() {
+        let hello = b"++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.";
+        assert_eq!(
+            run_both(hello, b"", Config::default()),
+            (Ok(()), b"Hello".to_vec())
+        );
+        assert_eq!(run_both(b",[.,]", b"echo", Config::default()).1, b"echo");
+        assert_eq!(
+            run_both(b"+[>+]", b"", Config::default()).0,
+            Err("Memory overflow".to_owned())
+        );
+        assert_eq!(
+            run_both(b">><<<", b"", Config::default()).0,
+            Err("Memory underflow".to_owned())
+        );
+    }
+
+    #[test]
+    fn run_with_config() {
+        let eof = |eof| Config {
+            eof,
+            ..Config::default()
+        };
+        assert_eq!(run_both(b",+.", b"", eof(Eof::Zero)).1, [1]);
+        assert_eq!(run_both(b"+++,.", b"", eof(Eof::Unchanged)).1, [3]);
+        assert_eq!(run_both(b",.", b"", eof(Eof::MinusOne)).1, [255]);
+        assert_eq!(run_both(b"+.,+.", b"", eof(Eof::Halt)).1, [1]);
+
+        let wide = Config {
+            cell_size: CellSize::Bits16,
+            ..Config::default()
+        };
+        assert_eq!(run_both(b"-[>+<-]>>-<<+.", b"", wide).1, [1]);
+        let wrap = Config {
+            tape_size: 5,
+            tape_mode: TapeMode::Wrap,
+            ..Config::default()
+        };
+        assert!(run_both(b"+<<+>>>>>>>+[>>>+]", b"", wrap).0.is_ok());
+        let grow = Config {
+            tape_size: 4,
+            tape_mode: TapeMode::Grow,
+            ..Config::default()
+        };
+        assert_eq!(run_both(b"+>>>>>>>>>>>>>>+<<<[-]++.", b"", grow).1, [2]);
+    }
+
+    /// Builds `code` with the C runtime into an executable in `dir`.
+    #[cfg(all(feature = "jit", unix))]
+    fn link(dir: &std::path::Path, code: &[u8], config: &Config) -> std::path::PathBuf {
+        let ops = ir::compile(code).unwrap();
+        let object = dir.join("program.o");
+        std::fs::write(&object, super::object(&ops, config, "speed").unwrap()).unwrap();
+        let runtime = dir.join("runtime.c");
+        std::fs::write(&runtime, runtime_c(config)).unwrap();
+        let exe = dir.join("program");
+        let built = std::process::Command::new("cc")
+            .arg(&runtime)
+            .arg(&object)
+            .arg("-o")
+            .arg(&exe)
+            .status()
+            .unwrap();
+        assert!(built.success());
+        exe
+    }
+
+    #[cfg(all(feature = "jit", unix))]
+    #[test]
+    fn link_object() {
+        let dir = crate::temp_dir::TempDir::new("bf-native-test").unwrap();
+        let config = Config {
+            tape_size: 4,
+            tape_mode: TapeMode::Grow,
+            eof: Eof::Zero,
+            ..Config::default()
+        };
+        let exe = link(dir.path(), b",[.,]>>>>>>>>+.<<<<<<<<<<", &config);
+
+        let mut child = std::process::Command::new(&exe)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(b"hi").unwrap();
+        let ran = child.wait_with_output().unwrap();
+        assert_eq!(ran.stdout, b"hi\x01");
+        assert_eq!(ran.stderr, b"ERROR: Memory underflow\n");
+        assert!(!ran.status.success());
+    }
+
+    #[cfg(all(feature = "jit", target_os = "linux"))]
+    #[test]
+    fn link_object_output_fails() {
+        let dir = crate::temp_dir::TempDir::new("bf-native-test").unwrap();
+        let exe = link(dir.path(), b"++++++++[>++++++++<-]>+.", &Config::default());
+        let ran = std::process::Command::new(&exe)
+            .stdout(std::fs::File::create("/dev/full").unwrap())
+            .output()
+            .unwrap();
+        assert_eq!(ran.stderr, b"ERROR: Failed to write output\n");
+        assert!(!ran.status.success());
+    }
+}
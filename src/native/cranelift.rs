@@ -0,0 +1,355 @@
+use super::{Code, Entry, RawTape, FINISHED, OVERFLOW, STOPPED, UNDERFLOW};
+use crate::config::{CellSize, Config, TapeMode};
+use crate::ir::Op;
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, Block, InstBuilder, MemFlags, Type, Value};
+use cranelift_codegen::isa::OwnedTargetIsa;
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+use std::mem::offset_of;
+
+/// A program compiled in memory.
+pub(super) struct Jit {
+    /// Only `None` while it's being freed.
+    module: Option<JITModule>,
+    entry: Entry,
+}
+
+impl Code for Jit {
+    fn entry(&self) -> Entry {
+        self.entry
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        // SAFETY: `entry` can't be called once `self` is gone.
+        unsafe { self.module.take().unwrap().free_memory() }
+    }
+}
+
+/// Compiles `ops` to machine code for this machine.
+pub(super) fn compile(ops: &[Op], config: &Config) -> Result<Jit, String> {
+    let error = |e: &dyn std::fmt::Display| format!("Cranelift failed: {}", e);
+    let mut builder = JITBuilder::with_isa(isa("speed")?, default_libcall_names());
+    builder.symbols([
+        ("bf_output", super::output as *const u8),
+        ("bf_input", super::input as *const u8),
+        ("bf_grow", super::grow as *const u8),
+    ]);
+    let mut module = JITModule::new(builder);
+
+    let id = define(&mut module, "bf_program", ops, config)?;
+    module.finalize_definitions().map_err(|e| error(&e))?;
+    let code = module.get_finalized_function(id);
+    Ok(Jit {
+        module: Some(module),
+        // SAFETY: `define` gives the function the signature of `Entry`.
+        entry: unsafe { std::mem::transmute::<*const u8, Entry>(code) },
+    })
+}
+
+/// Compiles `ops` to an object file for this machine, defining
+/// `bf_program` for the runtime from `super::runtime_c` to call.
+pub(super) fn object(ops: &[Op], config: &Config, opt_level: &str) -> Result<Vec<u8>, String> {
+    let error = |e: &dyn std::fmt::Display| format!("Cranelift failed: {}", e);
+    let builder = ObjectBuilder::new(isa(opt_level)?, "bf", default_libcall_names())
+        .map_err(|e| error(&e))?;
+    let mut module = ObjectModule::new(builder);
+    define(&mut module, "bf_program", ops, config)?;
+    module.finish().emit().map_err(|e| error(&e))
+}
+
+/// The target for this machine, with Cranelift's `opt_level` setting.
+fn isa(opt_level: &str) -> Result<OwnedTargetIsa, String> {
+    let mut flags = settings::builder();
+    // As `JITBuilder::new` sets them, so calls reach the runtime wherever
+    // it is in memory, and as position-independent executables need.
+    flags.set("use_colocated_libcalls", "false").unwrap();
+    flags.set("is_pic", "true").unwrap();
+    flags.set("opt_level", opt_level).unwrap();
+    cranelift_native::builder()
+        .map_err(|e| format!("Cranelift doesn't support this machine: {}", e))?
+        .finish(settings::Flags::new(flags))
+        .map_err(|e| format!("Cranelift failed: {}", e))
+}
+
+/// Defines the function `name` in `module` that runs `ops`. It takes the
+/// runtime and the `RawTape` and returns how the program ended, and calls
+/// `bf_output`, `bf_input` and `bf_grow` for `.`, `,` and to grow the tape.
+pub(super) fn define<M: Module>(
+    module: &mut M,
+    name: &str,
+    ops: &[Op],
+    config: &Config,
+) -> Result<FuncId, String> {
+    let error = |e: cranelift_module::ModuleError| format!("Cranelift failed: {}", e);
+    let pointer = module.target_config().pointer_type();
+    let signature = |params: &[Type], returns: &[Type]| {
+        let mut signature = module.make_signature();
+        signature.params = params.iter().map(|&ty| AbiParam::new(ty)).collect();
+        signature.returns = returns.iter().map(|&ty| AbiParam::new(ty)).collect();
+        signature
+    };
+    let entry = signature(&[pointer, pointer], &[types::I32]);
+    let output = signature(&[pointer, types::I32], &[types::I32]);
+    let input = signature(&[pointer, types::I32], &[types::I64]);
+    let grow = signature(&[pointer, pointer, pointer], &[]);
+    let output = module
+        .declare_function("bf_output", Linkage::Import, &output)
+        .map_err(error)?;
+    let input = module
+        .declare_function("bf_input", Linkage::Import, &input)
+        .map_err(error)?;
+    let grow = module
+        .declare_function("bf_grow", Linkage::Import, &grow)
+        .map_err(error)?;
+    let id = module
+        .declare_function(name, Linkage::Export, &entry)
+        .map_err(error)?;
+
+    let mut context = module.make_context();
+    context.func.signature = entry;
+    let mut builder_context = FunctionBuilderContext::new();
+    let mut b = FunctionBuilder::new(&mut context.func, &mut builder_context);
+    let output = module.declare_func_in_func(output, b.func);
+    let input = module.declare_func_in_func(input, b.func);
+    let grow = module.declare_func_in_func(grow, b.func);
+
+    let start = b.create_block();
+    b.append_block_params_for_function_params(start);
+    b.switch_to_block(start);
+    let runtime = b.block_params(start)[0];
+    let tape = b.block_params(start)[1];
+    let exit = b.create_block();
+    b.append_block_param(exit, types::I32);
+    // Where moves off the tape go, with the data pointer stopped at the end
+    // it ran off like the interpreter's.
+    let overflow = b.create_block();
+    let underflow = b.create_block();
+    let mut f = Function {
+        b,
+        pointer,
+        cell: match config.cell_size {
+            CellSize::Bits8 => types::I8,
+            CellSize::Bits16 => types::I16,
+            CellSize::Bits32 => types::I32,
+        },
+        shift: config.cell_size.bytes().trailing_zeros() as i64,
+        tape,
+        cells: Variable::from_u32(0),
+        len: Variable::from_u32(1),
+        ptr: Variable::from_u32(2),
+        exit,
+    };
+    for var in [f.cells, f.len, f.ptr] {
+        f.b.declare_var(var, pointer);
+    }
+    f.load_tape();
+    let ptr = f.field(offset_of!(RawTape, ptr));
+    f.b.def_var(f.ptr, ptr);
+
+    let mut loops = vec![];
+    for op in ops.iter().copied() {
+        match op {
+            Op::Add(n) => {
+                let n = n & config.cell_size.max();
+                let (address, value) = f.load_cell();
+                let n = f.b.ins().iconst(f.cell, n as i64);
+                let value = f.b.ins().iadd(value, n);
+                f.store_cell(address, value);
+            }
+            Op::Set(n) => {
+                let n = n & config.cell_size.max();
+                let address = f.cell_address();
+                let value = f.b.ins().iconst(f.cell, n as i64);
+                f.store_cell(address, value);
+            }
+            Op::Move(n) => {
+                let n = super::wrapped_move(config, n);
+                let ptr = f.b.use_var(f.ptr);
+                let len = f.b.use_var(f.len);
+                if n < 0 {
+                    let n = n.unsigned_abs() as i64;
+                    let off = f.b.ins().icmp_imm(IntCC::UnsignedLessThan, ptr, n);
+                    f.branch_if(off, underflow, &[]);
+                    let ptr = f.b.ins().iadd_imm(ptr, -n);
+                    f.b.def_var(f.ptr, ptr);
+                    continue;
+                }
+                if n == 0 {
+                    continue;
+                }
+                let moved = f.b.ins().iadd_imm(ptr, n as i64);
+                let off =
+                    f.b.ins()
+                        .icmp(IntCC::UnsignedGreaterThanOrEqual, moved, len);
+                match config.tape_mode {
+                    TapeMode::Fixed => {
+                        f.branch_if(off, overflow, &[]);
+                        f.b.def_var(f.ptr, moved);
+                    }
+                    TapeMode::Grow => {
+                        let grown = f.b.create_block();
+                        let next = f.b.create_block();
+                        f.b.ins().brif(off, grown, &[], next, &[]);
+                        f.b.switch_to_block(grown);
+                        f.b.ins().call(grow, &[runtime, tape, moved]);
+                        f.load_tape();
+                        f.b.ins().jump(next, &[]);
+                        f.b.switch_to_block(next);
+                        f.b.def_var(f.ptr, moved);
+                    }
+                    // `n` is less than the tape size, so it wraps around
+                    // at most once.
+                    TapeMode::Wrap => {
+                        let wrapped = f.b.ins().isub(moved, len);
+                        let ptr = f.b.ins().select(off, wrapped, moved);
+                        f.b.def_var(f.ptr, ptr);
+                    }
+                }
+            }
+            Op::Output => {
+                let (_, value) = f.load_cell();
+                let value = f.extend(value);
+                let call = f.b.ins().call(output, &[runtime, value]);
+                let failed = f.b.inst_results(call)[0];
+                let stopped = f.b.ins().iconst(types::I32, STOPPED as i64);
+                f.branch_if(failed, f.exit, &[stopped]);
+            }
+            Op::Input => {
+                let (address, value) = f.load_cell();
+                let value = f.extend(value);
+                let call = f.b.ins().call(input, &[runtime, value]);
+                let value = f.b.inst_results(call)[0];
+                let stop = f.b.ins().icmp_imm(IntCC::SignedLessThan, value, 0);
+                let stopped = f.b.ins().iconst(types::I32, STOPPED as i64);
+                f.branch_if(stop, f.exit, &[stopped]);
+                let value = f.b.ins().ireduce(f.cell, value);
+                f.store_cell(address, value);
+            }
+            Op::LoopStart(_) => {
+                let body = f.b.create_block();
+                let after = f.b.create_block();
+                let (_, value) = f.load_cell();
+                f.b.ins().brif(value, body, &[], after, &[]);
+                f.b.switch_to_block(body);
+                loops.push((body, after));
+            }
+            Op::LoopEnd(_) => {
+                let (body, after) = loops.pop().unwrap();
+                let (_, value) = f.load_cell();
+                f.b.ins().brif(value, body, &[], after, &[]);
+                f.b.switch_to_block(after);
+            }
+            // Only `bf run --debug-char` has `#`, and it needs the
+            // interpreter.
+            Op::Debug => {}
+            _ => unreachable!("only Brainfuck is compiled"),
+        }
+    }
+    let finished = f.b.ins().iconst(types::I32, FINISHED as i64);
+    f.b.ins().jump(f.exit, &[finished]);
+
+    f.b.switch_to_block(overflow);
+    let len = f.b.use_var(f.len);
+    let last = f.b.ins().iadd_imm(len, -1);
+    f.b.def_var(f.ptr, last);
+    let status = f.b.ins().iconst(types::I32, OVERFLOW as i64);
+    f.b.ins().jump(f.exit, &[status]);
+
+    f.b.switch_to_block(underflow);
+    let first = f.b.ins().iconst(pointer, 0);
+    f.b.def_var(f.ptr, first);
+    let status = f.b.ins().iconst(types::I32, UNDERFLOW as i64);
+    f.b.ins().jump(f.exit, &[status]);
+
+    f.b.switch_to_block(f.exit);
+    let status = f.b.block_params(f.exit)[0];
+    let ptr = f.b.use_var(f.ptr);
+    f.b.ins().store(
+        MemFlags::trusted(),
+        ptr,
+        tape,
+        offset_of!(RawTape, ptr) as i32,
+    );
+    f.b.ins().return_(&[status]);
+    f.b.seal_all_blocks();
+    f.b.finalize();
+
+    module.define_function(id, &mut context).map_err(error)?;
+    Ok(id)
+}
+
+/// The function being built and where it keeps the tape.
+struct Function<'a> {
+    b: FunctionBuilder<'a>,
+    pointer: Type,
+    cell: Type,
+    /// Bytes per cell as a power of 2.
+    shift: i64,
+    /// The `RawTape` argument.
+    tape: Value,
+    /// `RawTape::cells` and `RawTape::len`, reloaded after the tape grows.
+    cells: Variable,
+    len: Variable,
+    /// The data pointer, stored in `RawTape::ptr` on the way out.
+    ptr: Variable,
+    /// Returns the status it is given.
+    exit: Block,
+}
+
+impl Function<'_> {
+    fn field(&mut self, offset: usize) -> Value {
+        self.b
+            .ins()
+            .load(self.pointer, MemFlags::trusted(), self.tape, offset as i32)
+    }
+
+    fn load_tape(&mut self) {
+        let cells = self.field(offset_of!(RawTape, cells));
+        self.b.def_var(self.cells, cells);
+        let len = self.field(offset_of!(RawTape, len));
+        self.b.def_var(self.len, len);
+    }
+
+    fn cell_address(&mut self) -> Value {
+        let cells = self.b.use_var(self.cells);
+        let ptr = self.b.use_var(self.ptr);
+        let offset = self.b.ins().ishl_imm(ptr, self.shift);
+        self.b.ins().iadd(cells, offset)
+    }
+
+    /// The address and the value of the current cell.
+    fn load_cell(&mut self) -> (Value, Value) {
+        let address = self.cell_address();
+        let flags = MemFlags::new().with_notrap();
+        let value = self.b.ins().load(self.cell, flags, address, 0);
+        (address, value)
+    }
+
+    fn store_cell(&mut self, address: Value, value: Value) {
+        let flags = MemFlags::new().with_notrap();
+        self.b.ins().store(flags, value, address, 0);
+    }
+
+    /// Widens a cell value to the `u32` the runtime takes.
+    fn extend(&mut self, value: Value) -> Value {
+        if self.cell == types::I32 {
+            value
+        } else {
+            self.b.ins().uextend(types::I32, value)
+        }
+    }
+
+    /// Goes to `target` if `condition` holds, and carries on in a new block
+    /// otherwise.
+    fn branch_if(&mut self, condition: Value, target: Block, args: &[Value]) {
+        let next = self.b.create_block();
+        self.b.ins().brif(condition, target, args, next, &[]);
+        self.b.switch_to_block(next);
+    }
+}
@@ -0,0 +1,239 @@
+use super::{Code, Entry, RawTape, FINISHED, OVERFLOW, STOPPED, UNDERFLOW};
+use crate::config::{CellSize, Config, TapeMode};
+use crate::ir::Op;
+use dynasmrt::x64::Assembler;
+use dynasmrt::{dynasm, AssemblyOffset, DynasmApi, DynasmLabelApi, ExecutableBuffer};
+use std::mem::offset_of;
+
+// Registers, all callee-saved so they survive calls into the runtime:
+// r12 holds the runtime, r13 the `RawTape`, r14 the address of the cells,
+// r15 the size of the tape in bytes and rbx the data pointer as a byte
+// offset into the cells.
+
+/// A program assembled in memory.
+pub(super) struct Asm {
+    buffer: ExecutableBuffer,
+    start: AssemblyOffset,
+}
+
+impl Code for Asm {
+    fn entry(&self) -> Entry {
+        // SAFETY: `compile` assembles a function with the signature of
+        // `Entry` at `start`.
+        unsafe { std::mem::transmute::<*const u8, Entry>(self.buffer.ptr(self.start)) }
+    }
+}
+
+/// Assembles `ops` to x86-64 code, for the System V calling convention.
+pub(super) fn compile(ops: &[Op], config: &Config) -> Result<Asm, String> {
+    let error = |e: &dyn std::fmt::Display| format!("Failed to assemble the program: {}", e);
+    let mut a = Assembler::new().map_err(|e| error(&e))?;
+    let bytes = config.cell_size.bytes() as i64;
+    let shift = config.cell_size.bytes().trailing_zeros() as i8;
+    let exit = a.new_dynamic_label();
+    let stopped = a.new_dynamic_label();
+    let overflow = a.new_dynamic_label();
+    let underflow = a.new_dynamic_label();
+
+    let start = a.offset();
+    // Five pushes after the return address leave the stack aligned to 16
+    // bytes for calls.
+    dynasm!(a
+        ; .arch x64
+        ; push rbx
+        ; push r12
+        ; push r13
+        ; push r14
+        ; push r15
+        ; mov r12, rdi
+        ; mov r13, rsi
+        ; mov rbx, [r13 + offset_of!(RawTape, ptr) as i32]
+        ; shl rbx, shift
+    );
+    load_tape(&mut a, shift);
+
+    let mut loops = vec![];
+    for op in ops.iter().copied() {
+        match op {
+            Op::Add(n) => {
+                let n = n & config.cell_size.max();
+                match config.cell_size {
+                    CellSize::Bits8 => dynasm!(a; .arch x64; add BYTE [r14 + rbx], n as i8),
+                    CellSize::Bits16 => dynasm!(a; .arch x64; add WORD [r14 + rbx], n as i16),
+                    CellSize::Bits32 => dynasm!(a; .arch x64; add DWORD [r14 + rbx], n as i32),
+                }
+            }
+            Op::Set(n) => {
+                let n = n & config.cell_size.max();
+                match config.cell_size {
+                    CellSize::Bits8 => dynasm!(a; .arch x64; mov BYTE [r14 + rbx], n as i8),
+                    CellSize::Bits16 => dynasm!(a; .arch x64; mov WORD [r14 + rbx], n as i16),
+                    CellSize::Bits32 => dynasm!(a; .arch x64; mov DWORD [r14 + rbx], n as i32),
+                }
+            }
+            Op::Move(n) => {
+                let n = super::wrapped_move(config, n) as i64 * bytes;
+                // Moves as far as a 32-bit immediate reaches are the usual.
+                if n < 0 {
+                    let n = -n;
+                    // Borrows if the data pointer is less than the move.
+                    match i32::try_from(n) {
+                        Ok(n) => dynasm!(a; .arch x64; sub rbx, n),
+                        Err(_) => dynasm!(a; .arch x64; mov rax, QWORD n; sub rbx, rax),
+                    }
+                    dynasm!(a; .arch x64; jb =>underflow);
+                    continue;
+                }
+                match i32::try_from(n).ok() {
+                    Some(0) => continue,
+                    Some(n) => dynasm!(a; .arch x64; lea rax, [rbx + n]),
+                    None => dynasm!(a; .arch x64; mov rax, QWORD n; add rax, rbx),
+                }
+                match config.tape_mode {
+                    TapeMode::Fixed => dynasm!(a
+                        ; .arch x64
+                        ; cmp rax, r15
+                        ; jae =>overflow
+                        ; mov rbx, rax
+                    ),
+                    TapeMode::Grow => {
+                        let next = a.new_dynamic_label();
+                        dynasm!(a
+                            ; .arch x64
+                            ; mov rbx, rax
+                            ; cmp rbx, r15
+                            ; jb =>next
+                            ; mov rdi, r12
+                            ; mov rsi, r13
+                            ; mov rdx, rbx
+                            ; shr rdx, shift
+                            ; mov rax, QWORD super::grow as *const u8 as i64
+                            ; call rax
+                        );
+                        load_tape(&mut a, shift);
+                        dynasm!(a; .arch x64; =>next);
+                    }
+                    // The move is less than the tape size, so it wraps
+                    // around at most once.
+                    TapeMode::Wrap => dynasm!(a
+                        ; .arch x64
+                        ; mov rbx, rax
+                        ; sub rax, r15
+                        ; cmovae rbx, rax
+                    ),
+                }
+            }
+            Op::Output => {
+                load_cell(&mut a, config.cell_size);
+                dynasm!(a
+                    ; .arch x64
+                    ; mov rdi, r12
+                    ; mov rax, QWORD super::output as *const u8 as i64
+                    ; call rax
+                    ; test eax, eax
+                    ; jnz =>stopped
+                );
+            }
+            Op::Input => {
+                load_cell(&mut a, config.cell_size);
+                dynasm!(a
+                    ; .arch x64
+                    ; mov rdi, r12
+                    ; mov rax, QWORD super::input as *const u8 as i64
+                    ; call rax
+                    ; test rax, rax
+                    ; js =>stopped
+                );
+                match config.cell_size {
+                    CellSize::Bits8 => dynasm!(a; .arch x64; mov [r14 + rbx], al),
+                    CellSize::Bits16 => dynasm!(a; .arch x64; mov [r14 + rbx], ax),
+                    CellSize::Bits32 => dynasm!(a; .arch x64; mov [r14 + rbx], eax),
+                }
+            }
+            Op::LoopStart(_) => {
+                let body = a.new_dynamic_label();
+                let after = a.new_dynamic_label();
+                compare_cell(&mut a, config.cell_size);
+                dynasm!(a
+                    ; .arch x64
+                    ; je =>after
+                    ; =>body
+                );
+                loops.push((body, after));
+            }
+            Op::LoopEnd(_) => {
+                let (body, after) = loops.pop().unwrap();
+                compare_cell(&mut a, config.cell_size);
+                dynasm!(a
+                    ; .arch x64
+                    ; jne =>body
+                    ; =>after
+                );
+            }
+            // Only `bf run --debug-char` has `#`, and it needs the
+            // interpreter.
+            Op::Debug => {}
+            _ => unreachable!("only Brainfuck is compiled"),
+        }
+    }
+
+    // The data pointer stops at the end it ran off, like the interpreter's.
+    dynasm!(a
+        ; .arch x64
+        ; mov eax, FINISHED as i32
+        ; jmp =>exit
+        ; =>stopped
+        ; mov eax, STOPPED as i32
+        ; jmp =>exit
+        ; =>overflow
+        ; lea rbx, [r15 - bytes as i32]
+        ; mov eax, OVERFLOW as i32
+        ; jmp =>exit
+        ; =>underflow
+        ; xor ebx, ebx
+        ; mov eax, UNDERFLOW as i32
+        ; =>exit
+        ; shr rbx, shift
+        ; mov [r13 + offset_of!(RawTape, ptr) as i32], rbx
+        ; pop r15
+        ; pop r14
+        ; pop r13
+        ; pop r12
+        ; pop rbx
+        ; ret
+    );
+
+    let buffer = a
+        .finalize()
+        .map_err(|_| "Failed to assemble the program".to_owned())?;
+    Ok(Asm { buffer, start })
+}
+
+/// Loads the address of the cells and the size of the tape in bytes from
+/// the `RawTape`, as after it grows.
+fn load_tape(a: &mut Assembler, shift: i8) {
+    dynasm!(a
+        ; .arch x64
+        ; mov r14, [r13 + offset_of!(RawTape, cells) as i32]
+        ; mov r15, [r13 + offset_of!(RawTape, len) as i32]
+        ; shl r15, shift
+    );
+}
+
+/// Loads the current cell into esi, the second argument of a call.
+fn load_cell(a: &mut Assembler, cell_size: CellSize) {
+    match cell_size {
+        CellSize::Bits8 => dynasm!(a; .arch x64; movzx esi, BYTE [r14 + rbx]),
+        CellSize::Bits16 => dynasm!(a; .arch x64; movzx esi, WORD [r14 + rbx]),
+        CellSize::Bits32 => dynasm!(a; .arch x64; mov esi, DWORD [r14 + rbx]),
+    }
+}
+
+/// Sets the flags for whether the current cell is 0.
+fn compare_cell(a: &mut Assembler, cell_size: CellSize) {
+    match cell_size {
+        CellSize::Bits8 => dynasm!(a; .arch x64; cmp BYTE [r14 + rbx], 0),
+        CellSize::Bits16 => dynasm!(a; .arch x64; cmp WORD [r14 + rbx], 0),
+        CellSize::Bits32 => dynasm!(a; .arch x64; cmp DWORD [r14 + rbx], 0),
+    }
+}
@@ -0,0 +1,77 @@
+//! `--numeric-io`: `.` and `,` exchange decimal numbers instead of bytes.
+
+use std::io::{self, Read, Write};
+
+/// Writes `value` in decimal followed by a newline.
+pub(crate) fn write(output: &mut dyn Write, value: u32) -> io::Result<()> {
+    writeln!(output, "{}", value)
+}
+
+/// Reads a decimal number, skipping leading whitespace. The number ends at
+/// the first byte that is not a digit, which is consumed. A leading `-`
+/// negates it, wrapping around like `-` does on a cell.
+///
+/// Returns `None` at the end of input.
+pub(crate) fn read(input: &mut dyn Read) -> Result<Option<u32>, String> {
+    let mut next = || {
+        let mut buf = [0u8; 1];
+        match input.read(&mut buf) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(buf[0])),
+            Err(e) => Err(format!("Failed to read input: {}", e)),
+        }
+    };
+
+    let mut byte = next()?;
+    while byte.is_some_and(|b| b.is_ascii_whitespace()) {
+        byte = next()?;
+    }
+    let negative = byte == Some(b'-');
+    if negative {
+        byte = next()?;
+    }
+
+    let mut value: u32 = 0;
+    let mut digits = 0;
+    while let Some(digit @ b'0'..=b'9') = byte {
+        value = value.wrapping_mul(10).wrapping_add((digit - b'0') as u32);
+        digits += 1;
+        byte = next()?;
+    }
+    match byte {
+        _ if digits > 0 => {}
+        None if !negative => return Ok(None),
+        Some(b) => {
+            return Err(format!(
+                "Expected a number in the input, found {:?}",
+                b as char
+            ))
+        }
+        None => return Err("Expected a number in the input".to_owned()),
+    }
+    if negative {
+        value = value.wrapping_neg();
+    }
+    Ok(Some(value))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn read_numbers() {
+        let mut input: &[u8] = b"  42\n-1 7";
+        assert_eq!(read(&mut input), Ok(Some(42)));
+        assert_eq!(read(&mut input), Ok(Some(u32::MAX)));
+        assert_eq!(read(&mut input), Ok(Some(7)));
+        assert_eq!(read(&mut input), Ok(None));
+
+        let mut input: &[u8] = b"\nx";
+        assert_eq!(
+            read(&mut input).unwrap_err(),
+            "Expected a number in the input, found 'x'"
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Token {
+    IncDataPtr,
+    DecDataPtr,
+    IncByte,
+    DecByte,
+    WriteByte,
+    ReadByte,
+    BeginLoop,
+    EndLoop,
+    /// `#`, only a command with `--debug-char`.
+    Debug,
+    /// pbrain's `(`, `)` and `:`, see `PBRAIN`.
+    BeginProcedure,
+    EndProcedure,
+    Call,
+    /// Brainfork's `Y`, see `BRAINFORK`.
+    Fork,
+    /// See `EXTENDED1`.
+    Extended(Extended),
+    /// A command registered with `plugin::Registry`.
+    Plugin(u8),
+    /// `:` and `;` of the decimal dialect, see `DECIMAL`.
+    WriteNumber,
+    ReadNumber,
+}
+
+/// The commands Extended Brainfuck Type I adds, working on the current cell
+/// and a storage cell.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Extended {
+    /// `@`: ends the program.
+    End,
+    /// `$`: copies the current cell to the storage.
+    Store,
+    /// `!`: copies the storage to the current cell.
+    Load,
+    /// `{`
+    ShiftLeft,
+    /// `}`
+    ShiftRight,
+    /// `~`: flips every bit.
+    Not,
+    /// `^`, `&` and `|`, with the storage.
+    Xor,
+    And,
+    Or,
+}
+
+/// pbrain adds procedures to Brainfuck: `(` and `)` define one numbered by
+/// the current cell and `:` calls the one numbered by the current cell.
+pub(crate) const PBRAIN: &[(u8, Token)] = &[
+    (b'(', Token::BeginProcedure),
+    (b')', Token::EndProcedure),
+    (b':', Token::Call),
+];
+
+/// Brainfork adds threads to Brainfuck: `Y` starts a new one.
+pub(crate) const BRAINFORK: &[(u8, Token)] = &[(b'Y', Token::Fork)];
+
+/// The decimal dialect adds number I/O to Brainfuck: `:` writes the current
+/// cell in decimal and `;` reads a decimal number into it.
+pub(crate) const DECIMAL: &[(u8, Token)] = &[(b':', Token::WriteNumber), (b';', Token::ReadNumber)];
+
+impl Extended {
+    /// How the instruction is listed, e.g. in a trace.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Extended::End => "end",
+            Extended::Store => "store",
+            Extended::Load => "load",
+            Extended::ShiftLeft => "shift_left",
+            Extended::ShiftRight => "shift_right",
+            Extended::Not => "not",
+            Extended::Xor => "xor",
+            Extended::And => "and",
+            Extended::Or => "or",
+        }
+    }
+
+    /// The command as written.
+    pub(crate) fn command(self) -> &'static str {
+        match self {
+            Extended::End => "@",
+            Extended::Store => "$",
+            Extended::Load => "!",
+            Extended::ShiftLeft => "{",
+            Extended::ShiftRight => "}",
+            Extended::Not => "~",
+            Extended::Xor => "^",
+            Extended::And => "&",
+            Extended::Or => "|",
+        }
+    }
+}
+
+/// Extended Brainfuck Type I adds a storage cell and bitwise operations.
+pub(crate) const EXTENDED1: &[(u8, Token)] = &[
+    (b'@', Token::Extended(Extended::End)),
+    (b'$', Token::Extended(Extended::Store)),
+    (b'!', Token::Extended(Extended::Load)),
+    (b'{', Token::Extended(Extended::ShiftLeft)),
+    (b'}', Token::Extended(Extended::ShiftRight)),
+    (b'~', Token::Extended(Extended::Not)),
+    (b'^', Token::Extended(Extended::Xor)),
+    (b'&', Token::Extended(Extended::And)),
+    (b'|', Token::Extended(Extended::Or)),
+];
+
+/// Parses the commands in `program`. `#` is only a command if `debug_char`
+/// is set, and is otherwise ignored like any other comment.
+pub(crate) fn parse_program(program: &[u8], debug_char: bool) -> Box<[Token]> {
+    program
+        .iter()
+        .flat_map(|&b| token(b, debug_char))
+        .collect::<Vec<_>>()
+        .into_boxed_slice()
+}
+
+/// The Brainfuck command `b` stands for, if any. All other bytes are ignored.
+fn token(b: u8, debug_char: bool) -> Option<Token> {
+    use Token::*;
+    match b {
+        b'#' if debug_char => Some(Debug),
+        b'>' => Some(IncDataPtr),
+        b'<' => Some(DecDataPtr),
+        b'+' => Some(IncByte),
+        b'-' => Some(DecByte),
+        b'.' => Some(WriteByte),
+        b',' => Some(ReadByte),
+        b'[' => Some(BeginLoop),
+        b']' => Some(EndLoop),
+        _ => None,
+    }
+}
+
+/// Byte offsets of the commands in `program`, one per token returned by
+/// `parse_program`.
+pub(crate) fn token_offsets(program: &[u8], debug_char: bool) -> Vec<usize> {
+    program
+        .iter()
+        .enumerate()
+        .filter(|&(_, &b)| b"<>+-.,[]".contains(&b) || (debug_char && b == b'#'))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Like `parse_program` and `token_offsets` together, for a dialect that
+/// adds the `extensions` commands to Brainfuck, such as `PBRAIN`.
+pub(crate) fn parse_extended(
+    program: &[u8],
+    debug_char: bool,
+    extensions: &[(u8, Token)],
+) -> (Box<[Token]>, Vec<usize>) {
+    let mut tokens = vec![];
+    let mut offsets = vec![];
+    for (i, &b) in program.iter().enumerate() {
+        let extension = extensions.iter().find(|(c, _)| *c == b);
+        let Some(token) = extension.map(|&(_, token)| token).or(token(b, debug_char)) else {
+            continue;
+        };
+        tokens.push(token);
+        offsets.push(i);
+    }
+    (tokens.into_boxed_slice(), offsets)
+}
+
+/// Maps each `[` to its `]` and back, and likewise for pbrain's `(` and `)`.
+/// Loops and procedures must nest within each other.
+pub(crate) fn find_matching_parens(program: &[Token]) -> Result<HashMap<usize, usize>, String> {
+    let mut map = HashMap::new();
+    let mut stack = vec![];
+
+    for (i, b) in program.iter().copied().enumerate() {
+        let open = match b {
+            Token::BeginLoop | Token::BeginProcedure => {
+                stack.push((i, b));
+                continue;
+            }
+            Token::EndLoop => Token::BeginLoop,
+            Token::EndProcedure => Token::BeginProcedure,
+            _ => continue,
+        };
+        match stack.pop() {
+            Some((matching_index, token)) if token == open => {
+                map.insert(i, matching_index);
+                map.insert(matching_index, i);
+            }
+            Some((_, token)) => return Err(missing(token)),
+            None if open == Token::BeginLoop => return Err("Missing '['".to_owned()),
+            None => return Err("Missing '('".to_owned()),
+        }
+    }
+
+    match stack.pop() {
+        Some((_, token)) => Err(missing(token)),
+        None => Ok(map),
+    }
+}
+
+/// The error for an unclosed `[` or `(`.
+fn missing(open: Token) -> String {
+    match open {
+        Token::BeginLoop => "Missing ']'".to_owned(),
+        _ => "Missing ')'".to_owned(),
+    }
+}
@@ -0,0 +1,135 @@
+//! Profile-guided optimization: `bf run --profile-out` saves how often each
+//! instruction ran, and `bf compile --profile` reads it back to tell the C
+//! compiler or LLVM which loops to unroll.
+
+use crate::ir::Op;
+use crate::json::Json;
+
+/// Share of all executed instructions, in percent, that the body of an
+/// innermost loop has to account for to be unrolled.
+const HOT_PERCENT: u64 = 1;
+
+/// Average number of iterations per entry below which unrolling doesn't pay.
+const MIN_ITERATIONS: u64 = 4;
+
+/// What to tell the compiler about a loop.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum Unroll {
+    /// Nothing, the compiler decides.
+    Default,
+    /// Keep the loop rolled, as its body never ran.
+    Never,
+    /// Unroll the loop this many times.
+    Times(u32),
+}
+
+/// The counts of a profile, checked against the program they were recorded
+/// for.
+#[derive(Debug)]
+pub struct Profile {
+    counts: Box<[u64]>,
+}
+
+impl Profile {
+    /// Saves `counts`, the executions of each of `ops`, as JSON.
+    pub fn save(ops: &[Op], counts: &[u64]) -> String {
+        let counts = counts.iter().map(|&n| Json::Number(n as f64)).collect();
+        let json = Json::object([
+            ("program", Json::from(fingerprint(ops))),
+            ("counts", Json::Array(counts)),
+        ]);
+        format!("{}\n", json)
+    }
+
+    /// Reads a profile saved by `save`, which has to be for `ops`.
+    pub(crate) fn load(json: &str, ops: &[Op]) -> Result<Profile, String> {
+        let json = Json::parse(json)?;
+        let invalid = || "Invalid profile".to_owned();
+        let counts = json
+            .get("counts")
+            .and_then(Json::as_array)
+            .ok_or_else(invalid)?
+            .iter()
+            .map(|n| n.as_usize().map(|n| n as u64))
+            .collect::<Option<Box<[u64]>>>()
+            .ok_or_else(invalid)?;
+        if json.get("program").and_then(Json::as_str) != Some(&fingerprint(ops))
+            || counts.len() != ops.len()
+        {
+            return Err("The profile was recorded for a different program".to_owned());
+        }
+        Ok(Profile { counts })
+    }
+
+    /// The hint for each of `ops`, which only matters for loop starts. Hot
+    /// innermost loops are unrolled more the shorter they are.
+    pub(crate) fn unroll(&self, ops: &[Op]) -> Box<[Unroll]> {
+        let total: u64 = self.counts.iter().sum();
+        let mut hints = vec![Unroll::Default; ops.len()].into_boxed_slice();
+        for (start, op) in ops.iter().enumerate() {
+            let Op::LoopStart(end) = *op else {
+                continue;
+            };
+            // A `]` runs once per iteration.
+            let (entries, iterations) = (self.counts[start], self.counts[end]);
+            if iterations == 0 {
+                hints[start] = Unroll::Never;
+                continue;
+            }
+            let body = &ops[start + 1..end];
+            let innermost = !body.iter().any(|op| matches!(op, Op::LoopStart(_)));
+            let work: u64 = self.counts[start + 1..end].iter().sum();
+            if innermost
+                && work * 100 >= total * HOT_PERCENT
+                && iterations >= entries * MIN_ITERATIONS
+            {
+                hints[start] = Unroll::Times(match body.len() {
+                    0..=4 => 8,
+                    5..=16 => 4,
+                    _ => 2,
+                });
+            }
+        }
+        hints
+    }
+}
+
+/// Identifies a program by its instructions, so a profile isn't applied to
+/// another one by mistake.
+fn fingerprint(ops: &[Op]) -> String {
+    // FNV-1a over the instructions as shown in listings.
+    let hash = ops
+        .iter()
+        .flat_map(|op| format!("{:?};", op).into_bytes())
+        .fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+            (hash ^ b as u64).wrapping_mul(0x100_0000_01b3)
+        });
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::ir;
+
+    #[test]
+    fn unroll_hot_loops() {
+        // The first loop runs 10 times, the second never.
+        let ops = ir::compile(b"++++++++++[>+>++<<-]>[<]").unwrap();
+        let counts = [1, 1, 10, 10, 10, 10, 10, 10, 10, 1, 1, 0, 0];
+        let json = Profile::save(&ops, &counts);
+        let profile = Profile::load(&json, &ops).unwrap();
+        let hints = profile.unroll(&ops);
+        assert_eq!(hints[1], Unroll::Times(4));
+        assert_eq!(hints[10], Unroll::Never);
+        assert_eq!(hints[0], Unroll::Default);
+
+        let other = ir::compile(b"+[>+<-]").unwrap();
+        assert_eq!(
+            Profile::load(&json, &other).unwrap_err(),
+            "The profile was recorded for a different program"
+        );
+        assert_eq!(Profile::load("{}", &ops).unwrap_err(), "Invalid profile");
+    }
+}
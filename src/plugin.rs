@@ -0,0 +1,168 @@
+//! Commands added to Brainfuck by plugins, such as `?` with `--random`.
+//! Each maps a character to an `Instruction`, which gets the tape and the
+//! data pointer when the command runs, so sound, graphics or system calls
+//! can be added without changing the interpreter.
+
+use crate::parser::Token;
+use crate::tape::Tape;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+/// What a plugin command can see and change.
+pub struct InterpreterState<'a> {
+    pub(crate) tape: &'a mut Tape,
+    /// Must be left on the tape.
+    pub(crate) data_ptr: &'a mut usize,
+}
+
+pub trait Instruction {
+    fn run(&mut self, state: &mut InterpreterState) -> Result<(), String>;
+}
+
+impl<F: FnMut(&mut InterpreterState) -> Result<(), String>> Instruction for F {
+    fn run(&mut self, state: &mut InterpreterState) -> Result<(), String> {
+        self(state)
+    }
+}
+
+/// The plugin commands of a run. Clones share the plugins, so Brainfork
+/// threads use the same ones.
+#[derive(Clone, Default)]
+pub struct Registry {
+    commands: Vec<(u8, Rc<RefCell<dyn Instruction>>)>,
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.commands.iter().map(|(command, _)| *command as char))
+            .finish()
+    }
+}
+
+impl Registry {
+    /// Makes `command` run `instruction`. Brainfuck's own commands can't be
+    /// replaced.
+    pub fn register(
+        &mut self,
+        command: u8,
+        instruction: impl Instruction + 'static,
+    ) -> Result<(), String> {
+        if b"<>+-.,[]".contains(&command) {
+            return Err(format!(
+                "'{}' is already a Brainfuck command",
+                command as char
+            ));
+        }
+        if self.commands.iter().any(|(c, _)| *c == command) {
+            return Err(format!("'{}' is already registered", command as char));
+        }
+        self.commands
+            .push((command, Rc::new(RefCell::new(instruction))));
+        Ok(())
+    }
+
+    /// The registered commands, for `parser::parse_extended`.
+    pub fn extensions(&self) -> Vec<(u8, Token)> {
+        self.commands
+            .iter()
+            .map(|&(command, _)| (command, Token::Plugin(command)))
+            .collect()
+    }
+
+    pub(crate) fn run(&self, command: u8, state: &mut InterpreterState) -> Result<(), String> {
+        let (_, instruction) = self
+            .commands
+            .iter()
+            .find(|(c, _)| *c == command)
+            .ok_or_else(|| format!("No plugin for '{}'", command as char))?;
+        instruction.borrow_mut().run(state)?;
+        if *state.data_ptr >= state.tape.len() {
+            return Err(format!(
+                "The plugin for '{}' moved the data pointer off the tape",
+                command as char
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// `--random`: stores a random byte in the current cell. The same seed gives
+/// the same bytes.
+pub fn random(seed: u64) -> impl Instruction {
+    let mut state = seed;
+    move |interpreter: &mut InterpreterState| {
+        let byte = splitmix64(&mut state) >> 56;
+        interpreter.tape.set(*interpreter.data_ptr, byte as u32);
+        Ok(())
+    }
+}
+
+/// The next number from `state`, which starts as the seed. splitmix64 is
+/// fast and good enough for games.
+pub(crate) fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut x = *state;
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::bf_interpreter::BfInterpreter;
+    use crate::ir;
+
+    fn run(program: &[u8], plugins: Registry) -> Result<Vec<u8>, String> {
+        let (ops, _) = ir::compile_extended(program, false, &plugins.extensions()).unwrap();
+        BfInterpreter::builder()
+            .plugins(plugins)
+            .build_ops(ops)
+            .unwrap()
+            .run_to_end(b"")
+    }
+
+    #[test]
+    fn plugin_commands() {
+        let mut plugins = Registry::default();
+        let double = |state: &mut InterpreterState| {
+            let cell = state.tape.get(*state.data_ptr);
+            state.tape.set(*state.data_ptr, cell * 2);
+            Ok(())
+        };
+        plugins.register(b'*', double).unwrap();
+        plugins
+            .register(b'^', |state: &mut InterpreterState| {
+                *state.data_ptr += 1_000_000;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(run(b"+++**.", plugins.clone()).unwrap(), [12]);
+        assert_eq!(
+            run(b"^", plugins.clone()).unwrap_err(),
+            "The plugin for '^' moved the data pointer off the tape"
+        );
+        assert_eq!(
+            plugins.register(b'*', double).unwrap_err(),
+            "'*' is already registered"
+        );
+        assert_eq!(
+            plugins.register(b'+', double).unwrap_err(),
+            "'+' is already a Brainfuck command"
+        );
+    }
+
+    #[test]
+    fn random_bytes() {
+        let bytes = |seed| {
+            let mut plugins = Registry::default();
+            plugins.register(b'?', random(seed)).unwrap();
+            run(b"?.?.?.?.", plugins).unwrap()
+        };
+        assert_eq!(bytes(1), bytes(1));
+        assert_ne!(bytes(1), bytes(2));
+    }
+}
@@ -0,0 +1,276 @@
+//! Macros and includes, expanded before a program is compiled:
+//!
+//! ```text
+//! @include "lib.b"
+//! @def clear [-]
+//! @def move_right [->+<]
+//! +++ @use move_right > @use clear
+//! ```
+//!
+//! `@def <name> <code>` defines a macro as the rest of the line, which may
+//! use other macros, and `@use <name>` expands to its code. `@include` expands
+//! to another file, resolved relative to the one it's in, whose macros can be
+//! used after it. Any other `@` is a comment, as in Brainfuck.
+
+use crate::profile;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// A file being preprocessed, for resolving includes and locating errors.
+struct File<'a> {
+    /// `None` for a program from `-e` or stdin, whose includes are resolved
+    /// relative to the current directory.
+    path: Option<&'a Path>,
+    source: &'a [u8],
+    /// Whether another file included it, so errors in it name it.
+    included: bool,
+}
+
+impl File<'_> {
+    fn error(&self, offset: usize, message: String) -> String {
+        let (line, column) = profile::position(self.source, offset);
+        match self.path {
+            Some(path) if self.included => {
+                format!("{}:{}:{}: {}", path.display(), line, column, message)
+            }
+            _ => format!("{}:{}: {}", line, column, message),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Preprocessor {
+    macros: HashMap<Vec<u8>, Vec<u8>>,
+    /// The macros being expanded, innermost last, to catch a macro that uses
+    /// itself.
+    expanding: Vec<Vec<u8>>,
+    /// The files being included, innermost last, to catch cycles.
+    including: Vec<PathBuf>,
+    program: Vec<u8>,
+    /// The offset in the top-level source of each byte of `program`.
+    positions: Vec<usize>,
+    /// Whether there was anything to expand.
+    expanded: bool,
+}
+
+/// Expands the macros and includes in `source`, the program read from `path`.
+/// Also returns the offset in `source` of each byte of the result if
+/// anything was expanded. Code from a macro or an included file has the
+/// offset of the `@use` or `@include` in `source` it came from.
+pub fn preprocess(
+    source: &[u8],
+    path: Option<&Path>,
+) -> Result<(Vec<u8>, Option<Vec<usize>>), String> {
+    let mut preprocessor = Preprocessor::default();
+    if let Some(path) = path {
+        preprocessor.including.push(canonical(path));
+    }
+    let file = File {
+        path,
+        source,
+        included: false,
+    };
+    preprocessor.expand(&file, source, None, None)?;
+    if !preprocessor.expanded {
+        return Ok((source.to_vec(), None));
+    }
+    Ok((preprocessor.program, Some(preprocessor.positions)))
+}
+
+impl Preprocessor {
+    /// Expands `text` from `file`: either its source, or the code of a macro
+    /// used at offset `site` in it. `origin` is the offset in the top-level
+    /// source that everything maps to, if `file` is an included one.
+    fn expand(
+        &mut self,
+        file: &File,
+        text: &[u8],
+        site: Option<usize>,
+        origin: Option<usize>,
+    ) -> Result<(), String> {
+        let mut i = 0;
+        while i < text.len() {
+            // Errors in a macro's code are shown where it's used.
+            let at = site.unwrap_or(i);
+            let error = |message: String| file.error(at, message);
+            let Some((directive, rest)) = directive(&text[i..]) else {
+                self.program.push(text[i]);
+                self.positions.push(origin.or(site).unwrap_or(i));
+                i += 1;
+                continue;
+            };
+            self.expanded = true;
+            let rest = &rest[blanks(rest)..];
+            let mut end = i + text[i..].len() - rest.len();
+            match directive {
+                "def" => {
+                    let line = rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len());
+                    end += line;
+                    let (name, code) = split_name(&rest[..line]);
+                    if name.is_empty() {
+                        return Err(error("Expected a macro name after @def".to_owned()));
+                    }
+                    let code = &code[blanks(code)..];
+                    self.macros.insert(name.to_vec(), trim_end(code).to_vec());
+                }
+                "use" => {
+                    let (name, _) = split_name(rest);
+                    end += name.len();
+                    let Some(code) = self.macros.get(name).cloned() else {
+                        return Err(error(format!(
+                            "Undefined macro '{}'",
+                            String::from_utf8_lossy(name)
+                        )));
+                    };
+                    if self.expanding.iter().any(|n| n == name) {
+                        return Err(error(format!(
+                            "Macro '{}' uses itself",
+                            String::from_utf8_lossy(name)
+                        )));
+                    }
+                    self.expanding.push(name.to_vec());
+                    self.expand(file, &code, Some(at), origin)?;
+                    self.expanding.pop();
+                }
+                _ => {
+                    let name = rest
+                        .strip_prefix(b"\"")
+                        .and_then(|name| {
+                            let len = name.iter().position(|&b| b == b'"' || b == b'\n')?;
+                            (name[len] == b'"').then_some(&name[..len])
+                        })
+                        .ok_or_else(|| {
+                            error("Expected a quoted file name after @include".to_owned())
+                        })?;
+                    end += name.len() + 2;
+                    let name = String::from_utf8_lossy(name).into_owned();
+                    let path = match file.path.and_then(Path::parent) {
+                        Some(dir) => dir.join(&name),
+                        None => PathBuf::from(&name),
+                    };
+                    let source = std::fs::read(&path)
+                        .map_err(|e| error(format!("{}: {}", path.display(), e)))?;
+                    let canonical = canonical(&path);
+                    if self.including.contains(&canonical) {
+                        return Err(error(format!("'{}' includes itself", name)));
+                    }
+                    self.including.push(canonical);
+                    let included = File {
+                        path: Some(&path),
+                        source: &source,
+                        included: true,
+                    };
+                    self.expand(&included, &source, None, Some(origin.unwrap_or(at)))?;
+                    self.including.pop();
+                }
+            }
+            i = end;
+        }
+        Ok(())
+    }
+}
+
+/// The directive `text` starts with, if any, and the text after its name.
+fn directive(text: &[u8]) -> Option<(&'static str, &[u8])> {
+    let rest = text.strip_prefix(b"@")?;
+    ["def", "use", "include"].into_iter().find_map(|name| {
+        let rest = rest.strip_prefix(name.as_bytes())?;
+        matches!(rest.first(), Some(b' ' | b'\t')).then_some((name, rest))
+    })
+}
+
+/// The number of spaces and tabs `text` starts with.
+fn blanks(text: &[u8]) -> usize {
+    text.iter()
+        .position(|&b| b != b' ' && b != b'\t')
+        .unwrap_or(text.len())
+}
+
+/// Splits `text` into the macro name it starts with and what follows.
+fn split_name(text: &[u8]) -> (&[u8], &[u8]) {
+    let end = text
+        .iter()
+        .position(|&b| !(b.is_ascii_alphanumeric() || b == b'_'))
+        .unwrap_or(text.len());
+    text.split_at(end)
+}
+
+fn trim_end(text: &[u8]) -> &[u8] {
+    let end = text
+        .iter()
+        .rposition(|&b| !b.is_ascii_whitespace())
+        .map_or(0, |i| i + 1);
+    &text[..end]
+}
+
+/// `path` with symbolic links and `..` resolved, so the same file is
+/// recognized however it's included.
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn expand_macros() {
+        let source = b"@def clear [-]\n@def zero2 @use clear>@use clear<\n+@use zero2.";
+        let (program, positions) = preprocess(source, None).unwrap();
+        assert_eq!(program, b"\n\n+[-]>[-]<.");
+        let positions = positions.unwrap();
+        let use_zero2 = source.iter().rposition(|&b| b == b'@').unwrap();
+        assert_eq!(positions[2], use_zero2 - 1);
+        assert!(positions[3..11].iter().all(|&p| p == use_zero2));
+        assert_eq!(positions[11], source.len() - 1);
+
+        // Nothing to expand.
+        assert_eq!(preprocess(b"+@.", None).unwrap(), (b"+@.".to_vec(), None));
+    }
+
+    #[test]
+    fn include_files() {
+        let dir = std::env::temp_dir().join(format!("bf-preprocess-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(dir.join("lib/clear.b"), "@def clear [-]\n").unwrap();
+        std::fs::write(dir.join("lib/all.b"), "@include \"clear.b\"\n+").unwrap();
+        std::fs::write(dir.join("loop.b"), "@include \"loop.b\"").unwrap();
+
+        let main = dir.join("main.b");
+        let source = b">@include \"lib/all.b\"\n@use clear";
+        let (program, positions) = preprocess(source, Some(&main)).unwrap();
+        assert_eq!(program, b">\n\n+\n[-]");
+        assert_eq!(positions.unwrap(), [0, 1, 1, 1, 21, 22, 22, 22]);
+
+        let error = preprocess(b"@include \"loop.b\"", Some(&main)).unwrap_err();
+        assert_eq!(
+            error,
+            format!(
+                "{}:1:1: 'loop.b' includes itself",
+                dir.join("loop.b").display()
+            )
+        );
+        let error = preprocess(b"\n @include \"missing.b\"", Some(&main)).unwrap_err();
+        assert!(error.starts_with(&format!("2:2: {}: ", dir.join("missing.b").display())));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn report_errors() {
+        for (source, error) in [
+            ("+\n@use nothing", "2:1: Undefined macro 'nothing'"),
+            (
+                "@def a @use b\n@def b @use a\n @use a",
+                "3:2: Macro 'a' uses itself",
+            ),
+            ("@def \n", "1:1: Expected a macro name after @def"),
+            (
+                "@include lib.b",
+                "1:1: Expected a quoted file name after @include",
+            ),
+        ] {
+            assert_eq!(preprocess(source.as_bytes(), None).unwrap_err(), error);
+        }
+    }
+}
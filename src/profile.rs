@@ -0,0 +1,249 @@
+//! The `--profile` report: where a program spends its steps, and the
+//! `--coverage` report: which parts of it never ran.
+
+use crate::ir::Op;
+use std::fmt::Write;
+
+/// Source text and the offset of each instruction in it, used to show
+/// `line:column` locations. Without it, instructions are shown by index.
+#[derive(Copy, Clone)]
+pub struct SourceMap<'a> {
+    pub source: &'a [u8],
+    pub offsets: &'a [usize],
+}
+
+impl SourceMap<'_> {
+    /// The first instruction at or after `line:column`, both counted from 1.
+    pub(crate) fn instruction_at(&self, line: usize, column: usize) -> Option<usize> {
+        let mut lines = self.source.split(|&b| b == b'\n');
+        let start = lines
+            .by_ref()
+            .take(line.checked_sub(1)?)
+            .map(|line| line.len() + 1)
+            .sum::<usize>();
+        if column == 0 || column > lines.next()?.len() + 1 {
+            return None;
+        }
+        let offset = start + column - 1;
+        let i = self.offsets.partition_point(|&o| o < offset);
+        (i < self.offsets.len()).then_some(i)
+    }
+}
+
+/// Where instruction `i` came from, as `line:column` or as `#i` without
+/// source.
+pub fn location(source: Option<&SourceMap>, i: usize) -> String {
+    match source {
+        Some(map) => line_column(map.source, map.offsets[i]),
+        None => format!("#{}", i),
+    }
+}
+
+/// Formats the `top` most executed instructions and loops. A loop's count
+/// includes everything executed inside it, nested loops too.
+pub fn report(ops: &[Op], counts: &[u64], source: Option<&SourceMap>, top: usize) -> String {
+    let total: u64 = counts.iter().sum();
+    let location = |i| location(source, i);
+
+    let mut out = String::new();
+    writeln!(out, "hottest instructions ({} steps in total):", total).unwrap();
+    writeln!(
+        out,
+        "{:>12}  {:>6}  {:<10}  instruction",
+        "steps", "%", "location"
+    )
+    .unwrap();
+    for (i, count) in hottest(counts.iter().copied().enumerate(), top) {
+        writeln!(
+            out,
+            "{:>12}  {:>6}  {:<10}  {}",
+            count,
+            percent(count, total),
+            location(i),
+            ops[i]
+        )
+        .unwrap();
+    }
+
+    let loops = ops.iter().enumerate().filter_map(|(i, op)| match op {
+        Op::LoopStart(end) => Some((i, counts[i..=*end].iter().sum())),
+        _ => None,
+    });
+    writeln!(out, "\nhottest loops:").unwrap();
+    writeln!(out, "{:>12}  {:>6}  location", "steps", "%").unwrap();
+    for (i, count) in hottest(loops, top) {
+        writeln!(
+            out,
+            "{:>12}  {:>6}  {}",
+            count,
+            percent(count, total),
+            location(i)
+        )
+        .unwrap();
+    }
+
+    out
+}
+
+/// Shows `source` with the commands that never ran highlighted in red, or
+/// marked with `^` on the line below without `color`, after a summary of
+/// how many ran.
+pub fn coverage(map: &SourceMap, counts: &[u64], color: bool) -> String {
+    // Instruction `i` covers the source from its offset up to the next
+    // instruction's, e.g. a whole run of `+` or a clear loop.
+    let ran = |offset: usize| counts[map.offsets.partition_point(|&o| o <= offset) - 1] > 0;
+    let is_command = |b: &u8| b"<>+-.,[]".contains(b);
+    let commands = map.source.iter().filter(|b| is_command(b)).count();
+    let run = map
+        .source
+        .iter()
+        .enumerate()
+        .filter(|&(offset, b)| is_command(b) && ran(offset))
+        .count();
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "coverage: {} of {} commands ran ({})",
+        run,
+        commands,
+        percent(run as u64, commands.max(1) as u64)
+    )
+    .unwrap();
+    let mut offset = 0;
+    let source = map.source.strip_suffix(b"\n").unwrap_or(map.source);
+    for (i, line) in source.split(|&b| b == b'\n').enumerate() {
+        let mut text = vec![];
+        let mut marks = String::new();
+        for (j, &b) in line.iter().enumerate() {
+            let missed = is_command(&b) && !ran(offset + j);
+            if missed && color {
+                text.extend_from_slice(b"\x1b[31m");
+                text.push(b);
+                text.extend_from_slice(b"\x1b[0m");
+            } else {
+                text.push(b);
+            }
+            match b {
+                _ if missed => marks.push('^'),
+                b'\t' => marks.push('\t'),
+                // One mark per character, not per byte.
+                0x80..=0xbf => {}
+                _ => marks.push(' '),
+            }
+        }
+        offset += line.len() + 1;
+        writeln!(out, "{:>5} | {}", i + 1, String::from_utf8_lossy(&text)).unwrap();
+        if !color && marks.contains('^') {
+            writeln!(out, "      | {}", marks.trim_end()).unwrap();
+        }
+    }
+    out
+}
+
+/// The `top` entries with the highest non-zero counts, earliest first on ties.
+fn hottest(entries: impl Iterator<Item = (usize, u64)>, top: usize) -> Vec<(usize, u64)> {
+    let mut entries = entries.filter(|&(_, count)| count > 0).collect::<Vec<_>>();
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    entries.truncate(top);
+    entries
+}
+
+fn percent(count: u64, total: u64) -> String {
+    format!("{:.1}%", count as f64 * 100.0 / total as f64)
+}
+
+/// 1-based `line:column` of a byte offset.
+fn line_column(source: &[u8], offset: usize) -> String {
+    let (line, column) = position(source, offset);
+    format!("{}:{}", line, column)
+}
+
+/// The line and column of `offset` in `source`, both counted from 1.
+pub fn position(source: &[u8], offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line = before.iter().filter(|&&b| b == b'\n').count() + 1;
+    let column = offset
+        - before
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1)
+        + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::ir;
+
+    #[test]
+    fn report_hotspots() {
+        let source = b"++\n[>+<-]";
+        let (ops, offsets) = ir::compile_with_offsets(source, false).unwrap();
+        // Add(2), then two iterations of the loop.
+        let counts = [1, 1, 2, 2, 2, 2, 2];
+        let map = SourceMap {
+            source,
+            offsets: &offsets,
+        };
+
+        assert_eq!(
+            report(&ops, &counts, Some(&map), 2),
+            "hottest instructions (12 steps in total):
+       steps       %  location    instruction
+           2   16.7%  2:2         move 1
+           2   16.7%  2:3         add 1
+
+hottest loops:
+       steps       %  location
+          11   91.7%  2:1
+"
+        );
+    }
+
+    #[test]
+    fn report_coverage() {
+        let source = "+[->+<]\n>[-\t.] é,".as_bytes();
+        let (_, offsets) = ir::compile_with_offsets(source, false).unwrap();
+        // The first loop ran, the second didn't, and `,` ran.
+        let counts = [1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 1];
+        let map = SourceMap {
+            source,
+            offsets: &offsets,
+        };
+
+        assert_eq!(
+            coverage(&map, &counts, false),
+            "coverage: 9 of 13 commands ran (69.2%)
+    1 | +[->+<]
+    2 | >[-\t.] é,
+      |  ^^\t^^
+"
+        );
+        assert_eq!(
+            coverage(&map, &counts, true).lines().nth(2),
+            Some("    2 | >\x1b[31m[\x1b[0m\x1b[31m-\x1b[0m\t\x1b[31m.\x1b[0m\x1b[31m]\x1b[0m é,")
+        );
+    }
+
+    #[test]
+    fn instruction_at_position() {
+        let source = b"++\n[>+<-] comment\n.";
+        let (_, offsets) = ir::compile_with_offsets(source, false).unwrap();
+        let map = SourceMap {
+            source,
+            offsets: &offsets,
+        };
+
+        assert_eq!(map.instruction_at(1, 1), Some(0));
+        assert_eq!(map.instruction_at(2, 3), Some(3));
+        // Comments and line ends stop at the next instruction.
+        assert_eq!(map.instruction_at(2, 9), Some(7));
+        assert_eq!(map.instruction_at(3, 1), Some(7));
+        assert_eq!(map.instruction_at(3, 2), None);
+        assert_eq!(map.instruction_at(4, 1), None);
+        assert_eq!(map.instruction_at(0, 1), None);
+    }
+}
@@ -0,0 +1,136 @@
+//! The `bf` Python module, built with `maturin develop --features python`:
+//!
+//! ```python
+//! import bf
+//!
+//! bf.run("++++++++[>++++++++<-]>+.")  # b"A"
+//!
+//! interpreter = bf.Interpreter(",[.,]")
+//! interpreter.feed_input(b"hi")
+//! interpreter.step(1000)  # "input"
+//! interpreter.take_output()  # b"hi"
+//! ```
+
+use crate::config::Config;
+use crate::embed::{Session, Status};
+use crate::gen;
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+
+create_exception!(
+    bf,
+    BfError,
+    PyException,
+    "A program that doesn't compile or fails."
+);
+
+fn error(message: String) -> PyErr {
+    BfError::new_err(message)
+}
+
+/// Runs `program` to the end on `input` and returns what it printed.
+#[pyfunction]
+#[pyo3(signature = (program, input = b"".as_slice()))]
+fn run(program: &str, input: &[u8]) -> PyResult<Vec<u8>> {
+    let mut interpreter = Interpreter::new(program)?;
+    interpreter.feed_input(input);
+    interpreter.close_input();
+    while interpreter.step(u32::MAX)? != "finished" {}
+    Ok(interpreter.take_output())
+}
+
+/// A random program of `size` commands with balanced brackets, for fuzzing.
+/// The same `seed` gives the same program, and `loops` is the chance of each
+/// command being a bracket.
+#[pyfunction]
+#[pyo3(signature = (size, seed, loops = 0.1))]
+fn gen_random(size: usize, seed: u64, loops: f64) -> String {
+    gen::random(size, seed, loops)
+}
+
+/// A program that runs a number of steps at a time, see `embed::Session`.
+#[pyclass(unsendable)]
+struct Interpreter(Session);
+
+#[pymethods]
+impl Interpreter {
+    /// Starts `program` on a tape of 30,000 8-bit cells.
+    #[new]
+    fn new(program: &str) -> PyResult<Interpreter> {
+        let mut session = Session::new(Config::default()).map_err(error)?;
+        session.load(program.as_bytes()).map_err(error)?;
+        Ok(Interpreter(session))
+    }
+
+    /// Runs up to `steps` instructions and tells where the run stopped:
+    /// "running", "input" if a `,` is waiting for `feed_input` or
+    /// `close_input`, "out of fuel" if the fuel given with `set_fuel` ran
+    /// out, or "finished".
+    #[pyo3(signature = (steps = 1_000_000))]
+    fn step(&mut self, steps: u32) -> PyResult<&'static str> {
+        Ok(match self.0.step(steps).map_err(error)? {
+            Status::Running => "running",
+            Status::Input => "input",
+            Status::OutOfFuel => "out of fuel",
+            Status::Finished => "finished",
+        })
+    }
+
+    /// Gives the program `fuel` to burn; `step` returns "out of fuel" once
+    /// it's used up.
+    fn set_fuel(&mut self, fuel: u64) {
+        self.0.set_fuel(fuel);
+    }
+
+    /// Adds fuel, for `step` to go on after "out of fuel".
+    fn add_fuel(&mut self, fuel: u64) {
+        self.0.add_fuel(fuel);
+    }
+
+    /// The fuel left, or `None` if the program isn't metered.
+    #[getter]
+    fn fuel(&self) -> Option<u64> {
+        self.0.fuel()
+    }
+
+    /// Adds bytes for `,` to read.
+    fn feed_input(&mut self, input: &[u8]) {
+        self.0.feed_input(input);
+    }
+
+    /// Ends the input once what was fed is read.
+    fn close_input(&mut self) {
+        self.0.close_input();
+    }
+
+    /// The bytes printed since the last call.
+    fn take_output(&mut self) -> Vec<u8> {
+        self.0.take_output()
+    }
+
+    /// The cells from `start` up to `end`.
+    fn tape(&self, start: usize, end: usize) -> Vec<u32> {
+        self.0.tape_slice(start..end)
+    }
+
+    #[getter]
+    fn data_pointer(&self) -> usize {
+        self.0.data_ptr()
+    }
+
+    /// The instructions executed since the program was loaded.
+    #[getter]
+    fn steps(&self) -> u64 {
+        self.0.steps()
+    }
+}
+
+#[pymodule]
+fn bf(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(run, m)?)?;
+    m.add_function(wrap_pyfunction!(gen_random, m)?)?;
+    m.add_class::<Interpreter>()?;
+    m.add("BfError", m.py().get_type::<BfError>())?;
+    Ok(())
+}
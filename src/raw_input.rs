@@ -0,0 +1,105 @@
+//! `--raw-input`: puts the terminal into non-canonical mode so `,` sees each
+//! keypress right away, without waiting for Enter.
+//!
+//! The terminal is configured with `stty`, which keeps this free of
+//! platform-specific `termios` bindings. Under WASI it can't be configured.
+
+use std::io::{self, IsTerminal, Read, Stdin};
+
+pub(crate) const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+
+/// Keeps the terminal in raw mode while alive, and restores the previous
+/// settings when dropped, including when unwinding from a panic.
+pub struct RawMode {
+    /// Settings as printed by `stty -g`.
+    saved: String,
+}
+
+impl RawMode {
+    pub fn enable() -> Result<Self, String> {
+        if !io::stdin().is_terminal() {
+            return Err("--raw-input needs a terminal on stdin".to_owned());
+        }
+        // Signals are turned off as well, so that Ctrl-C reaches `RawStdin`
+        // and the terminal is restored on the way out.
+        Self::with(&["-icanon", "-echo", "-isig", "min", "1"])
+    }
+
+    /// Like `enable`, but a read waits at most a tenth of a second for a key
+    /// and returns nothing if none was pressed, so the caller can keep
+    /// working in between. Stdin must be a terminal.
+    pub(crate) fn enable_polling() -> Result<Self, String> {
+        Self::with(&["-icanon", "-echo", "-isig", "min", "0", "time", "1"])
+    }
+
+    fn with(settings: &[&str]) -> Result<Self, String> {
+        let saved = stty(&["-g"])?;
+        stty(settings)?;
+        Ok(Self {
+            saved: saved.trim().to_owned(),
+        })
+    }
+}
+
+/// The number of rows and columns of the terminal on stdin, if it reports a
+/// size.
+pub(crate) fn terminal_size() -> Option<(usize, usize)> {
+    let size = stty(&["size"]).ok()?;
+    let (rows, columns) = size.trim().split_once(' ')?;
+    let size = (rows.parse().ok()?, columns.parse().ok()?);
+    (size.0 > 0 && size.1 > 0).then_some(size)
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // Nothing left to do if this fails.
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+#[cfg(not(target_os = "wasi"))]
+fn stty(args: &[&str]) -> Result<String, String> {
+    use std::process::{Command, Stdio};
+
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Failed to run stty: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("stty failed with {}", output.status));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// WASI programs can't run other programs, and the terminal is the host's.
+#[cfg(target_os = "wasi")]
+fn stty(_: &[&str]) -> Result<String, String> {
+    Err("The terminal can't be configured under WASI".to_owned())
+}
+
+/// Stdin read one keypress at a time, with Ctrl-D as the end of input and
+/// Ctrl-C as an error, since the terminal no longer handles them.
+pub struct RawStdin(pub Stdin);
+
+impl Read for RawStdin {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut byte = [0u8; 1];
+        if self.0.read(&mut byte)? == 0 {
+            return Ok(0);
+        }
+        match byte[0] {
+            CTRL_C => Err(io::Error::other("interrupted")),
+            CTRL_D => Ok(0),
+            b => {
+                buf[0] = b;
+                Ok(1)
+            }
+        }
+    }
+}
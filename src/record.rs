@@ -0,0 +1,276 @@
+//! `--record` and `--replay`: a log of everything a run read, so that it can
+//! be run again exactly, e.g. to debug an interactive session afterwards.
+//!
+//! A recording is `MAGIC`, a version byte and, from version 2 on, a header
+//! of fields for what else a run depends on, each a tag byte and a value,
+//! ended by `0x00`. A field this version doesn't know, or a newer version,
+//! is refused rather than replayed differently. The fields are:
+//!
+//! - `0x01` and a LEB128 number: the seed of `--random`, whose bytes `?`
+//!   reads as well as input
+//!
+//! Then comes one event per read:
+//!
+//! - `0x00`: the end of input
+//! - `0x01` and a byte: a byte read
+//! - `0x02`, a LEB128 length and that many bytes of UTF-8: a read that
+//!   failed with that message, e.g. Ctrl-C with `--raw-input`
+
+use crate::leb128;
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8] = b"bfrec";
+/// The version written. Version 1 recordings have no header.
+const VERSION: u8 = 2;
+
+/// Ends the header.
+const END: u8 = 0x00;
+const SEED: u8 = 0x01;
+
+const EOF: u8 = 0x00;
+const BYTE: u8 = 0x01;
+const ERROR: u8 = 0x02;
+
+/// Passes reads through to `inner`, writing each one to `out` as an event.
+pub struct Recorder<R, W> {
+    inner: R,
+    out: W,
+}
+
+impl<R: Read, W: Write> Recorder<R, W> {
+    /// Starts a recording of a run that reads `inner`, and with `--random`
+    /// the bytes from `seed`.
+    pub fn new(inner: R, mut out: W, seed: Option<u64>) -> Result<Self, String> {
+        let mut header = MAGIC.to_vec();
+        header.push(VERSION);
+        if let Some(seed) = seed {
+            header.push(SEED);
+            leb128::write_unsigned(&mut header, seed);
+        }
+        header.push(END);
+        out.write_all(&header)
+            .map_err(|e| format!("Failed to write the recording: {}", e))?;
+        Ok(Self { inner, out })
+    }
+
+    fn record(&mut self, event: &[u8]) -> io::Result<()> {
+        // Flushed right away, so the recording is complete even if the
+        // program is killed.
+        self.out
+            .write_all(event)
+            .and_then(|_| self.out.flush())
+            .map_err(|e| io::Error::other(format!("Failed to write the recording: {}", e)))
+    }
+}
+
+impl<R: Read, W: Write> Read for Recorder<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self.inner.read(buf) {
+            Ok(0) => {
+                self.record(&[EOF])?;
+                Ok(0)
+            }
+            Ok(n) => {
+                let mut event = vec![];
+                for &byte in &buf[..n] {
+                    event.extend([BYTE, byte]);
+                }
+                self.record(&event)?;
+                Ok(n)
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Err(e),
+            Err(e) => {
+                let message = e.to_string();
+                let mut event = vec![ERROR];
+                leb128::write_unsigned(&mut event, message.len() as u64);
+                event.extend_from_slice(message.as_bytes());
+                self.record(&event)?;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Reads the events of a recording back as input.
+pub struct Replay {
+    events: Vec<u8>,
+    pos: usize,
+    seed: Option<u64>,
+}
+
+impl Replay {
+    pub fn new(recording: Vec<u8>) -> Result<Self, String> {
+        let newer = || "The recording was made by a newer version of bf".to_owned();
+        let Some(&version) = recording.strip_prefix(MAGIC).and_then(|rest| rest.first()) else {
+            return Err("Not a recording made with --record".to_owned());
+        };
+        let corrupt = || "The recording is corrupt".to_owned();
+        let mut pos = MAGIC.len() + 1;
+        let mut seed = None;
+        match version {
+            1 => {}
+            VERSION => loop {
+                let tag = *recording.get(pos).ok_or_else(corrupt)?;
+                pos += 1;
+                match tag {
+                    END => break,
+                    SEED => {
+                        seed =
+                            Some(leb128::read_unsigned(&recording, &mut pos).ok_or_else(corrupt)?)
+                    }
+                    _ => return Err(newer()),
+                }
+            },
+            _ => return Err(newer()),
+        }
+        Ok(Self {
+            events: recording,
+            pos,
+            seed,
+        })
+    }
+
+    /// The seed of `--random` the recording was made with, if it was.
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let invalid = || io::Error::other("the recording is corrupt");
+        let Some(&event) = self.events.get(self.pos) else {
+            return Err(io::Error::other(
+                "the recording has no more input, was it made with another program?",
+            ));
+        };
+        self.pos += 1;
+        match event {
+            EOF => Ok(0),
+            BYTE => {
+                buf[0] = *self.events.get(self.pos).ok_or_else(invalid)?;
+                self.pos += 1;
+                Ok(1)
+            }
+            ERROR => {
+                let len = leb128::read_unsigned(&self.events, &mut self.pos).ok_or_else(invalid)?;
+                let message = self
+                    .events
+                    .get(self.pos..self.pos + len as usize)
+                    .ok_or_else(invalid)?;
+                self.pos += len as usize;
+                Err(io::Error::other(String::from_utf8_lossy(message)))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Gives `data`, then the end of input, then fails like Ctrl-C with
+    /// `--raw-input`.
+    struct Interrupted<'a>(&'a [u8], bool);
+
+    impl Read for Interrupted<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 if self.1 => Err(io::Error::other("interrupted")),
+                0 => {
+                    self.1 = true;
+                    Ok(0)
+                }
+                n => Ok(n),
+            }
+        }
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let mut recorder = Recorder::new(Interrupted(b"ab", false), vec![], None).unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(recorder.read(&mut buf).unwrap(), 2);
+        assert_eq!(recorder.read(&mut buf).unwrap(), 0);
+        assert!(recorder.read(&mut buf).is_err());
+        assert_eq!(
+            recorder.out,
+            b"bfrec\x02\x00\x01a\x01b\x00\x02\x0binterrupted"
+        );
+
+        let mut replay = Replay::new(recorder.out).unwrap();
+        assert_eq!(replay.seed(), None);
+        let mut byte = [0u8; 1];
+        assert_eq!(replay.read(&mut byte).unwrap(), 1);
+        assert_eq!(byte, *b"a");
+        assert_eq!(replay.read(&mut byte).unwrap(), 1);
+        assert_eq!(byte, *b"b");
+        assert_eq!(replay.read(&mut byte).unwrap(), 0);
+        assert_eq!(
+            replay.read(&mut byte).unwrap_err().to_string(),
+            "interrupted"
+        );
+        assert!(replay.read(&mut byte).is_err());
+
+        assert_eq!(
+            Replay::new(b"abc".to_vec()).err().unwrap(),
+            "Not a recording made with --record"
+        );
+        // Version 1 had no header.
+        let mut replay = Replay::new(b"bfrec\x01\x01a".to_vec()).unwrap();
+        assert_eq!(replay.read(&mut byte).unwrap(), 1);
+        assert_eq!(byte, *b"a");
+        for newer in [&b"bfrec\x03\x00"[..], b"bfrec\x02\x7f\x00"] {
+            assert_eq!(
+                Replay::new(newer.to_vec()).err().unwrap(),
+                "The recording was made by a newer version of bf"
+            );
+        }
+    }
+
+    #[test]
+    fn replay_random() {
+        use crate::bf_interpreter::BfInterpreter;
+        use crate::plugin::{self, Registry};
+
+        let run = |input: &mut dyn Read, seed| {
+            let mut plugins = Registry::default();
+            plugins.register(b'?', plugin::random(seed)).unwrap();
+            let program = b"?.,.?.,.?.";
+            let (ops, _) =
+                crate::ir::compile_extended(program, false, &plugins.extensions()).unwrap();
+            let mut interpreter = BfInterpreter::builder()
+                .plugins(plugins)
+                .build_ops(ops)
+                .unwrap();
+            let mut output = vec![];
+            let result = crate::runner::run_interpreter(
+                &mut interpreter,
+                input,
+                &mut output,
+                None,
+                None,
+                None,
+                false,
+            );
+            (result, output)
+        };
+
+        let seed = 0x1234_5678_9abc;
+        let mut recorder = Recorder::new(&b"xy"[..], vec![], Some(seed)).unwrap();
+        let recorded = run(&mut recorder, seed);
+        assert!(recorded.0.is_ok());
+
+        let mut replay = Replay::new(recorder.out).unwrap();
+        let seed = replay.seed().unwrap();
+        assert_eq!(run(&mut replay, seed), recorded);
+    }
+}
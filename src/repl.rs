@@ -0,0 +1,444 @@
+//! `bf repl`: runs lines of Brainfuck on one long-lived tape, plus a few
+//! colon-prefixed commands to inspect and manage it.
+
+use crate::bf_interpreter::BfInterpreter;
+use crate::cli::ReplOptions;
+use crate::gen;
+use crate::highlight;
+use crate::tape::Tape;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{BufRead, Cursor, Read, Write};
+use std::ops::Range;
+use std::time::Instant;
+
+pub fn run(options: ReplOptions) -> Result<(), String> {
+    // One interpreter for the whole session, so every line continues on the
+    // tape left by the previous one.
+    let interpreter = BfInterpreter::builder()
+        .config(options.config)
+        .max_steps(options.max_steps)
+        .build(b"")?;
+    // Read before stdin is locked below, in case the program is `-`.
+    let program = match &options.program {
+        Some(path) => Some(crate::runner::read_file(path)?),
+        None => None,
+    };
+    // Stdin and stdout stay locked for the session instead of being locked
+    // again for every line.
+    let mut session = Session::new(
+        interpreter,
+        highlight::enabled(),
+        Box::new(std::io::stdin().lock()),
+        Box::new(std::io::stdout().lock()),
+    );
+    if let Some(program) = program {
+        if let Err(e) = session.run_line(&program) {
+            println!("ERROR: {}", e);
+        }
+    }
+    // With `--script`, lines come from the file and are echoed after the
+    // prompt as if they had been typed.
+    let mut script = match &options.script {
+        Some(path) => Some(Cursor::new(crate::runner::read_file(path)?)),
+        None => None,
+    };
+
+    let mut buf = String::new();
+    // Lines entered so far while a loop is still open.
+    let mut program = String::new();
+    loop {
+        let prompt = if program.is_empty() { "# " } else { ".. " };
+        if script.is_none() {
+            print!("{}", prompt);
+            std::io::stdout().flush().unwrap();
+        }
+
+        buf.clear();
+        let input_ret = match &mut script {
+            Some(script) => script.read_line(&mut buf),
+            None => session.input.read_line(&mut buf),
+        };
+        let buf = buf.trim_end();
+
+        match input_ret {
+            Ok(0) => {
+                // End of input.
+                if script.is_none() {
+                    println!();
+                }
+                break;
+            }
+            Ok(_) => {
+                let is_code = !program.is_empty() || !buf.starts_with(':');
+                if script.is_some() {
+                    let line = if session.color && is_code {
+                        highlight::highlight(buf)
+                    } else {
+                        buf.to_owned()
+                    };
+                    println!("{}{}", prompt, line);
+                } else if session.color && is_code {
+                    // Replace the line the terminal echoed with a highlighted
+                    // copy.
+                    println!("\x1b[1A\r\x1b[2K{}{}", prompt, highlight::highlight(buf));
+                }
+                if program.is_empty() && buf == "exit" {
+                    break;
+                }
+
+                if let Err(e) = session.feed_line(&mut program, buf) {
+                    println!("ERROR: {}", e);
+                    std::io::stdout().flush().unwrap();
+                }
+            }
+            Err(_) => {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How many lines `:undo` can go back.
+const UNDO_LIMIT: usize = 100;
+
+/// The tape and the data pointer as they were before a line, for `:undo`.
+struct Checkpoint {
+    tape: Tape,
+    data_ptr: usize,
+}
+
+/// A tape that lines run on one after another, shared by `bf repl` and
+/// `bf kernel`.
+pub(crate) struct Session {
+    interpreter: BfInterpreter,
+    /// Highlight input and output, see `highlight::enabled`.
+    color: bool,
+    /// The state before each of the last lines, newest last.
+    history: VecDeque<Checkpoint>,
+    /// Where lines and the input for `,` are read from.
+    input: Box<dyn BufRead>,
+    /// Where the output of `.` is written to.
+    output: Box<dyn Write>,
+}
+
+impl Session {
+    pub(crate) fn new(
+        interpreter: BfInterpreter,
+        color: bool,
+        input: Box<dyn BufRead>,
+        output: Box<dyn Write>,
+    ) -> Self {
+        Session {
+            interpreter,
+            color,
+            history: VecDeque::new(),
+            input,
+            output,
+        }
+    }
+
+    /// Replaces what `,` reads from.
+    pub(crate) fn set_input(&mut self, input: Box<dyn BufRead>) {
+        self.input = input;
+    }
+
+    /// Handles one line: a command such as `:dump`, or code that runs once
+    /// every `[` in it and in the lines kept in `pending` is closed.
+    pub(crate) fn feed_line(&mut self, pending: &mut String, line: &str) -> Result<(), String> {
+        match line.strip_prefix(':') {
+            Some(command) if pending.is_empty() => self.meta_command(command),
+            _ => {
+                pending.push_str(line);
+                pending.push('\n');
+                if has_open_loop(pending.as_bytes()) {
+                    return Ok(());
+                }
+                let result = self.run_line(pending.as_bytes());
+                pending.clear();
+                result
+            }
+        }
+    }
+
+    /// Remembers the current state as the one `:undo` goes back to. Once the
+    /// history is full, the oldest checkpoint's pages are reused for it.
+    fn checkpoint(&mut self) {
+        let tape = self.interpreter.tape();
+        let data_ptr = self.interpreter.data_ptr();
+        if self.history.len() == UNDO_LIMIT {
+            let mut oldest = self.history.pop_front().unwrap();
+            oldest.tape.clone_from(tape);
+            oldest.data_ptr = data_ptr;
+            self.history.push_back(oldest);
+        } else {
+            self.history.push_back(Checkpoint {
+                tape: tape.clone(),
+                data_ptr,
+            });
+        }
+    }
+
+    pub(crate) fn run_line(&mut self, program: &[u8]) -> Result<(), String> {
+        self.load(program)?;
+        self.run()
+    }
+
+    fn load(&mut self, program: &[u8]) -> Result<(), String> {
+        // Loading leaves the tape alone, so the checkpoint can wait until the
+        // line is known to parse.
+        self.interpreter.load(program)?;
+        self.checkpoint();
+        Ok(())
+    }
+
+    /// Runs the loaded program, stopping with an error once it exceeds the
+    /// step limit so that `+[]` can't hang the session.
+    fn run(&mut self) -> Result<(), String> {
+        if self.color {
+            print!("{}", highlight::OUTPUT);
+        }
+        let result = crate::runner::run_interpreter(
+            &mut self.interpreter,
+            &mut self.input,
+            &mut self.output,
+            None,
+            None,
+            None,
+            false,
+        );
+        if self.color {
+            print!("{}", highlight::RESET);
+        }
+        result
+    }
+
+    /// Like `crate::runner::read_file`, reading `-` from the locked stdin.
+    fn read_file(&mut self, path: &str) -> Result<Vec<u8>, String> {
+        if path != "-" {
+            return crate::runner::read_file(path);
+        }
+        let mut content = vec![];
+        self.input
+            .read_to_end(&mut content)
+            .map_err(|e| format!("<stdin>: {}", e))?;
+        Ok(content)
+    }
+
+    /// Runs a command such as `:dump 0..10`, given without the colon.
+    fn meta_command(&mut self, command: &str) -> Result<(), String> {
+        let mut text = String::new();
+        let result = self.run_meta_command(command, &mut text);
+        self.output
+            .write_all(text.as_bytes())
+            .and_then(|_| self.output.flush())
+            .map_err(|e| format!("Failed to write output: {}", e))?;
+        result
+    }
+
+    /// Runs a meta command, adding what it shows to `text`.
+    fn run_meta_command(&mut self, command: &str, text: &mut String) -> Result<(), String> {
+        let (name, arg) = match command.split_once(' ') {
+            Some((name, arg)) => (name, Some(arg.trim())),
+            None => (command, None),
+        };
+        let path = || arg.ok_or(format!("Missing file name for :{}", name));
+
+        match name {
+            "reset" => {
+                self.checkpoint();
+                self.interpreter.reset_tape();
+                Ok(())
+            }
+            "undo" => {
+                let mut state = self.history.pop_back().ok_or("Nothing to undo")?;
+                self.interpreter
+                    .swap_tape(&mut state.tape, &mut state.data_ptr);
+                Ok(())
+            }
+            "dump" => {
+                let cells = arg.map(parse_range).transpose()?;
+                text.push_str(&self.interpreter.dump_cells(cells));
+                Ok(())
+            }
+            "ptr" => {
+                writeln!(text, "data pointer: {}", self.interpreter.data_ptr()).unwrap();
+                Ok(())
+            }
+            "load" => {
+                let program = self.read_file(path()?)?;
+                self.run_line(&program)
+            }
+            "time" => {
+                self.load(arg.ok_or("Missing code for :time")?.as_bytes())?;
+                let start = Instant::now();
+                let result = self.run();
+                writeln!(
+                    text,
+                    "{} instructions in {:?}",
+                    self.interpreter.stats().steps,
+                    start.elapsed()
+                )
+                .unwrap();
+                result
+            }
+            "save" => {
+                let path = path()?;
+                std::fs::write(path, snapshot(&self.interpreter))
+                    .map_err(|e| format!("{}: {}", path, e))
+            }
+            "export" => {
+                let path = path()?;
+                let tape = self.interpreter.tape();
+                std::fs::write(path, gen::tape(tape, self.interpreter.data_ptr()))
+                    .map_err(|e| format!("{}: {}", path, e))
+            }
+            _ => Err(format!(
+                "Unknown command ':{}', expected :reset, :undo, :dump, :ptr, :load, :save, :export or :time",
+                name
+            )),
+        }
+    }
+}
+
+/// Whether `program` has a `[` without a matching `]` yet, meaning more lines
+/// should be read before running it. Stray `]`s are left for the parser to
+/// report.
+pub(crate) fn has_open_loop(program: &[u8]) -> bool {
+    let mut depth: usize = 0;
+    for &byte in program {
+        match byte {
+            b'[' => depth += 1,
+            b']' if depth == 0 => return false,
+            b']' => depth -= 1,
+            _ => {}
+        }
+    }
+    depth > 0
+}
+
+/// Parses the range for `:dump`: `start..end`, `start..`, `..end` or a single
+/// cell.
+pub(crate) fn parse_range(s: &str) -> Result<Range<usize>, String> {
+    let invalid = || format!("Invalid range '{}', expected e.g. 0..10", s);
+    let parse = |n: &str, default: usize| match n {
+        "" => Ok(default),
+        n => n.parse().map_err(|_| invalid()),
+    };
+    match s.split_once("..") {
+        Some((start, end)) => Ok(parse(start, 0)?..parse(end, usize::MAX)?),
+        None => {
+            let cell = s.parse::<usize>().map_err(|_| invalid())?;
+            Ok(cell..cell + 1)
+        }
+    }
+}
+
+/// The session state as JSON for `:save`. Trailing zero cells are left out.
+fn snapshot(interpreter: &BfInterpreter) -> String {
+    let tape = interpreter.tape();
+    let used = tape.nonzero().last().map_or(0, |(i, _)| i + 1);
+    let cells = (0..used)
+        .map(|i| tape.get(i).to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut out = String::new();
+    writeln!(out, "{{").unwrap();
+    writeln!(out, "  \"data_pointer\": {},", interpreter.data_ptr()).unwrap();
+    writeln!(out, "  \"cell_size\": {},", tape.cell_size().bits()).unwrap();
+    writeln!(out, "  \"tape_size\": {},", tape.len()).unwrap();
+    writeln!(out, "  \"tape\": [{}]", cells).unwrap();
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn open_loops() {
+        assert!(has_open_loop(b"+[>[-]"));
+        assert!(!has_open_loop(b"+[>[-]]"));
+        assert!(!has_open_loop(b"]["));
+        assert!(!has_open_loop(b"+."));
+    }
+
+    #[test]
+    fn undo_lines() {
+        let mut session = Session {
+            interpreter: BfInterpreter::builder().build(b"").unwrap(),
+            color: false,
+            history: VecDeque::new(),
+            input: Box::new(std::io::empty()),
+            output: Box::new(std::io::sink()),
+        };
+        session.run_line(b"++>").unwrap();
+        session.run_line(b"+>").unwrap();
+        session.meta_command("reset").unwrap();
+
+        session.meta_command("undo").unwrap();
+        assert_eq!(session.interpreter.data_ptr(), 2);
+        session.meta_command("undo").unwrap();
+        assert_eq!(session.interpreter.data_ptr(), 1);
+        assert_eq!(session.interpreter.tape().get(1), 0);
+        session.meta_command("undo").unwrap();
+        assert_eq!(session.interpreter.tape().get(0), 0);
+        assert_eq!(session.meta_command("undo").unwrap_err(), "Nothing to undo");
+    }
+
+    #[test]
+    fn reuse_checkpoints() {
+        let mut session = Session {
+            interpreter: BfInterpreter::builder().build(b"").unwrap(),
+            color: false,
+            history: VecDeque::new(),
+            input: Box::new(Cursor::new(b"ab".to_vec())),
+            output: Box::new(std::io::sink()),
+        };
+        session.run_line(b",>,>").unwrap();
+        for _ in 1..UNDO_LIMIT {
+            session.run_line(b">").unwrap();
+        }
+        assert_eq!(session.history.len(), UNDO_LIMIT);
+        session.run_line(b"+").unwrap();
+        assert_eq!(session.history.len(), UNDO_LIMIT);
+
+        // The newest checkpoint is the state before the last line.
+        session.meta_command("undo").unwrap();
+        assert_eq!(session.interpreter.data_ptr(), UNDO_LIMIT + 1);
+        assert_eq!(session.interpreter.tape().get(UNDO_LIMIT + 1), 0);
+        assert_eq!(session.interpreter.tape().get(1), b'b' as u32);
+    }
+
+    #[test]
+    fn parse_ranges() {
+        assert_eq!(parse_range("2..5"), Ok(2..5));
+        assert_eq!(parse_range("..5"), Ok(0..5));
+        assert_eq!(parse_range("7"), Ok(7..8));
+        assert_eq!(
+            parse_range("a..b").unwrap_err(),
+            "Invalid range 'a..b', expected e.g. 0..10"
+        );
+    }
+
+    #[test]
+    fn snapshot_state() {
+        let mut bf = BfInterpreter::builder().build(b"").unwrap();
+        bf.load(b"+>>+++>").unwrap();
+        while bf.step().unwrap() != crate::bf_interpreter::Ret::Finished {}
+        assert_eq!(
+            snapshot(&bf),
+            "{
+  \"data_pointer\": 3,
+  \"cell_size\": 8,
+  \"tape_size\": 30000,
+  \"tape\": [1, 0, 3]
+}
+"
+        );
+    }
+}
@@ -0,0 +1,202 @@
+//! Loading programs and running them to completion with their I/O hooked
+//! up, shared by `bf run` and the other commands that run programs.
+
+use crate::bf_interpreter::{BfInterpreter, BfInterpreterBuilder, Ret};
+use crate::hang::HangDetector;
+use crate::trace::Tracer;
+use crate::{bytecode, ir, numeric, snapshot};
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Reads a program file, or all of stdin if `path` is `-`.
+pub fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    if path == "-" {
+        let mut content = vec![];
+        std::io::stdin()
+            .read_to_end(&mut content)
+            .map_err(|e| format!("<stdin>: {}", e))?;
+        return Ok(content);
+    }
+    std::fs::read(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// The path of a program file for `preprocess`, or `None` for stdin.
+pub fn source_path(path: &str) -> Option<&Path> {
+    (path != "-").then(|| Path::new(path))
+}
+
+/// Creates an interpreter for either Brainfuck source or `.bfc` bytecode.
+/// For source, the offset of each instruction in it is returned as well.
+pub fn load_program(
+    content: &[u8],
+    builder: BfInterpreterBuilder,
+    debug_char: bool,
+) -> Result<(BfInterpreter, Option<ir::Offsets>), String> {
+    let (ops, offsets) = load_ops(content, debug_char)?;
+    Ok((builder.build_ops(ops)?, offsets))
+}
+
+/// Compiles Brainfuck source, or decodes bytecode, which has no offsets.
+pub fn load_ops(
+    content: &[u8],
+    debug_char: bool,
+) -> Result<(Box<[ir::Op]>, Option<ir::Offsets>), String> {
+    if bytecode::is_bytecode(content) {
+        Ok((bytecode::decode(content)?, None))
+    } else {
+        let (ops, offsets) = ir::compile_with_offsets(content, debug_char)?;
+        Ok((ops, Some(offsets)))
+    }
+}
+
+/// Runs `interpreter` to completion, logging every instruction to `trace` if
+/// given and stopping loops `hangs` thinks never end. With `numeric_io`, `.`
+/// and `,` exchange decimal numbers instead of bytes. Output is flushed
+/// before every read and when the program stops, so prompts show up before
+/// the program waits.
+pub fn run_interpreter(
+    interpreter: &mut BfInterpreter,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    timeout: Option<Duration>,
+    mut trace: Option<&mut Tracer>,
+    hangs: Option<&mut HangDetector>,
+    numeric_io: bool,
+) -> Result<(), String> {
+    let result = run_steps(
+        interpreter,
+        input,
+        output,
+        timeout,
+        trace.as_deref_mut(),
+        hangs,
+        numeric_io,
+    );
+    output
+        .flush()
+        .map_err(|e| format!("Failed to write output: {}", e))?;
+    if let Some(trace) = trace {
+        trace.finish()?;
+    }
+    result
+}
+
+/// Steps run between checks for a timeout or Ctrl-C.
+pub const BATCH_STEPS: u32 = 4096;
+
+pub fn run_steps(
+    interpreter: &mut BfInterpreter,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    timeout: Option<Duration>,
+    mut trace: Option<&mut Tracer>,
+    mut hangs: Option<&mut HangDetector>,
+    numeric_io: bool,
+) -> Result<(), String> {
+    // A run resumed from a snapshot may have stopped while reading.
+    if interpreter.pending_input() {
+        handle_ret(interpreter, Ret::Input, input, output, numeric_io)?;
+    }
+    let deadline = timeout.map(|timeout| (Instant::now() + timeout, timeout));
+    // Without a trace or hang detection nothing needs to see every step, so
+    // the interpreter runs up to a batch of them before coming back here.
+    let batch = if trace.is_none() && hangs.is_none() {
+        BATCH_STEPS
+    } else {
+        1
+    };
+    let mut steps: u32 = 0;
+    loop {
+        // Reading the clock on every step would dominate the run time. A read
+        // blocked on input is not interrupted.
+        steps = steps.wrapping_add(batch);
+        if let Some((deadline, timeout)) = deadline {
+            if steps.is_multiple_of(BATCH_STEPS) && Instant::now() >= deadline {
+                return Err(format!("Timed out after {:?}", timeout));
+            }
+        }
+
+        if snapshot::interrupted() {
+            return Err("Interrupted".to_owned());
+        }
+        if let Some(trace) = trace.as_deref_mut() {
+            trace.record(interpreter)?;
+        }
+        if let Some(hangs) = hangs.as_deref_mut() {
+            hangs.check(interpreter)?;
+        }
+        match interpreter.run_batch(batch)? {
+            Ret::Finished => break,
+            ret => handle_ret(interpreter, ret, input, output, numeric_io)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Does the I/O asked for by what `step` returned: reads input for `,`,
+/// writes output for `.` and prints the state for `#`. The decimal dialect's
+/// `;` and `:` read and write numbers.
+pub fn handle_ret(
+    interpreter: &mut BfInterpreter,
+    ret: Ret,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    numeric_io: bool,
+) -> Result<(), String> {
+    match ret {
+        Ret::Input => {
+            output
+                .flush()
+                .map_err(|e| format!("Failed to write output: {}", e))?;
+            if numeric_io {
+                match numeric::read(input)? {
+                    Some(value) => interpreter.set_input_number(value),
+                    None => interpreter.set_eof(),
+                }
+                return Ok(());
+            }
+            let mut buf = [0u8; 1];
+            match input.read_exact(&mut buf) {
+                Ok(_) => {
+                    interpreter.set_input(buf[0]);
+                }
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    interpreter.set_eof();
+                }
+                Err(e) => return Err(format!("Failed to read input: {}", e)),
+            }
+        }
+        Ret::Output(byte) => {
+            if numeric_io {
+                numeric::write(output, interpreter.current_cell())
+            } else {
+                output.write_all(&[byte])
+            }
+            .map_err(|e| format!("Failed to write output: {}", e))?;
+        }
+        Ret::InputNumber => {
+            output
+                .flush()
+                .map_err(|e| format!("Failed to write output: {}", e))?;
+            match numeric::read(input)? {
+                Some(value) => interpreter.set_input_number(value),
+                None => interpreter.set_eof(),
+            }
+        }
+        Ret::OutputNumber(value) => {
+            write!(output, "{}", value).map_err(|e| format!("Failed to write output: {}", e))?;
+        }
+        Ret::Debug => {
+            output
+                .flush()
+                .map_err(|e| format!("Failed to write output: {}", e))?;
+            eprint!("{}", interpreter.debug_dump());
+        }
+        // Threads are started by `threads::run`, and `bf run` doesn't meter
+        // runs with fuel.
+        Ret::Continue | Ret::Finished | Ret::Fork | Ret::OutOfFuel => {}
+    }
+    Ok(())
+}
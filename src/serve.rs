@@ -0,0 +1,509 @@
+//! `bf serve`: an HTTP server that runs programs sent to it, within limits
+//! on steps, time and memory, e.g. for an online playground.
+//!
+//! ```text
+//! POST /run
+//! {"program": ",[.,]", "input": "hi", "maxSteps": 1000}
+//!
+//! {"output": "hi", "error": null, "stats": {"steps": 7, "time": 0.000012}}
+//! ```
+
+use crate::bf_interpreter::BfInterpreter;
+use crate::cli::{self, Limits, ServeOptions};
+use crate::config::TapeMode;
+use crate::dap;
+use crate::fuse;
+use crate::ir;
+use crate::json::Json;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The largest request body read.
+const MAX_BODY: usize = 16 << 20;
+
+/// The longest request line or header line read.
+const MAX_LINE: usize = 8 << 10;
+
+/// The most header lines read.
+const MAX_HEADERS: usize = 100;
+
+/// The most connections answered at once, each on a thread of its own.
+/// Connections past that are told to try again later.
+const MAX_CONNECTIONS: usize = 64;
+
+/// How long a client gets to send its whole request, from when it connects.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn run(options: ServeOptions) -> Result<(), String> {
+    let address = format!("{}:{}", options.host, options.port);
+    let listener = TcpListener::bind(&address).map_err(|e| format!("{}: {}", address, e))?;
+    eprintln!("Listening on http://{}", address);
+    let active = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else {
+            continue;
+        };
+        let Some(slot) = Slot::take(&active) else {
+            // Best effort; the client is told before it's dropped.
+            let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+            let busy = Response::error(503, "Too many connections, try again later");
+            let _ = stream.write_all(&busy.to_bytes());
+            continue;
+        };
+        let limits = options.limits;
+        std::thread::spawn(move || {
+            let _slot = slot;
+            if let Err(e) = handle_connection(stream, limits) {
+                eprintln!("{}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// One of the `MAX_CONNECTIONS` connections answered at once, given back
+/// when dropped, even by a thread that panicked.
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn take(active: &Arc<AtomicUsize>) -> Option<Slot> {
+        active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < MAX_CONNECTIONS).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Slot(Arc::clone(active)))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Answers one request and closes the connection.
+fn handle_connection(stream: TcpStream, limits: Limits) -> Result<(), String> {
+    let error = |e: io::Error| format!("Failed to answer a request: {}", e);
+    let mut writer = stream.try_clone().map_err(error)?;
+    let reader = DeadlineReader {
+        stream,
+        deadline: Instant::now() + READ_TIMEOUT,
+    };
+    let response = match read_request(&mut BufReader::new(reader)) {
+        Ok(request) => respond(&request, limits),
+        Err(response) => response,
+    };
+    writer
+        .write_all(&response.to_bytes())
+        .and_then(|_| writer.flush())
+        .map_err(error)
+}
+
+/// Reads from a connection until `deadline`, however slowly the client
+/// sends, so a client can't hold on to its slot by trickling in bytes.
+struct DeadlineReader {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Response {
+    status: u16,
+    /// A JSON body, or none.
+    body: Option<Json>,
+}
+
+impl Response {
+    fn json(status: u16, body: Json) -> Self {
+        Response {
+            status,
+            body: Some(body),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Response::json(status, Json::object([("error", message.into())]))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let reason = match self.status {
+            200 => "OK",
+            204 => "No Content",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            413 => "Content Too Large",
+            431 => "Request Header Fields Too Large",
+            503 => "Service Unavailable",
+            _ => "",
+        };
+        let body = self.body.as_ref().map(Json::to_string).unwrap_or_default();
+        // Playgrounds served from elsewhere can call it from the browser.
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n\
+             Access-Control-Allow-Origin: *\r\n\
+             Access-Control-Allow-Methods: POST, OPTIONS\r\n\
+             Access-Control-Allow-Headers: Content-Type\r\n\
+             Connection: close\r\n",
+            self.status, reason
+        );
+        if self.body.is_some() {
+            head += "Content-Type: application/json\r\n";
+        }
+        head += &format!("Content-Length: {}\r\n\r\n", body.len());
+        [head.into_bytes(), body.into_bytes()].concat()
+    }
+}
+
+/// Reads the request line, the headers and the body. A request that can't be
+/// read gets the response to send instead.
+fn read_request(reader: &mut impl BufRead) -> Result<Request, Response> {
+    let bad_request = |message: &str| Response::error(400, message);
+    // Timeouts show up as either kind, depending on the platform.
+    let read_error = |e: io::Error| match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+            Response::error(408, "Timed out reading the request")
+        }
+        _ => bad_request("Incomplete request"),
+    };
+    let mut line = String::new();
+    let read_line = |reader: &mut dyn BufRead, line: &mut String| {
+        line.clear();
+        match reader.take(MAX_LINE as u64 + 1).read_line(line) {
+            Ok(0) => Err(bad_request("Incomplete request")),
+            Err(e) => Err(read_error(e)),
+            Ok(n) if n > MAX_LINE => Err(Response::error(431, "Line too long")),
+            Ok(_) => Ok(()),
+        }
+    };
+
+    read_line(reader, &mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(bad_request("Invalid request line"));
+    };
+    let (method, path) = (method.to_owned(), path.to_owned());
+
+    let mut length = 0;
+    for headers in 0.. {
+        if headers == MAX_HEADERS {
+            return Err(Response::error(431, "Too many headers"));
+        }
+        read_line(reader, &mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| bad_request("Invalid Content-Length"))?;
+            }
+        }
+    }
+    if length > MAX_BODY {
+        return Err(Response::error(413, "Request body too large"));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => bad_request("Incomplete request"),
+        _ => read_error(e),
+    })?;
+    Ok(Request { method, path, body })
+}
+
+fn respond(request: &Request, limits: Limits) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/run") => {
+            let body = String::from_utf8_lossy(&request.body);
+            match Json::parse(&body).and_then(|body| run_request(&body, limits)) {
+                Ok(result) => Response::json(200, result),
+                Err(e) => Response::error(400, &e),
+            }
+        }
+        // A browser asking whether it may post from another origin.
+        ("OPTIONS", _) => Response {
+            status: 204,
+            body: None,
+        },
+        (_, "/run") => Response::error(405, "Only POST is allowed"),
+        _ => Response::error(404, "Not found, programs are run with POST /run"),
+    }
+}
+
+/// Runs the program of a `/run` request. Errors are about the request; a
+/// program that doesn't compile or fails still gets a result, with its error.
+fn run_request(request: &Json, limits: Limits) -> Result<Json, String> {
+    let program = request
+        .get("program")
+        .and_then(Json::as_str)
+        .ok_or("Missing program")?;
+    let input = match request.get("input") {
+        Some(input) => input.as_str().ok_or("Invalid input, expected a string")?,
+        None => "",
+    };
+    let mut config = dap::config_options(request)?;
+
+    // Limits may be given as the strings the options take, or as numbers.
+    let limit = |key: &str| match request.get(key) {
+        None => Ok(None),
+        Some(Json::String(s)) => Ok(Some(s.clone())),
+        Some(n @ Json::Number(_)) => Ok(Some(n.to_string())),
+        Some(_) => Err(format!("Invalid {}", key)),
+    };
+    let max_steps = match limit("maxSteps")? {
+        Some(n) => cli::parse_step_count(&n)?.min(limits.max_steps),
+        None => limits.max_steps,
+    };
+    let timeout = match limit("timeout")? {
+        Some(time) => cli::parse_duration(&time)?.min(limits.timeout),
+        None => limits.timeout,
+    };
+    let max_memory = match limit("maxMemory")? {
+        Some(size) => cli::parse_memory(&size)?.min(limits.max_memory),
+        None => limits.max_memory,
+    };
+
+    // A growing tape stops growing at the memory limit, and the default tape
+    // is cut short to fit.
+    let max_cells = max_memory / config.cell_size.bytes();
+    if max_cells == 0 {
+        return Err(format!(
+            "A memory limit of {} bytes is less than a cell",
+            max_memory
+        ));
+    }
+    if config.tape_mode == TapeMode::Grow {
+        config.tape_mode = TapeMode::Fixed;
+        config.tape_size = max_cells;
+    } else if request.get("tapeSize").is_none() {
+        config.tape_size = config.tape_size.min(max_cells);
+    } else if config.tape_size > max_cells {
+        return Err(format!(
+            "A tape of {} cells needs more than {} bytes",
+            config.tape_size, max_memory
+        ));
+    }
+
+    let mut output = LimitedOutput {
+        bytes: vec![],
+        limit: max_memory,
+    };
+    let start = Instant::now();
+    let mut steps = 0;
+    let result = ir::compile(program.as_bytes()).and_then(|ops| {
+        let mut interpreter = BfInterpreter::builder()
+            .config(config)
+            .max_steps(Some(max_steps))
+            .build_ops(fuse::fuse(&ops, None).ops)?;
+        let result = crate::runner::run_interpreter(
+            &mut interpreter,
+            &mut Cursor::new(input.as_bytes()),
+            &mut output,
+            Some(timeout),
+            None,
+            None,
+            false,
+        );
+        steps = interpreter.stats().steps;
+        result
+    });
+    let time = start.elapsed();
+
+    Ok(Json::object([
+        (
+            "output",
+            String::from_utf8_lossy(&output.bytes).into_owned().into(),
+        ),
+        ("error", result.err().map_or(Json::Null, Json::from)),
+        (
+            "stats",
+            Json::object([
+                ("steps", (steps as usize).into()),
+                ("time", Json::Number(time.as_secs_f64())),
+            ]),
+        ),
+    ]))
+}
+
+/// Collects the output, failing once there's more than `limit` bytes of it.
+struct LimitedOutput {
+    bytes: Vec<u8>,
+    limit: usize,
+}
+
+impl Write for LimitedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.bytes.len() + buf.len() > self.limit {
+            return Err(io::Error::other("the output is over the memory limit"));
+        }
+        self.bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn post(body: &str, limits: Limits) -> (u16, String) {
+        let request = format!(
+            "POST /run HTTP/1.1\r\nHost: localhost\r\ncontent-length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let request = read_request(&mut Cursor::new(request)).unwrap();
+        let response = respond(&request, limits);
+        (response.status, response.body.unwrap().to_string())
+    }
+
+    /// The result without the time, which varies.
+    fn result(body: &str, limits: Limits) -> String {
+        let (status, result) = post(body, limits);
+        assert_eq!(status, 200);
+        result[..result.find(r#","time""#).unwrap()].to_owned()
+    }
+
+    #[test]
+    fn run_programs() {
+        assert_eq!(
+            result(
+                r#"{"program": ",[.,]", "input": "hi", "cellSize": "16"}"#,
+                Limits::default()
+            ),
+            r#"{"output":"hi","error":null,"stats":{"steps":7"#
+        );
+        assert_eq!(
+            result(r#"{"program": "+["}"#, Limits::default()),
+            r#"{"output":"","error":"Missing ']'","stats":{"steps":0"#
+        );
+        assert_eq!(
+            post(r#"{"input": "x"}"#, Limits::default()),
+            (400, r#"{"error":"Missing program"}"#.to_owned())
+        );
+        assert_eq!(post("{", Limits::default()).0, 400);
+    }
+
+    #[test]
+    fn enforce_limits() {
+        let limits = Limits {
+            max_steps: 1000,
+            timeout: Duration::from_secs(10),
+            max_memory: 100,
+        };
+        assert!(result(r#"{"program": "+[]"}"#, limits).contains(r#""steps":1000"#));
+        assert!(result(r#"{"program": "+[]", "maxSteps": 10}"#, limits).contains(r#""steps":10"#));
+        assert!(result(r#"{"program": "+[]", "timeout": "0s"}"#, limits).contains("Timed out"));
+        assert!(result(r#"{"program": "+[>+]", "tape": "grow"}"#, limits)
+            .contains(r#""error":"Memory overflow""#));
+        assert!(result(r#"{"program": "+[>+]"}"#, limits).contains("Memory overflow"));
+        assert!(result(r#"{"program": "+[.]"}"#, limits).contains("over the memory limit"));
+        assert_eq!(
+            post(r#"{"program": "", "tapeSize": "30000"}"#, limits),
+            (
+                400,
+                r#"{"error":"A tape of 30000 cells needs more than 100 bytes"}"#.to_owned()
+            )
+        );
+        assert_eq!(
+            post(r#"{"program": "+>", "maxMemory": 0}"#, limits),
+            (
+                400,
+                r#"{"error":"A memory limit of 0 bytes is less than a cell"}"#.to_owned()
+            )
+        );
+        assert_eq!(
+            post(
+                r#"{"program": "<", "maxMemory": "3", "cellSize": "32"}"#,
+                limits
+            )
+            .0,
+            400
+        );
+    }
+
+    #[test]
+    fn answer_other_requests() {
+        let response = |request: &str| {
+            match read_request(&mut Cursor::new(request)) {
+                Ok(request) => respond(&request, Limits::default()),
+                Err(response) => response,
+            }
+            .status
+        };
+        assert_eq!(response("GET /run HTTP/1.1\r\n\r\n"), 405);
+        assert_eq!(response("GET / HTTP/1.1\r\n\r\n"), 404);
+        assert_eq!(response("OPTIONS /run HTTP/1.1\r\n\r\n"), 204);
+        assert_eq!(response("POST /run HTTP/1.1\r\n"), 400);
+        assert_eq!(
+            response("POST /run HTTP/1.1\r\nContent-Length: 999999999\r\n\r\n"),
+            413
+        );
+        let long = format!("POST /run HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_LINE));
+        assert_eq!(response(&long), 431);
+        let many = format!(
+            "POST /run HTTP/1.1\r\n{}\r\n",
+            "X: a\r\n".repeat(MAX_HEADERS)
+        );
+        assert_eq!(response(&many), 431);
+        let deep = "[".repeat(200_000);
+        assert_eq!(post(&deep, Limits::default()).0, 400);
+
+        // A client trickling in its request runs out of time all the same.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let trickle = std::thread::spawn(move || {
+            for byte in b"POST /run HTTP/1.1\r\nX: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa" {
+                if client.write_all(&[*byte]).is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        });
+        let reader = DeadlineReader {
+            stream,
+            deadline: Instant::now() + Duration::from_millis(200),
+        };
+        let start = Instant::now();
+        let response = read_request(&mut BufReader::new(reader)).unwrap_err();
+        assert_eq!(response.status, 408);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        trickle.join().unwrap();
+
+        let bytes = Response::error(404, "x").to_bytes();
+        let bytes = String::from_utf8(bytes).unwrap();
+        assert!(bytes.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(bytes.ends_with("Content-Length: 13\r\n\r\n{\"error\":\"x\"}"));
+    }
+}
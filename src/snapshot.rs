@@ -0,0 +1,193 @@
+//! The `.bfstate` format of `--snapshot-out` and `--resume`: the state of a
+//! stopped run, so that it can be continued later.
+//!
+//! Layout (integers are LEB128 unless noted otherwise):
+//!
+//! - magic `BFS\0`
+//! - format version, `u16` little endian
+//! - a hash of the program, `u64` little endian, so that a snapshot can't be
+//!   resumed with another program
+//! - the program counter and the data pointer
+//! - whether a `,` is waiting for input, as one byte
+//! - the cell width in bits as one byte, the tape length, the number of
+//!   non-zero cells and then each of them as an index and a value
+
+use crate::bf_interpreter::BfInterpreter;
+use crate::bytecode;
+use crate::config::CellSize;
+use crate::leb128;
+use crate::tape::Tape;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const MAGIC: &[u8; 4] = b"BFS\0";
+const VERSION: u16 = 1;
+
+pub fn encode(interpreter: &BfInterpreter) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&program_hash(interpreter).to_le_bytes());
+    leb128::write_unsigned(&mut out, interpreter.pc() as u64);
+    leb128::write_unsigned(&mut out, interpreter.data_ptr() as u64);
+    out.push(interpreter.pending_input() as u8);
+
+    let tape = interpreter.tape();
+    out.push(tape.cell_size().bits() as u8);
+    leb128::write_unsigned(&mut out, tape.len() as u64);
+    let cells = tape.nonzero().collect::<Vec<_>>();
+    leb128::write_unsigned(&mut out, cells.len() as u64);
+    for (i, value) in cells {
+        leb128::write_unsigned(&mut out, i as u64);
+        leb128::write_unsigned(&mut out, value as u64);
+    }
+    out
+}
+
+/// Continues the run a snapshot was taken of in `interpreter`, which must
+/// have been built for the same program.
+pub fn restore(interpreter: &mut BfInterpreter, bytes: &[u8]) -> Result<(), String> {
+    if !bytes.starts_with(MAGIC) {
+        return Err("Not a snapshot file".to_owned());
+    }
+    let version = bytes
+        .get(4..6)
+        .map(|v| u16::from_le_bytes([v[0], v[1]]))
+        .ok_or("Truncated snapshot")?;
+    if version != VERSION {
+        return Err(format!(
+            "Unsupported snapshot version {} (expected {})",
+            version, VERSION
+        ));
+    }
+    let hash = bytes
+        .get(6..14)
+        .map(|h| u64::from_le_bytes(h.try_into().unwrap()))
+        .ok_or("Truncated snapshot")?;
+    if hash != program_hash(interpreter) {
+        return Err("The snapshot was taken of another program".to_owned());
+    }
+
+    let mut pos = 14;
+    let pc = read(bytes, &mut pos)? as usize;
+    let data_ptr = read(bytes, &mut pos)? as usize;
+    let pending_input = read_byte(bytes, &mut pos)? != 0;
+    let cell_size = read_byte(bytes, &mut pos)?
+        .to_string()
+        .parse::<CellSize>()
+        .map_err(|_| "The snapshot is corrupt")?;
+    let mut tape = Tape::new(cell_size, read(bytes, &mut pos)? as usize);
+    for _ in 0..read(bytes, &mut pos)? {
+        let i = read(bytes, &mut pos)? as usize;
+        if i >= tape.len() {
+            return Err("The snapshot is corrupt".to_owned());
+        }
+        tape.set(i, read(bytes, &mut pos)? as u32);
+    }
+    interpreter.restore(pc, data_ptr, tape, pending_input)
+}
+
+fn read(bytes: &[u8], pos: &mut usize) -> Result<u64, String> {
+    leb128::read_unsigned(bytes, pos).ok_or_else(|| "Truncated snapshot".to_owned())
+}
+
+fn read_byte(bytes: &[u8], pos: &mut usize) -> Result<u8, String> {
+    let byte = *bytes.get(*pos).ok_or("Truncated snapshot")?;
+    *pos += 1;
+    Ok(byte)
+}
+
+/// FNV-1a of the program's bytecode, which is the same whichever way the
+/// program was loaded.
+fn program_hash(interpreter: &BfInterpreter) -> u64 {
+    bytecode::encode(interpreter.program(), &[])
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3)
+        })
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Makes Ctrl-C set `interrupted` instead of killing the process, so that a
+/// snapshot can be written. A second Ctrl-C still exits right away, e.g. if
+/// the program is waiting for input.
+pub fn catch_interrupt() {
+    #[cfg(unix)]
+    {
+        extern "C" {
+            fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+            fn _exit(status: i32) -> !;
+        }
+        const SIGINT: i32 = 2;
+
+        extern "C" fn on_interrupt(_: i32) {
+            if INTERRUPTED.swap(true, Ordering::Relaxed) {
+                // SAFETY: _exit is async-signal-safe.
+                unsafe { _exit(130) }
+            }
+        }
+        // SAFETY: the handler only touches an atomic and calls _exit.
+        unsafe {
+            signal(SIGINT, on_interrupt);
+        }
+    }
+}
+
+/// Whether Ctrl-C was pressed since `catch_interrupt`.
+pub(crate) fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Forgets an earlier Ctrl-C, for `bf kernel`, which carries on with the next
+/// cell after an interrupted one.
+pub(crate) fn clear_interrupt() {
+    INTERRUPTED.store(false, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::bf_interpreter::Ret;
+    use crate::config::Config;
+
+    fn interpreter(program: &[u8], cell_size: CellSize) -> BfInterpreter {
+        BfInterpreter::builder()
+            .config(Config {
+                cell_size,
+                ..Config::default()
+            })
+            .build(program)
+            .unwrap()
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let program = b"+++>>-<,.";
+        let mut bf = interpreter(program, CellSize::Bits16);
+        while bf.step().unwrap() != Ret::Input {}
+        let snapshot = encode(&bf);
+
+        let mut resumed = interpreter(program, CellSize::Bits16);
+        restore(&mut resumed, &snapshot).unwrap();
+        assert_eq!(resumed.pc(), bf.pc());
+        assert_eq!(resumed.data_ptr(), 1);
+        assert!(resumed.pending_input());
+        assert_eq!(resumed.tape().get(0), 3);
+        assert_eq!(resumed.tape().get(2), 0xffff);
+        resumed.set_input(b'x');
+        assert_eq!(resumed.step().unwrap(), Ret::Output(b'x'));
+
+        assert_eq!(
+            restore(&mut interpreter(b"+", CellSize::Bits16), &snapshot).unwrap_err(),
+            "The snapshot was taken of another program"
+        );
+        assert_eq!(
+            restore(&mut interpreter(program, CellSize::Bits8), &snapshot).unwrap_err(),
+            "The snapshot has 16-bit cells, but this run uses 8-bit cells"
+        );
+        assert_eq!(
+            restore(&mut interpreter(program, CellSize::Bits16), &snapshot[..20]).unwrap_err(),
+            "Truncated snapshot"
+        );
+    }
+}
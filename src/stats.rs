@@ -0,0 +1,86 @@
+//! `bf stats`: what a program is made of, from its source alone.
+
+use crate::bounds;
+use crate::ir;
+use crate::parser::{find_matching_parens, parse_program, Token};
+use std::fmt::Write;
+
+pub fn report(program: &[u8]) -> Result<String, String> {
+    let tokens = parse_program(program, false);
+    find_matching_parens(&tokens)?;
+    let ops = ir::compile(program)?;
+
+    let mut counts = [0; 8];
+    let mut depth = 0;
+    let mut max_depth = 0;
+    for token in tokens.iter() {
+        let kind = match token {
+            Token::IncDataPtr => 0,
+            Token::DecDataPtr => 1,
+            Token::IncByte => 2,
+            Token::DecByte => 3,
+            Token::WriteByte => 4,
+            Token::ReadByte => 5,
+            Token::BeginLoop => {
+                depth += 1;
+                max_depth = max_depth.max(depth);
+                6
+            }
+            Token::EndLoop => {
+                depth -= 1;
+                7
+            }
+            Token::Debug
+            | Token::BeginProcedure
+            | Token::EndProcedure
+            | Token::Call
+            | Token::Fork
+            | Token::Extended(_)
+            | Token::Plugin(_)
+            | Token::WriteNumber
+            | Token::ReadNumber => continue,
+        };
+        counts[kind] += 1;
+    }
+
+    let mut out = String::new();
+    writeln!(out, "size: {} bytes", program.len()).unwrap();
+    writeln!(out, "commands: {}", tokens.len()).unwrap();
+    for (command, count) in "><+-.,[]".chars().zip(counts) {
+        writeln!(out, "  {:<12}{}", command, count).unwrap();
+    }
+    writeln!(out, "instructions after optimization: {}", ops.len()).unwrap();
+    writeln!(out, "loops: {}", counts[6]).unwrap();
+    writeln!(out, "max loop nesting depth: {}", max_depth).unwrap();
+    writeln!(out, "min tape usage: {} cells", bounds::min_cells(&ops)).unwrap();
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn report_stats() {
+        assert_eq!(
+            report(b"read a byte: ,\n>>+[[-]<,.>]").unwrap(),
+            "size: 27 bytes
+commands: 13
+  >           3
+  <           1
+  +           1
+  -           1
+  .           1
+  ,           2
+  [           2
+  ]           2
+instructions after optimization: 10
+loops: 2
+max loop nesting depth: 2
+min tape usage: 3 cells
+"
+        );
+        assert_eq!(report(b"]").unwrap_err(), "Missing '['");
+    }
+}
@@ -0,0 +1,423 @@
+use crate::config::CellSize;
+use crate::mmap::MappedFile;
+use std::ops::Range;
+
+/// Cells per page, see `Tape`.
+const PAGE: usize = 4096;
+
+/// Cell storage for the interpreter. Values are passed around as `u32` and
+/// truncated to the cell width on store, which makes arithmetic wrap at the
+/// right place.
+///
+/// The cells are kept in pages that are only allocated once something other
+/// than 0 is stored in them, so a tape costs next to nothing until it's used,
+/// however long it is. A tape can also be kept in a file, see `mapped`.
+#[derive(Debug)]
+pub struct Tape {
+    len: usize,
+    cells: Cells,
+}
+
+/// Each cell width gets its own pages so that byte-sized tapes don't pay for
+/// wider cells.
+#[derive(Debug)]
+enum Cells {
+    Bits8(Pages<u8>),
+    Bits16(Pages<u16>),
+    Bits32(Pages<u32>),
+    /// Cells of the given size in a file, little endian.
+    Mapped(CellSize, MappedFile),
+}
+
+impl Clone for Tape {
+    fn clone(&self) -> Self {
+        let cells = match &self.cells {
+            Cells::Bits8(pages) => Cells::Bits8(pages.clone()),
+            Cells::Bits16(pages) => Cells::Bits16(pages.clone()),
+            Cells::Bits32(pages) => Cells::Bits32(pages.clone()),
+            // A copy, such as a Brainfork thread's, is kept in memory.
+            Cells::Mapped(cell_size, _) => {
+                let mut tape = Tape::new(*cell_size, self.len);
+                for (i, value) in self.nonzero() {
+                    tape.set(i, value);
+                }
+                return tape;
+            }
+        };
+        Tape {
+            len: self.len,
+            cells,
+        }
+    }
+
+    /// Copies `source` into the pages already allocated where it can, so the
+    /// REPL's undo history doesn't allocate a tape's worth of pages per line.
+    fn clone_from(&mut self, source: &Self) {
+        match (&mut self.cells, &source.cells) {
+            (Cells::Bits8(to), Cells::Bits8(from)) => to.clone_from(from),
+            (Cells::Bits16(to), Cells::Bits16(from)) => to.clone_from(from),
+            (Cells::Bits32(to), Cells::Bits32(from)) => to.clone_from(from),
+            _ => return *self = source.clone(),
+        }
+        self.len = source.len;
+    }
+}
+
+/// The pages up to the last one written to, with `None` for those that are
+/// all 0.
+#[derive(Debug)]
+struct Pages<T>(Vec<Option<Box<[T; PAGE]>>>);
+
+impl<T: Copy> Clone for Pages<T> {
+    fn clone(&self) -> Self {
+        Pages(self.0.clone())
+    }
+
+    /// Reuses the pages allocated in both.
+    fn clone_from(&mut self, source: &Self) {
+        self.0.clone_from(&source.0);
+    }
+}
+
+impl<T: Copy + Default + Into<u32>> Pages<T> {
+    fn get(&self, index: usize) -> u32 {
+        match self.0.get(index / PAGE) {
+            Some(Some(page)) => page[index % PAGE].into(),
+            _ => 0,
+        }
+    }
+
+    fn set(&mut self, index: usize, value: T, zero: bool) {
+        let page = index / PAGE;
+        if page >= self.0.len() {
+            if zero {
+                return;
+            }
+            self.0.resize_with(page + 1, || None);
+        }
+        match &mut self.0[page] {
+            Some(cells) => cells[index % PAGE] = value,
+            None if zero => {}
+            slot => {
+                let mut cells = Box::new([T::default(); PAGE]);
+                cells[index % PAGE] = value;
+                *slot = Some(cells);
+            }
+        }
+    }
+
+    /// Extends the page table to cover cell `index`, without allocating any
+    /// pages.
+    fn reserve(&mut self, index: usize) {
+        let pages = index / PAGE + 1;
+        if self.0.len() < pages {
+            self.0.resize_with(pages, || None);
+        }
+    }
+
+    /// Like `get`, for a cell covered by the page table.
+    ///
+    /// # Safety
+    ///
+    /// `index` must be below `PAGE` times the length of the page table.
+    unsafe fn get_unchecked(&self, index: usize) -> u32 {
+        match unsafe { self.0.get_unchecked(index / PAGE) } {
+            Some(page) => page[index % PAGE].into(),
+            None => 0,
+        }
+    }
+
+    /// Like `set`, for a cell covered by the page table.
+    ///
+    /// # Safety
+    ///
+    /// As for `get_unchecked`.
+    unsafe fn set_unchecked(&mut self, index: usize, value: T, zero: bool) {
+        match unsafe { self.0.get_unchecked_mut(index / PAGE) } {
+            Some(cells) => cells[index % PAGE] = value,
+            None if zero => {}
+            slot => {
+                let mut cells = Box::new([T::default(); PAGE]);
+                cells[index % PAGE] = value;
+                *slot = Some(cells);
+            }
+        }
+    }
+
+    /// Drops the pages from cell `len` on, and zeroes the rest of the last
+    /// one kept, so that growing the tape again gives zeroed cells.
+    fn truncate(&mut self, len: usize) {
+        self.0.truncate(len.div_ceil(PAGE));
+        if let Some(Some(cells)) = self.0.last_mut() {
+            if !len.is_multiple_of(PAGE) {
+                cells[len % PAGE..].fill(T::default());
+            }
+        }
+    }
+
+    /// The cells of the pages that are allocated, of a tape `len` cells
+    /// long.
+    fn allocated(&self, len: usize) -> Vec<Range<usize>> {
+        (0..self.0.len())
+            .filter(|&page| self.0[page].is_some())
+            .map(|page| page * PAGE..((page + 1) * PAGE).min(len))
+            .collect()
+    }
+}
+
+impl Tape {
+    pub(crate) fn new(cell_size: CellSize, len: usize) -> Self {
+        let cells = match cell_size {
+            CellSize::Bits8 => Cells::Bits8(Pages(vec![])),
+            CellSize::Bits16 => Cells::Bits16(Pages(vec![])),
+            CellSize::Bits32 => Cells::Bits32(Pages(vec![])),
+        };
+        Tape { len, cells }
+    }
+
+    /// A tape of `len` zeroed cells kept in the file at `path`, which is
+    /// created or overwritten. Everything stored is in the file right away,
+    /// so it's still there if the process dies.
+    pub(crate) fn mapped(cell_size: CellSize, len: usize, path: &str) -> Result<Self, String> {
+        let bytes = len
+            .checked_mul(cell_size.bits() as usize / 8)
+            .ok_or_else(|| format!("{}: a tape of {} cells is too large", path, len))?;
+        let file = MappedFile::create(path, bytes)?;
+        Ok(Tape {
+            len,
+            cells: Cells::Mapped(cell_size, file),
+        })
+    }
+
+    pub(crate) fn cell_size(&self) -> CellSize {
+        match self.cells {
+            Cells::Bits8(_) => CellSize::Bits8,
+            Cells::Bits16(_) => CellSize::Bits16,
+            Cells::Bits32(_) => CellSize::Bits32,
+            Cells::Mapped(cell_size, _) => cell_size,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn get(&self, index: usize) -> u32 {
+        match &self.cells {
+            Cells::Bits8(pages) => pages.get(index),
+            Cells::Bits16(pages) => pages.get(index),
+            Cells::Bits32(pages) => pages.get(index),
+            Cells::Mapped(cell_size, file) => {
+                let bytes = file.bytes();
+                match cell_size {
+                    CellSize::Bits8 => bytes[index] as u32,
+                    CellSize::Bits16 => {
+                        u16::from_le_bytes([bytes[2 * index], bytes[2 * index + 1]]) as u32
+                    }
+                    CellSize::Bits32 => {
+                        u32::from_le_bytes(bytes[4 * index..4 * index + 4].try_into().unwrap())
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) fn set(&mut self, index: usize, value: u32) {
+        match &mut self.cells {
+            Cells::Bits8(pages) => pages.set(index, value as u8, value as u8 == 0),
+            Cells::Bits16(pages) => pages.set(index, value as u16, value as u16 == 0),
+            Cells::Bits32(pages) => pages.set(index, value, value == 0),
+            Cells::Mapped(cell_size, file) => {
+                let bytes = file.bytes_mut();
+                match cell_size {
+                    CellSize::Bits8 => bytes[index] = value as u8,
+                    CellSize::Bits16 => bytes[2 * index..2 * index + 2]
+                        .copy_from_slice(&(value as u16).to_le_bytes()),
+                    CellSize::Bits32 => {
+                        bytes[4 * index..4 * index + 4].copy_from_slice(&value.to_le_bytes())
+                    }
+                }
+            }
+        }
+    }
+
+    /// Makes `get_unchecked` and `set_unchecked` safe to use on the cells up
+    /// to `index`, which has to be on the tape. Until the tape is resized
+    /// smaller or replaced, that is.
+    pub(crate) fn reserve(&mut self, index: usize) {
+        assert!(index < self.len, "reserving past the end of the tape");
+        match &mut self.cells {
+            Cells::Bits8(pages) => pages.reserve(index),
+            Cells::Bits16(pages) => pages.reserve(index),
+            Cells::Bits32(pages) => pages.reserve(index),
+            // Every cell of a file is there from the start.
+            Cells::Mapped(..) => {}
+        }
+    }
+
+    /// Like `get`, without checking that `index` is on the tape.
+    ///
+    /// # Safety
+    ///
+    /// `index` must be at most the one given to `reserve` on this tape.
+    pub(crate) unsafe fn get_unchecked(&self, index: usize) -> u32 {
+        match &self.cells {
+            Cells::Bits8(pages) => unsafe { pages.get_unchecked(index) },
+            Cells::Bits16(pages) => unsafe { pages.get_unchecked(index) },
+            Cells::Bits32(pages) => unsafe { pages.get_unchecked(index) },
+            Cells::Mapped(cell_size, file) => {
+                let bytes = file.bytes();
+                let width = cell_size.bits() as usize / 8;
+                let cell = unsafe { bytes.get_unchecked(width * index..width * (index + 1)) };
+                match cell_size {
+                    CellSize::Bits8 => cell[0] as u32,
+                    CellSize::Bits16 => u16::from_le_bytes([cell[0], cell[1]]) as u32,
+                    CellSize::Bits32 => u32::from_le_bytes([cell[0], cell[1], cell[2], cell[3]]),
+                }
+            }
+        }
+    }
+
+    /// Like `set`, without checking that `index` is on the tape.
+    ///
+    /// # Safety
+    ///
+    /// As for `get_unchecked`.
+    pub(crate) unsafe fn set_unchecked(&mut self, index: usize, value: u32) {
+        match &mut self.cells {
+            Cells::Bits8(pages) => unsafe {
+                pages.set_unchecked(index, value as u8, value as u8 == 0)
+            },
+            Cells::Bits16(pages) => unsafe {
+                pages.set_unchecked(index, value as u16, value as u16 == 0)
+            },
+            Cells::Bits32(pages) => unsafe { pages.set_unchecked(index, value, value == 0) },
+            Cells::Mapped(cell_size, file) => {
+                let bytes = file.bytes_mut();
+                let width = cell_size.bits() as usize / 8;
+                let cell = unsafe { bytes.get_unchecked_mut(width * index..width * (index + 1)) };
+                cell.copy_from_slice(&value.to_le_bytes()[..width]);
+            }
+        }
+    }
+
+    /// Resizes the tape to `len` cells, new ones being 0. Tapes kept in a
+    /// file can't be resized.
+    pub(crate) fn resize(&mut self, len: usize) {
+        if len < self.len {
+            match &mut self.cells {
+                Cells::Bits8(pages) => pages.truncate(len),
+                Cells::Bits16(pages) => pages.truncate(len),
+                Cells::Bits32(pages) => pages.truncate(len),
+                Cells::Mapped(..) => unreachable!("resizing a tape file"),
+            }
+        } else if let Cells::Mapped(..) = self.cells {
+            unreachable!("resizing a tape file")
+        }
+        self.len = len;
+    }
+
+    /// The index and value of every cell that isn't 0, in order. Pages never
+    /// written to are skipped, so this is cheap on a long tape.
+    pub(crate) fn nonzero(&self) -> impl Iterator<Item = (usize, u32)> + '_ {
+        let allocated = match &self.cells {
+            Cells::Bits8(pages) => pages.allocated(self.len),
+            Cells::Bits16(pages) => pages.allocated(self.len),
+            Cells::Bits32(pages) => pages.allocated(self.len),
+            // The whole file has to be read.
+            Cells::Mapped(..) => std::iter::once(0..self.len).collect(),
+        };
+        allocated
+            .into_iter()
+            .flatten()
+            .map(move |i| (i, self.get(i)))
+            .filter(|&(_, value)| value != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn wrap_at_cell_width() {
+        for (cell_size, max) in [
+            (CellSize::Bits8, 255),
+            (CellSize::Bits16, 65_535),
+            (CellSize::Bits32, u32::MAX),
+        ] {
+            let mut tape = Tape::new(cell_size, 2);
+            tape.set(1, 0u32.wrapping_sub(1));
+            assert_eq!(tape.get(1), max);
+            tape.set(1, tape.get(1).wrapping_add(1));
+            assert_eq!(tape.get(1), 0);
+        }
+    }
+
+    #[test]
+    fn resize() {
+        let mut tape = Tape::new(CellSize::Bits16, 1);
+        tape.set(0, 7);
+        tape.resize(4);
+        assert_eq!(tape.len(), 4);
+        assert_eq!(tape.get(0), 7);
+        assert_eq!(tape.get(3), 0);
+
+        // Cells cut off are 0 when the tape grows back.
+        tape.set(3, 1);
+        tape.resize(2);
+        tape.resize(4);
+        assert_eq!(tape.get(3), 0);
+    }
+
+    #[test]
+    fn clone_into_allocated_pages() {
+        let mut source = Tape::new(CellSize::Bits8, 3 * PAGE);
+        source.set(PAGE, 5);
+        let mut tape = Tape::new(CellSize::Bits8, 3 * PAGE);
+        tape.set(0, 1);
+        tape.set(2 * PAGE, 2);
+        tape.clone_from(&source);
+        assert_eq!(tape.nonzero().collect::<Vec<_>>(), [(PAGE, 5)]);
+
+        let wider = Tape::new(CellSize::Bits16, 10);
+        tape.clone_from(&wider);
+        assert_eq!(tape.cell_size(), CellSize::Bits16);
+        assert_eq!(tape.len(), 10);
+    }
+
+    #[test]
+    fn tape_file() {
+        let path = std::env::temp_dir().join(format!("bf-tape-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut tape = Tape::mapped(CellSize::Bits16, 1 << 20, path).unwrap();
+        tape.set(1, 0x1234);
+        tape.set(3, 0x10000);
+        assert_eq!(tape.get(1), 0x1234);
+        assert_eq!(tape.get(3), 0);
+        let copy = tape.clone();
+        drop(tape);
+
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(bytes.len(), 2 << 20);
+        assert_eq!(bytes[..4], [0, 0, 0x34, 0x12]);
+        assert_eq!(copy.nonzero().collect::<Vec<_>>(), [(1, 0x1234)]);
+    }
+
+    #[test]
+    fn sparse_pages() {
+        let mut tape = Tape::new(CellSize::Bits8, usize::MAX);
+        tape.set(3, 1);
+        tape.set(1 << 20, 2);
+        tape.set(1 << 30, 0);
+        assert_eq!(tape.get(1 << 40), 0);
+        assert_eq!(tape.nonzero().collect::<Vec<_>>(), [(3, 1), (1 << 20, 2)]);
+        // Only the pages written to other than 0 are allocated.
+        let Cells::Bits8(pages) = &tape.cells else {
+            unreachable!()
+        };
+        assert_eq!(pages.0.iter().flatten().count(), 2);
+    }
+}
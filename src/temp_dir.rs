@@ -0,0 +1,86 @@
+//! Private temporary directories, for commands that hand files to other
+//! programs, such as `bf build` giving C to the C compiler.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// A new directory in the system temp directory that only this user can
+/// get into, removed with everything in it when dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates a directory named `prefix` and a random suffix. The name
+    /// can't be guessed ahead of time and the directory mustn't exist yet,
+    /// so other users can't plant files or links where it will be.
+    pub fn new(prefix: &str) -> Result<TempDir, String> {
+        let base = std::env::temp_dir();
+        for _ in 0..16 {
+            let path = base.join(format!("{}-{:016x}", prefix, random()));
+            match create(&path) {
+                Ok(()) => return Ok(TempDir { path }),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(format!("{}: {}", path.display(), e)),
+            }
+        }
+        Err(format!(
+            "Failed to create a temporary directory in {}",
+            base.display()
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        // Best effort; there's no one to tell.
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+fn create(path: &Path) -> std::io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(path)
+}
+
+/// 64 random bits. Every `RandomState` has new keys, seeded from the
+/// operating system, so hashing the same thing with each gives a new value.
+fn random() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn create_and_remove() {
+        let a = TempDir::new("bf-temp-dir-test").unwrap();
+        let b = TempDir::new("bf-temp-dir-test").unwrap();
+        assert_ne!(a.path(), b.path());
+        std::fs::write(a.path().join("file"), "contents").unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(a.path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+
+        let path = a.path().to_owned();
+        drop(a);
+        assert!(!path.exists());
+        assert!(b.path().exists());
+    }
+}
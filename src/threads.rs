@@ -0,0 +1,158 @@
+//! Brainfork threads. `Y` starts a new thread with a copy of the tape: the
+//! thread that ran it sees its current cell set to 0, the new one has the
+//! data pointer one cell to the right, on a 1.
+//!
+//! The threads take turns running one instruction each, in a fixed order
+//! where every thread comes right after the one that started it, so a run
+//! always interleaves the same way. Output is written in the order the `.`
+//! instructions run, and each `,` reads the next input byte whichever thread
+//! runs it.
+
+use crate::bf_interpreter::{BfInterpreter, Ret};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+/// Threads running at the same time at most, so a program that keeps
+/// forking fails instead of using up all memory.
+const MAX_THREADS: usize = 1024;
+
+/// Runs `main` and all the threads it starts until every one has finished.
+/// `main` keeps the state of the first thread, for `--stats` and the like.
+pub fn run(
+    main: &mut BfInterpreter,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    timeout: Option<Duration>,
+    numeric_io: bool,
+) -> Result<(), String> {
+    let result = run_threads(main, input, output, timeout, numeric_io);
+    output
+        .flush()
+        .map_err(|e| format!("Failed to write output: {}", e))?;
+    result
+}
+
+fn run_threads(
+    main: &mut BfInterpreter,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    timeout: Option<Duration>,
+    numeric_io: bool,
+) -> Result<(), String> {
+    let deadline = timeout.map(|timeout| (Instant::now() + timeout, timeout));
+    let mut main_running = true;
+    // The other threads in turn order, with the number each was started as.
+    let mut threads: Vec<(usize, BfInterpreter)> = vec![];
+    let mut started = 1;
+    let mut rounds: u32 = 0;
+
+    while main_running || !threads.is_empty() {
+        rounds = rounds.wrapping_add(1);
+        if let Some((deadline, timeout)) = deadline {
+            if rounds.is_multiple_of(4096) && Instant::now() >= deadline {
+                return Err(format!("Timed out after {:?}", timeout));
+            }
+        }
+
+        // A new thread gets its turn right after the one that started it,
+        // in the same round.
+        if main_running {
+            match step(main, input, output, numeric_io)? {
+                Some(Ret::Finished) => main_running = false,
+                Some(_) => {}
+                None => start(&mut threads, 0, main.fork()?, &mut started)?,
+            }
+        }
+        let mut i = 0;
+        while i < threads.len() {
+            let (number, thread) = &mut threads[i];
+            let number = *number;
+            let error = |e| format!("Thread {}: {}", number, e);
+            match step(thread, input, output, numeric_io).map_err(error)? {
+                Some(Ret::Finished) => {
+                    threads.remove(i);
+                    continue;
+                }
+                Some(_) => {}
+                None => {
+                    let thread = thread.fork().map_err(error)?;
+                    start(&mut threads, i + 1, thread, &mut started)?;
+                }
+            }
+            i += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Runs one instruction of `thread`. Returns None if it asked for a new
+/// thread.
+fn step(
+    thread: &mut BfInterpreter,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    numeric_io: bool,
+) -> Result<Option<Ret>, String> {
+    match thread.step()? {
+        Ret::Fork => Ok(None),
+        ret => {
+            crate::runner::handle_ret(thread, ret, input, output, numeric_io)?;
+            Ok(Some(ret))
+        }
+    }
+}
+
+/// Puts a new `thread` at `index` in the turn order.
+fn start(
+    threads: &mut Vec<(usize, BfInterpreter)>,
+    index: usize,
+    thread: BfInterpreter,
+    started: &mut usize,
+) -> Result<(), String> {
+    if threads.len() + 1 == MAX_THREADS {
+        return Err(format!("More than {} threads", MAX_THREADS));
+    }
+    threads.insert(index, (*started, thread));
+    *started += 1;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::ir;
+    use crate::parser::BRAINFORK;
+    use std::io::Cursor;
+
+    fn run_program(program: &[u8], input: &[u8]) -> (Result<(), String>, Vec<u8>) {
+        let (ops, _) = ir::compile_extended(program, false, BRAINFORK).unwrap();
+        let mut main = BfInterpreter::builder().build_ops(ops).unwrap();
+        let mut output = vec![];
+        let result = run(&mut main, &mut Cursor::new(input), &mut output, None, false);
+        (result, output)
+    }
+
+    #[test]
+    fn fork_threads() {
+        // The threads take turns, the new one on its own copy of the tape.
+        // It already runs in the round it was started, so it's a step ahead.
+        let (result, output) = run_program(b"++Y>.<.", b"");
+        assert_eq!(result, Ok(()));
+        assert_eq!(output, [0, 0, 1, 0]);
+
+        // Both threads read from the same input, in turn.
+        let (result, output) = run_program(b"Y,.", b"xy");
+        assert_eq!(result, Ok(()));
+        assert_eq!(output, b"xy");
+
+        assert_eq!(
+            run_program(b"Y[<<]", b"").0.unwrap_err(),
+            "Thread 1: Memory underflow"
+        );
+        assert_eq!(
+            run_program(b"+[Y+]", b"").0.unwrap_err(),
+            "More than 1024 threads"
+        );
+    }
+}
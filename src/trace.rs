@@ -0,0 +1,412 @@
+//! The `--trace` log: one line per executed instruction, a Chrome trace of
+//! the loops and I/O for chrome://tracing or Perfetto, or folded stacks of
+//! loops for flame graphs.
+
+use crate::bf_interpreter::BfInterpreter;
+use crate::ir::Op;
+use crate::profile::{self, SourceMap};
+use std::collections::HashMap;
+use std::io::Write;
+use std::str::FromStr;
+use std::time::Instant;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum TraceFormat {
+    #[default]
+    Text,
+    /// The JSON array format of the Trace Event Format: every run of a loop,
+    /// from entering it to leaving it, is a slice named after where the loop
+    /// starts, and every `.` and `,` is an instant event.
+    Chrome,
+    /// The folded stacks `flamegraph.pl` and inferno read: a line per nest
+    /// of loops with the instructions executed in it, see `Stacks`.
+    Folded,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(TraceFormat::Text),
+            "chrome" => Ok(TraceFormat::Chrome),
+            "folded" => Ok(TraceFormat::Folded),
+            _ => Err(format!(
+                "Invalid trace format '{}', expected text, chrome or folded",
+                s
+            )),
+        }
+    }
+}
+
+pub struct Tracer<'a> {
+    out: Box<dyn Write + 'a>,
+    format: TraceFormat,
+    /// Instructions left before the log stops, if limited.
+    remaining: Option<u64>,
+    source: Option<SourceMap<'a>>,
+    /// When the trace started, which Chrome trace timestamps count from.
+    start: Instant,
+    /// The `[` of each loop entered and not left yet in a Chrome trace, and
+    /// how many times its body started.
+    loops: Vec<(usize, u64)>,
+    /// Whether a Chrome trace event has been written, so the next one needs
+    /// a comma.
+    started: bool,
+    stacks: Stacks,
+}
+
+impl<'a> Tracer<'a> {
+    pub fn new(
+        out: Box<dyn Write + 'a>,
+        format: TraceFormat,
+        limit: Option<u64>,
+        source: Option<SourceMap<'a>>,
+    ) -> Self {
+        Self {
+            out,
+            format,
+            remaining: limit,
+            source,
+            start: Instant::now(),
+            loops: vec![],
+            started: false,
+            stacks: Stacks::new(),
+        }
+    }
+
+    /// Logs the instruction `interpreter` is about to execute, along with
+    /// the data pointer and the value of the current cell, or the event it
+    /// is in a Chrome trace. Folded stacks only count it, and are written by
+    /// `finish`.
+    pub(crate) fn record(&mut self, interpreter: &BfInterpreter) -> Result<(), String> {
+        let Some(op) = interpreter.program().get(interpreter.pc()).copied() else {
+            return Ok(());
+        };
+        match &mut self.remaining {
+            Some(0) => return Ok(()),
+            Some(remaining) => *remaining -= 1,
+            None => {}
+        }
+
+        let result = match self.format {
+            TraceFormat::Text => self.line(interpreter, op),
+            TraceFormat::Chrome => self.chrome(interpreter, op),
+            TraceFormat::Folded => {
+                self.fold(interpreter, op);
+                Ok(())
+            }
+        };
+        result.map_err(|e| format!("Failed to write trace: {}", e))
+    }
+
+    fn line(&mut self, interpreter: &BfInterpreter, op: Op) -> std::io::Result<()> {
+        writeln!(
+            self.out,
+            "{:>10}  {:<10}  pc={} ptr={} cell={}  {}",
+            interpreter.stats().steps,
+            profile::location(self.source.as_ref(), interpreter.pc()),
+            interpreter.pc(),
+            interpreter.data_ptr(),
+            interpreter.current_cell(),
+            op
+        )
+        .and_then(|_| match self.remaining {
+            Some(0) => writeln!(self.out, "trace limit reached"),
+            _ => Ok(()),
+        })
+    }
+
+    /// Writes the Chrome trace event `op` makes, if any: entering a loop,
+    /// leaving it, or I/O. A loop that's skipped over isn't in the trace.
+    fn chrome(&mut self, interpreter: &BfInterpreter, op: Op) -> std::io::Result<()> {
+        let step = interpreter.stats().steps;
+        let cell = interpreter.current_cell();
+        match op {
+            Op::LoopStart(_) if cell != 0 => {
+                let start = interpreter.pc();
+                self.loops.push((start, 1));
+                self.event(start, "B", &format!("\"step\":{}", step))
+            }
+            Op::LoopEnd(_) if cell != 0 => {
+                if let Some((_, iterations)) = self.loops.last_mut() {
+                    *iterations += 1;
+                }
+                Ok(())
+            }
+            // A run resumed from a snapshot can leave a loop it was already
+            // in when the trace started.
+            Op::LoopEnd(_) => match self.loops.pop() {
+                Some((start, iterations)) => self.event(
+                    start,
+                    "E",
+                    &format!("\"step\":{},\"iterations\":{}", step, iterations),
+                ),
+                None => Ok(()),
+            },
+            Op::Output | Op::OutputNumber => {
+                self.instant("output", &format!("\"step\":{},\"value\":{}", step, cell))
+            }
+            Op::Input | Op::InputNumber => self.instant("input", &format!("\"step\":{}", step)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts `op` in the nest of loops it runs in. A `[` runs outside the
+    /// loop it enters, and a `]` inside the loop it leaves.
+    fn fold(&mut self, interpreter: &BfInterpreter, op: Op) {
+        self.stacks.count();
+        match op {
+            Op::LoopStart(_) if interpreter.current_cell() != 0 => {
+                self.stacks.enter(interpreter.pc())
+            }
+            Op::LoopEnd(_) if interpreter.current_cell() == 0 => self.stacks.leave(),
+            _ => {}
+        }
+    }
+
+    /// Writes the `ph` event of the loop starting at instruction `start`.
+    fn event(&mut self, start: usize, ph: &str, args: &str) -> std::io::Result<()> {
+        let name = format!("loop {}", profile::location(self.source.as_ref(), start));
+        self.write_event(&name, &format!("\"ph\":\"{}\"", ph), args)
+    }
+
+    fn instant(&mut self, name: &str, args: &str) -> std::io::Result<()> {
+        self.write_event(name, "\"ph\":\"i\",\"s\":\"t\"", args)
+    }
+
+    /// Names are locations and fixed words, which need no escaping.
+    fn write_event(&mut self, name: &str, phase: &str, args: &str) -> std::io::Result<()> {
+        let ts = self.start.elapsed().as_secs_f64() * 1e6;
+        let separator = if self.started { ",\n" } else { "[\n" };
+        self.started = true;
+        write!(
+            self.out,
+            "{}{{\"name\":\"{}\",{},\"ts\":{:.3},\"pid\":1,\"tid\":1,\"args\":{{{}}}}}",
+            separator, name, phase, ts, args
+        )
+    }
+
+    /// Ends the log and flushes it. The slices of the loops still running in
+    /// a Chrome trace, such as when the program stopped with an error, end
+    /// here, and folded stacks are written.
+    pub(crate) fn finish(&mut self) -> Result<(), String> {
+        let mut result = Ok(());
+        match self.format {
+            TraceFormat::Text => {}
+            TraceFormat::Chrome => {
+                while let Some((start, iterations)) = self.loops.pop() {
+                    let args = format!("\"iterations\":{}", iterations);
+                    result = result.and_then(|_| self.event(start, "E", &args));
+                }
+                let end = if self.started { "\n]\n" } else { "[]\n" };
+                result = result.and_then(|_| self.out.write_all(end.as_bytes()));
+            }
+            TraceFormat::Folded => {
+                let folded = self.stacks.folded(self.source.as_ref());
+                result = self.out.write_all(folded.as_bytes());
+            }
+        }
+        result
+            .and_then(|_| self.out.flush())
+            .map_err(|e| format!("Failed to write trace: {}", e))
+    }
+}
+
+/// The instructions executed in each nest of loops, kept as a tree with the
+/// code outside of any loop at the root, which is nest 0.
+struct Stacks {
+    nests: Vec<Nest>,
+    /// The nest each loop entered from a nest makes, by the index of the
+    /// outer nest and the `[`.
+    children: HashMap<(usize, usize), usize>,
+    /// The nest the instruction about to run is in.
+    current: usize,
+}
+
+struct Nest {
+    /// The `[` of the innermost loop, unused at the root.
+    start: usize,
+    parent: usize,
+    /// The instructions executed in this nest and not in one inside it.
+    steps: u64,
+}
+
+impl Stacks {
+    fn new() -> Self {
+        Stacks {
+            nests: vec![Nest {
+                start: 0,
+                parent: 0,
+                steps: 0,
+            }],
+            children: HashMap::new(),
+            current: 0,
+        }
+    }
+
+    fn count(&mut self) {
+        self.nests[self.current].steps += 1;
+    }
+
+    /// Moves into the loop starting at instruction `start`.
+    fn enter(&mut self, start: usize) {
+        let next = self.nests.len();
+        let current = self.current;
+        self.current = *self.children.entry((current, start)).or_insert(next);
+        if self.current == next {
+            self.nests.push(Nest {
+                start,
+                parent: current,
+                steps: 0,
+            });
+        }
+    }
+
+    /// Moves out of the innermost loop. A run resumed from a snapshot can
+    /// leave a loop it was already in when the trace started, which is
+    /// ignored.
+    fn leave(&mut self) {
+        self.current = self.nests[self.current].parent;
+    }
+
+    /// A line for each nest that executed instructions: the loops from the
+    /// outside in, named after where they start and separated by `;`, and
+    /// the count, sorted by the stack.
+    fn folded(&self, source: Option<&SourceMap>) -> String {
+        let mut lines: Vec<String> = self
+            .nests
+            .iter()
+            .enumerate()
+            .filter(|(_, nest)| nest.steps > 0)
+            .map(|(mut i, nest)| {
+                let mut frames = vec![];
+                while i != 0 {
+                    let start = self.nests[i].start;
+                    frames.push(format!("loop {}", profile::location(source, start)));
+                    i = self.nests[i].parent;
+                }
+                frames.push("program".to_owned());
+                frames.reverse();
+                format!("{} {}\n", frames.join(";"), nest.steps)
+            })
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::bf_interpreter::Ret;
+    use crate::config::Config;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Collects everything written to it, readable after the tracer is done.
+    #[derive(Clone, Default)]
+    struct Log(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Log {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_with_limit() {
+        let log = Log::default();
+        let mut tracer = Tracer::new(Box::new(log.clone()), TraceFormat::Text, Some(3), None);
+        let mut bf = BfInterpreter::builder().build(b"++>-<[-]").unwrap();
+        loop {
+            tracer.record(&bf).unwrap();
+            if bf.step().unwrap() == Ret::Finished {
+                break;
+            }
+        }
+
+        assert_eq!(
+            String::from_utf8(log.0.take()).unwrap(),
+            "         0  #0          pc=0 ptr=0 cell=0  add 2
+         1  #1          pc=1 ptr=0 cell=2  move 1
+         2  #2          pc=2 ptr=1 cell=0  add -1
+trace limit reached
+"
+        );
+    }
+
+    #[test]
+    fn chrome_trace() {
+        let log = Log::default();
+        let mut tracer = Tracer::new(Box::new(log.clone()), TraceFormat::Chrome, None, None);
+        let config = Config {
+            tape_size: 4,
+            ..Config::default()
+        };
+        let mut bf = BfInterpreter::builder()
+            .config(config)
+            .build(b"++[>+[-]<-]>.[]+[>+]")
+            .unwrap();
+        loop {
+            tracer.record(&bf).unwrap();
+            match bf.step() {
+                Ok(Ret::Finished) | Err(_) => break,
+                _ => {}
+            }
+        }
+        tracer.finish().unwrap();
+
+        // Timestamps differ from run to run.
+        let trace = String::from_utf8(log.0.take()).unwrap();
+        let events: Vec<String> = trace
+            .lines()
+            .map(|line| match line.split_once("\"ts\":") {
+                Some((before, after)) => before.to_owned() + &after[after.find(',').unwrap() + 1..],
+                None => line.to_owned(),
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "[",
+                r#"{"name":"loop #1","ph":"B","pid":1,"tid":1,"args":{"step":1}},"#,
+                r#"{"name":"loop #1","ph":"E","pid":1,"tid":1,"args":{"step":13,"iterations":2}},"#,
+                r#"{"name":"output","ph":"i","s":"t","pid":1,"tid":1,"args":{"step":15,"value":0}},"#,
+                r#"{"name":"loop #13","ph":"B","pid":1,"tid":1,"args":{"step":18}},"#,
+                r#"{"name":"loop #13","ph":"E","pid":1,"tid":1,"args":{"iterations":3}}"#,
+                "]",
+            ]
+        );
+    }
+
+    #[test]
+    fn folded_stacks() {
+        let log = Log::default();
+        let mut tracer = Tracer::new(Box::new(log.clone()), TraceFormat::Folded, None, None);
+        let mut bf = BfInterpreter::builder()
+            .build(b"++[>+++[>+<-]<-][>]>>[<]+")
+            .unwrap();
+        loop {
+            tracer.record(&bf).unwrap();
+            if bf.step().unwrap() == Ret::Finished {
+                break;
+            }
+        }
+        tracer.finish().unwrap();
+
+        assert_eq!(
+            String::from_utf8(log.0.take()).unwrap(),
+            "program 6\n\
+             program;loop #1 12\n\
+             program;loop #17 2\n\
+             program;loop #1;loop #4 30\n"
+        );
+    }
+}
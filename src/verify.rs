@@ -0,0 +1,309 @@
+//! `bf verify`: runs a program both with a naive interpreter that executes
+//! one command at a time and with the optimized one, and checks that they
+//! agree, to catch optimizer bugs.
+
+use crate::bf_interpreter::BfInterpreter;
+use crate::cli::{Input, VerifyOptions};
+use crate::config::{Config, Eof, TapeMode};
+use crate::parser::{find_matching_parens, parse_program, Token};
+use crate::tape::Tape;
+use std::io::Cursor;
+
+/// What a run left behind.
+#[derive(Debug)]
+pub(crate) struct Outcome {
+    pub(crate) result: Result<(), String>,
+    pub(crate) output: Vec<u8>,
+    /// The data pointer and the tape, unless the program ran in a process
+    /// of its own.
+    pub(crate) memory: Option<(usize, Tape)>,
+}
+
+pub fn run(options: VerifyOptions) -> Result<(), String> {
+    let program = crate::runner::read_file(&options.program)?;
+    if crate::bytecode::is_bytecode(&program) {
+        return Err("Bytecode has no source to verify against".to_owned());
+    }
+    let input = match options.input {
+        Input::Stdin => crate::runner::read_file("-")?,
+        Input::File(path) => crate::runner::read_file(&path)?,
+        Input::String(input) => input.into_bytes(),
+    };
+
+    let naive = run_naive(&program, &options.config, &input, options.max_steps)?;
+    if let Err(e) = &naive.result {
+        if e.starts_with("Step limit") {
+            return Err(format!("The naive run stopped: {}", e));
+        }
+    }
+    // The optimized run needs fewer steps, so the same limit is enough.
+    let optimized = run_optimized(&program, options.config, &input, options.max_steps)?;
+
+    compare(&naive, &optimized, ["naive", "optimized"])?;
+    println!(
+        "OK: the output ({} bytes), the data pointer and the tape match",
+        naive.output.len()
+    );
+    Ok(())
+}
+
+/// Runs `program` with the optimized interpreter, as `bf run` does.
+fn run_optimized(
+    program: &[u8],
+    config: Config,
+    input: &[u8],
+    max_steps: Option<u64>,
+) -> Result<Outcome, String> {
+    let mut interpreter = BfInterpreter::builder()
+        .config(config)
+        .max_steps(max_steps)
+        .build(program)?;
+    let mut output = vec![];
+    let result = crate::runner::run_interpreter(
+        &mut interpreter,
+        &mut Cursor::new(input),
+        &mut output,
+        None,
+        None,
+        None,
+        false,
+    );
+    Ok(Outcome {
+        result,
+        output,
+        memory: Some((interpreter.data_ptr(), interpreter.tape().clone())),
+    })
+}
+
+/// Checks that the runs `a` and `b`, called `names` in errors, agree. The
+/// data pointer and the tape are only compared if both runs have them.
+pub(crate) fn compare(a: &Outcome, b: &Outcome, names: [&str; 2]) -> Result<(), String> {
+    let [a_name, b_name] = names;
+    if a.result != b.result {
+        let describe = |result: &Result<(), String>| match result {
+            Ok(()) => "finished".to_owned(),
+            Err(e) => format!("failed with '{}'", e),
+        };
+        return Err(format!(
+            "The {} run {}, but the {} run {}",
+            a_name,
+            describe(&a.result),
+            b_name,
+            describe(&b.result)
+        ));
+    }
+    if a.output != b.output {
+        let i = a
+            .output
+            .iter()
+            .zip(&b.output)
+            .position(|(a, b)| a != b)
+            .unwrap_or(a.output.len().min(b.output.len()));
+        return Err(format!(
+            "The output differs at byte {}: {:?} {}, {:?} {}",
+            i,
+            a.output.get(i),
+            a_name,
+            b.output.get(i),
+            b_name
+        ));
+    }
+    let (Some((a_ptr, a_tape)), Some((b_ptr, b_tape))) = (&a.memory, &b.memory) else {
+        return Ok(());
+    };
+    if a_ptr != b_ptr {
+        return Err(format!(
+            "The data pointer differs: {} {}, {} {}",
+            a_ptr, a_name, b_ptr, b_name
+        ));
+    }
+    // A growing tape may have grown differently, which doesn't matter as
+    // long as the extra cells are 0.
+    let cell = |tape: &Tape, i: usize| if i < tape.len() { tape.get(i) } else { 0 };
+    let mut cells: Vec<usize> = a_tape
+        .nonzero()
+        .chain(b_tape.nonzero())
+        .map(|(i, _)| i)
+        .collect();
+    cells.sort_unstable();
+    cells.dedup();
+    for i in cells {
+        let (a, b) = (cell(a_tape, i), cell(b_tape, i));
+        if a != b {
+            return Err(format!(
+                "Cell {} differs: {} {}, {} {}",
+                i, a, a_name, b, b_name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Runs `program` one command at a time, with none of the optimizations of
+/// `ir`.
+pub(crate) fn run_naive(
+    program: &[u8],
+    config: &Config,
+    input: &[u8],
+    max_steps: Option<u64>,
+) -> Result<Outcome, String> {
+    let tokens = parse_program(program, false);
+    let matching = find_matching_parens(&tokens)?;
+    let mut result = Ok(());
+    let mut output = vec![];
+    let mut ptr = 0;
+    let mut tape = Tape::new(config.cell_size, config.tape_size);
+    let mut input = input.iter();
+    let mut steps = 0;
+    let mut pc = 0;
+
+    while pc < tokens.len() {
+        if max_steps == Some(steps) {
+            result = Err(format!("Step limit of {} exceeded", steps));
+            break;
+        }
+        steps += 1;
+
+        match tokens[pc] {
+            Token::IncByte => tape.set(ptr, tape.get(ptr).wrapping_add(1)),
+            Token::DecByte => tape.set(ptr, tape.get(ptr).wrapping_sub(1)),
+            Token::IncDataPtr | Token::DecDataPtr => {
+                let right = tokens[pc] == Token::IncDataPtr;
+                match move_pointer(&mut tape, ptr, right, config.tape_mode) {
+                    Ok(moved) => ptr = moved,
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+            Token::WriteByte => output.push(tape.get(ptr) as u8),
+            Token::ReadByte => match (input.next(), config.eof) {
+                (Some(&byte), _) => tape.set(ptr, byte as u32),
+                (None, Eof::Zero) => tape.set(ptr, 0),
+                (None, Eof::MinusOne) => tape.set(ptr, u32::MAX),
+                (None, Eof::Unchanged) => {}
+                (None, Eof::Halt) => break,
+            },
+            Token::BeginLoop if tape.get(ptr) == 0 => pc = matching[&pc],
+            Token::EndLoop if tape.get(ptr) != 0 => pc = matching[&pc],
+            Token::BeginLoop | Token::EndLoop | Token::Debug => {}
+            Token::BeginProcedure
+            | Token::EndProcedure
+            | Token::Call
+            | Token::Fork
+            | Token::Extended(_)
+            | Token::Plugin(_)
+            | Token::WriteNumber
+            | Token::ReadNumber => {
+                unreachable!("extension in a Brainfuck program")
+            }
+        }
+        pc += 1;
+    }
+    Ok(Outcome {
+        result,
+        output,
+        memory: Some((ptr, tape)),
+    })
+}
+
+fn move_pointer(tape: &mut Tape, ptr: usize, right: bool, mode: TapeMode) -> Result<usize, String> {
+    let len = tape.len();
+    match (right, mode) {
+        (false, TapeMode::Wrap) => Ok((ptr + len - 1) % len),
+        (false, _) if ptr == 0 => Err("Memory underflow".to_owned()),
+        (false, _) => Ok(ptr - 1),
+        (true, TapeMode::Wrap) => Ok((ptr + 1) % len),
+        (true, TapeMode::Fixed) if ptr + 1 == len => Err("Memory overflow".to_owned()),
+        (true, TapeMode::Grow) if ptr + 1 == len => {
+            tape.resize(len + 1);
+            Ok(ptr + 1)
+        }
+        (true, _) => Ok(ptr + 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn outcome(output: &[u8], data_ptr: usize, cells: &[u32]) -> Outcome {
+        let mut tape = Tape::new(crate::config::CellSize::Bits8, 4);
+        for (i, &value) in cells.iter().enumerate() {
+            tape.set(i, value);
+        }
+        Outcome {
+            result: Ok(()),
+            output: output.to_vec(),
+            memory: Some((data_ptr, tape)),
+        }
+    }
+
+    #[test]
+    fn naive_run() {
+        let config = Config {
+            tape_size: 4,
+            eof: Eof::Zero,
+            ..Config::default()
+        };
+        let naive = run_naive(b",[>+++<-]>.<<", &config, b"\x02", None).unwrap();
+        assert_eq!(naive.result, Err("Memory underflow".to_owned()));
+        assert_eq!(naive.output, [6]);
+        let (data_ptr, tape) = naive.memory.unwrap();
+        assert_eq!(data_ptr, 0);
+        assert_eq!((tape.get(0), tape.get(1)), (0, 6));
+
+        let naive = run_naive(b"+[]", &config, b"", Some(10)).unwrap();
+        assert_eq!(naive.result, Err("Step limit of 10 exceeded".to_owned()));
+    }
+
+    #[test]
+    fn verify_random_programs() {
+        let config = Config {
+            tape_size: 16,
+            ..Config::default()
+        };
+        for seed in 0..200 {
+            let program = crate::gen::random(100, seed, 0.1);
+            let naive = run_naive(program.as_bytes(), &config, b"abc", Some(10_000)).unwrap();
+            if naive
+                .result
+                .as_ref()
+                .is_err_and(|e| e.starts_with("Step limit"))
+            {
+                continue;
+            }
+            let optimized =
+                run_optimized(program.as_bytes(), config.clone(), b"abc", Some(10_000)).unwrap();
+            if let Err(e) = compare(&naive, &optimized, ["naive", "optimized"]) {
+                panic!("seed {}: {}: {}", seed, program, e);
+            }
+        }
+    }
+
+    #[test]
+    fn compare_outcomes() {
+        let names = ["naive", "optimized"];
+        let a = outcome(b"ab", 1, &[0, 3]);
+        assert!(compare(&a, &outcome(b"ab", 1, &[0, 3]), names).is_ok());
+        assert_eq!(
+            compare(&a, &outcome(b"ac", 1, &[0, 3]), names).unwrap_err(),
+            "The output differs at byte 1: Some(98) naive, Some(99) optimized"
+        );
+        assert_eq!(
+            compare(&a, &outcome(b"ab", 1, &[0, 3, 1]), names).unwrap_err(),
+            "Cell 2 differs: 0 naive, 1 optimized"
+        );
+        let mut failed = outcome(b"ab", 1, &[0, 3]);
+        failed.result = Err("Memory overflow".to_owned());
+        assert_eq!(
+            compare(&a, &failed, names).unwrap_err(),
+            "The naive run finished, but the optimized run failed with 'Memory overflow'"
+        );
+        let mut output_only = outcome(b"ab", 0, &[]);
+        output_only.memory = None;
+        assert!(compare(&a, &output_only, ["naive", "c"]).is_ok());
+    }
+}
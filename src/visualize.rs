@@ -0,0 +1,346 @@
+//! `bf visualize`: runs a program in a full-screen terminal view of its
+//! source, tape and output, at an adjustable speed.
+
+use crate::bf_interpreter::BfInterpreter;
+use crate::bytecode;
+use crate::cli::{Input, VisualizeOptions};
+use crate::ir;
+use crate::profile::{self, SourceMap};
+use crate::raw_input::{self, RawMode, CTRL_C};
+use std::io::{Cursor, IsTerminal, Read, Write};
+
+/// Instructions run per frame at each speed. A frame lasts about a tenth of
+/// a second, the time a key read waits.
+const SPEEDS: [usize; 4] = [1, 10, 100, 1000];
+
+/// Rows taken by everything but the source: the header, the tape and output
+/// panes with their titles, and the help line.
+const FIXED_ROWS: usize = 9;
+const OUTPUT_ROWS: usize = 3;
+/// Width of a cell in the tape pane.
+const CELL_WIDTH: usize = 6;
+
+const REVERSE: &str = "\x1b[7m";
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+pub fn run(options: VisualizeOptions) -> Result<(), String> {
+    if !std::io::stdin().is_terminal() || !std::io::stdout().is_terminal() {
+        return Err("bf visualize needs a terminal".to_owned());
+    }
+    let content = crate::runner::read_file(&options.program)?;
+    if bytecode::is_bytecode(&content) {
+        return Err("Bytecode can't be visualized, use the Brainfuck source".to_owned());
+    }
+    let (ops, offsets) = ir::compile_with_offsets(&content, false)?;
+    let interpreter = BfInterpreter::builder()
+        .config(options.config)
+        .build_ops(ops)?;
+    // Stdin is the keyboard, so `,` only has input when it's given as an
+    // option.
+    let input: Box<dyn Read> = match options.input {
+        Input::Stdin => Box::new(std::io::empty()),
+        Input::File(path) => Box::new(Cursor::new(crate::runner::read_file(&path)?)),
+        Input::String(input) => Box::new(Cursor::new(input.into_bytes())),
+    };
+    let mut visualizer = Visualizer {
+        interpreter,
+        source: SourceMap {
+            source: &content,
+            offsets: &offsets,
+        },
+        input,
+        output: vec![],
+        speed: 0,
+        paused: true,
+        status: None,
+    };
+
+    let screen = Screen::enter()?;
+    let mut stdout = std::io::stdout();
+    let result = visualizer.run(&mut stdout);
+    drop(screen);
+
+    // The screen is gone, so show what the program printed.
+    stdout
+        .write_all(&visualizer.output)
+        .and_then(|_| stdout.flush())
+        .map_err(|e| format!("Failed to write output: {}", e))?;
+    result
+}
+
+/// Keeps the terminal in raw mode on the alternate screen, which keeps the
+/// contents from before intact, with the cursor hidden. Dropping it puts
+/// the terminal back as it was, including when unwinding from a panic.
+struct Screen {
+    _raw_mode: RawMode,
+}
+
+impl Screen {
+    fn enter() -> Result<Screen, String> {
+        let raw_mode = RawMode::enable_polling()?;
+        let mut stdout = std::io::stdout();
+        stdout
+            .write_all(b"\x1b[?1049h\x1b[?25l")
+            .and_then(|_| stdout.flush())
+            .map_err(|e| format!("Failed to draw: {}", e))?;
+        Ok(Screen {
+            _raw_mode: raw_mode,
+        })
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        // Nothing left to do if this fails. Raw mode ends after this, when
+        // the field is dropped.
+        let mut stdout = std::io::stdout();
+        let _ = stdout
+            .write_all(b"\x1b[?25h\x1b[?1049l")
+            .and_then(|_| stdout.flush());
+    }
+}
+
+struct Visualizer<'a> {
+    interpreter: BfInterpreter,
+    source: SourceMap<'a>,
+    input: Box<dyn Read>,
+    /// Everything the program has written.
+    output: Vec<u8>,
+    /// Index into `SPEEDS`.
+    speed: usize,
+    paused: bool,
+    /// Why the program stopped, once it has.
+    status: Option<String>,
+}
+
+impl Visualizer<'_> {
+    fn run(&mut self, stdout: &mut dyn Write) -> Result<(), String> {
+        let mut stdin = std::io::stdin();
+        loop {
+            let (height, width) = raw_input::terminal_size().unwrap_or((24, 80));
+            let mut frame = String::from("\x1b[H");
+            for line in self.render(width, height) {
+                frame.push_str(&line);
+                frame.push_str("\x1b[K\r\n");
+            }
+            frame.push_str("\x1b[J");
+            stdout
+                .write_all(frame.as_bytes())
+                .and_then(|_| stdout.flush())
+                .map_err(|e| format!("Failed to draw: {}", e))?;
+
+            let mut key = [0u8; 1];
+            let read = stdin
+                .read(&mut key)
+                .map_err(|e| format!("Failed to read a key: {}", e))?;
+            if read == 1 {
+                match key[0] {
+                    b'q' | CTRL_C => return Ok(()),
+                    b' ' => self.paused = !self.paused,
+                    b's' => {
+                        self.paused = true;
+                        self.step();
+                    }
+                    b'+' | b'=' => self.speed = (self.speed + 1).min(SPEEDS.len() - 1),
+                    b'-' => self.speed = self.speed.saturating_sub(1),
+                    _ => {}
+                }
+            }
+            if !self.paused {
+                for _ in 0..SPEEDS[self.speed] {
+                    self.step();
+                }
+            }
+        }
+    }
+
+    /// Runs one instruction, unless the program has stopped.
+    fn step(&mut self) {
+        if self.status.is_some() {
+            return;
+        }
+        if self.interpreter.pc() >= self.interpreter.program().len() {
+            self.status = Some("finished".to_owned());
+            return;
+        }
+        let result = self.interpreter.step().and_then(|ret| {
+            crate::runner::handle_ret(
+                &mut self.interpreter,
+                ret,
+                &mut self.input,
+                &mut self.output,
+                false,
+            )
+        });
+        if let Err(e) = result {
+            self.status = Some(format!("error: {}", e));
+        }
+    }
+
+    /// The screen as lines of at most `width` visible characters.
+    fn render(&self, width: usize, height: usize) -> Vec<String> {
+        let state = match &self.status {
+            Some(status) => status.clone(),
+            None if self.paused => "paused".to_owned(),
+            None => format!("running at {}x", SPEEDS[self.speed]),
+        };
+        let mut lines = vec![format!(
+            "{}bf visualize{}  {}  {} steps",
+            BOLD,
+            RESET,
+            state,
+            self.interpreter.stats().steps
+        )];
+        lines.extend(self.render_source(width, height.saturating_sub(FIXED_ROWS).max(1)));
+        lines.push(format!("{}tape{}", DIM, RESET));
+        lines.extend(self.render_tape(width));
+        lines.push(format!("{}output{}", DIM, RESET));
+        lines.extend(self.render_output(width));
+        lines.push(format!(
+            "{}space run/pause  s step  +/- speed  q quit{}",
+            DIM, RESET
+        ));
+        lines
+    }
+
+    /// `rows` lines of source around the next instruction, which is shown
+    /// reversed.
+    fn render_source(&self, width: usize, rows: usize) -> Vec<String> {
+        let pc = self.interpreter.pc();
+        let current = self
+            .source
+            .offsets
+            .get(pc)
+            .map(|&offset| profile::position(self.source.source, offset));
+        let source_lines = self
+            .source
+            .source
+            .split(|&b| b == b'\n')
+            .collect::<Vec<_>>();
+        let first = current
+            .map_or(0, |(line, _)| (line - 1).saturating_sub(rows / 2))
+            .min(source_lines.len().saturating_sub(rows));
+
+        // Line numbers take 5 columns.
+        let text_width = width.saturating_sub(5).max(1);
+        let mut lines = vec![];
+        for (i, line) in source_lines.iter().enumerate().skip(first).take(rows) {
+            let column = current
+                .filter(|&(current_line, _)| current_line == i + 1)
+                .map(|(_, column)| column - 1);
+            // Scroll long lines so the current instruction stays visible.
+            let start = match column {
+                Some(column) if column >= text_width => column + 1 - text_width / 2,
+                _ => 0,
+            };
+            let mut text = format!("{}{:>4}{} ", DIM, i + 1, RESET);
+            for (j, &byte) in line.iter().enumerate().skip(start).take(text_width) {
+                let c = if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    ' '
+                };
+                if Some(j) == column {
+                    text.push_str(&format!("{}{}{}", REVERSE, c, RESET));
+                } else {
+                    text.push(c);
+                }
+            }
+            lines.push(text);
+        }
+        lines.resize(rows, String::new());
+        lines
+    }
+
+    /// Indices and values of the cells around the data pointer, whose cell
+    /// is shown reversed.
+    fn render_tape(&self, width: usize) -> Vec<String> {
+        let tape = self.interpreter.tape();
+        let data_ptr = self.interpreter.data_ptr();
+        let count = (width / CELL_WIDTH).max(1).min(tape.len());
+        let first = data_ptr.saturating_sub(count / 2).min(tape.len() - count);
+
+        let mut indices = String::new();
+        let mut values = String::new();
+        for i in first..first + count {
+            indices.push_str(&format!("{}{:>5}{} ", DIM, i, RESET));
+            if i == data_ptr {
+                values.push_str(&format!("{}{:>5}{} ", REVERSE, tape.get(i), RESET));
+            } else {
+                values.push_str(&format!("{:>5} ", tape.get(i)));
+            }
+        }
+        vec![indices, values]
+    }
+
+    /// The last lines of output, with control characters left out.
+    fn render_output(&self, width: usize) -> Vec<String> {
+        let output = String::from_utf8_lossy(&self.output);
+        let mut lines = output
+            .split('\n')
+            .map(|line| {
+                line.chars()
+                    .filter(|c| !c.is_control())
+                    .take(width)
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>();
+        let skip = lines.len().saturating_sub(OUTPUT_ROWS);
+        lines.drain(..skip);
+        lines.resize(OUTPUT_ROWS, String::new());
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn render_frame() {
+        let source = b"+++++ +++\n[>++ ++++<-]>.\n";
+        let (ops, offsets) = ir::compile_with_offsets(source, false).unwrap();
+        let mut visualizer = Visualizer {
+            interpreter: BfInterpreter::builder().build_ops(ops).unwrap(),
+            source: SourceMap {
+                source,
+                offsets: &offsets,
+            },
+            input: Box::new(std::io::empty()),
+            output: vec![],
+            speed: 1,
+            paused: false,
+            status: None,
+        };
+        for _ in 0..4 {
+            visualizer.step();
+        }
+
+        assert_eq!(
+            visualizer.render(20, 12),
+            [
+                "\x1b[1mbf visualize\x1b[0m  running at 10x  4 steps",
+                "\x1b[2m   1\x1b[0m +++++ +++",
+                "\x1b[2m   2\x1b[0m [>++ ++++\x1b[7m<\x1b[0m-]>.",
+                "\x1b[2m   3\x1b[0m ",
+                "\x1b[2mtape\x1b[0m",
+                "\x1b[2m    0\x1b[0m \x1b[2m    1\x1b[0m \x1b[2m    2\x1b[0m ",
+                "    8 \x1b[7m    6\x1b[0m     0 ",
+                "\x1b[2moutput\x1b[0m",
+                "",
+                "",
+                "",
+                "\x1b[2mspace run/pause  s step  +/- speed  q quit\x1b[0m"
+            ]
+        );
+
+        for _ in 0..100 {
+            visualizer.step();
+        }
+        assert_eq!(visualizer.output, b"0");
+        assert_eq!(visualizer.status.as_deref(), Some("finished"));
+    }
+}
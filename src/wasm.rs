@@ -0,0 +1,128 @@
+//! JavaScript bindings for a browser playground, built with
+//! `cargo build --lib --target wasm32-unknown-unknown --features wasm` and
+//! `wasm-bindgen`:
+//!
+//! ```js
+//! const session = new BfSession();
+//! session.load(",[.,]");
+//! session.feedInput(new TextEncoder().encode("hi"));
+//! session.closeInput();
+//! while (session.step(10000) === Status.Running) {}
+//! console.log(new TextDecoder().decode(session.takeOutput()));
+//! ```
+
+use crate::config::Config;
+use crate::embed::{self, Session};
+use crate::gen;
+use wasm_bindgen::prelude::*;
+
+/// Where a run stopped after `BfSession.step`.
+#[wasm_bindgen]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Status {
+    Running,
+    Input,
+    OutOfFuel,
+    Finished,
+}
+
+impl From<embed::Status> for Status {
+    fn from(status: embed::Status) -> Self {
+        match status {
+            embed::Status::Running => Status::Running,
+            embed::Status::Input => Status::Input,
+            embed::Status::OutOfFuel => Status::OutOfFuel,
+            embed::Status::Finished => Status::Finished,
+        }
+    }
+}
+
+/// A program and its tape, see `embed::Session`. Errors are thrown as
+/// `Error`s with the message `bf run` would print.
+#[wasm_bindgen]
+pub struct BfSession(Session);
+
+#[wasm_bindgen]
+impl BfSession {
+    /// A session with the default tape and cells, and an empty program.
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Result<BfSession, JsError> {
+        Ok(BfSession(Session::new(Config::default()).map_err(error)?))
+    }
+
+    /// Starts `program` from the beginning on a fresh tape.
+    pub fn load(&mut self, program: &str) -> Result<(), JsError> {
+        self.0.load(program.as_bytes()).map_err(error)
+    }
+
+    /// Runs up to `n` instructions.
+    pub fn step(&mut self, n: u32) -> Result<Status, JsError> {
+        self.0.step(n).map(Status::from).map_err(error)
+    }
+
+    /// Gives the program `fuel`, a `BigInt`, to burn; `step` returns
+    /// `Status.OutOfFuel` once it's used up.
+    #[wasm_bindgen(js_name = setFuel)]
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.0.set_fuel(fuel);
+    }
+
+    /// Adds fuel, for `step` to go on after `Status.OutOfFuel`.
+    #[wasm_bindgen(js_name = addFuel)]
+    pub fn add_fuel(&mut self, fuel: u64) {
+        self.0.add_fuel(fuel);
+    }
+
+    /// The fuel left, or `undefined` if the program isn't metered.
+    #[wasm_bindgen(getter)]
+    pub fn fuel(&self) -> Option<u64> {
+        self.0.fuel()
+    }
+
+    /// Adds bytes for `,` to read.
+    #[wasm_bindgen(js_name = feedInput)]
+    pub fn feed_input(&mut self, input: &[u8]) {
+        self.0.feed_input(input);
+    }
+
+    /// Ends the input once what was fed is read.
+    #[wasm_bindgen(js_name = closeInput)]
+    pub fn close_input(&mut self) {
+        self.0.close_input();
+    }
+
+    /// The bytes printed since the last call, as a `Uint8Array`.
+    #[wasm_bindgen(js_name = takeOutput)]
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.0.take_output()
+    }
+
+    /// The cells from `start` up to `end`, as a `Uint32Array`.
+    #[wasm_bindgen(js_name = tapeSlice)]
+    pub fn tape_slice(&self, start: usize, end: usize) -> Vec<u32> {
+        self.0.tape_slice(start..end)
+    }
+
+    #[wasm_bindgen(getter, js_name = dataPointer)]
+    pub fn data_pointer(&self) -> usize {
+        self.0.data_ptr()
+    }
+
+    /// The instructions executed since the program was loaded.
+    #[wasm_bindgen(getter)]
+    pub fn steps(&self) -> u64 {
+        self.0.steps()
+    }
+}
+
+/// A random program of `size` commands with balanced brackets, for fuzzing.
+/// The same `seed` gives the same program, and `loops`, from 0 to 1, is the
+/// chance of each command being a bracket.
+#[wasm_bindgen(js_name = genRandom)]
+pub fn gen_random(size: usize, seed: u64, loops: f64) -> String {
+    gen::random(size, seed, loops)
+}
+
+fn error(message: String) -> JsError {
+    JsError::new(&message)
+}
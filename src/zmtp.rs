@@ -0,0 +1,182 @@
+//! Just enough of ZMTP 3.0, the wire protocol of ZeroMQ, for `bf kernel` to
+//! talk to Jupyter over TCP: the greeting and handshake with the NULL
+//! mechanism, and multi-part messages. Each connection is handled on its
+//! own, so the routing and fan-out of ZeroMQ sockets is left to the caller.
+
+use std::io::{Read, Write};
+
+/// Frame flags.
+const MORE: u8 = 1;
+const LONG: u8 = 2;
+const COMMAND: u8 = 4;
+
+/// Largest frame body accepted. The length comes from the peer before it
+/// can be checked, so it's bounded before allocating the body; Jupyter
+/// messages are far smaller.
+const MAX_FRAME_SIZE: u64 = 64 << 20;
+
+/// Sends the greeting and the READY command as a `socket_type` socket, such
+/// as `ROUTER`, and waits for the peer's.
+pub(crate) fn handshake(stream: &mut (impl Read + Write), socket_type: &str) -> Result<(), String> {
+    let mut greeting = [0; 64];
+    greeting[0] = 0xff;
+    greeting[9] = 0x7f;
+    // Version 3.0.
+    greeting[10] = 3;
+    greeting[12..16].copy_from_slice(b"NULL");
+    stream
+        .write_all(&greeting)
+        .map_err(|e| format!("Failed to send the greeting: {}", e))?;
+
+    stream
+        .read_exact(&mut greeting)
+        .map_err(|e| format!("Failed to read the greeting: {}", e))?;
+    if greeting[0] != 0xff || greeting[9] & 1 != 1 || greeting[10] < 3 {
+        return Err("Not a ZMTP 3 peer".to_owned());
+    }
+    if &greeting[12..32] != b"NULL\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0" {
+        return Err("Unsupported security mechanism, expected NULL".to_owned());
+    }
+
+    let mut ready = vec![5];
+    ready.extend_from_slice(b"READY");
+    ready.push(11);
+    ready.extend_from_slice(b"Socket-Type");
+    ready.extend_from_slice(&(socket_type.len() as u32).to_be_bytes());
+    ready.extend_from_slice(socket_type.as_bytes());
+    let mut frame = vec![];
+    write_frame(&mut frame, COMMAND, &ready);
+    stream
+        .write_all(&frame)
+        .map_err(|e| format!("Failed to send the handshake: {}", e))?;
+
+    let (flags, body) = read_frame(stream)?.ok_or("Connection closed in the handshake")?;
+    if flags & COMMAND == 0 || !body.starts_with(b"\x05READY") {
+        return Err("Expected a READY command".to_owned());
+    }
+    Ok(())
+}
+
+/// Reads the frames of the next message, skipping commands. Returns `None`
+/// once the peer closes the connection.
+pub(crate) fn read_message(stream: &mut impl Read) -> Result<Option<Vec<Vec<u8>>>, String> {
+    let mut frames = vec![];
+    loop {
+        let Some((flags, body)) = read_frame(stream)? else {
+            if frames.is_empty() {
+                return Ok(None);
+            }
+            return Err("Connection closed in the middle of a message".to_owned());
+        };
+        if flags & COMMAND != 0 {
+            continue;
+        }
+        frames.push(body);
+        if flags & MORE == 0 {
+            return Ok(Some(frames));
+        }
+    }
+}
+
+/// Sends `frames` as one message.
+pub(crate) fn write_message(stream: &mut impl Write, frames: &[Vec<u8>]) -> Result<(), String> {
+    let mut out = vec![];
+    for (i, frame) in frames.iter().enumerate() {
+        let more = if i + 1 < frames.len() { MORE } else { 0 };
+        write_frame(&mut out, more, frame);
+    }
+    stream
+        .write_all(&out)
+        .map_err(|e| format!("Failed to send a message: {}", e))
+}
+
+fn write_frame(out: &mut Vec<u8>, flags: u8, body: &[u8]) {
+    if body.len() > u8::MAX as usize {
+        out.push(flags | LONG);
+        out.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    } else {
+        out.push(flags);
+        out.push(body.len() as u8);
+    }
+    out.extend_from_slice(body);
+}
+
+/// Reads a frame's flags and body, or `None` at the end of the stream.
+fn read_frame(stream: &mut impl Read) -> Result<Option<(u8, Vec<u8>)>, String> {
+    let error = |e: std::io::Error| format!("Failed to read a message: {}", e);
+    let mut flags = [0];
+    if stream.read(&mut flags).map_err(error)? == 0 {
+        return Ok(None);
+    }
+    let len = if flags[0] & LONG != 0 {
+        let mut len = [0; 8];
+        stream.read_exact(&mut len).map_err(error)?;
+        u64::from_be_bytes(len)
+    } else {
+        let mut len = [0];
+        stream.read_exact(&mut len).map_err(error)?;
+        len[0] as u64
+    };
+    if len > MAX_FRAME_SIZE {
+        return Err(format!(
+            "Frame of {} bytes is over the limit of {}",
+            len, MAX_FRAME_SIZE
+        ));
+    }
+    let mut body = vec![0; len as usize];
+    stream.read_exact(&mut body).map_err(error)?;
+    Ok(Some((flags[0], body)))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::Cursor;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn frames() {
+        let long = vec![7; 300];
+        let mut out = vec![];
+        write_message(&mut out, &[b"id".to_vec(), vec![], long.clone()]).unwrap();
+        assert_eq!(&out[..6], b"\x01\x02id\x01\x00");
+        assert_eq!(&out[6..15], b"\x02\x00\x00\x00\x00\x00\x00\x01\x2c");
+
+        // A command between messages is skipped.
+        let mut input = vec![];
+        write_frame(&mut input, COMMAND, b"\x04PING");
+        input.extend_from_slice(&out);
+        let mut input = Cursor::new(input);
+        assert_eq!(
+            read_message(&mut input).unwrap(),
+            Some(vec![b"id".to_vec(), vec![], long])
+        );
+        assert_eq!(read_message(&mut input).unwrap(), None);
+        assert!(read_message(&mut Cursor::new(b"\x01\x01a")).is_err());
+        // A huge length is refused before allocating the body.
+        assert_eq!(
+            read_message(&mut Cursor::new(b"\x02\xff\xff\xff\xff\xff\xff\xff\xff")).unwrap_err(),
+            "Frame of 18446744073709551615 bytes is over the limit of 67108864"
+        );
+    }
+
+    #[test]
+    fn handshake_with_a_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            handshake(&mut stream, "DEALER").unwrap();
+            write_message(&mut stream, &[b"ping".to_vec()]).unwrap();
+            read_message(&mut stream).unwrap()
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, "ROUTER").unwrap();
+        let message = read_message(&mut stream).unwrap().unwrap();
+        assert_eq!(message, [b"ping"]);
+        write_message(&mut stream, &[b"pong".to_vec()]).unwrap();
+        assert_eq!(peer.join().unwrap(), Some(vec![b"pong".to_vec()]));
+    }
+}